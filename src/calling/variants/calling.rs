@@ -7,25 +7,29 @@ use anyhow::{Context, Result};
 use bio::stats::{bayesian, LogProb};
 use derive_builder::Builder;
 use itertools::Itertools;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rust_htslib::bcf::{self, Read};
 
+use crate::calling::variants::hotspots::SomaticHotspots;
+use crate::calling::variants::population::PopulationAlleleFreqs;
 use crate::calling::variants::preprocessing::{
     read_observations, remove_observation_header_entries, OBSERVATION_FORMAT_VERSION,
 };
-use crate::calling::variants::SampleInfo;
 use crate::calling::variants::{
     chrom, event_tag_name, Call, CallBuilder, SampleInfoBuilder, VariantBuilder,
 };
+use crate::calling::variants::{AlleleFreqPosterior, SampleInfo};
 use crate::errors;
 use crate::grammar;
 use crate::utils;
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
 use crate::variants::model;
+use crate::variants::model::bias::{BiasContext, BiasFlags, BiasParams, Biases};
 use crate::variants::model::modes::generic::{
     self, GenericLikelihood, GenericModelBuilder, GenericPosterior,
 };
+use crate::variants::model::AlleleFreq;
 use crate::variants::model::Contamination;
-use crate::variants::model::{bias::Biases, AlleleFreq};
 use crate::variants::types::breakends::BreakendIndex;
 
 pub(crate) type AlleleFreqCombination = Vec<model::likelihood::Event>;
@@ -33,6 +37,9 @@ pub(crate) type AlleleFreqCombination = Vec<model::likelihood::Event>;
 pub(crate) type Model<Pr> =
     bayesian::Model<GenericLikelihood, Pr, GenericPosterior, generic::Cache>;
 
+/// Seed for sampling the records used for learning the prior, making results reproducible.
+const PRIOR_LEARNING_SEED: u64 = 8473;
+
 #[derive(Builder)]
 #[builder(pattern = "owned")]
 pub(crate) struct Caller<Pr>
@@ -41,17 +48,31 @@ where
 {
     samplenames: grammar::SampleInfo<String>,
     observations: grammar::SampleInfo<Option<PathBuf>>,
-    omit_strand_bias: bool,
-    omit_read_orientation_bias: bool,
-    omit_read_position_bias: bool,
-    omit_softclip_bias: bool,
-    omit_divindel_bias: bool,
-    min_divindel_other_rate: f64,
+    omit_biases: BiasFlags,
+    bias_params: BiasParams,
+    ffpe_samples: grammar::SampleInfo<bool>,
     scenario: grammar::Scenario,
     outbcf: Option<PathBuf>,
     contaminations: grammar::SampleInfo<Option<Contamination>>,
     resolutions: grammar::SampleInfo<usize>,
+    /// Width of the allele frequency credible interval to report, if any.
+    #[builder(default)]
+    af_credible_interval: Option<f64>,
+    /// Whether to report the discretized allele frequency posterior.
+    #[builder(default)]
+    af_posterior_density: bool,
     prior: Pr,
+    /// Learn the prior hyperparameters from the given number of records before calling.
+    #[builder(default)]
+    learn_prior: Option<usize>,
+    /// Indexed VCF/BCF with population allele frequencies, used for a site specific
+    /// germline prior.
+    #[builder(default)]
+    population_allele_freqs: Option<PathBuf>,
+    /// Weights of recurrent somatic mutation hotspots, used to locally scale the somatic
+    /// effective mutation rate.
+    #[builder(default)]
+    somatic_hotspots: Option<grammar::SomaticHotspots>,
     breakend_index: BreakendIndex,
     #[builder(default)]
     breakend_results: RwLock<HashMap<Vec<u8>, BreakendResult>>,
@@ -62,6 +83,7 @@ where
     Pr: bayesian::model::Prior<Event = AlleleFreqCombination>
         + model::prior::UpdatablePrior
         + model::prior::CheckablePrior
+        + model::prior::LearnablePrior
         + Clone
         + Default,
 {
@@ -69,7 +91,7 @@ where
         self.samplenames.len()
     }
 
-    pub(crate) fn header(&self) -> Result<bcf::Header> {
+    pub(crate) fn header(&self, learned_prior: Option<&Pr>) -> Result<bcf::Header> {
         let mut header = bcf::Header::from_template(
             bcf::Reader::from_path(self.observations.first_not_none().as_ref().unwrap())?.header(),
         );
@@ -104,6 +126,38 @@ where
              Description=\"Posterior probability for not having a variant (PHRED)\">",
         );
 
+        if let Some(hotspots) = &self.somatic_hotspots {
+            header.push_record(
+                format!(
+                    "##varlociraptor_somatic_hotspot_weighting=At somatic mutation hotspots \
+                     given by {}, the somatic effective mutation rate is multiplied by \
+                     1 + hotspot weight (capped such that the probability for a somatic mutation \
+                     does not exceed 1). Weights are taken from {}.",
+                    hotspots.path().display(),
+                    if hotspots.is_bed() {
+                        "the fourth column".to_owned()
+                    } else {
+                        format!("the INFO field {}", hotspots.info_field())
+                    }
+                )
+                .as_bytes(),
+            );
+        }
+
+        if let Some(prior) = learned_prior {
+            // record learned prior parameters for reproducibility
+            for (parameter, value) in prior.parameters() {
+                header.push_record(
+                    format!(
+                        "##varlociraptor_learned_prior_{}={}",
+                        parameter.name(&self.samplenames),
+                        value
+                    )
+                    .as_bytes(),
+                );
+            }
+        }
+
         // register sample specific tags
         header.push_record(
             b"##FORMAT=<ID=DP,Number=1,Type=Integer,\
@@ -137,55 +191,42 @@ where
             b"##FORMAT=<ID=AF,Number=A,Type=Float,\
               Description=\"Maximum a posteriori probability estimate of allele frequency\">",
         );
-        header.push_record(
-            b"##FORMAT=<ID=SB,Number=A,Type=String,\
-              Description=\"Strand bias estimate: + indicates that ALT allele is associated with \
-              forward strand, - indicates that ALT allele is associated with reverse strand, \
-              . indicates no strand bias. Strand bias is indicative for systematic sequencing \
-              errors. Probability for strand bias is captured by the ARTIFACT event (PROB_ARTIFACT).\">",
-        );
-        header.push_record(
-            b"##FORMAT=<ID=ROB,Number=A,Type=String,\
-              Description=\"Read orientation bias estimate: > indicates that ALT allele is associated with \
-              F1R2 orientation, < indicates that ALT allele is associated with F2R1 orientation, \
-              . indicates no read orientation bias. Read orientation bias is indicative of Guanin \
-              oxidation artifacts. Probability for read orientation bias is captured by the ARTIFACT \
-              event (PROB_ARTIFACT).\">",
-        );
-        header.push_record(
-            b"##FORMAT=<ID=RPB,Number=A,Type=String,\
-              Description=\"Read position bias estimate: ^ indicates that ALT allele is associated with \
-              the most found read position, . indicates that there is no read position bias.
-              Read position bias is indicative of systematic sequencing errors, e.g. in a specific cycle. \
-              Probability for read orientation bias is captured by the ARTIFACT \
-              event (PROB_ARTIFACT).\">",
-        );
-        header.push_record(
-            b"##FORMAT=<ID=SCB,Number=A,Type=String,\
-              Description=\"Softclip bias estimate: $ indicates that ALT allele is associated with \
-              with softclips in the same alignment, . indicates that there is no softclip bias.
-              Softclip bias is indicative of systematic alignment errors, cause by a part of the read \
-              that does not properly align to the reference (and is thus soft clipped). Note that \
-              softclips can also be caused by structural variants. However, structural variants on the \
-              same haplotype as e.g. an SNV should not cause a softclip bias, because there will usually \
-              still be reads that do not reach the SV, thereby providing evidence against a softclip \
-              bias. Probability for softclip bias is captured by the ARTIFACT \
-              event (PROB_ARTIFACT).\">",
-        );
-        header.push_record(
-            b"##FORMAT=<ID=DIB,Number=A,Type=String,\
-              Description=\"Divindel bias estimate: # indicates that ALT allele is associated with \
-              with indel operations of varying length, . indicates that there is no divindel bias.
-              Divindel bias is indicative of systematic PCR amplification errors, e.g. induced by \
-              homopolymers. Probability for divindel bias is captured by the ARTIFACT \
-              event (PROB_ARTIFACT).\">",
-        );
+        if let Some(width) = self.af_credible_interval {
+            header.push_record(
+                format!(
+                    "##FORMAT=<ID=AFLO,Number=A,Type=Float,\
+                     Description=\"Lower bound of the {}% credible interval of the allele frequency\">",
+                    width * 100.0
+                )
+                .as_bytes(),
+            );
+            header.push_record(
+                format!(
+                    "##FORMAT=<ID=AFHI,Number=A,Type=Float,\
+                     Description=\"Upper bound of the {}% credible interval of the allele frequency\">",
+                    width * 100.0
+                )
+                .as_bytes(),
+            );
+        }
+        if self.af_posterior_density {
+            header.push_record(
+                b"##FORMAT=<ID=AFD,Number=A,Type=String,\
+                  Description=\"Discretized posterior distribution of the allele frequency, given as \
+                  |-separated entries AF:PROB over the evaluated allele frequency grid (entries with \
+                  probability below 0.001 are omitted). Use varlociraptor decode-af-posterior to \
+                  convert into a table.\">",
+            );
+        }
+        for entry in Biases::format_header_entries() {
+            header.push_record(entry.as_bytes());
+        }
 
         Ok(header)
     }
 
-    pub(crate) fn writer(&self) -> Result<bcf::Writer> {
-        let header = self.header();
+    pub(crate) fn writer(&self, learned_prior: Option<&Pr>) -> Result<bcf::Writer> {
+        let header = self.header(learned_prior);
 
         Ok(if let Some(ref path) = self.outbcf {
             bcf::Writer::from_path(path, header.as_ref().unwrap(), false, bcf::Format::BCF)
@@ -196,10 +237,10 @@ where
         })
     }
 
-    fn model(&self) -> Model<Pr> {
+    fn model(&self, prior: &Pr) -> Model<Pr> {
         GenericModelBuilder::default()
             // TODO allow to define prior in the grammar
-            .prior(self.prior.clone())
+            .prior(prior.clone())
             .contaminations(self.contaminations.clone())
             .resolutions(self.resolutions.clone())
             .build()
@@ -222,8 +263,6 @@ where
 
     pub(crate) fn call(&self) -> Result<()> {
         let mut observations = self.observations()?;
-        let mut bcf_writer = self.writer()?;
-        bcf_writer.set_threads(1)?;
 
         // Check observation format.
         for obs_reader in observations.iter_not_none() {
@@ -242,51 +281,27 @@ where
             }
         }
 
+        let (prior, mut bcf_writer) = if let Some(n_records) = self.learn_prior {
+            let prior = self.learn_prior(n_records)?;
+            let writer = self.writer(Some(&prior))?;
+            (prior, writer)
+        } else {
+            (self.prior.clone(), self.writer(None)?)
+        };
+        bcf_writer.set_threads(1)?;
+
+        let mut population_allele_freqs = self.population_allele_freqs()?;
+        let mut somatic_hotspots = self.somatic_hotspots()?;
+
         // data structures
         // For SNVs and MNVs we need a special model as here read orientation bias and read position bias needs to be considered.
         let mut models = HashMap::new();
-        let mut events = Vec::new();
+        let mut event_universes = HashMap::new();
         let mut last_rids = HashMap::new();
 
         // process calls
         let mut i = 0;
-        loop {
-            let mut records =
-                observations.map(|reader| reader.as_ref().map(|reader| reader.empty_record()));
-            let mut eof = Vec::new();
-            for item in observations.iter_mut().zip(records.iter_mut()) {
-                if let (Some(reader), Some(record)) = item {
-                    eof.push(match reader.read(record) {
-                        None => true,
-                        Some(res) => {
-                            res?;
-                            false
-                        }
-                    });
-                }
-            }
-
-            if eof.iter().all(|v| *v) {
-                return Ok(());
-            } else if !eof.iter().all(|v| !v) {
-                // only some are EOF, this is an error
-                return Err(errors::Error::InconsistentObservations.into());
-            }
-
-            // ensure that all observation BCFs contain exactly the same calls
-            let first_record = records.first_not_none()?;
-            let current_rid = first_record.rid();
-            let current_pos = first_record.pos();
-            let current_alleles = first_record.alleles();
-            for record in records[1..].iter().flatten() {
-                if record.rid() != current_rid
-                    || record.pos() != current_pos
-                    || record.alleles() != current_alleles
-                {
-                    return Err(errors::Error::InconsistentObservations.into());
-                }
-            }
-
+        while let Some(mut records) = self.read_records(&mut observations)? {
             let mut work_item = self.preprocess_record(&mut records, i, &observations)?;
 
             // process work item
@@ -294,12 +309,10 @@ where
             let _model;
             let _last_rid;
 
-            let model_mode = (
-                work_item.check_read_orientation_bias,
-                work_item.check_read_position_bias,
-                work_item.check_softclip_bias,
-            );
-            _model = models.entry(model_mode).or_insert_with(|| self.model());
+            let model_mode = work_item.considered_biases;
+            _model = models
+                .entry(model_mode)
+                .or_insert_with(|| self.model(&prior));
             {
                 let entry = last_rids.entry(model_mode).or_insert(None);
                 _last_rid = *entry;
@@ -309,30 +322,284 @@ where
             // obtain variant type
             let variant_type =
                 utils::collect_variants(records.first_not_none_mut()?, false, None)?[0].to_type();
+            let site = variant_site(records.first_not_none()?, contig);
 
-            self.configure_model(
+            let events = self.configure_model(
                 work_item.rid,
                 _last_rid,
                 _model,
-                &mut events,
-                contig,
+                &mut event_universes,
+                &site,
                 variant_type,
-                work_item.check_read_orientation_bias,
-                work_item.check_strand_bias,
-                work_item.check_read_position_bias,
-                work_item.check_softclip_bias,
-                work_item.check_divindel_bias,
+                &work_item.considered_biases,
+            )?;
+            let (population_allele_freq, somatic_hotspot_weight) = self.site_prior_info(
+                &records,
+                contig,
+                &mut population_allele_freqs,
+                &mut somatic_hotspots,
             )?;
+            _model
+                .prior_mut()
+                .set_population_allele_freq(population_allele_freq);
+            _model
+                .prior_mut()
+                .set_somatic_hotspot_weight(somatic_hotspot_weight);
 
-            self.call_record(&mut work_item, _model, &events);
+            self.call_record(&mut work_item, _model, events);
 
-            work_item.call.write_final_record(&mut bcf_writer)?;
+            work_item.call.write_final_record(
+                &mut bcf_writer,
+                self.af_credible_interval,
+                self.af_posterior_density,
+            )?;
             if (i + 1) % 100 == 0 {
                 info!("{} records processed.", i + 1);
             }
 
             i += 1;
         }
+
+        Ok(())
+    }
+
+    /// Read the next record from each observation file. Returns None if all files are
+    /// exhausted.
+    fn read_records(
+        &self,
+        observations: &mut grammar::SampleInfo<Option<bcf::Reader>>,
+    ) -> Result<Option<grammar::SampleInfo<Option<bcf::Record>>>> {
+        let mut records =
+            observations.map(|reader| reader.as_ref().map(|reader| reader.empty_record()));
+        let mut eof = Vec::new();
+        for item in observations.iter_mut().zip(records.iter_mut()) {
+            if let (Some(reader), Some(record)) = item {
+                eof.push(match reader.read(record) {
+                    None => true,
+                    Some(res) => {
+                        res?;
+                        false
+                    }
+                });
+            }
+        }
+
+        if eof.iter().all(|v| *v) {
+            return Ok(None);
+        } else if !eof.iter().all(|v| !v) {
+            // only some are EOF, this is an error
+            return Err(errors::Error::InconsistentObservations.into());
+        }
+
+        // ensure that all observation BCFs contain exactly the same calls
+        let first_record = records.first_not_none()?;
+        let current_rid = first_record.rid();
+        let current_pos = first_record.pos();
+        let current_alleles = first_record.alleles();
+        for record in records[1..].iter().flatten() {
+            if record.rid() != current_rid
+                || record.pos() != current_pos
+                || record.alleles() != current_alleles
+            {
+                return Err(errors::Error::InconsistentObservations.into());
+            }
+        }
+
+        Ok(Some(records))
+    }
+
+    fn population_allele_freqs(&self) -> Result<Option<PopulationAlleleFreqs>> {
+        self.population_allele_freqs
+            .as_ref()
+            .map(PopulationAlleleFreqs::new)
+            .transpose()
+    }
+
+    fn somatic_hotspots(&self) -> Result<Option<SomaticHotspots>> {
+        self.somatic_hotspots
+            .as_ref()
+            .map(SomaticHotspots::new)
+            .transpose()
+    }
+
+    /// Site specific prior information: population allele frequency and somatic hotspot
+    /// weight of the variant in the given records.
+    fn site_prior_info(
+        &self,
+        records: &grammar::SampleInfo<Option<bcf::Record>>,
+        contig: &str,
+        population_allele_freqs: &mut Option<PopulationAlleleFreqs>,
+        somatic_hotspots: &mut Option<SomaticHotspots>,
+    ) -> Result<(Option<AlleleFreq>, Option<f64>)> {
+        let record = records.first_not_none()?;
+        let alleles = record.alleles();
+        let pos = record.pos() as u64;
+        let population_allele_freq = if let Some(population_allele_freqs) = population_allele_freqs
+        {
+            population_allele_freqs.get(contig.as_bytes(), pos, alleles[0], alleles[1])?
+        } else {
+            None
+        };
+        let somatic_hotspot_weight = if let Some(somatic_hotspots) = somatic_hotspots {
+            somatic_hotspots.weight(contig, pos, alleles[0], alleles[1])?
+        } else {
+            None
+        };
+        Ok((population_allele_freq, somatic_hotspot_weight))
+    }
+
+    /// Learn the hyperparameters of the prior (empirical Bayes), by maximizing the marginal
+    /// likelihood of `n_records` records, sampled uniformly from the input. Only parameters
+    /// that are defined in the scenario are learned, their given values serve as starting points.
+    fn learn_prior(&self, n_records: usize) -> Result<Pr> {
+        let mut observations = self.observations()?;
+        let mut population_allele_freqs = self.population_allele_freqs()?;
+        let mut somatic_hotspots = self.somatic_hotspots()?;
+
+        // METHOD: collect records via reservoir sampling, such that the learned parameters
+        // are not biased towards the beginning of the input (e.g. the first chromosome).
+        // Only records that enter the reservoir have to be preprocessed.
+        let mut rng = StdRng::seed_from_u64(PRIOR_LEARNING_SEED);
+        let mut samples: Vec<PriorLearningSample> = Vec::new();
+        let mut n_candidates = 0;
+        let mut i = 0;
+        while let Some(mut records) = self.read_records(&mut observations)? {
+            let index = i;
+            i += 1;
+            if utils::is_bnd(records.first_not_none_mut()?)? {
+                // Breakends are skipped, as they are evaluated jointly with their mates.
+                continue;
+            }
+            let slot = if samples.len() < n_records {
+                Some(samples.len())
+            } else {
+                let slot = rng.gen_range(0, n_candidates + 1);
+                if slot < n_records {
+                    Some(slot)
+                } else {
+                    None
+                }
+            };
+            n_candidates += 1;
+            let slot = if let Some(slot) = slot {
+                slot
+            } else {
+                continue;
+            };
+
+            let work_item = self.preprocess_record(&mut records, index, &observations)?;
+            let variant_type =
+                utils::collect_variants(records.first_not_none_mut()?, false, None)?[0].to_type();
+            let contig = str::from_utf8(work_item.call.chrom())?.to_owned();
+            let (population_allele_freq, somatic_hotspot_weight) = self.site_prior_info(
+                &records,
+                &contig,
+                &mut population_allele_freqs,
+                &mut somatic_hotspots,
+            )?;
+            let sample = PriorLearningSample {
+                site: variant_site(records.first_not_none()?, &contig),
+                work_item,
+                variant_type,
+                population_allele_freq,
+                somatic_hotspot_weight,
+            };
+            if slot < samples.len() {
+                samples[slot] = sample;
+            } else {
+                samples.push(sample);
+            }
+        }
+        // restore the order of the input, such that models are reconfigured only per contig
+        samples.sort_by_key(|sample| sample.work_item.index);
+
+        let prior = self.prior.clone();
+        if samples.is_empty() {
+            warn!("No records for learning the prior found, using the prior as given.");
+            return Ok(prior);
+        }
+        info!("Learning prior from {} records.", samples.len());
+
+        // Only consider variant type fractions of variant types that occur in the sample,
+        // the marginal likelihood does not depend on the others.
+        let parameters = prior
+            .parameters()
+            .into_iter()
+            .map(|(parameter, _)| parameter)
+            .filter(|parameter| match parameter {
+                model::prior::PriorParameter::IndelFraction
+                | model::prior::PriorParameter::MnvFraction
+                | model::prior::PriorParameter::SvFraction => samples.iter().any(|sample| {
+                    model::prior::PriorParameter::variant_type_fraction(&sample.variant_type)
+                        == Some(*parameter)
+                }),
+                _ => true,
+            })
+            .collect_vec();
+
+        let prior = model::prior::maximize_marginal_likelihood(prior, &parameters, |prior| {
+            self.log_marginal_likelihood(prior, &samples)
+        })?;
+
+        for (parameter, value) in prior.parameters() {
+            if parameters.contains(&parameter) {
+                info!(
+                    "Learned prior parameter {}: {}",
+                    parameter.name(&self.samplenames),
+                    value
+                );
+            }
+        }
+
+        Ok(prior)
+    }
+
+    /// Log marginal likelihood of the given records under the given prior.
+    fn log_marginal_likelihood(&self, prior: &Pr, samples: &[PriorLearningSample]) -> Result<f64> {
+        let mut models = HashMap::new();
+        let mut event_universes = HashMap::new();
+        let mut last_contigs: HashMap<BiasFlags, &str> = HashMap::new();
+        let mut log_marginal = 0.0;
+        for sample in samples {
+            let work_item = &sample.work_item;
+            let model_mode = work_item.considered_biases;
+            let model = models
+                .entry(model_mode)
+                .or_insert_with(|| self.model(prior));
+            // events have to be updated when the contig changes
+            let last_rid = if last_contigs.get(&model_mode) == Some(&sample.site.contig().as_str())
+            {
+                Some(work_item.rid)
+            } else {
+                None
+            };
+            last_contigs.insert(model_mode, sample.site.contig());
+            let events = self.configure_model(
+                work_item.rid,
+                last_rid,
+                model,
+                &mut event_universes,
+                &sample.site,
+                sample.variant_type.clone(),
+                &work_item.considered_biases,
+            )?;
+            model
+                .prior_mut()
+                .set_population_allele_freq(sample.population_allele_freq);
+            model
+                .prior_mut()
+                .set_somatic_hotspot_weight(sample.somatic_hotspot_weight);
+
+            let data = model::modes::generic::Data::new(
+                work_item.pileups.clone().unwrap(),
+                work_item.snv.clone(),
+            );
+            let event_universe = learn_bias_parameters(events, &data, &work_item.bias_context);
+            log_marginal += *model
+                .compute(event_universe.iter().cloned(), &data)
+                .marginal();
+        }
+        Ok(log_marginal)
     }
 
     fn preprocess_record(
@@ -397,6 +664,8 @@ where
         let mut variant_builder = VariantBuilder::default();
         variant_builder.record(records.first_not_none_mut()?)?;
 
+        let bias_context = BiasContext::new(is_snv_or_mnv, snv.clone(), self.ffpe_samples.to_vec());
+
         let mut work_item = WorkItem {
             rid,
             call,
@@ -405,11 +674,8 @@ where
             bnd_event,
             variant_builder,
             index,
-            check_read_orientation_bias: is_snv_or_mnv && !self.omit_read_orientation_bias,
-            check_strand_bias: !self.omit_strand_bias,
-            check_read_position_bias: is_snv_or_mnv && !self.omit_read_position_bias,
-            check_softclip_bias: is_snv_or_mnv && !self.omit_softclip_bias,
-            check_divindel_bias: !self.omit_divindel_bias,
+            considered_biases: BiasFlags::applicable(&bias_context).without(&self.omit_biases),
+            bias_context,
         };
 
         if let Some(ref event) = work_item.bnd_event {
@@ -434,7 +700,7 @@ where
                     // clearly not influenced by a close SV.
                     pileup = Observation::remove_nonstandard_alignments(
                         pileup,
-                        self.omit_read_orientation_bias,
+                        self.omit_biases.read_orientation_bias,
                     );
                 }
 
@@ -450,20 +716,22 @@ where
         Ok(work_item)
     }
 
-    fn configure_model(
+    /// Configure the model for the given site and return the event universe of the model mode
+    /// given by the considered biases. Since the considered biases differ between modes (e.g. FFPE
+    /// bias only applies to C>T and G>A SNVs), each mode has its own event universe, which is
+    /// rebuilt whenever the rid differs from the last rid of that mode.
+    fn configure_model<'a>(
         &self,
         current_rid: u32,
         rid: Option<u32>,
         model: &mut Model<Pr>,
-        events: &mut Vec<model::Event>,
-        contig: &str,
+        event_universes: &'a mut HashMap<BiasFlags, Vec<model::Event>>,
+        site: &model::prior::Site,
         variant_type: model::VariantType,
-        consider_read_orientation_bias: bool,
-        consider_strand_bias: bool,
-        consider_read_position_bias: bool,
-        consider_softclip_bias: bool,
-        consider_divindel_bias: bool,
-    ) -> Result<()> {
+        considered_biases: &BiasFlags,
+    ) -> Result<&'a [model::Event]> {
+        let contig = site.contig();
+        let events = event_universes.entry(*considered_biases).or_default();
         if !rid.map_or(false, |rid: u32| current_rid == rid) {
             // rid is not the same as before, obtain event universe
             // clear old events
@@ -484,15 +752,9 @@ where
                     biases: vec![Biases::none()],
                 });
 
-                let biases: Vec<_> = Biases::all_artifact_combinations(
-                    consider_read_orientation_bias,
-                    consider_strand_bias,
-                    consider_read_position_bias,
-                    consider_softclip_bias,
-                    consider_divindel_bias,
-                    self.min_divindel_other_rate,
-                )
-                .collect();
+                let biases: Vec<_> =
+                    Biases::all_artifact_combinations(considered_biases, &self.bias_params)
+                        .collect();
                 if !biases.is_empty() {
                     // Corresponding biased event.
                     events.push(model::Event {
@@ -520,9 +782,11 @@ where
             model.prior().check()?;
         }
 
-        model.prior_mut().set_variant_type(variant_type);
+        model
+            .prior_mut()
+            .set_variant_type(variant_type, Some(site))?;
 
-        Ok(())
+        Ok(events)
     }
 
     fn call_record(
@@ -554,15 +818,8 @@ where
                 work_item.snv.clone(),
             );
 
-            let mut event_universe: Vec<_> = event_universe.to_vec();
-            for event in &mut event_universe {
-                // METHOD: learn parameters for each bias (if necessary).
-                // By this, we can avoid marginalization of them, which is
-                // unnecessarily expensive.
-                for bias in &mut event.biases {
-                    bias.learn_parameters(data.pileups());
-                }
-            }
+            let event_universe =
+                learn_bias_parameters(event_universe, &data, &work_item.bias_context);
 
             // Compute probabilities for given events.
             let m = model.compute(event_universe.iter().cloned(), &data);
@@ -604,9 +861,12 @@ where
             work_item.variant_builder.event_probs(Some(event_probs));
 
             // add sample specific information
-            work_item
-                .variant_builder
-                .sample_info(self.sample_infos(&m, is_artifact, data));
+            work_item.variant_builder.sample_info(self.sample_infos(
+                &m,
+                &event_universe,
+                is_artifact,
+                data,
+            ));
         } else {
             unreachable!();
         }
@@ -635,9 +895,31 @@ where
     fn sample_infos(
         &self,
         model_instance: &bayesian::model::ModelInstance<AlleleFreqCombination, model::Event>,
+        event_universe: &[model::Event],
         is_artifact: bool,
         data: model::modes::generic::Data,
     ) -> Vec<Option<SampleInfo>> {
+        // METHOD: the allele frequency posterior of each sample is obtained by marginalizing
+        // over all non-artifact events and the allele frequencies of the other samples,
+        // with each evaluated allele frequency combination weighted by its integration weight.
+        // Hence, it describes the allele frequency given that the variant is not an artifact.
+        let joint_posteriors: HashMap<&AlleleFreqCombination, LogProb> =
+            model_instance.event_posteriors().collect();
+        let allelefreq_posteriors = GenericPosterior::new(self.resolutions.clone())
+            .allele_freq_marginals(
+                event_universe.iter().filter(|event| !event.is_artifact()),
+                &data,
+                |allele_freqs| {
+                    joint_posteriors
+                        .get(allele_freqs)
+                        .cloned()
+                        .unwrap_or_else(LogProb::ln_zero)
+                },
+            )
+            .into_iter()
+            .map(AlleleFreqPosterior::new)
+            .collect_vec();
+
         for (map_estimates, _) in model_instance.event_posteriors() {
             if map_estimates
                 .iter()
@@ -652,9 +934,12 @@ where
                 .into_pileups()
                 .into_iter()
                 .zip(map_estimates.iter())
-                .map(|(pileup, estimate)| {
+                .zip(allelefreq_posteriors.iter())
+                .map(|((pileup, estimate), allelefreq_posterior)| {
                     let mut sample_builder = SampleInfoBuilder::default();
-                    sample_builder.observations(pileup);
+                    sample_builder
+                        .observations(pileup)
+                        .allelefreq_posterior(allelefreq_posterior.clone());
                     match estimate {
                         model::likelihood::Event { biases, .. } if biases.is_artifact() => {
                             sample_builder
@@ -683,6 +968,45 @@ pub(crate) struct BreakendResult {
     sample_info: Vec<Option<SampleInfo>>,
 }
 
+/// Learn the parameters of the biases of the given events from the given data.
+fn learn_bias_parameters(
+    event_universe: &[model::Event],
+    data: &model::modes::generic::Data,
+    bias_context: &BiasContext,
+) -> Vec<model::Event> {
+    let mut event_universe = event_universe.to_vec();
+    for event in &mut event_universe {
+        // METHOD: learn parameters for each bias (if necessary).
+        // By this, we can avoid marginalization of them, which is
+        // unnecessarily expensive.
+        for bias in &mut event.biases {
+            bias.learn_parameters(data.pileups(), bias_context);
+        }
+    }
+    event_universe
+}
+
+/// Site of the variant in the given record.
+fn variant_site(record: &bcf::Record, contig: &str) -> model::prior::Site {
+    model::prior::Site::new(
+        contig.to_owned(),
+        record.pos() as u64,
+        record
+            .alleles()
+            .get(1)
+            .map_or_else(Vec::new, |alt| alt.to_vec()),
+    )
+}
+
+/// A record used for learning the prior.
+struct PriorLearningSample {
+    work_item: WorkItem,
+    site: model::prior::Site,
+    variant_type: model::VariantType,
+    population_allele_freq: Option<AlleleFreq>,
+    somatic_hotspot_weight: Option<f64>,
+}
+
 struct WorkItem {
     rid: u32,
     call: Call,
@@ -691,9 +1015,94 @@ struct WorkItem {
     snv: Option<model::modes::generic::Snv>,
     bnd_event: Option<Vec<u8>>,
     index: usize,
-    check_read_orientation_bias: bool,
-    check_strand_bias: bool,
-    check_read_position_bias: bool,
-    check_softclip_bias: bool,
-    check_divindel_bias: bool,
+    considered_biases: BiasFlags,
+    bias_context: BiasContext,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::variants::model::bias::Bias;
+    use crate::variants::model::prior::Prior;
+
+    fn caller() -> Caller<Prior> {
+        let scenario: grammar::Scenario = serde_yaml::from_str(
+            r#"samples:
+  tumor:
+    resolution: 100
+    universe: "[0.0,1.0]"
+events:
+  present: "tumor:]0.0,1.0]""#,
+        )
+        .unwrap();
+        CallerBuilder::default()
+            .samplenames(grammar::SampleInfo::from(vec!["tumor".to_owned()]))
+            .observations(grammar::SampleInfo::from(vec![None]))
+            .omit_biases(BiasFlags::default())
+            .bias_params(BiasParams::new(0.05))
+            .ffpe_samples(grammar::SampleInfo::from(vec![true]))
+            .scenario(scenario)
+            .outbcf(None)
+            .contaminations(grammar::SampleInfo::from(vec![None]))
+            .resolutions(grammar::SampleInfo::from(vec![100]))
+            .prior(Prior::default())
+            .breakend_index(BreakendIndex::default())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_configure_model_interleaved_modes() {
+        let caller = caller();
+        // C>T SNVs are subject to FFPE bias, other SNVs are not
+        let ffpe_mode = BiasFlags {
+            strand_bias: true,
+            ffpe_bias: true,
+            ..Default::default()
+        };
+        let other_mode = BiasFlags {
+            strand_bias: true,
+            ..Default::default()
+        };
+        let has_ffpe_artifact = |events: &[model::Event]| {
+            events
+                .iter()
+                .flat_map(|event| event.biases.iter())
+                .any(|biases| biases.ffpe_bias().is_artifact())
+        };
+
+        let mut models = HashMap::new();
+        let mut event_universes = HashMap::new();
+        let mut last_rids: HashMap<BiasFlags, u32> = HashMap::new();
+        // interleave the two modes on the same contig
+        for (i, (mode, alt)) in [
+            (ffpe_mode, b"T"),
+            (other_mode, b"G"),
+            (ffpe_mode, b"T"),
+            (other_mode, b"A"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let model = models
+                .entry(*mode)
+                .or_insert_with(|| caller.model(&Prior::default()));
+            let site = model::prior::Site::new("chr1".to_owned(), i as u64, alt.to_vec());
+            let events = caller
+                .configure_model(
+                    0,
+                    last_rids.insert(*mode, 0),
+                    model,
+                    &mut event_universes,
+                    &site,
+                    model::VariantType::Snv,
+                    mode,
+                )
+                .unwrap();
+            // absent, present and present with artifacts
+            assert_eq!(events.len(), 3);
+            assert_eq!(has_ffpe_artifact(events), *mode == ffpe_mode);
+        }
+    }
 }
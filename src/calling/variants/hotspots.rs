@@ -0,0 +1,188 @@
+use std::str;
+
+use anyhow::{Context, Result};
+use rust_htslib::bcf::{self, record::Numeric, Read};
+use rust_htslib::tbx::{self, Read as TbxRead};
+
+use crate::errors;
+use crate::grammar;
+
+enum HotspotSource {
+    /// Allele specific weights, given by an INFO field of an indexed VCF/BCF.
+    Vcf {
+        reader: bcf::IndexedReader,
+        info_field: String,
+    },
+    /// Position specific weights, given by the fourth column of a tabix indexed
+    /// BED file (like a bedGraph).
+    Bed { reader: tbx::Reader },
+}
+
+/// Weights of recurrent somatic mutation hotspots (e.g. COSMIC counts).
+pub(crate) struct SomaticHotspots {
+    source: HotspotSource,
+}
+
+impl SomaticHotspots {
+    /// Open the given hotspots. BED files are interpreted as position specific weights, VCF/BCF
+    /// files as allele specific weights (taken from the configured INFO field).
+    pub(crate) fn new(spec: &grammar::SomaticHotspots) -> Result<Self> {
+        let path = spec.path();
+        let info_field = spec.info_field();
+        let context = || {
+            format!(
+                "Unable to open somatic hotspots {} (an index is required).",
+                path.display()
+            )
+        };
+        let source = if spec.is_bed() {
+            HotspotSource::Bed {
+                reader: tbx::Reader::from_path(path).with_context(context)?,
+            }
+        } else {
+            let reader = bcf::IndexedReader::from_path(path).with_context(context)?;
+            if reader.header().info_type(info_field.as_bytes()).is_err() {
+                return Err(errors::Error::MissingBCFTag {
+                    name: info_field.to_owned(),
+                }
+                .into());
+            }
+            HotspotSource::Vcf {
+                reader,
+                info_field: info_field.to_owned(),
+            }
+        };
+        Ok(SomaticHotspots { source })
+    }
+
+    /// Hotspot weight of the given allele, None if the allele is not a known hotspot.
+    ///
+    /// # Arguments
+    /// * `contig` - contig name
+    /// * `pos` - 0-based position
+    /// * `ref_allele` - reference allele
+    /// * `alt_allele` - alternative allele
+    pub(crate) fn weight(
+        &mut self,
+        contig: &str,
+        pos: u64,
+        ref_allele: &[u8],
+        alt_allele: &[u8],
+    ) -> Result<Option<f64>> {
+        match &mut self.source {
+            HotspotSource::Vcf { reader, info_field } => {
+                let rid = if let Ok(rid) = reader.header().name2rid(contig.as_bytes()) {
+                    rid
+                } else {
+                    return Ok(None);
+                };
+                reader.fetch(rid, pos, pos + 1)?;
+                let mut record = reader.empty_record();
+                while let Some(res) = reader.read(&mut record) {
+                    res?;
+                    let alleles = record.alleles();
+                    if record.pos() as u64 != pos || alleles[0] != ref_allele {
+                        continue;
+                    }
+                    if let Some(i) = alleles[1..].iter().position(|alt| *alt == alt_allele) {
+                        // Weights can be given as integer (e.g. counts) or float.
+                        let weight = match reader.header().info_type(info_field.as_bytes())?.0 {
+                            bcf::header::TagType::Integer => record
+                                .info(info_field.as_bytes())
+                                .integer()?
+                                .and_then(|values| {
+                                    // per allele values or a single value for the site
+                                    values.get(i).or_else(|| values.first()).cloned()
+                                })
+                                .filter(|value| !value.is_missing())
+                                .map(|value| value as f64),
+                            _ => record
+                                .info(info_field.as_bytes())
+                                .float()?
+                                .and_then(|values| {
+                                    values.get(i).or_else(|| values.first()).cloned()
+                                })
+                                .filter(|value| !value.is_missing() && !value.is_nan())
+                                .map(|value| value as f64),
+                        };
+                        return Ok(weight);
+                    }
+                }
+                Ok(None)
+            }
+            HotspotSource::Bed { reader } => {
+                let tid = if let Ok(tid) = reader.tid(contig) {
+                    tid
+                } else {
+                    return Ok(None);
+                };
+                reader.fetch(tid, pos, pos + 1)?;
+                let mut weight: Option<f64> = None;
+                for line in reader.records() {
+                    let line = line?;
+                    let value = str::from_utf8(&line)?.split('\t').nth(3).ok_or_else(|| {
+                        errors::Error::InvalidHotspotBED {
+                            line: String::from_utf8_lossy(&line).into_owned(),
+                        }
+                    })?;
+                    let value: f64 =
+                        value
+                            .parse()
+                            .map_err(|_| errors::Error::InvalidHotspotBED {
+                                line: String::from_utf8_lossy(&line).into_owned(),
+                            })?;
+                    // METHOD: for overlapping hotspot intervals, take the maximum weight.
+                    weight = Some(weight.map_or(value, |w| w.max(value)));
+                }
+                Ok(weight)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(path: &str, info_field: &str) -> Result<SomaticHotspots> {
+        let spec: grammar::SomaticHotspots = serde_yaml::from_str(&format!(
+            "path: tests/resources/test_somatic_hotspots/{}\ninfo-field: {}",
+            path, info_field
+        ))
+        .unwrap();
+        SomaticHotspots::new(&spec)
+    }
+
+    #[test]
+    fn test_weight_vcf() {
+        let mut hotspots = open("hotspots.vcf.gz", "WEIGHT").unwrap();
+        // per allele counts
+        assert_eq!(hotspots.weight("chr1", 9, b"A", b"G").unwrap(), Some(5.0));
+        assert_eq!(hotspots.weight("chr1", 9, b"A", b"T").unwrap(), Some(2.0));
+        // unknown allele, missing value, other position and unknown contig
+        assert_eq!(hotspots.weight("chr1", 9, b"A", b"C").unwrap(), None);
+        assert_eq!(hotspots.weight("chr1", 19, b"C", b"T").unwrap(), None);
+        assert_eq!(hotspots.weight("chr1", 10, b"A", b"G").unwrap(), None);
+        assert_eq!(hotspots.weight("chr2", 9, b"A", b"G").unwrap(), None);
+
+        // a single float value for the whole site
+        let mut hotspots = open("hotspots.vcf.gz", "SCORE").unwrap();
+        assert_eq!(hotspots.weight("chr1", 9, b"A", b"T").unwrap(), Some(0.5));
+        assert_eq!(hotspots.weight("chr1", 19, b"C", b"T").unwrap(), Some(1.5));
+
+        assert!(open("hotspots.vcf.gz", "COUNT").is_err());
+    }
+
+    #[test]
+    fn test_weight_bed() {
+        let mut hotspots = open("hotspots.bed.gz", "WEIGHT").unwrap();
+        assert_eq!(hotspots.weight("chr1", 9, b"A", b"G").unwrap(), Some(1.5));
+        // overlapping intervals yield the maximum weight
+        assert_eq!(hotspots.weight("chr1", 55, b"A", b"G").unwrap(), Some(3.0));
+        assert_eq!(hotspots.weight("chr1", 205, b"C", b"T").unwrap(), Some(2.0));
+        // BED end coordinates are exclusive
+        assert_eq!(hotspots.weight("chr1", 210, b"C", b"T").unwrap(), None);
+        assert_eq!(hotspots.weight("chr1", 150, b"A", b"G").unwrap(), None);
+        assert_eq!(hotspots.weight("chr2", 9, b"A", b"G").unwrap(), None);
+    }
+}
@@ -4,29 +4,28 @@
 // except according to those terms.
 
 pub(crate) mod calling;
+pub(crate) mod hotspots;
+pub(crate) mod population;
 pub(crate) mod preprocessing;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::str;
 use std::u8;
 
 use anyhow::Result;
 use bio::stats::{LogProb, PHREDProb};
-use bio_types::sequence::SequenceReadPairOrientation;
 use derive_builder::Builder;
 use itertools::Itertools;
 use rust_htslib::bcf::{self, record::Numeric, Read};
 use vec_map::VecMap;
 
 use crate::calling::variants::preprocessing::write_observations;
+use crate::errors;
 use crate::utils;
 use crate::variants::evidence::observation::expected_depth;
-use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition, Strand};
+use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
 use crate::variants::model;
-use crate::variants::model::{
-    bias::Biases, bias::DivIndelBias, bias::ReadOrientationBias, bias::ReadPositionBias,
-    bias::SoftclipBias, bias::StrandBias, AlleleFreq,
-};
+use crate::variants::model::{bias::Biases, AlleleFreq};
 
 pub(crate) use crate::calling::variants::calling::CallerBuilder;
 
@@ -96,7 +95,18 @@ impl Call {
         Ok(())
     }
 
-    pub(crate) fn write_final_record(&self, bcf_writer: &mut bcf::Writer) -> Result<()> {
+    /// Write the final record with calling results.
+    ///
+    /// # Arguments
+    /// * `bcf_writer` - the writer
+    /// * `af_credible_interval` - width of the allele frequency credible interval to report (AFLO, AFHI), if any
+    /// * `af_posterior_density` - whether to report the discretized allele frequency posterior (AFD)
+    pub(crate) fn write_final_record(
+        &self,
+        bcf_writer: &mut bcf::Writer,
+        af_credible_interval: Option<f64>,
+        af_posterior_density: bool,
+    ) -> Result<()> {
         let rid = bcf_writer.header().name2rid(&self.chrom)?;
 
         let variant = self.variant.as_ref().unwrap();
@@ -112,14 +122,11 @@ impl Call {
 
         let mut event_probs = HashMap::new();
         let mut allelefreq_estimates = VecMap::new();
+        let mut allelefreq_posteriors = VecMap::new();
         let mut observations = VecMap::new();
         let mut simple_observations = VecMap::new();
         let mut obs_counts = VecMap::new();
-        let mut strand_bias = VecMap::new();
-        let mut read_orientation_bias = VecMap::new();
-        let mut read_position_bias = VecMap::new();
-        let mut softclip_bias = VecMap::new();
-        let mut divindel_bias = VecMap::new();
+        let mut biases = VecMap::new();
         let mut alleles = Vec::new();
         let mut svlens = Vec::new();
         let mut events = Vec::new();
@@ -144,45 +151,10 @@ impl Call {
 
         for (i, sample_info) in variant.sample_info.iter().enumerate() {
             if let Some(ref sample_info) = sample_info {
-                strand_bias.insert(
-                    i,
-                    match sample_info.biases.strand_bias() {
-                        StrandBias::None => b'.',
-                        StrandBias::Forward => b'+',
-                        StrandBias::Reverse => b'-',
-                    },
-                );
-                read_orientation_bias.insert(
-                    i,
-                    match sample_info.biases.read_orientation_bias() {
-                        ReadOrientationBias::None => b'.',
-                        ReadOrientationBias::F1R2 => b'>',
-                        ReadOrientationBias::F2R1 => b'<',
-                    },
-                );
-                read_position_bias.insert(
-                    i,
-                    match sample_info.biases.read_position_bias() {
-                        ReadPositionBias::None => b'.',
-                        ReadPositionBias::Some => b'^',
-                    },
-                );
-                softclip_bias.insert(
-                    i,
-                    match sample_info.biases.softclip_bias() {
-                        SoftclipBias::None => b'.',
-                        SoftclipBias::Some => b'$',
-                    },
-                );
-                divindel_bias.insert(
-                    i,
-                    match sample_info.biases.divindel_bias() {
-                        DivIndelBias::None => b'.',
-                        DivIndelBias::Some { .. } => b'#',
-                    },
-                );
+                biases.insert(i, sample_info.biases.format_symbols());
 
                 allelefreq_estimates.insert(i, *sample_info.allelefreq_estimate as f32);
+                allelefreq_posteriors.insert(i, &sample_info.allelefreq_posterior);
 
                 obs_counts.insert(i, expected_depth(&sample_info.observations) as i32);
 
@@ -192,35 +164,14 @@ impl Call {
                         sample_info.observations.iter().map(|obs| {
                             let score = utils::bayes_factor_to_letter(obs.bayes_factor_alt());
                             format!(
-                                "{}{}{}{}{}{}{}",
+                                "{}{}{}",
                                 if obs.prob_mapping_orig() < LogProb(0.95_f64.ln()) {
                                     score.to_ascii_lowercase()
                                 } else {
                                     score.to_ascii_uppercase()
                                 },
                                 if obs.paired { 'p' } else { 's' },
-                                match obs.strand {
-                                    Strand::Both => '*',
-                                    Strand::Reverse => '-',
-                                    Strand::Forward => '+',
-                                    _ => panic!("bug: unknown strandedness"),
-                                },
-                                match obs.read_orientation {
-                                    SequenceReadPairOrientation::F1R2 => '>',
-                                    SequenceReadPairOrientation::F2R1 => '<',
-                                    SequenceReadPairOrientation::None => '*',
-                                    _ => '!',
-                                },
-                                match obs.read_position {
-                                    ReadPosition::Major => '^',
-                                    ReadPosition::Some => '*',
-                                },
-                                if obs.softclipped { '$' } else { '.' },
-                                match obs.indel_operations {
-                                    IndelOperations::Major => '*',
-                                    IndelOperations::Other => '#',
-                                    IndelOperations::None => '.',
-                                },
+                                Biases::observation_symbols(obs),
                             )
                         }),
                         false,
@@ -340,6 +291,41 @@ impl Call {
             let afs = allelefreq_estimates.values().cloned().collect_vec();
             record.push_format_float(b"AF", &afs)?;
 
+            if let Some(width) = af_credible_interval {
+                let intervals = allelefreq_posteriors
+                    .values()
+                    .map(|posterior: &&AlleleFreqPosterior| {
+                        if posterior.is_empty() {
+                            (f32::missing(), f32::missing())
+                        } else {
+                            let (lower, upper) = posterior.credible_interval(width);
+                            (*lower as f32, *upper as f32)
+                        }
+                    })
+                    .collect_vec();
+                record.push_format_float(
+                    b"AFLO",
+                    &intervals.iter().map(|(lower, _)| *lower).collect_vec(),
+                )?;
+                record.push_format_float(
+                    b"AFHI",
+                    &intervals.iter().map(|(_, upper)| *upper).collect_vec(),
+                )?;
+            }
+            if af_posterior_density {
+                let densities = allelefreq_posteriors
+                    .values()
+                    .map(|posterior| {
+                        if posterior.is_empty() {
+                            b".".to_vec()
+                        } else {
+                            posterior.encode().into_bytes()
+                        }
+                    })
+                    .collect_vec();
+                record.push_format_string(b"AFD", &densities)?;
+            }
+
             let obs = observations
                 .values()
                 .map(|sample_obs| {
@@ -352,26 +338,13 @@ impl Call {
                 .collect_vec();
             record.push_format_string(b"OBS", &obs)?;
 
-            let sb = strand_bias.values().map(|sb| vec![*sb]).collect_vec();
-            record.push_format_string(b"SB", &sb)?;
-
-            let rob = read_orientation_bias
-                .values()
-                .map(|rob| vec![*rob])
-                .collect_vec();
-            record.push_format_string(b"ROB", &rob)?;
-
-            let rpb = read_position_bias
-                .values()
-                .map(|rpb| vec![*rpb])
-                .collect_vec();
-            record.push_format_string(b"RPB", &rpb)?;
-
-            let scb = softclip_bias.values().map(|scb| vec![*scb]).collect_vec();
-            record.push_format_string(b"SCB", &scb)?;
-
-            let dib = divindel_bias.values().map(|dib| vec![*dib]).collect_vec();
-            record.push_format_string(b"DIB", &dib)?;
+            for (i, tag) in Biases::format_tags().into_iter().enumerate() {
+                let values = biases
+                    .values()
+                    .map(|symbols: &Vec<u8>| vec![symbols[i]])
+                    .collect_vec();
+                record.push_format_string(tag.as_bytes(), &values)?;
+            }
 
             let sobs = simple_observations
                 .values()
@@ -387,11 +360,26 @@ impl Call {
         } else {
             record.push_format_integer(b"DP", &vec![i32::missing(); variant.sample_info.len()])?;
             record.push_format_float(b"AF", &vec![f32::missing(); variant.sample_info.len()])?;
+            if af_credible_interval.is_some() {
+                for tag in &[b"AFLO", b"AFHI"] {
+                    record.push_format_float(
+                        *tag,
+                        &vec![f32::missing(); variant.sample_info.len()],
+                    )?;
+                }
+            }
+            if af_posterior_density {
+                record
+                    .push_format_string(b"AFD", &vec![b".".to_vec(); variant.sample_info.len()])?;
+            }
             record.push_format_string(b"OBS", &vec![b".".to_vec(); variant.sample_info.len()])?;
             record.push_format_string(b"SOBS", &vec![b".".to_vec(); variant.sample_info.len()])?;
-            record.push_format_string(b"SB", &vec![b".".to_vec(); variant.sample_info.len()])?;
-            record.push_format_string(b"ROB", &vec![b".".to_vec(); variant.sample_info.len()])?;
-            record.push_format_string(b"RPB", &vec![b".".to_vec(); variant.sample_info.len()])?;
+            for tag in Biases::format_tags() {
+                record.push_format_string(
+                    tag.as_bytes(),
+                    &vec![b".".to_vec(); variant.sample_info.len()],
+                )?;
+            }
         }
 
         bcf_writer.write(&record)?;
@@ -511,11 +499,95 @@ impl VariantBuilder {
 #[derive(Clone, Debug, Builder)]
 pub(crate) struct SampleInfo {
     allelefreq_estimate: AlleleFreq,
+    #[builder(default)]
+    allelefreq_posterior: AlleleFreqPosterior,
     #[builder(default = "Vec::new()")]
     observations: Vec<Observation<ReadPosition, IndelOperations>>,
     biases: Biases,
 }
 
+/// Minimum posterior probability of a grid point to be reported in the AFD field.
+const MIN_REPORTED_AF_DENSITY: f64 = 0.001;
+
+/// Marginal posterior distribution of the allele frequency of a sample, discretized
+/// over the grid points evaluated by the model.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct AlleleFreqPosterior {
+    /// Allele frequencies (sorted) along with their normalized posterior probabilities.
+    points: Vec<(AlleleFreq, f64)>,
+}
+
+impl AlleleFreqPosterior {
+    pub(crate) fn new<I: IntoIterator<Item = (AlleleFreq, LogProb)>>(points: I) -> Self {
+        let mut marginals: BTreeMap<AlleleFreq, Vec<LogProb>> = BTreeMap::new();
+        for (allele_freq, prob) in points {
+            marginals.entry(allele_freq).or_default().push(prob);
+        }
+        let marginals = marginals
+            .into_iter()
+            .map(|(allele_freq, probs)| (allele_freq, LogProb::ln_sum_exp(&probs)))
+            .collect_vec();
+        let total = LogProb::ln_sum_exp(&marginals.iter().map(|(_, prob)| *prob).collect_vec());
+        AlleleFreqPosterior {
+            points: marginals
+                .into_iter()
+                .map(|(allele_freq, prob)| (allele_freq, (prob - total).exp()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.points.is_empty() || self.points.iter().any(|(_, prob)| prob.is_nan())
+    }
+
+    /// Equal-tailed credible interval of the given width.
+    pub(crate) fn credible_interval(&self, width: f64) -> (AlleleFreq, AlleleFreq) {
+        let tail = (1.0 - width) / 2.0;
+        let quantile = |q: f64| {
+            let mut cdf = 0.0;
+            for (allele_freq, prob) in &self.points {
+                cdf += prob;
+                if cdf >= q {
+                    return *allele_freq;
+                }
+            }
+            self.points.last().unwrap().0
+        };
+        (quantile(tail), quantile(1.0 - tail))
+    }
+
+    /// Compact representation as used in the AFD format field: `|`-separated entries `AF:PROB`,
+    /// omitting grid points with negligible probability.
+    pub(crate) fn encode(&self) -> String {
+        self.points
+            .iter()
+            .filter(|(_, prob)| *prob >= MIN_REPORTED_AF_DENSITY)
+            .map(|(allele_freq, prob)| format!("{:.3}:{:.3}", **allele_freq, prob))
+            .join("|")
+    }
+
+    /// Parse the representation of the AFD format field into pairs of allele frequency and
+    /// posterior probability.
+    pub(crate) fn decode(value: &str) -> Result<Vec<(f64, f64)>> {
+        if value == "." || value.is_empty() {
+            return Ok(Vec::new());
+        }
+        value
+            .split('|')
+            .map(|entry| {
+                let invalid = || errors::Error::InvalidBCFRecord {
+                    msg: format!("invalid AFD entry {}", entry),
+                };
+                let (allele_freq, prob) = entry.split(':').collect_tuple().ok_or_else(invalid)?;
+                Ok((
+                    allele_freq.parse().map_err(|_| invalid())?,
+                    prob.parse().map_err(|_| invalid())?,
+                ))
+            })
+            .collect()
+    }
+}
+
 /// Wrapper for comparing alleles for compatibility in BCF files.
 /// PartialEq::eq() returns true for all alleles that can occur in the same BCF record.
 pub(crate) struct BCFGrouper<'a>(pub(crate) &'a Variant);
@@ -541,3 +613,28 @@ fn chrom<'a>(inbcf: &'a bcf::Reader, record: &bcf::Record) -> &'a [u8] {
 pub(crate) fn event_tag_name(event: &str) -> String {
     format!("PROB_{}", event.to_ascii_uppercase())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bio::stats::Prob;
+
+    #[test]
+    fn test_allelefreq_posterior() {
+        let posterior = AlleleFreqPosterior::new(vec![
+            (AlleleFreq(0.0), LogProb::from(Prob(0.05))),
+            (AlleleFreq(0.5), LogProb::from(Prob(0.4))),
+            (AlleleFreq(0.5), LogProb::from(Prob(0.4))),
+            (AlleleFreq(1.0), LogProb::from(Prob(0.15))),
+        ]);
+        let (lower, upper) = posterior.credible_interval(0.8);
+        assert_eq!(*lower, 0.5);
+        assert_eq!(*upper, 1.0);
+
+        let encoded = posterior.encode();
+        assert_eq!(encoded, "0.000:0.050|0.500:0.800|1.000:0.150");
+        let decoded = AlleleFreqPosterior::decode(&encoded).unwrap();
+        assert_eq!(decoded, vec![(0.0, 0.05), (0.5, 0.8), (1.0, 0.15)]);
+        assert!(AlleleFreqPosterior::decode(".").unwrap().is_empty());
+    }
+}
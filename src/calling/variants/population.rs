@@ -0,0 +1,122 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use rust_htslib::bcf::{self, record::Numeric, Read};
+
+use crate::errors;
+use crate::variants::model::AlleleFreq;
+
+/// Site specific population allele frequencies, obtained from the AF INFO field of an
+/// indexed VCF/BCF (e.g. from gnomAD).
+pub(crate) struct PopulationAlleleFreqs {
+    reader: bcf::IndexedReader,
+}
+
+impl PopulationAlleleFreqs {
+    pub(crate) fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let reader = bcf::IndexedReader::from_path(&path).context(format!(
+            "Unable to open population allele frequencies {} (an index is required).",
+            path.as_ref().display()
+        ))?;
+        if reader.header().info_type(b"AF").is_err() {
+            return Err(errors::Error::MissingBCFTag {
+                name: "AF".to_owned(),
+            }
+            .into());
+        }
+        Ok(PopulationAlleleFreqs { reader })
+    }
+
+    /// Population allele frequency of the given allele, None if the allele is unknown (or has
+    /// an allele frequency of zero).
+    ///
+    /// # Arguments
+    /// * `contig` - contig name
+    /// * `pos` - 0-based position
+    /// * `ref_allele` - reference allele
+    /// * `alt_allele` - alternative allele
+    pub(crate) fn get(
+        &mut self,
+        contig: &[u8],
+        pos: u64,
+        ref_allele: &[u8],
+        alt_allele: &[u8],
+    ) -> Result<Option<AlleleFreq>> {
+        let rid = if let Ok(rid) = self.reader.header().name2rid(contig) {
+            rid
+        } else {
+            // contig not covered by the population VCF
+            return Ok(None);
+        };
+        self.reader.fetch(rid, pos, pos + 1)?;
+
+        let mut record = self.reader.empty_record();
+        while let Some(res) = self.reader.read(&mut record) {
+            res?;
+            if record.pos() as u64 != pos {
+                continue;
+            }
+            let alleles = record.alleles();
+            if alleles[0] != ref_allele {
+                continue;
+            }
+            if let Some(i) = alleles[1..].iter().position(|alt| *alt == alt_allele) {
+                let afs = record.info(b"AF").float()?;
+                let af = afs.as_ref().and_then(|afs| afs.get(i).cloned());
+                return Ok(af
+                    .filter(|af| !af.is_missing() && !af.is_nan() && *af > 0.0)
+                    .map(|af| AlleleFreq(af.min(1.0) as f64)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn population() -> PopulationAlleleFreqs {
+        PopulationAlleleFreqs::new("tests/resources/test_population_afs/population.vcf.gz").unwrap()
+    }
+
+    #[test]
+    fn test_get() {
+        let mut population = population();
+        let af = population.get(b"chr1", 9, b"A", b"G").unwrap().unwrap();
+        assert_relative_eq!(*af, 0.1, epsilon = 1e-6);
+        // unknown alt allele or mismatching ref allele
+        assert!(population.get(b"chr1", 9, b"A", b"T").unwrap().is_none());
+        assert!(population.get(b"chr1", 9, b"C", b"G").unwrap().is_none());
+        // allele frequency of zero
+        assert!(population.get(b"chr1", 29, b"A", b"C").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_multiallelic() {
+        let mut population = population();
+        let af = population.get(b"chr1", 19, b"C", b"T").unwrap().unwrap();
+        assert_relative_eq!(*af, 0.2, epsilon = 1e-6);
+        let af = population.get(b"chr1", 19, b"C", b"G").unwrap().unwrap();
+        assert_relative_eq!(*af, 0.05, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_get_missing_site() {
+        let mut population = population();
+        // no record at position (records at neighboring positions are not used)
+        assert!(population.get(b"chr1", 10, b"A", b"G").unwrap().is_none());
+        // no record in the fetched region at all
+        assert!(population.get(b"chr1", 500, b"A", b"G").unwrap().is_none());
+        // contig is not contained in the index
+        assert!(population.get(b"chr2", 9, b"A", b"G").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_missing_population() {
+        assert!(
+            PopulationAlleleFreqs::new("tests/resources/test_population_afs/missing.vcf.gz")
+                .is_err()
+        );
+    }
+}
@@ -33,7 +33,7 @@ use crate::variants::evidence::observation::{
 use crate::variants::evidence::realignment;
 use crate::variants::model;
 use crate::variants::sample::Sample;
-use crate::variants::sample::{ProtocolStrandedness, SampleBuilder};
+use crate::variants::sample::{DuplicateDetection, ProtocolStrandedness, SampleBuilder};
 use crate::variants::types::breakends::{Breakend, BreakendIndex};
 
 #[derive(TypedBuilder)]
@@ -47,6 +47,10 @@ pub(crate) struct ObservationProcessor<R: realignment::Realigner + Clone> {
     outbcf: Option<PathBuf>,
     inbam: PathBuf,
     min_bam_refetch_distance: u64,
+    #[builder(default)]
+    duplicate_detection: Option<DuplicateDetection>,
+    #[builder(default)]
+    use_alt_hits: bool,
     options: cli::Varlociraptor,
     breakend_index: BreakendIndex,
     #[builder(default)]
@@ -100,7 +104,6 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
             "PROB_MISSED_ALLELE",
             "PROB_SAMPLE_ALT",
             "PROB_DOUBLE_OVERLAP",
-            "PROB_HIT_BASE",
             "STRAND",
             "READ_ORIENTATION",
             "READ_POSITION",
@@ -157,8 +160,10 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
             .protocol_strandedness(self.protocol_strandedness)
             .alignments(
                 bam_reader,
-                self.alignment_properties,
+                self.alignment_properties.clone(),
                 self.min_bam_refetch_distance,
+                self.duplicate_detection.clone(),
+                self.use_alt_hits,
             )
             .build()
             .unwrap();
@@ -450,7 +455,7 @@ impl<R: realignment::Realigner + Clone + std::marker::Send + std::marker::Sync>
     }
 }
 
-pub(crate) static OBSERVATION_FORMAT_VERSION: &str = "8";
+pub(crate) static OBSERVATION_FORMAT_VERSION: &str = "9";
 
 /// Read observations from BCF record.
 pub(crate) fn read_observations(
@@ -488,7 +493,6 @@ pub(crate) fn read_observations(
     let prob_missed_allele: Vec<MiniLogProb> = read_values(record, b"PROB_MISSED_ALLELE")?;
     let prob_sample_alt: Vec<MiniLogProb> = read_values(record, b"PROB_SAMPLE_ALT")?;
     let prob_double_overlap: Vec<MiniLogProb> = read_values(record, b"PROB_DOUBLE_OVERLAP")?;
-    let prob_hit_base: Vec<MiniLogProb> = read_values(record, b"PROB_HIT_BASE")?;
     let strand: Vec<Strand> = read_values(record, b"STRAND")?;
     let read_orientation: Vec<SequenceReadPairOrientation> =
         read_values(record, b"READ_ORIENTATION")?;
@@ -506,7 +510,6 @@ pub(crate) fn read_observations(
                 .prob_missed_allele(prob_missed_allele[i].to_logprob())
                 .prob_sample_alt(prob_sample_alt[i].to_logprob())
                 .prob_overlap(prob_double_overlap[i].to_logprob())
-                .prob_hit_base(prob_hit_base[i].to_logprob())
                 .strand(strand[i])
                 .read_orientation(read_orientation[i])
                 .read_position(read_position[i])
@@ -538,7 +541,6 @@ pub(crate) fn write_observations(
     let mut indel_operations = Vec::with_capacity(observations.len());
     let mut paired: BitVec<u8> = BitVec::with_capacity(observations.len() as u64);
     let mut read_position = Vec::with_capacity(observations.len());
-    let mut prob_hit_base = vec();
     let encode_logprob = |prob: LogProb| utils::MiniLogProb::new(prob);
     for obs in observations {
         prob_mapping.push(encode_logprob(obs.prob_mapping_orig()));
@@ -547,7 +549,6 @@ pub(crate) fn write_observations(
         prob_missed_allele.push(encode_logprob(obs.prob_missed_allele));
         prob_sample_alt.push(encode_logprob(obs.prob_sample_alt));
         prob_double_overlap.push(encode_logprob(obs.prob_double_overlap));
-        prob_hit_base.push(encode_logprob(obs.prob_hit_base));
         strand.push(obs.strand);
         read_orientation.push(obs.read_orientation);
         softclipped.push(obs.softclipped);
@@ -592,7 +593,6 @@ pub(crate) fn write_observations(
     push_values(record, b"INDEL_OPERATIONS", &indel_operations)?;
     push_values(record, b"PAIRED", &paired)?;
     push_values(record, b"READ_POSITION", &read_position)?;
-    push_values(record, b"PROB_HIT_BASE", &prob_hit_base)?;
 
     Ok(())
 }
@@ -609,7 +609,6 @@ pub(crate) fn remove_observation_header_entries(header: &mut bcf::Header) {
     header.remove_info(b"SOFTCLIPPED");
     header.remove_info(b"INDEL_OPERATIONS");
     header.remove_info(b"PAIRED");
-    header.remove_info(b"PROB_HIT_BASE");
     header.remove_info(b"READ_POSITION");
 }
 
@@ -31,10 +31,14 @@ use crate::testcase;
 use crate::variants::evidence::realignment;
 use crate::variants::evidence::realignment::pairhmm::GapParams;
 
-use crate::variants::model::prior::CheckablePrior;
+use crate::variants::model::bias::{BiasFlags, BiasParams};
+use crate::variants::model::modes::generic::GenericModelBuilder;
+use crate::variants::model::prior::{CheckablePrior, UpdatablePrior};
 use crate::variants::model::prior::{Inheritance, Prior};
 use crate::variants::model::{Contamination, VariantType};
-use crate::variants::sample::{estimate_alignment_properties, ProtocolStrandedness};
+use crate::variants::sample::{
+    estimate_alignment_properties, DuplicateDetection, ProtocolStrandedness,
+};
 use crate::variants::types::breakends::BreakendIndex;
 use crate::SimpleEvent;
 
@@ -78,6 +82,15 @@ pub enum Varlociraptor {
         setting = structopt::clap::AppSettings::ColoredHelp,
     )]
     DecodePHRED,
+    #[structopt(
+        name = "decode-af-posterior",
+        about = "Decode the discretized allele frequency posteriors (FORMAT field AFD, see \
+                 'varlociraptor call variants --af-posterior-density') of calls given at STDIN into a \
+                 TSV table with one row per record, sample and allele frequency, printed to STDOUT.",
+        usage = "varlociraptor decode-af-posterior < calls.bcf > af-posteriors.tsv",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    DecodeAFPosterior,
     #[structopt(
         name = "estimate",
         about = "Perform estimations.",
@@ -271,6 +284,50 @@ pub enum PreprocessKind {
         )]
         #[serde(default = "default_pairhmm_mode")]
         pairhmm_mode: String,
+        #[structopt(
+            long = "detect-duplicates",
+            help = "Detect duplicate fragments that have not been marked in the BAM file on the fly \
+                    (same start, end and orientation). Observations of duplicates are combined into a \
+                    single observation per fragment, such that they are not counted as independent evidence."
+        )]
+        #[serde(default)]
+        detect_duplicates: bool,
+        #[structopt(
+            long = "umi-tag",
+            requires = "detect-duplicates",
+            help = "Auxiliary BAM tag containing unique molecular identifiers (UMIs) of reads (e.g. RX). \
+                    If given, fragments are only considered duplicates if they also share the same UMI."
+        )]
+        #[serde(default)]
+        umi_tag: Option<String>,
+        #[structopt(
+            long = "use-alt-hits",
+            help = "Consider alternative hits reported by the read mapper (XA tag, e.g. from BWA) \
+                    when calculating mapping probabilities. Instead of relying on MAPQ, the probability \
+                    of each locus is calculated from the edit distances of all hits. This way, reads in \
+                    paralogous regions (including pairs with MAPQ 0) are considered instead of discarded."
+        )]
+        #[serde(default)]
+        use_alt_hits: bool,
+        #[structopt(
+            long = "estimate-mapq-calibration",
+            conflicts_with = "alignment-properties",
+            help = "Estimate a calibration of the MAPQ values reported by the read mapper from the \
+                    alternative hits (XA tag) of the first 10000 alignments, and use calibrated mapping \
+                    probabilities for all reads without alternative hits. A calibration can also be \
+                    supplied via the mapq_calibration entry of the --alignment-properties file."
+        )]
+        #[serde(default)]
+        estimate_mapq_calibration: bool,
+        #[structopt(
+            long = "empirical-insert-size",
+            help = "Estimate an empirical insert size distribution (via kernel density estimation) \
+                    instead of assuming a normal distribution. Use this for libraries with skewed \
+                    or heavy-tailed insert size distributions.",
+            conflicts_with = "alignment-properties"
+        )]
+        #[serde(default)]
+        empirical_insert_size: bool,
     },
 }
 
@@ -331,7 +388,9 @@ pub enum EstimateKind {
     #[structopt(
         name = "mutational-burden",
         about = "Estimate mutational burden. Takes Varlociraptor calls (must be annotated \
-                 with e.g. VEP but using ANN instead of CSQ) from STDIN, prints mutational burden estimate in Vega-lite JSON format to STDOUT. \
+                 with e.g. VEP but using ANN instead of CSQ, unless target regions are given) from STDIN, \
+                 prints mutational burden estimate in Vega-lite JSON format (or as TSV/JSON table with \
+                 credible intervals) to STDOUT. \
                  It can be converted to an image via vega-lite-cli (see conda package).",
         usage = "varlociraptor estimate mutational-burden --coding-genome-size 3e7 --events SOMATIC_TUMOR \
                  --sample tumor < calls.bcf | vg2svg > tmb.svg",
@@ -351,8 +410,17 @@ pub enum EstimateKind {
             help = "Size (in bases) of the covered coding genome."
         )]
         coding_genome_size: f64,
+        #[structopt(
+            long = "targets",
+            parse(from_os_str),
+            help = "BED file with target regions (e.g. of a gene panel). If given, only variants inside \
+                    the target regions are considered and the total size of the regions is used \
+                    instead of --coding-genome-size. ANN annotations are not required in this case."
+        )]
+        targets: Option<PathBuf>,
         #[structopt(
             long = "plot-mode",
+            default_value = "curve",
             possible_values = &estimation::mutational_burden::PlotMode::iter().map(|v| v.into()).collect_vec(),
             help = "How to plot (as stratified curve, histogram or multi-sample barplot)."
         )]
@@ -360,9 +428,419 @@ pub enum EstimateKind {
         #[structopt(
             long = "vaf-cutoff",
             default_value = "0.2",
-            help = "Minimal variant allelic fraction to consider for mutli-sample barplot"
+            help = "Minimal variant allelic fraction to consider for mutli-sample barplot and tabular output"
         )]
         cutoff: f64,
+        #[structopt(
+            long = "output-format",
+            default_value = "vega",
+            possible_values = &estimation::mutational_burden::OutputFormat::iter().map(|v| v.into()).collect_vec(),
+            help = "Output format: Vega-lite plot or table (TSV or JSON) with expected mutational \
+                    burden and credible interval per sample and variant type."
+        )]
+        output_format: estimation::mutational_burden::OutputFormat,
+        #[structopt(
+            long = "credible-interval",
+            default_value = "0.95",
+            help = "Width of the credible interval of the mutational burden (derived from the \
+                    posterior probabilities of the individual calls) in the tabular output."
+        )]
+        credible_interval: f64,
+    },
+    #[structopt(
+        name = "mutation-rate",
+        about = "Estimate the somatic effective mutation rate by fitting the neutral evolution model of \
+                 Williams et al. (Nature Genetics 2016) to the allele frequencies of somatic variants. \
+                 Takes Varlociraptor calls from STDIN, prints the estimate to STDOUT in YAML format, such \
+                 that it can be directly used as somatic-effective-mutation-rate in a scenario.",
+        usage = "varlociraptor estimate mutation-rate --events SOMATIC_TUMOR --sample tumor \
+                 --plot fit.vl.json < calls.bcf > rate.yaml",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    MutationRate {
+        #[structopt(long = "events", help = "Events to consider (e.g. SOMATIC_TUMOR).")]
+        events: Vec<String>,
+        #[structopt(long = "sample", help = "Name of the sample in the given VCF/BCF.")]
+        sample: String,
+        #[structopt(
+            long = "min-prob",
+            default_value = "0.95",
+            help = "Minimum posterior probability of the given events for a variant to be considered."
+        )]
+        min_prob: f64,
+        #[structopt(
+            long = "min-vaf",
+            default_value = "0.12",
+            help = "Minimum allele frequency of variants to consider for the fit. Below, \
+                    the allele frequency distribution is usually affected by limited sensitivity."
+        )]
+        min_vaf: f64,
+        #[structopt(
+            long = "max-vaf",
+            default_value = "0.24",
+            help = "Maximum allele frequency of variants to consider for the fit. Above, \
+                    the allele frequency distribution is usually dominated by clonal variants."
+        )]
+        max_vaf: f64,
+        #[structopt(
+            long = "genome-size",
+            requires = "covered-size",
+            help = "Size (in bases) of the genome, as given in the scenario. Has to be specified \
+                    together with --covered-size if calls stem from a subset of the genome (e.g. an exome)."
+        )]
+        genome_size: Option<f64>,
+        #[structopt(
+            long = "covered-size",
+            requires = "genome-size",
+            help = "Size (in bases) of the region covered by the calls (e.g. the exome)."
+        )]
+        covered_size: Option<f64>,
+        #[structopt(
+            long = "plot",
+            parse(from_os_str),
+            help = "Path to write a Vega-Lite diagnostic plot of the fit to."
+        )]
+        plot: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "mutational-signatures",
+        about = "Estimate the 96-channel trinucleotide-context spectrum of SNVs and fit the exposures \
+                 of the given mutational signatures (e.g. COSMIC SBS) via non-negative least squares. \
+                 Takes Varlociraptor calls from STDIN. Each SNV is weighted by the posterior \
+                 probability of the given events. Prints exposures with bootstrapped confidence \
+                 intervals to STDOUT in TSV format.",
+        usage = "varlociraptor estimate mutational-signatures --events SOMATIC_TUMOR \
+                 --reference reference.fa --signatures COSMIC_v3.2_SBS_GRCh38.txt < calls.bcf > exposures.tsv",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    MutationalSignatures {
+        #[structopt(long = "events", help = "Events to consider (e.g. SOMATIC_TUMOR).")]
+        events: Vec<String>,
+        #[structopt(
+            long = "reference",
+            parse(from_os_str),
+            help = "FASTA file with reference genome. Has to be indexed with samtools faidx."
+        )]
+        reference: PathBuf,
+        #[structopt(
+            long = "signatures",
+            parse(from_os_str),
+            help = "Tab-separated signature matrix in COSMIC format (one row per trinucleotide context, \
+                    e.g. A[C>T]G, one column per signature)."
+        )]
+        signatures: PathBuf,
+        #[structopt(
+            long = "bootstrap-samples",
+            default_value = "100",
+            help = "Number of bootstrap samples for calculating confidence intervals of the exposures."
+        )]
+        bootstrap_samples: usize,
+        #[structopt(
+            long = "confidence",
+            default_value = "0.95",
+            help = "Width of the confidence intervals of the exposures."
+        )]
+        confidence: f64,
+        #[structopt(
+            long = "spectrum",
+            parse(from_os_str),
+            help = "Path to write the (probability weighted) trinucleotide-context spectrum to (TSV format)."
+        )]
+        spectrum: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "clones",
+        about = "Cluster somatic variants into subclones by their cancer cell fractions across one or \
+                 more samples, using a finite mixture model fitted via expectation maximization (the \
+                 number of clusters is chosen by BIC). Takes Varlociraptor calls from STDIN. Allele \
+                 frequency likelihoods are taken from the AFD field if present (see \
+                 'varlociraptor call variants --af-posterior-density'), and approximated from AF and DP \
+                 otherwise. Prints the calls with cluster assignments (INFO fields CLUSTER and \
+                 CLUSTER_PROB) to STDOUT and writes cluster centroids per sample to the given TSV file.",
+        usage = "varlociraptor estimate clones --events SOMATIC_TUMOR --purity tumor=0.8 \
+                 --centroids clusters.tsv < calls.bcf > clustered-calls.bcf",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    Clones {
+        #[structopt(long = "events", help = "Events to consider (e.g. SOMATIC_TUMOR).")]
+        events: Vec<String>,
+        #[structopt(
+            long = "samples",
+            help = "Samples to cluster over (all samples in the given VCF/BCF if omitted)."
+        )]
+        samples: Vec<String>,
+        #[structopt(
+            long = "min-prob",
+            default_value = "0.95",
+            help = "Minimum posterior probability of the given events for a variant to be clustered."
+        )]
+        min_prob: f64,
+        #[structopt(
+            long = "max-clusters",
+            default_value = "10",
+            help = "Maximum number of clusters."
+        )]
+        max_clusters: usize,
+        #[structopt(
+            long = "purity",
+            help = "Tumor purity of a sample, given as samplename=value. Assumed to be 1.0 \
+                    for samples that are not given."
+        )]
+        purity: Vec<String>,
+        #[structopt(
+            long = "copy-numbers",
+            parse(from_os_str),
+            help = "TSV file with total copy numbers of genomic segments (columns chrom, start, \
+                    end, sample, copy_number; 0-based, half-open). Variants outside of the given \
+                    segments are assumed to be diploid."
+        )]
+        copy_numbers: Option<PathBuf>,
+        #[structopt(
+            long = "centroids",
+            parse(from_os_str),
+            help = "Path to write cluster centroids (cancer cell fraction per cluster and sample) to (TSV format)."
+        )]
+        centroids: PathBuf,
+    },
+    #[structopt(
+        name = "purity",
+        about = "Estimate tumor purity (and optionally ploidy) from the allele frequencies of \
+                 heterozygous germline variants and clonal somatic variants. Takes Varlociraptor calls \
+                 from STDIN. Calls have to be obtained without purity correction (i.e., with \
+                 --purity 1.0 in tumor-normal mode). Prints the estimate to STDOUT in YAML format.",
+        usage = "varlociraptor estimate purity --sample tumor --plot profile.vl.json < calls.bcf > purity.yaml",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    Purity {
+        #[structopt(
+            long = "sample",
+            default_value = "tumor",
+            help = "Name of the tumor sample in the given VCF/BCF."
+        )]
+        sample: String,
+        #[structopt(
+            long = "somatic-events",
+            default_value = "SOMATIC_TUMOR",
+            help = "Events representing somatic variants in the tumor sample."
+        )]
+        somatic_events: Vec<String>,
+        #[structopt(
+            long = "germline-events",
+            default_value = "GERMLINE_HET",
+            help = "Events representing heterozygous germline variants."
+        )]
+        germline_events: Vec<String>,
+        #[structopt(
+            long = "min-prob",
+            default_value = "0.95",
+            help = "Minimum posterior probability of the given events for a variant to be considered."
+        )]
+        min_prob: f64,
+        #[structopt(
+            long = "ploidy",
+            help = "Tumor ploidy to assume. If omitted, the ploidy is estimated jointly with the purity."
+        )]
+        ploidy: Option<f64>,
+        #[structopt(
+            long = "plot",
+            parse(from_os_str),
+            help = "Path to write a Vega-Lite plot of the profile likelihood of the purity to."
+        )]
+        plot: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "callable-regions",
+        about = "Determine regions in which a somatic variant at the given allele frequency would be \
+                 detectable with the given posterior probability, given the sequencing depth. \
+                 Detectability is assessed with a simple binomial model (equal prior probability for \
+                 presence and absence of the variant, uniform sequencing error rate, no mapping or \
+                 base quality weighting). Reads with MAPQ 0 (without alternative hits) are not \
+                 counted. With multiple BAM files, regions have to be callable in all of them. \
+                 Prints regions in BED format to STDOUT.",
+        usage = "varlociraptor estimate callable-regions --bams tumor.bam normal.bam --vaf 0.1 > callable.bed",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    CallableRegions {
+        #[structopt(
+            long = "bams",
+            required = true,
+            parse(from_os_str),
+            help = "BAM files with aligned reads (sorted)."
+        )]
+        bams: Vec<PathBuf>,
+        #[structopt(
+            long = "vaf",
+            default_value = "0.1",
+            help = "Allele frequency of the variant to detect."
+        )]
+        vaf: f64,
+        #[structopt(
+            long = "min-prob",
+            default_value = "0.95",
+            help = "Minimum posterior probability at which a variant is considered to be detected."
+        )]
+        min_prob: f64,
+        #[structopt(
+            long = "error-rate",
+            default_value = "0.001",
+            help = "Sequencing error rate, used to distinguish variants from errors."
+        )]
+        error_rate: f64,
+        #[structopt(
+            long = "use-alt-hits",
+            help = "Count reads with MAPQ 0 if the mapper reports alternative hits (XA tag), \
+                    as done by 'varlociraptor preprocess variants --use-alt-hits'."
+        )]
+        #[serde(default)]
+        use_alt_hits: bool,
+    },
+    #[structopt(
+        name = "power",
+        about = "Estimate the sensitivity of calling the events of a scenario. Pileups of SNVs \
+                 are simulated for the given sample at the given depths and allele frequencies, \
+                 and evaluated with the statistical model of Varlociraptor. \
+                 Prints sensitivities (the fraction of simulations in which the event is called \
+                 with at least the given posterior probability) per depth, allele frequency and \
+                 event as TSV to STDOUT.",
+        usage = "varlociraptor estimate power --scenario scenario.yaml --contig chr1 \
+                 --sample tumor --depths 30 60 100 --vafs 0.05 0.1 0.2 0.5 \
+                 --plot power.vl.json > power.tsv",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    Power {
+        #[structopt(
+            parse(from_os_str),
+            long = "scenario",
+            required = true,
+            help = "Variant calling scenario that configures the prior and the events."
+        )]
+        scenario: PathBuf,
+        #[structopt(
+            long = "contig",
+            required = true,
+            help = "Contig to consider for ploidy information."
+        )]
+        contig: String,
+        #[structopt(
+            long = "sample",
+            required = true,
+            help = "Sample for which the allele frequency is varied."
+        )]
+        sample: String,
+        #[structopt(
+            long = "depths",
+            required = true,
+            help = "Sequencing depths to simulate (for all samples)."
+        )]
+        depths: Vec<usize>,
+        #[structopt(
+            long = "vafs",
+            required = true,
+            help = "Allele frequencies to simulate for the given sample."
+        )]
+        vafs: Vec<f64>,
+        #[structopt(
+            long = "background-vafs",
+            help = "Allele frequencies of the other samples, given as samplename=value \
+                    (default: 0.0)."
+        )]
+        #[serde(default)]
+        background_vafs: Vec<String>,
+        #[structopt(
+            long = "mapq-profile",
+            parse(from_os_str),
+            help = "TSV file with columns mapq and weight, describing the distribution of \
+                    mapping qualities of the simulated reads (default: MAPQ 60 for all reads)."
+        )]
+        mapq_profile: Option<PathBuf>,
+        #[structopt(
+            long = "simulations",
+            default_value = "100",
+            help = "Number of simulated pileups per depth and allele frequency."
+        )]
+        simulations: usize,
+        #[structopt(
+            long = "min-prob",
+            default_value = "0.95",
+            help = "Minimum posterior probability at which an event is considered to be called."
+        )]
+        min_prob: f64,
+        #[structopt(
+            long = "min-sensitivity",
+            default_value = "0.9",
+            help = "Sensitivity for which the minimum detectable allele frequency is reported \
+                    per event and depth (in the log)."
+        )]
+        min_sensitivity: f64,
+        #[structopt(
+            long = "error-rate",
+            default_value = "0.001",
+            help = "Sequencing error rate of the simulated reads."
+        )]
+        error_rate: f64,
+        #[structopt(
+            long = "plot",
+            parse(from_os_str),
+            help = "Path to write a Vega-Lite plot of the sensitivity curves to."
+        )]
+        plot: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "alignment-properties",
+        about = "Estimate alignment properties (insert size, maximum CIGAR operation lengths, \
+                 softclip fraction) from the first 10000 usable alignments of the given BAM file, \
+                 as done during preprocessing. With multiple read groups, insert sizes are \
+                 estimated per read group. Prints the properties as JSON to STDOUT, such that \
+                 they can be passed to 'varlociraptor preprocess variants --alignment-properties'.",
+        usage = "varlociraptor estimate alignment-properties --bam sample.bam \
+                 --report report.vl.json > alignment-properties.json",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    AlignmentProperties {
+        #[structopt(
+            long = "bam",
+            required = true,
+            parse(from_os_str),
+            help = "BAM file with aligned reads (ideally unsorted, to avoid positional biases)."
+        )]
+        bam: PathBuf,
+        #[structopt(
+            long = "omit-insert-size",
+            help = "Do not estimate the insert size (e.g. for amplicon data)."
+        )]
+        #[serde(default)]
+        omit_insert_size: bool,
+        #[structopt(
+            long = "allow-hardclips",
+            help = "Consider hardclipped alignments for estimating the insert size distribution. \
+                    By default, they are skipped. Hardclips are always allowed if the insert size \
+                    is omitted."
+        )]
+        #[serde(default)]
+        allow_hardclips: bool,
+        #[structopt(
+            long = "estimate-mapq-calibration",
+            help = "Estimate a calibration of the MAPQ values reported by the read mapper from \
+                    the alternative hits (XA tag) of the alignments."
+        )]
+        #[serde(default)]
+        estimate_mapq_calibration: bool,
+        #[structopt(
+            long = "empirical-insert-size",
+            help = "Estimate an empirical insert size distribution (via kernel density estimation) \
+                    instead of assuming a normal distribution. Use this for libraries with skewed \
+                    or heavy-tailed insert size distributions."
+        )]
+        #[serde(default)]
+        empirical_insert_size: bool,
+        #[structopt(
+            long = "report",
+            parse(from_os_str),
+            help = "Path to write a Vega-Lite diagnostics report to, showing the empirical insert \
+                    size histogram versus the fitted distribution (per read group), along with \
+                    warnings about poor fits or bimodal distributions."
+        )]
+        report: Option<PathBuf>,
     },
 }
 
@@ -417,6 +895,14 @@ pub enum CallKind {
         )]
         #[serde(default)]
         omit_divindel_bias: bool,
+        #[structopt(
+            long = "omit-ffpe-bias",
+            help = "Do not consider FFPE bias when calculating the probability of an artifact. \
+                    FFPE bias is only considered for C>T and G>A substitutions in samples that are \
+                    marked as FFPE in the scenario (ffpe: true)."
+        )]
+        #[serde(default)]
+        omit_ffpe_bias: bool,
         #[structopt(
             long = "min-divindel-rate",
             default_value = "0.25",
@@ -428,6 +914,39 @@ pub enum CallKind {
         )]
         #[serde(default = "default_min_divindel_other_rate")]
         min_divindel_other_rate: f64,
+        #[structopt(
+            long = "af-credible-interval",
+            help = "Report a credible interval of the given width (e.g. 0.95) for the allele frequency \
+                    of each sample (FORMAT fields AFLO and AFHI)."
+        )]
+        #[serde(default)]
+        af_credible_interval: Option<f64>,
+        #[structopt(
+            long = "af-posterior-density",
+            help = "Report the discretized posterior distribution of the allele frequency of each sample \
+                    (FORMAT field AFD). Use 'varlociraptor decode-af-posterior' to convert it into a table."
+        )]
+        #[serde(default)]
+        af_posterior_density: bool,
+        #[structopt(
+            long = "learn-prior",
+            value_name = "N",
+            help = "Learn the prior hyperparameters (heterozygosity, mutation rates and variant type \
+                    fractions) from N records (sampled uniformly from the input) by maximizing their marginal likelihood \
+                    (empirical Bayes), before calling with the fitted prior. Only parameters that are \
+                    defined in the scenario are learned, with the given values serving as starting points. \
+                    Learned values are recorded in the header of the output BCF."
+        )]
+        #[serde(default)]
+        learn_prior: Option<usize>,
+        #[structopt(
+            long = "reference",
+            help = "FASTA file with reference genome (indexed with samtools faidx). Required for \
+                    looking up the sequence context of SNVs if context dependent mutation rates \
+                    are defined for the species in the scenario."
+        )]
+        #[serde(default)]
+        reference: Option<PathBuf>,
         #[structopt(
             long = "testcase-locus",
             help = "Create a test case for the given locus. Locus must be given in the form \
@@ -551,14 +1070,31 @@ pub enum VariantCallMode {
 pub enum FilterMethod {
     #[structopt(
         name = "control-fdr",
-        about = "Filter variant calls by controlling FDR. Filtered calls are printed to STDOUT.",
+        about = "Filter variant calls by controlling FDR. Filtered calls are printed to STDOUT. \
+                 If no call file is given, calls are read from STDIN (and temporarily spooled to \
+                 disk, such that memory usage stays bounded). If multiple call files are given \
+                 (e.g. of a cohort), FDR is controlled jointly, such that the union of the \
+                 filtered calls meets the FDR, and filtered calls are written to the files given \
+                 with --output (one per call file).",
         usage = "varlociraptor filter-calls control-fdr calls.bcf --events SOMATIC_TUMOR --fdr 0.05 \
                  --var SNV > calls.filtered.bcf",
         setting = structopt::clap::AppSettings::ColoredHelp,
     )]
     ControlFDR {
-        #[structopt(parse(from_os_str), help = "BCF file with varlociraptor calls.")]
-        calls: PathBuf,
+        #[structopt(
+            parse(from_os_str),
+            help = "BCF file(s) with varlociraptor calls (read from STDIN if omitted)."
+        )]
+        #[serde(default)]
+        calls: Vec<PathBuf>,
+        #[structopt(
+            long = "output",
+            parse(from_os_str),
+            help = "BCF files to write the filtered calls to, one per given call file \
+            (in the same order). Required if multiple call files are given."
+        )]
+        #[serde(default)]
+        output: Vec<PathBuf>,
         #[structopt(
             long = "var",
             possible_values = &VariantType::iter().map(|v| v.into()).collect_vec(),
@@ -575,6 +1111,48 @@ pub enum FilterMethod {
             of the selected events has to be at least 1-fdr."
         )]
         local: bool,
+        #[structopt(
+            long = "smart",
+            help = "First assign each allele its maximum a posteriori (MAP) event, and only consider \
+            alleles whose MAP event is among the given events. This ensures that calls where another \
+            event is more likely do not pass the filter. The MAP event and its posterior error \
+            probability are annotated as INFO tags MAP_EVENT and MAP_PEP."
+        )]
+        #[serde(default)]
+        smart: bool,
+        #[structopt(long, help = "Events to consider.")]
+        events: Vec<String>,
+        #[structopt(long, help = "Minimum indel length to consider.")]
+        minlen: Option<u64>,
+        #[structopt(long, help = "Maximum indel length to consider (exclusive).")]
+        maxlen: Option<u64>,
+    },
+    #[structopt(
+        name = "annotate-fdr",
+        about = "Annotate variant calls with q-values (the smallest FDR at which a call would be \
+                 accepted when controlling FDR) and posterior error probabilities (local FDR) of the \
+                 given events, as INFO tags Q_<EVENTS> and PEP_<EVENTS> (PHRED scaled). No calls are \
+                 removed. Annotated calls are printed to STDOUT.",
+        usage = "varlociraptor filter-calls annotate-fdr calls.bcf --events SOMATIC_TUMOR \
+                 --stratify-by-vartype > calls.annotated.bcf",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    AnnotateFDR {
+        #[structopt(parse(from_os_str), help = "BCF file with varlociraptor calls.")]
+        calls: PathBuf,
+        #[structopt(
+            long = "var",
+            possible_values = &VariantType::iter().map(|v| v.into()).collect_vec(),
+            help = "Variant type to consider. Alleles of other types are not annotated."
+        )]
+        vartype: Option<VariantType>,
+        #[structopt(
+            long = "stratify-by-vartype",
+            help = "Calculate q-values separately for each variant type. Otherwise, less certain \
+            variant types will obtain larger q-values than when considered on their own."
+        )]
+        #[serde(default)]
+        stratify: bool,
         #[structopt(long, help = "Events to consider.")]
         events: Vec<String>,
         #[structopt(long, help = "Minimum indel length to consider.")]
@@ -599,6 +1177,25 @@ pub enum FilterMethod {
         #[structopt(long, help = "Events to consider.")]
         events: Vec<String>,
     },
+    #[structopt(
+        name = "sample-filter",
+        about = "Filter variant calls by an expression over sample specific values. Samples are \
+                 addressed by their name, followed by a dot and one of the fields AF, AFLO, AFHI \
+                 (allele frequency estimate and credible interval bounds), DP (expected depth), \
+                 ALT (number of observations with at least barely positive evidence for the alt allele), \
+                 STRONG_ALT (number of observations with strong or very strong evidence for the alt \
+                 allele), or a bias tag (SB, ROB, RPB, SCB, DIB, FFB). Numeric fields can be compared \
+                 with <, <=, >, >=, ==, !=, bias tags with == and != against a quoted symbol. \
+                 Comparisons can be combined with &&, ||, ! and parentheses. Comparisons on missing \
+                 values are false. Calls are taken from STDIN, filtered calls are printed to STDOUT.",
+        usage = "varlociraptor filter-calls sample-filter 'tumor.AF >= 0.05 && normal.DP >= 10 && \
+                 tumor.SB == \".\"' < calls.bcf > calls.filtered.bcf",
+        setting = structopt::clap::AppSettings::ColoredHelp,
+    )]
+    SampleFilter {
+        #[structopt(help = "Filter expression, e.g. 'tumor.AF >= 0.05 && normal.DP >= 10'.")]
+        expression: String,
+    },
 }
 
 pub(crate) type PathMap = HashMap<String, PathBuf>;
@@ -644,6 +1241,11 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                     reference_buffer_size,
                     min_bam_refetch_distance,
                     pairhmm_mode,
+                    detect_duplicates,
+                    umi_tag,
+                    use_alt_hits,
+                    estimate_mapq_calibration,
+                    empirical_insert_size,
                 } => {
                     // TODO: handle testcases
 
@@ -667,6 +1269,8 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                         &bam,
                         omit_insert_size,
                         allow_hardclips,
+                        estimate_mapq_calibration,
+                        empirical_insert_size,
                     )?;
 
                     let gap_params = GapParams {
@@ -676,6 +1280,12 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                         prob_deletion_extend_artifact: LogProb::from(spurious_delext_rate),
                     };
 
+                    let duplicate_detection = if detect_duplicates {
+                        Some(DuplicateDetection::new(umi_tag))
+                    } else {
+                        None
+                    };
+
                     let reference_buffer = Arc::new(reference::Buffer::new(
                         fasta::IndexedReader::from_file(&reference)
                             .context("Unable to read genome reference.")?,
@@ -690,6 +1300,8 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                                 .max_depth(max_depth)
                                 .inbam(bam)
                                 .min_bam_refetch_distance(min_bam_refetch_distance)
+                                .duplicate_detection(duplicate_detection)
+                                .use_alt_hits(use_alt_hits)
                                 .reference_buffer(Arc::clone(&reference_buffer))
                                 .breakend_index(BreakendIndex::new(&candidates)?)
                                 .inbcf(candidates)
@@ -711,6 +1323,8 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                                 .max_depth(max_depth)
                                 .inbam(bam)
                                 .min_bam_refetch_distance(min_bam_refetch_distance)
+                                .duplicate_detection(duplicate_detection)
+                                .use_alt_hits(use_alt_hits)
                                 .reference_buffer(Arc::clone(&reference_buffer))
                                 .breakend_index(BreakendIndex::new(&candidates)?)
                                 .inbcf(candidates)
@@ -737,12 +1351,25 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                     omit_read_position_bias,
                     omit_softclip_bias,
                     omit_divindel_bias,
+                    omit_ffpe_bias,
                     min_divindel_other_rate,
+                    af_credible_interval,
+                    af_posterior_density,
+                    learn_prior,
+                    reference,
                     testcase_locus,
                     testcase_prefix,
                     testcase_anonymous,
                     output,
                 } => {
+                    if let Some(width) = af_credible_interval {
+                        if !(width > 0.0 && width < 1.0) {
+                            return Err(
+                                errors::Error::InvalidCredibleIntervalWidth { width }.into()
+                            );
+                        }
+                    }
+
                     let testcase_builder = if let Some(testcase_locus) = testcase_locus {
                         if let Some(testcase_prefix) = testcase_prefix {
                             // TODO obtain sample information from input bcfs?
@@ -788,6 +1415,27 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                         let breakend_index =
                             BreakendIndex::new(sample_observations.first_not_none()?)?;
 
+                        let context_mutation_rates = scenario
+                            .species()
+                            .as_ref()
+                            .and_then(|species| species.context_mutation_rates().clone());
+                        let reference_buffer = match (&context_mutation_rates, &reference) {
+                            (Some(_), Some(reference)) => Some(Arc::new(reference::Buffer::new(
+                                fasta::IndexedReader::from_file(reference)
+                                    .context("Unable to read genome reference.")?,
+                                default_reference_buffer_size(),
+                            ))),
+                            (Some(_), None) => {
+                                return Err(errors::Error::InvalidPriorConfiguration {
+                                    msg: "context mutation rates given but no reference genome: \
+                                          specify the reference genome via --reference"
+                                        .to_owned(),
+                                }
+                                .into())
+                            }
+                            (None, _) => None,
+                        };
+
                         let prior = Prior::builder()
                             .ploidies(None)
                             .universe(None)
@@ -807,22 +1455,58 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                                 species.heterozygosity().map(|het| LogProb::from(Prob(het)))
                             }))
                             .variant_type_fractions(scenario.variant_type_fractions())
+                            .context_mutation_rates(context_mutation_rates)
+                            .reference_buffer(reference_buffer)
                             .build();
 
+                        let population_allele_freqs = scenario
+                            .species()
+                            .as_ref()
+                            .and_then(|species| species.population_allele_frequencies().clone());
+                        if population_allele_freqs.is_some()
+                            && scenario
+                                .species()
+                                .as_ref()
+                                .and_then(|species| *species.heterozygosity())
+                                .is_none()
+                        {
+                            return Err(errors::Error::InvalidPriorConfiguration {
+                                msg: "population allele frequencies given but no heterozygosity \
+                                      defined: define heterozygosity of the species as a fallback \
+                                      for sites without known population allele frequency"
+                                    .to_owned(),
+                            }
+                            .into());
+                        }
+
+                        let somatic_hotspots = scenario
+                            .species()
+                            .as_ref()
+                            .and_then(|species| species.somatic_hotspots().clone());
+
                         // setup caller
                         let caller = calling::variants::CallerBuilder::default()
                             .samplenames(sample_infos.names)
                             .observations(sample_observations)
-                            .omit_strand_bias(omit_strand_bias)
-                            .omit_read_orientation_bias(omit_read_orientation_bias)
-                            .omit_read_position_bias(omit_read_position_bias)
-                            .omit_softclip_bias(omit_softclip_bias)
-                            .omit_divindel_bias(omit_divindel_bias)
-                            .min_divindel_other_rate(min_divindel_other_rate)
+                            .omit_biases(BiasFlags {
+                                strand_bias: omit_strand_bias,
+                                read_orientation_bias: omit_read_orientation_bias,
+                                read_position_bias: omit_read_position_bias,
+                                softclip_bias: omit_softclip_bias,
+                                ffpe_bias: omit_ffpe_bias,
+                                divindel_bias: omit_divindel_bias,
+                            })
+                            .bias_params(BiasParams::new(min_divindel_other_rate))
+                            .ffpe_samples(sample_infos.ffpe)
                             .scenario(scenario)
                             .prior(prior)
+                            .population_allele_freqs(population_allele_freqs)
+                            .somatic_hotspots(somatic_hotspots)
                             .contaminations(sample_infos.contaminations)
                             .resolutions(sample_infos.resolutions)
+                            .af_credible_interval(af_credible_interval)
+                            .af_posterior_density(af_posterior_density)
+                            .learn_prior(learn_prior)
                             .breakend_index(breakend_index)
                             .outbcf(output)
                             .build()
@@ -976,9 +1660,11 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
         Varlociraptor::FilterCalls { method } => match method {
             FilterMethod::ControlFDR {
                 calls,
+                output,
                 events,
                 fdr,
                 local,
+                smart,
                 vartype,
                 minlen,
                 maxlen,
@@ -987,23 +1673,50 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                     .into_iter()
                     .map(|event| SimpleEvent { name: event })
                     .collect_vec();
-                let vartype = match (vartype, minlen, maxlen) {
-                    (Some(VariantType::Insertion(None)), Some(minlen), Some(maxlen)) => {
-                        Some(VariantType::Insertion(Some(minlen..maxlen)))
-                    }
-                    (Some(VariantType::Deletion(None)), Some(minlen), Some(maxlen)) => {
-                        Some(VariantType::Deletion(Some(minlen..maxlen)))
-                    }
-                    (vartype, _, _) => vartype,
-                };
+                let vartype = vartype_with_len(vartype, minlen, maxlen);
 
-                filtration::fdr::control_fdr::<_, &PathBuf, &str>(
+                if calls.len() <= 1 && output.is_empty() {
+                    filtration::fdr::control_fdr::<_, &PathBuf, &str>(
+                        calls.first(),
+                        None,
+                        &events,
+                        vartype.as_ref(),
+                        LogProb::from(Prob::checked(fdr)?),
+                        local,
+                        smart,
+                    )?;
+                } else {
+                    filtration::fdr::control_fdr_joint(
+                        &calls,
+                        &output,
+                        &events,
+                        vartype.as_ref(),
+                        LogProb::from(Prob::checked(fdr)?),
+                        local,
+                        smart,
+                    )?;
+                }
+            }
+            FilterMethod::AnnotateFDR {
+                calls,
+                events,
+                vartype,
+                stratify,
+                minlen,
+                maxlen,
+            } => {
+                let events = events
+                    .into_iter()
+                    .map(|event| SimpleEvent { name: event })
+                    .collect_vec();
+                let vartype = vartype_with_len(vartype, minlen, maxlen);
+
+                filtration::fdr::annotate_fdr::<_, &PathBuf, &str>(
                     &calls,
                     None,
                     &events,
                     vartype.as_ref(),
-                    LogProb::from(Prob::checked(fdr)?),
-                    local,
+                    stratify,
                 )?;
             }
             FilterMethod::PosteriorOdds { ref events, odds } => {
@@ -1018,7 +1731,16 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                     None, None, &events, odds,
                 )?;
             }
+            FilterMethod::SampleFilter { ref expression } => {
+                let filter: filtration::sample_filter::SampleFilter = expression.parse()?;
+                filtration::sample_filter::filter_by_sample_expression::<&PathBuf, &PathBuf>(
+                    None, None, &filter,
+                )?;
+            }
         },
+        Varlociraptor::DecodeAFPosterior => {
+            conversion::decode_af_posterior::decode_af_posterior()?;
+        }
         Varlociraptor::DecodePHRED => {
             conversion::decode_phred::decode_phred()?;
         }
@@ -1027,15 +1749,191 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
                 events,
                 sample,
                 coding_genome_size,
+                targets,
                 mode,
                 cutoff,
-            } => estimation::mutational_burden::collect_estimates(
+                output_format,
+                credible_interval,
+            } => {
+                let targets = targets
+                    .map(estimation::mutational_burden::Targets::from_bed)
+                    .transpose()?;
+                estimation::mutational_burden::collect_estimates(
+                    &events,
+                    &sample,
+                    coding_genome_size as u64,
+                    targets.as_ref(),
+                    mode,
+                    output_format,
+                    cutoff as f64,
+                    credible_interval,
+                )?
+            }
+            EstimateKind::MutationRate {
+                events,
+                sample,
+                min_prob,
+                min_vaf,
+                max_vaf,
+                genome_size,
+                covered_size,
+                plot,
+            } => {
+                let coverage_factor = match (genome_size, covered_size) {
+                    (Some(genome_size), Some(covered_size)) => genome_size / covered_size,
+                    _ => 1.0,
+                };
+                estimation::effective_mutation_rate::collect_estimate(
+                    &events,
+                    &sample,
+                    Prob::checked(min_prob)?,
+                    min_vaf,
+                    max_vaf,
+                    coverage_factor,
+                    plot,
+                )?
+            }
+            EstimateKind::MutationalSignatures {
+                events,
+                reference,
+                signatures,
+                bootstrap_samples,
+                confidence,
+                spectrum,
+            } => estimation::mutational_signatures::estimate(
                 &events,
+                reference,
+                signatures,
+                bootstrap_samples,
+                *Prob::checked(confidence)?,
+                spectrum,
+            )?,
+            EstimateKind::Clones {
+                events,
+                samples,
+                min_prob,
+                max_clusters,
+                purity,
+                copy_numbers,
+                centroids,
+            } => {
+                let purities = estimation::clones::parse_purities(&purity)?;
+                let copy_numbers = copy_numbers
+                    .map(estimation::clones::CopyNumbers::from_tsv)
+                    .transpose()?;
+                estimation::clones::estimate(
+                    &events,
+                    &samples,
+                    Prob::checked(min_prob)?,
+                    max_clusters,
+                    &purities,
+                    copy_numbers.as_ref(),
+                    centroids,
+                )?
+            }
+            EstimateKind::Purity {
+                sample,
+                somatic_events,
+                germline_events,
+                min_prob,
+                ploidy,
+                plot,
+            } => estimation::purity::estimate(
                 &sample,
-                coding_genome_size as u64,
-                mode,
-                cutoff as f64,
+                &somatic_events,
+                &germline_events,
+                Prob::checked(min_prob)?,
+                ploidy,
+                plot,
+            )?,
+            EstimateKind::CallableRegions {
+                bams,
+                vaf,
+                min_prob,
+                error_rate,
+                use_alt_hits,
+            } => estimation::callable_regions::estimate(
+                &bams,
+                estimation::callable_regions::Detectability::new(
+                    vaf,
+                    error_rate,
+                    Prob::checked(min_prob)?,
+                ),
+                use_alt_hits,
             )?,
+            EstimateKind::Power {
+                scenario,
+                contig,
+                sample,
+                depths,
+                vafs,
+                background_vafs,
+                mapq_profile,
+                simulations,
+                min_prob,
+                min_sensitivity,
+                error_rate,
+                plot,
+            } => {
+                let scenario = grammar::Scenario::from_path(scenario)?;
+                let sample_infos = SampleInfos::try_from(&scenario)?;
+                let sample_idx = scenario.idx(&sample).ok_or_else(|| {
+                    errors::Error::InvalidScenarioSampleName {
+                        name: sample.clone(),
+                    }
+                })?;
+
+                let mut prior = contig_prior(&scenario, &sample_infos, &contig)?;
+                prior.set_variant_type(VariantType::Snv, None)?;
+                let model = GenericModelBuilder::default()
+                    .prior(prior)
+                    .contaminations(sample_infos.contaminations.clone())
+                    .resolutions(sample_infos.resolutions)
+                    .build()
+                    .unwrap();
+
+                let mut analysis = estimation::power::PowerAnalysisBuilder::default()
+                    .model(model)
+                    .events(estimation::power::events(&scenario, &contig)?)
+                    .contaminations(sample_infos.contaminations)
+                    .sample(sample_idx)
+                    .background_vafs(estimation::power::parse_background_vafs(
+                        &scenario,
+                        &background_vafs,
+                    )?)
+                    .depths(depths)
+                    .vafs(vafs)
+                    .simulations(simulations)
+                    .min_prob(LogProb::from(Prob::checked(min_prob)?))
+                    .error_rate(error_rate);
+                if let Some(mapq_profile) = mapq_profile {
+                    analysis = analysis
+                        .mapq_profile(estimation::power::MapqProfile::from_tsv(mapq_profile)?);
+                }
+                analysis.build().unwrap().run(min_sensitivity, plot)?;
+            }
+            EstimateKind::AlignmentProperties {
+                bam,
+                omit_insert_size,
+                allow_hardclips,
+                estimate_mapq_calibration,
+                empirical_insert_size,
+                report,
+            } => {
+                let mut bam = rust_htslib::bam::Reader::from_path(bam)?;
+                let (properties, diagnostics) = AlignmentProperties::estimate_with_diagnostics(
+                    &mut bam,
+                    omit_insert_size,
+                    // if the insert size is omitted, hardclips can be safely allowed
+                    allow_hardclips || omit_insert_size,
+                    estimate_mapq_calibration,
+                    empirical_insert_size,
+                )?;
+                if let Some(report) = report {
+                    diagnostics.write_report(report)?;
+                }
+                println!("{}", serde_json::to_string_pretty(&properties)?);
+            }
         },
         Varlociraptor::Plot { kind } => match kind {
             PlotKind::VariantCallingPrior {
@@ -1045,41 +1943,7 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
             } => {
                 let scenario = grammar::Scenario::from_path(scenario)?;
                 let sample_infos = SampleInfos::try_from(&scenario)?;
-
-                let mut universes = scenario.sample_info();
-                let mut ploidies = scenario.sample_info();
-                for (sample_name, sample) in scenario.samples().iter() {
-                    universes = universes.push(
-                        sample_name,
-                        sample.contig_universe(&contig, scenario.species())?,
-                    );
-                    ploidies = ploidies.push(
-                        sample_name,
-                        sample.contig_ploidy(&contig, scenario.species())?,
-                    );
-                }
-                let universes = universes.build();
-                let ploidies = ploidies.build();
-
-                let prior = Prior::builder()
-                    .variant_type_fractions(scenario.variant_type_fractions())
-                    .ploidies(Some(ploidies))
-                    .universe(Some(universes))
-                    .uniform(sample_infos.uniform_prior)
-                    .germline_mutation_rate(sample_infos.germline_mutation_rates)
-                    .somatic_effective_mutation_rate(sample_infos.somatic_effective_mutation_rates)
-                    .inheritance(sample_infos.inheritance)
-                    .genome_size(
-                        scenario
-                            .species()
-                            .as_ref()
-                            .and_then(|species| *species.genome_size()),
-                    )
-                    .heterozygosity(scenario.species().as_ref().and_then(|species| {
-                        species.heterozygosity().map(|het| LogProb::from(Prob(het)))
-                    }))
-                    .build();
-                prior.check()?;
+                let prior = contig_prior(&scenario, &sample_infos, &contig)?;
 
                 prior.plot(&sample, &sample_infos.names)?;
             }
@@ -1097,18 +1961,90 @@ pub fn run(opt: Varlociraptor) -> Result<()> {
     Ok(())
 }
 
+/// Restrict insertion and deletion variant types to the given length range.
+fn vartype_with_len(
+    vartype: Option<VariantType>,
+    minlen: Option<u64>,
+    maxlen: Option<u64>,
+) -> Option<VariantType> {
+    match (vartype, minlen, maxlen) {
+        (Some(VariantType::Insertion(None)), Some(minlen), Some(maxlen)) => {
+            Some(VariantType::Insertion(Some(minlen..maxlen)))
+        }
+        (Some(VariantType::Deletion(None)), Some(minlen), Some(maxlen)) => {
+            Some(VariantType::Deletion(Some(minlen..maxlen)))
+        }
+        (vartype, _, _) => vartype,
+    }
+}
+
+/// Prior of the given scenario, with universes and ploidies of the given contig.
+fn contig_prior(
+    scenario: &grammar::Scenario,
+    sample_infos: &SampleInfos,
+    contig: &str,
+) -> Result<Prior> {
+    let mut universes = scenario.sample_info();
+    let mut ploidies = scenario.sample_info();
+    for (sample_name, sample) in scenario.samples().iter() {
+        universes = universes.push(
+            sample_name,
+            sample.contig_universe(contig, scenario.species())?,
+        );
+        ploidies = ploidies.push(
+            sample_name,
+            sample.contig_ploidy(contig, scenario.species())?,
+        );
+    }
+    let universes = universes.build();
+    let ploidies = ploidies.build();
+
+    let prior = Prior::builder()
+        .variant_type_fractions(scenario.variant_type_fractions())
+        .ploidies(Some(ploidies))
+        .universe(Some(universes))
+        .uniform(sample_infos.uniform_prior.clone())
+        .germline_mutation_rate(sample_infos.germline_mutation_rates.clone())
+        .somatic_effective_mutation_rate(sample_infos.somatic_effective_mutation_rates.clone())
+        .inheritance(sample_infos.inheritance.clone())
+        .genome_size(
+            scenario
+                .species()
+                .as_ref()
+                .and_then(|species| *species.genome_size()),
+        )
+        .heterozygosity(
+            scenario
+                .species()
+                .as_ref()
+                .and_then(|species| species.heterozygosity().map(|het| LogProb::from(Prob(het)))),
+        )
+        .build();
+    prior.check()?;
+
+    Ok(prior)
+}
+
 pub(crate) fn est_or_load_alignment_properties(
     alignment_properties_file: &Option<impl AsRef<Path>>,
     bam_file: impl AsRef<Path>,
     omit_insert_size: bool,
     allow_hardclips: bool,
+    estimate_mapq_calibration: bool,
+    empirical_insert_size: bool,
 ) -> Result<AlignmentProperties> {
     if let Some(alignment_properties_file) = alignment_properties_file {
         Ok(serde_json::from_reader(File::open(
             alignment_properties_file,
         )?)?)
     } else {
-        estimate_alignment_properties(bam_file, omit_insert_size, allow_hardclips)
+        estimate_alignment_properties(
+            bam_file,
+            omit_insert_size,
+            allow_hardclips,
+            estimate_mapq_calibration,
+            empirical_insert_size,
+        )
     }
 }
 
@@ -1119,6 +2055,7 @@ struct SampleInfos {
     germline_mutation_rates: grammar::SampleInfo<Option<f64>>,
     somatic_effective_mutation_rates: grammar::SampleInfo<Option<f64>>,
     inheritance: grammar::SampleInfo<Option<Inheritance>>,
+    ffpe: grammar::SampleInfo<bool>,
     names: grammar::SampleInfo<String>,
 }
 
@@ -1133,6 +2070,7 @@ impl<'a> TryFrom<&'a grammar::Scenario> for SampleInfos {
         let mut somatic_effective_mutation_rates = scenario.sample_info();
         let mut inheritance = scenario.sample_info();
         let mut uniform_prior = scenario.sample_info();
+        let mut ffpe = scenario.sample_info();
 
         for (sample_name, sample) in scenario.samples().iter() {
             let contamination = if let Some(contamination) = sample.contamination() {
@@ -1149,6 +2087,7 @@ impl<'a> TryFrom<&'a grammar::Scenario> for SampleInfos {
                 None
             };
             uniform_prior = uniform_prior.push(sample_name, sample.has_uniform_prior());
+            ffpe = ffpe.push(sample_name, *sample.ffpe());
             contaminations = contaminations.push(sample_name, contamination);
             resolutions = resolutions.push(sample_name, *sample.resolution());
             sample_names = sample_names.push(sample_name, sample_name.to_owned());
@@ -1204,6 +2143,7 @@ impl<'a> TryFrom<&'a grammar::Scenario> for SampleInfos {
             germline_mutation_rates: germline_mutation_rates.build(),
             somatic_effective_mutation_rates: somatic_effective_mutation_rates.build(),
             inheritance: inheritance.build(),
+            ffpe: ffpe.build(),
             names: sample_names.build(),
         })
     }
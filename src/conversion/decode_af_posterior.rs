@@ -0,0 +1,71 @@
+use std::io;
+use std::str;
+
+use anyhow::Result;
+use rust_htslib::bcf;
+use rust_htslib::bcf::Read;
+
+use crate::calling::variants::AlleleFreqPosterior;
+use crate::errors;
+
+#[derive(Debug, Serialize)]
+struct Row<'a> {
+    chrom: &'a str,
+    pos: i64,
+    #[serde(rename = "ref")]
+    ref_allele: &'a str,
+    alt: &'a str,
+    sample: &'a str,
+    af: f64,
+    prob: f64,
+}
+
+/// Decode discretized allele frequency posteriors (AFD) into a table, with one row per grid
+/// point, sample and ALT allele.
+pub(crate) fn decode_af_posterior() -> Result<()> {
+    let mut inbcf = bcf::Reader::from_stdin()?;
+    let samples = inbcf
+        .header()
+        .samples()
+        .into_iter()
+        .map(|sample| str::from_utf8(sample).map(|s| s.to_owned()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(io::stdout());
+
+    for record in inbcf.records() {
+        let record = record?;
+        let afd = record
+            .format(b"AFD")
+            .string()
+            .map_err(|_| errors::Error::MissingBCFTag {
+                name: "AFD".to_owned(),
+            })?;
+        let chrom = str::from_utf8(record.header().rid2name(record.rid().unwrap())?)?;
+        let alleles = record.alleles();
+        let ref_allele = str::from_utf8(alleles[0])?;
+
+        for (sample, value) in samples.iter().zip(afd.iter()) {
+            // AFD has one comma-separated entry per ALT allele.
+            for (alt, value) in alleles[1..].iter().zip(str::from_utf8(value)?.split(',')) {
+                let alt = str::from_utf8(alt)?;
+                for (af, prob) in AlleleFreqPosterior::decode(value)? {
+                    writer.serialize(Row {
+                        chrom,
+                        pos: record.pos() + 1,
+                        ref_allele,
+                        alt,
+                        sample,
+                        af,
+                        prob,
+                    })?;
+                }
+            }
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
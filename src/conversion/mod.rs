@@ -1 +1,2 @@
+pub(crate) mod decode_af_posterior;
 pub(crate) mod decode_phred;
@@ -30,6 +30,40 @@ pub(crate) enum Error {
     InvalidBCFRecord { msg: String },
     #[error("unable to estimate TMB because no valid records were found in the given BCF/VCF")]
     NoRecordsFound,
+    #[error("unable to estimate the effective mutation rate because less than two somatic variants were found in the given BCF/VCF")]
+    NotEnoughSomaticVariants,
+    #[error(
+        "unable to cluster variants because no somatic variants were found in the given BCF/VCF"
+    )]
+    NoClusterableVariants,
+    #[error("invalid purity {spec}, must be given as samplename=value with value in (0, 1]")]
+    InvalidPurity { spec: String },
+    #[error(
+        "invalid background allele frequency {spec}, must be given as samplename=value with value in [0, 1]"
+    )]
+    InvalidBackgroundVAF { spec: String },
+    #[error("sample {name} cannot be found in the scenario")]
+    InvalidScenarioSampleName { name: String },
+    #[error("sample {name} cannot be found in the given BCF/VCF")]
+    InvalidBCFSampleName { name: String },
+    #[error(
+        "number of outputs ({n_outputs}) has to match the number of given call files ({n_inputs})"
+    )]
+    InvalidFDROutputs { n_inputs: usize, n_outputs: usize },
+    #[error("invalid width of allele frequency credible interval ({width}), must be in (0, 1)")]
+    InvalidCredibleIntervalWidth { width: f64 },
+    #[error("invalid sample filter expression: {msg}")]
+    InvalidSampleFilter { msg: String },
+    #[error(
+        "invalid channel {name} in signature matrix, expected trinucleotide context like A[C>T]G"
+    )]
+    InvalidSignatureChannel { name: String },
+    #[error("channel {name} is missing in signature matrix")]
+    MissingSignatureChannel { name: String },
+    #[error("fitting of signature exposures failed: {msg}")]
+    NnlsFailed { msg: String },
+    #[error("invalid MAPQ profile: {msg}")]
+    InvalidMapqProfile { msg: String },
     #[error("contig {contig} not found in universe definition and no 'all' defined")]
     UniverseContigNotFound { contig: String },
     #[error("contig {contig} not found in ploidy definition and no 'all' defined")]
@@ -52,6 +86,10 @@ pub(crate) enum Error {
         "undefined expression {identifier}; please define under 'expressions:' in your scenario"
     )]
     UndefinedExpression { identifier: String },
+    #[error("invalid somatic hotspot BED record, expecting weight in fourth column: {line}")]
+    InvalidHotspotBED { line: String },
+    #[error("invalid context mutation rate {context}: {value}, expecting a trinucleotide (e.g. ACG) or a trinucleotide with alternative base (e.g. ACG>T) and a positive rate")]
+    InvalidContextMutationRate { context: String, value: f64 },
     #[error("invalid prior configuration: {msg}")]
     InvalidPriorConfiguration { msg: String },
     #[error("read position determined from cigar string exceeds record length")]
@@ -4,23 +4,39 @@
 // except according to those terms.
 
 use std::cmp;
+use std::collections::BTreeMap;
 use std::f64;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::str;
 use std::u32;
 
 use anyhow::Result;
+use bio::stats::{LogProb, PHREDProb, Prob};
 use itertools::Itertools;
 use ordered_float::NotNan;
 use rust_htslib::bam::{self, record::Cigar};
+use serde_json::{json, Value};
+use statrs::distribution::{Continuous, Normal, Univariate};
 use statrs::statistics::{OrderStatistics, Statistics};
 
-#[derive(Clone, Debug, Copy, Deserialize, Serialize)]
+use crate::variants::evidence::mapping;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct AlignmentProperties {
     pub(crate) insert_size: Option<InsertSize>,
+    /// Insert sizes of individual read groups, in case there are multiple read groups with
+    /// sufficient observations (e.g. mixed libraries).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) read_group_insert_sizes: BTreeMap<String, InsertSize>,
     pub(crate) max_del_cigar_len: Option<u32>,
     pub(crate) max_ins_cigar_len: Option<u32>,
     pub(crate) frac_max_softclip: Option<f64>,
     pub(crate) max_read_len: u32,
     #[serde(default)]
+    pub(crate) mapq_calibration: Option<MapqCalibration>,
+    #[serde(default)]
     initial: bool,
 }
 
@@ -76,24 +92,71 @@ impl AlignmentProperties {
         (is_regular, has_soft_clip)
     }
 
+    /// Insert size distribution of the read group of the given record. Falls back to the
+    /// distribution over all read groups if there is no read group specific one.
+    pub(crate) fn record_insert_size(&self, record: &bam::Record) -> Option<&InsertSize> {
+        read_group(record)
+            .and_then(|read_group| self.read_group_insert_sizes.get(read_group))
+            .or(self.insert_size.as_ref())
+    }
+
+    /// Maximum insert size that is expected to occur over all read groups
+    /// (see `InsertSize::max_expected`).
+    pub(crate) fn max_expected_insert_size(&self) -> Option<u64> {
+        self.insert_size
+            .iter()
+            .chain(self.read_group_insert_sizes.values())
+            .map(InsertSize::max_expected)
+            .max()
+    }
+
     /// Estimate `AlignmentProperties` from first 10000 fragments of bam file.
     /// Only reads that are mapped, not duplicates and where quality checks passed are taken.
+    /// If `estimate_mapq_calibration` is true, a MAPQ calibration is estimated from the
+    /// alternative hits reported by the mapper. If `empirical_insert_size` is true, an
+    /// empirical insert size distribution is estimated in addition to mean and standard
+    /// deviation.
     pub(crate) fn estimate<R: bam::Read>(
         bam: &mut R,
         omit_insert_size: bool,
         allow_hardclips: bool,
+        estimate_mapq_calibration: bool,
+        empirical_insert_size: bool,
     ) -> Result<Self> {
+        Self::estimate_with_diagnostics(
+            bam,
+            omit_insert_size,
+            allow_hardclips,
+            estimate_mapq_calibration,
+            empirical_insert_size,
+        )
+        .map(|(properties, _)| properties)
+    }
+
+    /// Estimate `AlignmentProperties` as `AlignmentProperties::estimate`, and additionally
+    /// return diagnostics of the insert size estimation.
+    pub(crate) fn estimate_with_diagnostics<R: bam::Read>(
+        bam: &mut R,
+        omit_insert_size: bool,
+        allow_hardclips: bool,
+        estimate_mapq_calibration: bool,
+        empirical_insert_size: bool,
+    ) -> Result<(Self, Diagnostics)> {
         let mut properties = AlignmentProperties {
             insert_size: None,
+            read_group_insert_sizes: BTreeMap::new(),
             max_del_cigar_len: None,
             max_ins_cigar_len: None,
             frac_max_softclip: None,
             max_read_len: 0,
+            mapq_calibration: None,
             initial: true,
         };
+        let mut mapq_calibration = MapqCalibrationEstimator::default();
 
         let mut record = bam::Record::new();
         let mut tlens = Vec::new();
+        let mut read_group_tlens: BTreeMap<String, Vec<f64>> = BTreeMap::new();
         let mut max_read_len = 0;
         let mut max_mapq = 0;
         let mut i = 0;
@@ -116,6 +179,16 @@ impl AlignmentProperties {
                 Some(res) => res?,
             }
 
+            if estimate_mapq_calibration
+                && !(record.is_secondary()
+                    || record.is_supplementary()
+                    || record.is_duplicate()
+                    || record.is_quality_check_failed()
+                    || record.is_unmapped())
+            {
+                mapq_calibration.update(&record);
+            }
+
             // Records to skip without updating max_cigar_ops_len AND without incrementing the
             // counter (to keep looking for 10000 useful records for the estimation)
             if record.mapq() == 0
@@ -156,7 +229,14 @@ impl AlignmentProperties {
                 }
             } else {
                 // record insert size
-                tlens.push(record.insert_size().abs() as f64);
+                let tlen = record.insert_size().abs() as f64;
+                tlens.push(tlen);
+                if let Some(read_group) = read_group(&record) {
+                    read_group_tlens
+                        .entry(read_group.to_owned())
+                        .or_default()
+                        .push(tlen);
+                }
             }
 
             i += 1;
@@ -164,6 +244,17 @@ impl AlignmentProperties {
 
         properties.max_read_len = max_read_len;
 
+        if estimate_mapq_calibration {
+            properties.mapq_calibration = mapq_calibration.estimate();
+            if properties.mapq_calibration.is_none() {
+                warn!(
+                    "No alternative hits (XA tag) found in the first 10000 alignments. \
+                    Varlociraptor will be unable to estimate a MAPQ calibration and \
+                    use the MAPQ values as reported by the mapper."
+                );
+            }
+        }
+
         if properties.max_del_cigar_len.is_none() {
             warn!(
                 "No deletion CIGAR operations found in first 10000 alignments. \
@@ -214,41 +305,507 @@ impl AlignmentProperties {
                 nr = skipped
             );
             properties.insert_size = None;
-            Ok(properties)
+            Ok((properties, Diagnostics::default()))
         } else {
-            let upper = tlens.percentile(95);
-            let lower = tlens.percentile(5);
-            let valid = tlens
-                .into_iter()
-                .filter(|l| *l <= upper && *l >= lower)
-                .collect_vec();
-
-            properties.insert_size = Some(InsertSize {
-                mean: valid.iter().sum::<f64>() / valid.len() as f64,
-                sd: valid.iter().std_dev(),
-            });
-            Ok(properties)
+            let mut diagnostics = Diagnostics::default();
+
+            let all_reads = InsertSizeDiagnostics::new(None, tlens, empirical_insert_size);
+            properties.insert_size = Some(all_reads.fit.clone());
+            diagnostics.insert_sizes.push(all_reads);
+
+            // METHOD: with multiple read groups (e.g. different libraries), insert sizes are
+            // estimated per read group, such that fragments are scored against the distribution
+            // of their own library. Read groups with too few observations fall back to the
+            // distribution over all reads.
+            if read_group_tlens.len() > 1 {
+                for (read_group, tlens) in read_group_tlens {
+                    if tlens.len() < MIN_READ_GROUP_INSERT_SIZES {
+                        warn!(
+                            "Only {} insert sizes observed for read group {}. Using the insert \
+                            size distribution over all read groups for it.",
+                            tlens.len(),
+                            read_group
+                        );
+                        continue;
+                    }
+                    let read_group_diagnostics = InsertSizeDiagnostics::new(
+                        Some(read_group.clone()),
+                        tlens,
+                        empirical_insert_size,
+                    );
+                    properties
+                        .read_group_insert_sizes
+                        .insert(read_group, read_group_diagnostics.fit.clone());
+                    diagnostics.insert_sizes.push(read_group_diagnostics);
+                }
+            }
+
+            for warning in diagnostics.warnings() {
+                warn!("{}", warning);
+            }
+
+            Ok((properties, diagnostics))
         }
     }
 }
 
-/// Expected insert size in terms of mean and standard deviation.
+/// Minimum number of observed insert sizes for estimating a read group specific distribution.
+const MIN_READ_GROUP_INSERT_SIZES: usize = 100;
+/// Maximum Kolmogorov-Smirnov distance between the empirical and the fitted insert size
+/// distribution before the fit is considered poor.
+const MAX_INSERT_SIZE_KS_DISTANCE: f64 = 0.1;
+/// Bimodality coefficients above this value (that of a uniform distribution) indicate a
+/// bimodal distribution.
+const MAX_BIMODALITY_COEFFICIENT: f64 = 5.0 / 9.0;
+/// Number of histogram bins in the diagnostics report.
+const INSERT_SIZE_HISTOGRAM_BINS: usize = 50;
+
+/// Read group of the given record, if any.
+fn read_group(record: &bam::Record) -> Option<&str> {
+    if let Some(bam::record::Aux::String(read_group)) = record.aux(b"RG") {
+        str::from_utf8(read_group).ok()
+    } else {
+        None
+    }
+}
+
+/// Expected insert size in terms of mean and standard deviation, and optionally an empirical
+/// distribution.
 /// This should be estimated from unsorted(!) bam files to avoid positional biases.
-#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub(crate) struct InsertSize {
     pub(crate) mean: f64,
     pub(crate) sd: f64,
+    /// Empirical distribution. If present, it is used instead of a normal distribution with
+    /// the given mean and standard deviation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) density: Option<InsertSizeDensity>,
+}
+
+impl InsertSize {
+    /// Fit mean and standard deviation to the given insert sizes, ignoring values outside of
+    /// the 5% and 95% percentile. If `empirical` is true, additionally estimate an empirical
+    /// distribution.
+    fn from_observations(tlens: &mut [f64], empirical: bool) -> Self {
+        let upper = tlens.percentile(95);
+        let lower = tlens.percentile(5);
+        let valid = tlens
+            .iter()
+            .cloned()
+            .filter(|l| *l <= upper && *l >= lower)
+            .collect_vec();
+
+        InsertSize {
+            mean: valid.iter().sum::<f64>() / valid.len() as f64,
+            sd: valid.iter().std_dev(),
+            density: if empirical {
+                InsertSizeDensity::from_observations(tlens)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Maximum insert size that is expected to occur. This is the upper end of the support of
+    /// the empirical distribution if present, and 6 standard deviations above the mean
+    /// otherwise.
+    pub(crate) fn max_expected(&self) -> u64 {
+        match self.density {
+            Some(ref density) => density.support().end - 1,
+            None => (self.mean + self.sd * 6.0) as u64,
+        }
+    }
+
+    /// Central interval of insert sizes that corresponds to one standard deviation around the
+    /// mean. With an empirical distribution, this is the central interval with the same
+    /// probability as mean ± sd under a normal distribution, which is not symmetric for skewed
+    /// insert sizes.
+    pub(crate) fn central_interval(&self) -> (f64, f64) {
+        match self.density {
+            Some(ref density) => (
+                density.quantile(CENTRAL_INTERVAL_QUANTILES.0) as f64,
+                density.quantile(CENTRAL_INTERVAL_QUANTILES.1) as f64,
+            ),
+            None => (self.mean - self.sd, self.mean + self.sd),
+        }
+    }
+}
+
+/// Quantiles of a normal distribution at one standard deviation below and above the mean.
+const CENTRAL_INTERVAL_QUANTILES: (f64, f64) = (0.158_655, 0.841_345);
+
+/// Percentiles outside of which insert sizes are ignored for the empirical distribution
+/// (e.g. chimeric fragments).
+const DENSITY_PERCENTILES: (usize, usize) = (1, 99);
+/// Number of bandwidths the Gaussian kernel extends to each side.
+const KERNEL_EXTENT: f64 = 4.0;
+
+/// Empirical insert size distribution, obtained by Gaussian kernel density estimation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct InsertSizeDensity {
+    /// Smallest insert size with a probability above zero.
+    offset: u64,
+    /// Probabilities of the insert sizes `offset`, `offset + 1`, ...
+    pmf: Vec<f64>,
+}
+
+impl InsertSizeDensity {
+    fn from_observations(tlens: &mut [f64]) -> Option<Self> {
+        if tlens.len() < 2 {
+            return None;
+        }
+        let upper = tlens.percentile(DENSITY_PERCENTILES.1);
+        let lower = tlens.percentile(DENSITY_PERCENTILES.0);
+        let iqr = tlens.percentile(75) - tlens.percentile(25);
+        let valid = tlens
+            .iter()
+            .cloned()
+            .filter(|l| *l <= upper && *l >= lower)
+            .collect_vec();
+        let n = valid.len() as f64;
+
+        // METHOD: Silverman's rule of thumb for the bandwidth, but at least a single base
+        // (insert sizes are discrete).
+        let sd = valid.iter().std_dev();
+        let spread = if iqr > 0.0 { sd.min(iqr / 1.34) } else { sd };
+        let bandwidth = (0.9 * spread * n.powf(-0.2)).max(1.0);
+        let kernel = Normal::new(0.0, bandwidth).ok()?;
+
+        let extent = (KERNEL_EXTENT * bandwidth).ceil() as u64;
+        let offset = (lower as u64).saturating_sub(extent);
+        let end = upper as u64 + extent;
+        let mut counts = vec![0.0; (end - offset + 1) as usize];
+        for l in &valid {
+            counts[(*l as u64 - offset) as usize] += 1.0;
+        }
+
+        let kernel_pmf = (0..=extent).map(|d| kernel.pdf(d as f64)).collect_vec();
+        let mut pmf = (offset..=end)
+            .map(|x| {
+                let start = x.saturating_sub(extent).max(offset);
+                let stop = (x + extent).min(end);
+                (start..=stop)
+                    .map(|y| {
+                        counts[(y - offset) as usize] * kernel_pmf[(x.max(y) - x.min(y)) as usize]
+                    })
+                    .sum::<f64>()
+            })
+            .collect_vec();
+        let total: f64 = pmf.iter().sum();
+        for p in &mut pmf {
+            *p /= total;
+        }
+
+        Some(InsertSizeDensity { offset, pmf })
+    }
+
+    /// Probability of the given insert size.
+    pub(crate) fn prob(&self, insert_size: f64) -> LogProb {
+        let insert_size = insert_size.round();
+        if insert_size < self.offset as f64 {
+            return LogProb::ln_zero();
+        }
+        match self.pmf.get((insert_size as u64 - self.offset) as usize) {
+            Some(p) => LogProb(p.ln()),
+            None => LogProb::ln_zero(),
+        }
+    }
+
+    /// Smallest insert size with a cumulative probability of at least q.
+    fn quantile(&self, q: f64) -> u64 {
+        let mut cdf = 0.0;
+        for (i, p) in self.pmf.iter().enumerate() {
+            cdf += p;
+            if cdf >= q {
+                return self.offset + i as u64;
+            }
+        }
+        self.support().end - 1
+    }
+
+    /// Range of insert sizes with probability above zero.
+    pub(crate) fn support(&self) -> Range<u64> {
+        self.offset..self.offset + self.pmf.len() as u64
+    }
+}
+
+/// Diagnostics of the alignment property estimation.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Diagnostics {
+    insert_sizes: Vec<InsertSizeDiagnostics>,
+}
+
+impl Diagnostics {
+    pub(crate) fn warnings(&self) -> Vec<String> {
+        self.insert_sizes
+            .iter()
+            .flat_map(|diagnostics| diagnostics.warnings())
+            .collect()
+    }
+
+    /// Write a Vega-Lite report with the insert size histogram and the fitted distribution of
+    /// all reads and each read group, as well as any warnings.
+    pub(crate) fn write_report<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut blueprint: Value = serde_json::from_str(include_str!(
+            "../../templates/plots/alignment_properties.json"
+        ))?;
+        if let Value::Object(ref mut blueprint) = blueprint {
+            blueprint["data"]["values"] = json!(self
+                .insert_sizes
+                .iter()
+                .flat_map(|diagnostics| diagnostics.histogram())
+                .collect_vec());
+            let warnings = self.warnings();
+            blueprint["title"]["subtitle"] = if warnings.is_empty() {
+                json!("no warnings")
+            } else {
+                json!(warnings)
+            };
+        }
+        serde_json::to_writer_pretty(File::create(path)?, &blueprint)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct HistogramBin {
+    read_group: String,
+    insert_size: f64,
+    empirical: f64,
+    fitted: f64,
+    smoothed: Option<f64>,
+}
+
+/// Diagnostics of the insert size estimation for all reads or a single read group.
+#[derive(Debug, Clone)]
+struct InsertSizeDiagnostics {
+    read_group: Option<String>,
+    /// Observed insert sizes between the 1% and the 99% percentile, sorted.
+    observations: Vec<f64>,
+    fit: InsertSize,
+}
+
+impl InsertSizeDiagnostics {
+    fn new(read_group: Option<String>, mut tlens: Vec<f64>, empirical: bool) -> Self {
+        let fit = InsertSize::from_observations(&mut tlens, empirical);
+        let upper = tlens.percentile(99);
+        let lower = tlens.percentile(1);
+        let observations = tlens
+            .into_iter()
+            .filter(|l| *l <= upper && *l >= lower)
+            .sorted_by(|a, b| a.partial_cmp(b).unwrap())
+            .collect_vec();
+        InsertSizeDiagnostics {
+            read_group,
+            observations,
+            fit,
+        }
+    }
+
+    fn name(&self) -> String {
+        match self.read_group {
+            Some(ref read_group) => format!("read group {}", read_group),
+            None => "all reads".to_owned(),
+        }
+    }
+
+    fn fitted(&self) -> Option<Normal> {
+        Normal::new(self.fit.mean, self.fit.sd).ok()
+    }
+
+    /// Maximum distance between the empirical and the fitted cumulative distribution function.
+    fn ks_distance(&self) -> f64 {
+        let fitted = match self.fitted() {
+            Some(fitted) => fitted,
+            None => return 0.0,
+        };
+        let n = self.observations.len() as f64;
+        self.observations
+            .iter()
+            .enumerate()
+            .map(|(i, x)| {
+                let cdf = fitted.cdf(*x);
+                (cdf - i as f64 / n)
+                    .abs()
+                    .max((cdf - (i + 1) as f64 / n).abs())
+            })
+            .fold(0.0, f64::max)
+    }
+
+    /// Sample bimodality coefficient (see Pfister et al. 2013, Frontiers in Psychology).
+    fn bimodality_coefficient(&self) -> f64 {
+        let n = self.observations.len() as f64;
+        if n < 4.0 {
+            return 0.0;
+        }
+        let mean = self.observations.iter().sum::<f64>() / n;
+        let moment = |k: i32| {
+            self.observations
+                .iter()
+                .map(|x| (x - mean).powi(k))
+                .sum::<f64>()
+                / n
+        };
+        let m2 = moment(2);
+        if m2 == 0.0 {
+            return 0.0;
+        }
+        let g = moment(3) / m2.powf(1.5);
+        let k = moment(4) / m2.powi(2) - 3.0;
+        // bias corrected skewness and excess kurtosis
+        let skewness = g * (n * (n - 1.0)).sqrt() / (n - 2.0);
+        let kurtosis = (n - 1.0) / ((n - 2.0) * (n - 3.0)) * ((n + 1.0) * k + 6.0);
+        (skewness.powi(2) + 1.0) / (kurtosis + 3.0 * (n - 1.0).powi(2) / ((n - 2.0) * (n - 3.0)))
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let ks_distance = self.ks_distance();
+        if ks_distance > MAX_INSERT_SIZE_KS_DISTANCE {
+            warnings.push(format!(
+                "Insert size distribution of {} is poorly fit by a normal distribution \
+                (Kolmogorov-Smirnov distance {:.3}).",
+                self.name(),
+                ks_distance
+            ));
+        }
+        let bimodality_coefficient = self.bimodality_coefficient();
+        if bimodality_coefficient > MAX_BIMODALITY_COEFFICIENT {
+            warnings.push(format!(
+                "Insert size distribution of {} appears to be bimodal (bimodality coefficient \
+                {:.3}). This can happen with mixed libraries. Consider to specify read groups \
+                for them.",
+                self.name(),
+                bimodality_coefficient
+            ));
+        }
+        warnings
+    }
+
+    /// Histogram of the empirical insert sizes, along with the density of the fitted normal
+    /// distribution and the empirical distribution (if estimated).
+    fn histogram(&self) -> Vec<HistogramBin> {
+        let (lower, upper) = match (self.observations.first(), self.observations.last()) {
+            (Some(lower), Some(upper)) => (*lower, *upper),
+            _ => return Vec::new(),
+        };
+        let width = ((upper - lower) / INSERT_SIZE_HISTOGRAM_BINS as f64)
+            .ceil()
+            .max(1.0);
+        let n_bins = ((upper - lower) / width) as usize + 1;
+        let mut counts = vec![0; n_bins];
+        for x in &self.observations {
+            counts[((x - lower) / width) as usize] += 1;
+        }
+        let n = self.observations.len() as f64;
+        let fitted = self.fitted();
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let start = lower + i as f64 * width;
+                HistogramBin {
+                    read_group: self.name(),
+                    insert_size: start + width / 2.0,
+                    empirical: count as f64 / (n * width),
+                    fitted: fitted
+                        .as_ref()
+                        .map_or(0.0, |fitted| fitted.pdf(start + width / 2.0)),
+                    smoothed: self
+                        .fit
+                        .density
+                        .as_ref()
+                        .map(|density| density.prob(start + width / 2.0).exp()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Calibration of the mapping qualities (MAPQ) reported by the read mapper.
+/// Maps MAPQ values to the probability that a read with that MAPQ is mismapped.
+/// MAPQ values without an entry are interpreted as PHRED scaled probabilities.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct MapqCalibration {
+    prob_mismapping: BTreeMap<u8, f64>,
+}
+
+impl MapqCalibration {
+    pub(crate) fn prob_mismapping(&self, mapq: u8) -> LogProb {
+        if let Some(prob) = self.prob_mismapping.get(&mapq) {
+            LogProb::from(Prob(*prob))
+        } else {
+            LogProb::from(PHREDProb(mapq as f64))
+        }
+    }
+}
+
+/// Number of pseudo observations that represent the MAPQ as reported by the mapper when
+/// estimating the calibration.
+const MAPQ_CALIBRATION_PSEUDO_COUNT: f64 = 10.0;
+
+#[derive(Debug, Default)]
+struct MapqCalibrationEstimator {
+    /// Per MAPQ, the number of records and the sum of their mismapping probabilities.
+    stats: BTreeMap<u8, (f64, f64)>,
+    has_alternative_hits: bool,
+}
+
+impl MapqCalibrationEstimator {
+    fn update(&mut self, record: &bam::Record) {
+        let prob_mismapping = if let Some(hits) = mapping::alternative_hits(record) {
+            if let Some(bam::record::Aux::Integer(nm)) = record.aux(b"NM") {
+                self.has_alternative_hits = true;
+                *Prob::from(mapping::prob_mismapping_alt_hits(nm as u32, &hits))
+            } else {
+                return;
+            }
+        } else if record.mapq() == 0 {
+            // METHOD: mappers omit alternative hits if there are too many of them.
+            // Hence, such records are not informative for the calibration.
+            return;
+        } else {
+            0.0
+        };
+        let entry = self.stats.entry(record.mapq()).or_insert((0.0, 0.0));
+        entry.0 += 1.0;
+        entry.1 += prob_mismapping;
+    }
+
+    fn estimate(&self) -> Option<MapqCalibration> {
+        if !self.has_alternative_hits {
+            return None;
+        }
+        // METHOD: the calibrated mismapping probability per MAPQ is the mean mismapping
+        // probability obtained from the alternative hits of the records, shrunk towards the
+        // MAPQ reported by the mapper via a fixed number of pseudo observations.
+        Some(MapqCalibration {
+            prob_mismapping: self
+                .stats
+                .iter()
+                .map(|(mapq, (n, sum))| {
+                    let prior = *Prob::from(PHREDProb(*mapq as f64));
+                    (
+                        *mapq,
+                        (sum + MAPQ_CALIBRATION_PSEUDO_COUNT * prior)
+                            / (n + MAPQ_CALIBRATION_PSEUDO_COUNT),
+                    )
+                })
+                .collect(),
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use statrs::distribution::InverseCDF;
 
     #[test]
     fn test_estimate() {
         let mut bam = bam::Reader::from_path("tests/resources/tumor-first30000.bam").unwrap();
 
-        let props = AlignmentProperties::estimate(&mut bam, false, false).unwrap();
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false, false).unwrap();
         println!("{:?}", props);
 
         if let Some(isize) = props.insert_size {
@@ -268,7 +825,7 @@ mod tests {
             bam::Reader::from_path("tests/resources/tumor-first30000.reads_with_soft_clips.bam")
                 .unwrap();
 
-        let props = AlignmentProperties::estimate(&mut bam, false, false).unwrap();
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false, false).unwrap();
         println!("{:?}", props);
 
         assert!(props.insert_size.is_none());
@@ -285,7 +842,7 @@ mod tests {
         )
         .unwrap();
 
-        let props = AlignmentProperties::estimate(&mut bam, false, false).unwrap();
+        let props = AlignmentProperties::estimate(&mut bam, false, false, false, false).unwrap();
         println!("{:?}", props);
 
         assert!(props.insert_size.is_none());
@@ -293,4 +850,77 @@ mod tests {
         assert_eq!(props.max_ins_cigar_len, None);
         assert_eq!(props.frac_max_softclip, Some(0.03));
     }
+
+    #[test]
+    fn test_insert_size_diagnostics() {
+        // deterministic, approximately normal insert sizes
+        let normal = Normal::new(300.0, 20.0).unwrap();
+        let unimodal = (1..1000)
+            .map(|i| normal.inverse_cdf(i as f64 / 1000.0).round())
+            .collect_vec();
+        let diagnostics = InsertSizeDiagnostics::new(None, unimodal.clone(), false);
+        assert!(diagnostics.warnings().is_empty());
+        assert!(!diagnostics.histogram().is_empty());
+
+        // mixture of two libraries
+        let bimodal = unimodal
+            .iter()
+            .map(|l| l + 200.0)
+            .chain(unimodal.iter().cloned())
+            .collect_vec();
+        let diagnostics = InsertSizeDiagnostics::new(Some("mixed".to_owned()), bimodal, false);
+        assert!(diagnostics.bimodality_coefficient() > MAX_BIMODALITY_COEFFICIENT);
+        assert!(diagnostics.ks_distance() > MAX_INSERT_SIZE_KS_DISTANCE);
+    }
+
+    #[test]
+    fn test_insert_size_density() {
+        // right-skewed insert sizes
+        let mut tlens = (0..1000)
+            .map(|i| 250.0 + (i % 100) as f64 + if i % 10 == 0 { 200.0 } else { 0.0 })
+            .collect_vec();
+        let isize = InsertSize::from_observations(&mut tlens, true);
+        let density = isize.density.as_ref().unwrap();
+        let total = LogProb::ln_sum_exp(
+            &density
+                .support()
+                .map(|x| density.prob(x as f64))
+                .collect_vec(),
+        );
+        assert_relative_eq!(total.exp(), 1.0, epsilon = 1e-6);
+        assert!(density.prob(300.0) > density.prob(500.0));
+        assert_eq!(density.prob(0.0), LogProb::ln_zero());
+        let max_expected = isize.max_expected();
+        assert!(density.prob(max_expected as f64) > LogProb::ln_zero());
+        assert_eq!(density.prob(max_expected as f64 + 1.0), LogProb::ln_zero());
+
+        // the central interval has the mass of mean ± sd of a normal distribution, but unlike
+        // the standard deviation, it is not inflated by the tail
+        let (lower, upper) = isize.central_interval();
+        let mass = LogProb::ln_sum_exp(
+            &(lower as u64..=upper as u64)
+                .map(|x| density.prob(x as f64))
+                .collect_vec(),
+        );
+        assert_relative_eq!(mass.exp(), 0.6827, epsilon = 0.02);
+        assert!(upper - lower < 2.0 * isize.sd);
+        let normal = InsertSize {
+            density: None,
+            ..isize
+        };
+        assert_eq!(
+            normal.central_interval(),
+            (normal.mean - normal.sd, normal.mean + normal.sd)
+        );
+    }
+
+    #[test]
+    fn test_insert_size_json_compatibility() {
+        let isize: InsertSize = serde_json::from_str(r#"{"mean": 312.0, "sd": 11.0}"#).unwrap();
+        assert!(isize.density.is_none());
+        assert_eq!(
+            serde_json::to_string(&isize).unwrap(),
+            r#"{"mean":312.0,"sd":11.0}"#
+        );
+    }
 }
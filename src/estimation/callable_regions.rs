@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::str;
+
+use anyhow::Result;
+use bio::io::bed;
+use bio::stats::{LogProb, Prob};
+use rust_htslib::bam::{self, Read};
+
+use crate::variants::evidence::mapping;
+use crate::variants::sample::is_valid_record;
+
+type Intervals = HashMap<String, Vec<(u64, u64)>>;
+
+/// Decides whether a somatic variant at a given VAF is detectable at a given depth.
+///
+/// This is an ad-hoc binomial power model, not the statistical model of Varlociraptor.
+/// It assumes a prior probability of 0.5 for the presence of the variant, a single uniform
+/// sequencing error rate, and that floor(depth * VAF) ALT observations are made.
+/// Mapping and base qualities as well as strand, read orientation and other biases are not
+/// taken into account.
+#[derive(Debug, Clone)]
+pub(crate) struct Detectability {
+    vaf: f64,
+    error_rate: f64,
+    min_prob: LogProb,
+    /// Cached decisions, indexed by depth.
+    cache: Vec<bool>,
+}
+
+impl Detectability {
+    pub(crate) fn new(vaf: f64, error_rate: f64, min_prob: Prob) -> Self {
+        Detectability {
+            vaf,
+            error_rate,
+            min_prob: LogProb::from(min_prob),
+            cache: Vec::new(),
+        }
+    }
+
+    /// Posterior probability of the variant, given `alt_count` ALT observations at the given depth.
+    fn posterior(&self, depth: u32, alt_count: u32) -> LogProb {
+        // METHOD: compare the hypothesis of a variant at the given VAF (observed with sequencing
+        // errors) against the hypothesis of no variant (all ALT observations being sequencing
+        // errors), with equal prior probabilities.
+        let ln_likelihood = |p: f64| {
+            LogProb(alt_count as f64 * p.ln() + (depth - alt_count) as f64 * (1.0 - p).ln())
+        };
+        let present =
+            ln_likelihood(self.vaf * (1.0 - self.error_rate) + (1.0 - self.vaf) * self.error_rate);
+        let absent = ln_likelihood(self.error_rate);
+        present - present.ln_add_exp(absent)
+    }
+
+    fn compute(&self, depth: u32) -> bool {
+        // METHOD: the variant is considered detectable if the median number of ALT observations
+        // at the given depth (which is at least floor(depth * vaf)) yields a posterior
+        // probability above the threshold.
+        let expected_alt_count = (depth as f64 * self.vaf).floor() as u32;
+        expected_alt_count > 0 && self.posterior(depth, expected_alt_count) >= self.min_prob
+    }
+
+    pub(crate) fn is_detectable(&mut self, depth: u32) -> bool {
+        while self.cache.len() <= depth as usize {
+            let decision = self.compute(self.cache.len() as u32);
+            self.cache.push(decision);
+        }
+        self.cache[depth as usize]
+    }
+}
+
+/// Scan the given BAM file for intervals in which a variant is detectable.
+fn callable_intervals<P: AsRef<Path>>(
+    bam: P,
+    use_alt_hits: bool,
+    detectability: &mut Detectability,
+) -> Result<(Vec<String>, Intervals)> {
+    let mut bam = bam::Reader::from_path(bam)?;
+    let contigs = bam
+        .header()
+        .target_names()
+        .into_iter()
+        .map(|name| Ok(str::from_utf8(name)?.to_owned()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut intervals: Intervals = HashMap::new();
+
+    let mut pileups = bam.pileup();
+    pileups.set_max_depth(i32::MAX as u32);
+    for pileup in pileups {
+        let pileup = pileup?;
+        // METHOD: consider valid records that do not have an ambiguous locus (MAPQ 0 without
+        // alternative hits) and actually cover the position with a base.
+        // This is conservative: the statistical model ignores ambiguous records only for
+        // paired-end evidence, while single-end records with MAPQ 0 are kept there (they
+        // however barely contribute, because their mapping probability is close to zero).
+        let depth = pileup
+            .alignments()
+            .filter(|alignment| !alignment.is_del() && !alignment.is_refskip())
+            .filter(|alignment| {
+                let record = alignment.record();
+                is_valid_record(&record) && !mapping::is_ambiguous(&record, use_alt_hits)
+            })
+            .count();
+        if detectability.is_detectable(depth as u32) {
+            let pos = pileup.pos() as u64;
+            let contig_intervals = intervals
+                .entry(contigs[pileup.tid() as usize].clone())
+                .or_default();
+            match contig_intervals.last_mut() {
+                Some(last) if last.1 == pos => last.1 = pos + 1,
+                _ => contig_intervals.push((pos, pos + 1)),
+            }
+        }
+    }
+
+    Ok((contigs, intervals))
+}
+
+/// Intersection of two sorted lists of non-overlapping intervals.
+fn intersect(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut intersection = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let start = a[i].0.max(b[j].0);
+        let end = a[i].1.min(b[j].1);
+        if start < end {
+            intersection.push((start, end));
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    intersection
+}
+
+/// Print regions (BED format) in which a somatic variant at the given VAF would be detectable
+/// in all given BAM files.
+///
+/// # Arguments
+/// * `bams` - BAM files (sorted)
+/// * `detectability` - the detection criterion
+/// * `use_alt_hits` - whether records with MAPQ zero are considered if they report alternative hits
+pub(crate) fn estimate<P: AsRef<Path>>(
+    bams: &[P],
+    mut detectability: Detectability,
+    use_alt_hits: bool,
+) -> Result<()> {
+    let mut contigs = Vec::new();
+    let mut callable: Option<Intervals> = None;
+    for bam in bams {
+        let (bam_contigs, intervals) = callable_intervals(bam, use_alt_hits, &mut detectability)?;
+        callable = Some(match callable {
+            None => {
+                contigs = bam_contigs;
+                intervals
+            }
+            Some(callable) => callable
+                .into_iter()
+                .filter_map(|(contig, contig_intervals)| {
+                    intervals
+                        .get(&contig)
+                        .map(|other| (contig.clone(), intersect(&contig_intervals, other)))
+                })
+                .collect(),
+        });
+    }
+
+    let mut writer = bed::Writer::new(io::stdout());
+    if let Some(callable) = callable {
+        for contig in contigs {
+            if let Some(intervals) = callable.get(&contig) {
+                for (start, end) in intervals {
+                    let mut record = bed::Record::new();
+                    record.set_chrom(&contig);
+                    record.set_start(*start);
+                    record.set_end(*end);
+                    writer.write(&record)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detectability() {
+        let mut detectability = Detectability::new(0.1, 0.001, Prob(0.95));
+        assert!(!detectability.is_detectable(0));
+        assert!(!detectability.is_detectable(5));
+        assert!(detectability.is_detectable(100));
+        assert!(detectability.is_detectable(1000));
+    }
+
+    #[test]
+    fn test_intersect() {
+        let a = vec![(0, 10), (20, 30)];
+        let b = vec![(5, 25)];
+        assert_eq!(intersect(&a, &b), vec![(5, 10), (20, 25)]);
+    }
+}
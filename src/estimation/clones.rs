@@ -0,0 +1,611 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str;
+
+use anyhow::Result;
+use bio::stats::{LogProb, PHREDProb, Prob};
+use itertools::Itertools;
+use rust_htslib::bcf::{self, Read};
+
+use crate::calling::variants::AlleleFreqPosterior;
+use crate::errors;
+use crate::utils;
+use crate::{Event, SimpleEvent};
+
+/// Number of grid points for the cancer cell fraction (CCF) of clusters.
+const CCF_GRID_POINTS: usize = 101;
+/// Lower bound for the likelihood of a cancer cell fraction, avoiding that single
+/// outlier observations dominate the clustering.
+const MIN_LIKELIHOOD: f64 = 1e-6;
+const EM_MAX_ITERATIONS: usize = 200;
+const EM_TOLERANCE: f64 = 1e-6;
+/// Minimum mixture weight of a cluster to be reported.
+const MIN_CLUSTER_WEIGHT: f64 = 1e-6;
+
+fn ccf_grid() -> Vec<f64> {
+    (0..CCF_GRID_POINTS)
+        .map(|i| i as f64 / (CCF_GRID_POINTS - 1) as f64)
+        .collect()
+}
+
+/// Total copy numbers of genomic segments, per sample.
+#[derive(Debug, Default)]
+pub(crate) struct CopyNumbers {
+    segments: HashMap<(String, String), Vec<CopyNumberSegment>>,
+}
+
+/// Segment of a copy number TSV file (columns chrom, start, end, sample, copy_number;
+/// 0-based, half-open intervals).
+#[derive(Debug, Clone, Deserialize)]
+struct CopyNumberSegment {
+    chrom: String,
+    start: u64,
+    end: u64,
+    sample: String,
+    copy_number: u32,
+}
+
+impl CopyNumbers {
+    pub(crate) fn from_tsv<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+        let mut segments: HashMap<_, Vec<_>> = HashMap::new();
+        for segment in reader.deserialize() {
+            let segment: CopyNumberSegment = segment?;
+            segments
+                .entry((segment.sample.clone(), segment.chrom.clone()))
+                .or_default()
+                .push(segment);
+        }
+        Ok(CopyNumbers { segments })
+    }
+
+    /// Total copy number at the given (0-based) position. Diploid if undefined.
+    fn get(&self, sample: &str, contig: &str, pos: u64) -> u32 {
+        self.segments
+            .get(&(sample.to_owned(), contig.to_owned()))
+            .and_then(|segments| {
+                segments
+                    .iter()
+                    .find(|segment| segment.start <= pos && pos < segment.end)
+            })
+            .map_or(2, |segment| segment.copy_number)
+    }
+}
+
+/// Parse purities given as `samplename=value`.
+pub(crate) fn parse_purities(specs: &[String]) -> Result<HashMap<String, f64>> {
+    specs
+        .iter()
+        .map(|spec| {
+            let invalid = || errors::Error::InvalidPurity {
+                spec: spec.to_owned(),
+            };
+            let (sample, purity) = spec.split('=').collect_tuple().ok_or_else(invalid)?;
+            let purity: f64 = purity.parse().map_err(|_| invalid())?;
+            if purity <= 0.0 || purity > 1.0 {
+                return Err(invalid().into());
+            }
+            Ok((sample.to_owned(), purity))
+        })
+        .collect()
+}
+
+/// Expected allele frequency of a somatic variant with the given cancer cell fraction.
+fn expected_vaf(ccf: f64, purity: f64, copy_number: u32) -> f64 {
+    // METHOD: as in PyClone, we assume that the variant is present on a single copy in the
+    // cancer cells, while normal cells are diploid.
+    let total_copies = purity * copy_number as f64 + 2.0 * (1.0 - purity);
+    if total_copies > 0.0 {
+        (purity * ccf / total_copies).min(1.0)
+    } else {
+        0.0
+    }
+}
+
+/// Likelihood of allele frequencies of a variant in a sample.
+enum VafLikelihood {
+    /// Discretized allele frequency posterior as reported by varlociraptor (AFD), along with
+    /// the step of the underlying grid.
+    Posterior { points: Vec<(f64, f64)>, step: f64 },
+    /// Binomial approximation via depth and MAP allele frequency.
+    Binomial { depth: f64, alt_depth: f64 },
+}
+
+impl VafLikelihood {
+    /// Likelihood given by the (sorted) points of an AFD field. At least two points are needed
+    /// to infer the grid step.
+    fn posterior(points: Vec<(f64, f64)>) -> Self {
+        assert!(points.len() >= 2, "bug: AFD with less than two points");
+        // Points with negligible probability are omitted from the AFD field. Hence, the grid
+        // step is the minimum distance between reported points.
+        let step = points
+            .iter()
+            .tuple_windows()
+            .map(|((left, _), (right, _))| right - left)
+            .fold(f64::INFINITY, f64::min);
+        VafLikelihood::Posterior { points, step }
+    }
+
+    fn ln_likelihood(&self, vaf: f64) -> f64 {
+        match self {
+            VafLikelihood::Posterior { points, step } => {
+                // METHOD: the posterior serves as an approximation of the likelihood. This neglects
+                // the prior of the calling scenario (and the integration weights of the grid
+                // points), which is reasonable as long as the observations dominate the prior.
+                // Between neighboring grid points, the posterior is interpolated linearly. Grid
+                // points that are not reported have negligible probability. Hence, within gaps
+                // and beyond the reported points, the likelihood is given by the nearest grid
+                // point, which is zero (floored at MIN_LIKELIHOOD) unless it is reported.
+                let i = points.partition_point(|(af, _)| *af <= vaf);
+                let nearest = |j: Option<usize>| {
+                    j.and_then(|j| points.get(j))
+                        .filter(|(af, _)| (af - vaf).abs() <= step / 2.0)
+                        .map(|(_, prob)| *prob)
+                };
+                let density =
+                    if i > 0 && i < points.len() && points[i].0 - points[i - 1].0 < 1.5 * step {
+                        let (left_af, left_prob) = points[i - 1];
+                        let (right_af, right_prob) = points[i];
+                        let t = (vaf - left_af) / (right_af - left_af);
+                        left_prob + t * (right_prob - left_prob)
+                    } else {
+                        nearest(i.checked_sub(1))
+                            .or_else(|| nearest(Some(i)))
+                            .unwrap_or(0.0)
+                    };
+                density.max(MIN_LIKELIHOOD).ln()
+            }
+            VafLikelihood::Binomial { depth, alt_depth } => {
+                let vaf = vaf.clamp(MIN_LIKELIHOOD, 1.0 - MIN_LIKELIHOOD);
+                alt_depth * vaf.ln() + (depth - alt_depth) * (1.0 - vaf).ln()
+            }
+        }
+    }
+}
+
+/// A somatic variant considered for clustering.
+struct Site {
+    record_index: usize,
+    /// Posterior probability of the variant being somatic.
+    weight: f64,
+    /// Log-likelihoods of the CCF grid points, per sample.
+    likelihoods: Vec<Vec<f64>>,
+}
+
+/// Finite mixture of clusters, each with a cancer cell fraction per sample.
+#[derive(Debug, Clone)]
+struct Clustering {
+    weights: Vec<f64>,
+    /// Index of the CCF grid point of each cluster and sample.
+    centroids: Vec<Vec<usize>>,
+    responsibilities: Vec<Vec<f64>>,
+    log_likelihood: f64,
+}
+
+impl Clustering {
+    fn fit(sites: &[Site], n_clusters: usize, n_samples: usize) -> Self {
+        // METHOD: deterministic initialization by sorting the variants by their mean maximum
+        // likelihood CCF and splitting them into equally sized groups.
+        let ml_ccfs = sites
+            .iter()
+            .map(|site| {
+                site.likelihoods
+                    .iter()
+                    .map(|likelihoods| argmax(likelihoods) as f64)
+                    .sum::<f64>()
+            })
+            .collect_vec();
+        let order = (0..sites.len())
+            .sorted_by(|a, b| ml_ccfs[*a].partial_cmp(&ml_ccfs[*b]).unwrap())
+            .collect_vec();
+        let mut responsibilities = vec![vec![0.0; n_clusters]; sites.len()];
+        for (rank, i) in order.into_iter().enumerate() {
+            responsibilities[i][rank * n_clusters / sites.len()] = 1.0;
+        }
+
+        let mut clustering = Clustering {
+            weights: vec![1.0 / n_clusters as f64; n_clusters],
+            centroids: vec![vec![0; n_samples]; n_clusters],
+            responsibilities,
+            log_likelihood: f64::NEG_INFINITY,
+        };
+
+        // METHOD: expectation maximization, with the M-step for the centroids performed
+        // exactly over the CCF grid.
+        for _ in 0..EM_MAX_ITERATIONS {
+            clustering.maximize(sites, n_samples);
+            let previous = clustering.log_likelihood;
+            clustering.expect(sites);
+            if (clustering.log_likelihood - previous).abs()
+                <= EM_TOLERANCE * clustering.log_likelihood.abs()
+            {
+                break;
+            }
+        }
+
+        clustering
+    }
+
+    fn maximize(&mut self, sites: &[Site], n_samples: usize) {
+        let total_weight: f64 = sites.iter().map(|site| site.weight).sum();
+        for k in 0..self.weights.len() {
+            let cluster_weights = sites
+                .iter()
+                .zip(self.responsibilities.iter())
+                .map(|(site, r)| site.weight * r[k])
+                .collect_vec();
+            let cluster_weight: f64 = cluster_weights.iter().sum();
+            self.weights[k] = cluster_weight / total_weight;
+            if cluster_weight == 0.0 {
+                continue;
+            }
+            for sample in 0..n_samples {
+                let objective = (0..CCF_GRID_POINTS)
+                    .map(|g| {
+                        sites
+                            .iter()
+                            .zip(cluster_weights.iter())
+                            .map(|(site, w)| w * site.likelihoods[sample][g])
+                            .sum::<f64>()
+                    })
+                    .collect_vec();
+                self.centroids[k][sample] = argmax(&objective);
+            }
+        }
+    }
+
+    fn expect(&mut self, sites: &[Site]) {
+        self.log_likelihood = 0.0;
+        for (site, r) in sites.iter().zip(self.responsibilities.iter_mut()) {
+            let joint = self
+                .weights
+                .iter()
+                .zip(self.centroids.iter())
+                .map(|(weight, centroid)| {
+                    LogProb(
+                        weight.ln()
+                            + centroid
+                                .iter()
+                                .zip(site.likelihoods.iter())
+                                .map(|(g, likelihoods)| likelihoods[*g])
+                                .sum::<f64>(),
+                    )
+                })
+                .collect_vec();
+            let marginal = LogProb::ln_sum_exp(&joint);
+            for (r, p) in r.iter_mut().zip(joint.iter()) {
+                *r = (*p - marginal).exp();
+            }
+            self.log_likelihood += site.weight * *marginal;
+        }
+    }
+
+    fn n_nonempty(&self) -> usize {
+        self.weights
+            .iter()
+            .filter(|weight| **weight > MIN_CLUSTER_WEIGHT)
+            .count()
+    }
+
+    /// Bayesian information criterion, used to select the number of clusters.
+    fn bic(&self, sites: &[Site], n_samples: usize) -> f64 {
+        let k = self.n_nonempty() as f64;
+        let n: f64 = sites.iter().map(|site| site.weight).sum();
+        let n_params = (k - 1.0) + k * n_samples as f64;
+        -2.0 * self.log_likelihood + n_params * n.ln()
+    }
+}
+
+fn argmax(values: &[f64]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+        .map_or(0, |(i, _)| i)
+}
+
+#[derive(Debug, Serialize)]
+struct Centroid<'a> {
+    cluster: usize,
+    sample: &'a str,
+    ccf: f64,
+    weight: f64,
+}
+
+/// Cluster somatic variants (read from STDIN) by their cancer cell fractions across samples.
+/// Writes the calls with cluster assignments (INFO fields CLUSTER and CLUSTER_PROB) to STDOUT.
+///
+/// # Arguments
+/// * `events` - events to consider as somatic mutations (e.g. SOMATIC_TUMOR)
+/// * `sample_names` - samples to cluster over (all samples if empty)
+/// * `min_prob` - minimum posterior probability for the given events
+/// * `max_clusters` - maximum number of clusters
+/// * `purities` - tumor purity per sample (1.0 if not given)
+/// * `copy_numbers` - optional total copy numbers (diploid if not given)
+/// * `centroids` - path to write cluster centroids to (TSV)
+pub(crate) fn estimate<P: AsRef<Path>>(
+    events: &[String],
+    sample_names: &[String],
+    min_prob: Prob,
+    max_clusters: usize,
+    purities: &HashMap<String, f64>,
+    copy_numbers: Option<&CopyNumbers>,
+    centroids: P,
+) -> Result<()> {
+    let mut inbcf = bcf::Reader::from_stdin()?;
+    let header = inbcf.header().to_owned();
+    let sample_names = if sample_names.is_empty() {
+        header
+            .samples()
+            .into_iter()
+            .map(|name| Ok(str::from_utf8(name)?.to_owned()))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        sample_names.to_vec()
+    };
+    let sample_ids = sample_names
+        .iter()
+        .map(|name| {
+            header
+                .sample_id(name.as_bytes())
+                .ok_or_else(|| errors::Error::InvalidBCFSampleName {
+                    name: name.to_owned(),
+                })
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let min_prob = LogProb::from(min_prob);
+    let grid = ccf_grid();
+    let tags = events
+        .iter()
+        .map(|e| SimpleEvent { name: e.to_owned() }.tag_name("PROB"))
+        .collect_vec();
+
+    let mut records = Vec::new();
+    let mut sites = Vec::new();
+    for record in inbcf.records() {
+        let mut rec = record?;
+        let record_index = records.len();
+        let contig = str::from_utf8(header.rid2name(rec.rid().unwrap())?)?.to_owned();
+
+        let prob = match utils::tags_prob_sum(&mut rec, &tags, None)?.first() {
+            Some(Some(prob)) => *prob,
+            _ => {
+                info!(
+                    "Skipping variant {}:{} because it does not contain any of the required INFO tags.",
+                    contig,
+                    rec.pos() + 1
+                );
+                records.push(rec);
+                continue;
+            }
+        };
+        if prob < min_prob {
+            records.push(rec);
+            continue;
+        }
+
+        let afs = rec.format(b"AF").float()?;
+        let depths = rec.format(b"DP").integer()?;
+        let densities = rec.format(b"AFD").string().ok();
+        let mut likelihoods = Vec::with_capacity(sample_ids.len());
+        for (sample_name, sample_id) in sample_names.iter().zip(sample_ids.iter()) {
+            let vaf_likelihood = match densities
+                .as_ref()
+                .map(|densities| {
+                    AlleleFreqPosterior::decode(str::from_utf8(densities[*sample_id])?)
+                })
+                .transpose()?
+            {
+                Some(points) if points.len() >= 2 => VafLikelihood::posterior(points),
+                _ => {
+                    // METHOD: without AFD (or if it does not allow to infer the grid), fall back to
+                    // a binomial approximation.
+                    let af = afs[*sample_id][0] as f64;
+                    let depth = depths[*sample_id][0] as f64;
+                    if af.is_nan() || depths[*sample_id][0] < 0 {
+                        // missing data, the sample does not contribute
+                        VafLikelihood::Binomial {
+                            depth: 0.0,
+                            alt_depth: 0.0,
+                        }
+                    } else {
+                        VafLikelihood::Binomial {
+                            depth,
+                            alt_depth: (af * depth).round(),
+                        }
+                    }
+                }
+            };
+            let purity = purities.get(sample_name).cloned().unwrap_or(1.0);
+            let copy_number = copy_numbers.map_or(2, |copy_numbers| {
+                copy_numbers.get(sample_name, &contig, rec.pos() as u64)
+            });
+            likelihoods.push(
+                grid.iter()
+                    .map(|ccf| {
+                        vaf_likelihood.ln_likelihood(expected_vaf(*ccf, purity, copy_number))
+                    })
+                    .collect_vec(),
+            );
+        }
+        sites.push(Site {
+            record_index,
+            weight: prob.exp(),
+            likelihoods,
+        });
+        records.push(rec);
+    }
+
+    if sites.is_empty() {
+        return Err(errors::Error::NoClusterableVariants.into());
+    }
+
+    let clustering = (1..=max_clusters.min(sites.len()))
+        .map(|n_clusters| Clustering::fit(&sites, n_clusters, sample_names.len()))
+        .min_by(|a, b| {
+            a.bic(&sites, sample_names.len())
+                .partial_cmp(&b.bic(&sites, sample_names.len()))
+                .unwrap()
+        })
+        .unwrap();
+
+    // METHOD: report non-empty clusters in descending order of their mean CCF, such that
+    // cluster 0 represents the (most) clonal cluster.
+    let reported = (0..clustering.weights.len())
+        .filter(|k| clustering.weights[*k] > MIN_CLUSTER_WEIGHT)
+        .sorted_by_key(|k| std::cmp::Reverse(clustering.centroids[*k].iter().sum::<usize>()))
+        .collect_vec();
+    info!("Found {} clusters.", reported.len());
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(centroids)?;
+    for (cluster, k) in reported.iter().enumerate() {
+        for (sample, g) in sample_names.iter().zip(clustering.centroids[*k].iter()) {
+            writer.serialize(Centroid {
+                cluster,
+                sample,
+                ccf: grid[*g],
+                weight: clustering.weights[*k],
+            })?;
+        }
+    }
+    writer.flush()?;
+
+    // write calls with cluster assignments
+    let mut out_header = bcf::Header::from_template(&header);
+    out_header.push_record(
+        b"##INFO=<ID=CLUSTER,Number=1,Type=Integer,\
+          Description=\"Most likely subclonal cluster of the variant (see cluster centroids).\">",
+    );
+    out_header.push_record(
+        b"##INFO=<ID=CLUSTER_PROB,Number=1,Type=Float,\
+          Description=\"Posterior probability for the variant to belong to the cluster given in CLUSTER (PHRED)\">",
+    );
+    let mut outbcf = bcf::Writer::from_stdout(&out_header, false, bcf::Format::BCF)?;
+    let mut assignments = HashMap::new();
+    for (site, r) in sites.iter().zip(clustering.responsibilities.iter()) {
+        let (cluster, k) = reported
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| r[**a].partial_cmp(&r[**b]).unwrap())
+            .unwrap();
+        assignments.insert(site.record_index, (cluster as i32, r[*k]));
+    }
+    for (i, mut rec) in records.into_iter().enumerate() {
+        outbcf.translate(&mut rec);
+        if let Some((cluster, prob)) = assignments.get(&i) {
+            rec.push_info_integer(b"CLUSTER", &[*cluster])?;
+            rec.push_info_float(
+                b"CLUSTER_PROB",
+                &[PHREDProb::from(Prob(*prob)).abs() as f32],
+            )?;
+        }
+        outbcf.write(&rec)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(record_index: usize, ccfs: &[f64]) -> Site {
+        let grid = ccf_grid();
+        Site {
+            record_index,
+            weight: 1.0,
+            likelihoods: ccfs
+                .iter()
+                .map(|ccf| {
+                    let likelihood = VafLikelihood::Binomial {
+                        depth: 200.0,
+                        alt_depth: (expected_vaf(*ccf, 1.0, 2) * 200.0).round(),
+                    };
+                    grid.iter()
+                        .map(|c| likelihood.ln_likelihood(expected_vaf(*c, 1.0, 2)))
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_expected_vaf() {
+        assert_relative_eq!(expected_vaf(1.0, 1.0, 2), 0.5);
+        assert_relative_eq!(expected_vaf(1.0, 0.5, 2), 0.25);
+        assert_relative_eq!(expected_vaf(0.5, 1.0, 1), 0.5);
+    }
+
+    #[test]
+    fn test_clustering() {
+        let sites = (0..20)
+            .map(|i| site(i, &[1.0, 1.0]))
+            .chain((20..40).map(|i| site(i, &[0.4, 0.0])))
+            .collect_vec();
+        let best = (1..=4)
+            .map(|k| Clustering::fit(&sites, k, 2))
+            .min_by(|a, b| a.bic(&sites, 2).partial_cmp(&b.bic(&sites, 2)).unwrap())
+            .unwrap();
+        assert_eq!(best.n_nonempty(), 2);
+        let centroids = best
+            .centroids
+            .iter()
+            .zip(best.weights.iter())
+            .filter(|(_, w)| **w > MIN_CLUSTER_WEIGHT)
+            .map(|(c, _)| c.clone())
+            .sorted()
+            .collect_vec();
+        assert_eq!(centroids, vec![vec![40, 0], vec![100, 100]]);
+    }
+
+    #[test]
+    fn test_posterior_sparse() {
+        // grid step 0.01, points between 0.33 and 0.59 are omitted because of negligible
+        // probability
+        let likelihood = VafLikelihood::posterior(vec![
+            (0.3, 0.2),
+            (0.31, 0.3),
+            (0.32, 0.2),
+            (0.6, 0.1),
+            (0.61, 0.1),
+        ]);
+        // interpolation between neighboring grid points
+        assert_relative_eq!(
+            likelihood.ln_likelihood(0.305),
+            0.25_f64.ln(),
+            epsilon = 1e-9
+        );
+        // nearest grid point at the border of a gap
+        assert_relative_eq!(
+            likelihood.ln_likelihood(0.324),
+            0.2_f64.ln(),
+            epsilon = 1e-9
+        );
+        assert_relative_eq!(
+            likelihood.ln_likelihood(0.597),
+            0.1_f64.ln(),
+            epsilon = 1e-9
+        );
+        // no interpolation across the gap
+        assert_relative_eq!(likelihood.ln_likelihood(0.45), MIN_LIKELIHOOD.ln());
+        assert_relative_eq!(likelihood.ln_likelihood(0.33), MIN_LIKELIHOOD.ln());
+        // beyond the reported points
+        assert_relative_eq!(likelihood.ln_likelihood(0.1), MIN_LIKELIHOOD.ln());
+        assert_relative_eq!(
+            likelihood.ln_likelihood(0.612),
+            0.1_f64.ln(),
+            epsilon = 1e-9
+        );
+        assert_relative_eq!(likelihood.ln_likelihood(0.9), MIN_LIKELIHOOD.ln());
+    }
+
+    #[test]
+    fn test_parse_purities() {
+        let purities = parse_purities(&["tumor=0.7".to_owned()]).unwrap();
+        assert_relative_eq!(purities["tumor"], 0.7);
+        assert!(parse_purities(&["tumor=1.5".to_owned()]).is_err());
+        assert!(parse_purities(&["tumor".to_owned()]).is_err());
+    }
+}
@@ -4,31 +4,70 @@
 // except according to those terms.
 
 use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::str;
 
 use anyhow::Result;
+use bio::stats::{LogProb, Prob};
 use itertools::Itertools;
 use ordered_float::NotNan;
+use rust_htslib::bcf::{self, Read};
 use rusty_machine::learning::lin_reg::LinRegressor;
 use rusty_machine::learning::SupModel;
 use rusty_machine::linalg::{Matrix, Vector};
+use serde_json::{json, Value};
 
+use crate::errors;
+use crate::utils;
 use crate::variants::model::AlleleFreq;
+use crate::{Event, SimpleEvent};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct Estimate {
     observations: Vec<(f64, u64)>,
     intercept: f64,
     slope: f64,
+    r_squared: f64,
+    ks_distance: f64,
 }
 
 impl Estimate {
-    #[allow(dead_code)]
     pub(crate) fn effective_mutation_rate(&self) -> f64 {
         self.slope
     }
+
+    /// Coefficient of determination of the linear fit of the cumulative counts.
+    pub(crate) fn r_squared(&self) -> f64 {
+        self.r_squared
+    }
+
+    /// Maximum distance between the normalized observed and fitted cumulative distributions
+    /// (Kolmogorov-Smirnov distance).
+    pub(crate) fn ks_distance(&self) -> f64 {
+        self.ks_distance
+    }
+
+    fn fitted(&self, reciprocal_freq: f64) -> f64 {
+        self.intercept + self.slope * reciprocal_freq
+    }
+}
+
+/// Output of the effective mutation rate estimation, usable as a sample or species
+/// definition in a scenario.
+#[derive(Debug, Serialize)]
+struct ScenarioEntry {
+    #[serde(rename = "somatic-effective-mutation-rate")]
+    somatic_effective_mutation_rate: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FitPoint {
+    reciprocal_vaf: f64,
+    observed: u64,
+    fitted: f64,
 }
 
-#[allow(dead_code)]
 pub(crate) fn estimate<F: IntoIterator<Item = AlleleFreq>>(
     allele_frequencies: F,
 ) -> Result<Estimate> {
@@ -61,13 +100,165 @@ pub(crate) fn estimate<F: IntoIterator<Item = AlleleFreq>>(
     let mut lin_mod = LinRegressor::default();
     lin_mod.train(&freqs, &counts)?;
 
+    let intercept = lin_mod.parameters().unwrap()[0];
+    let slope = lin_mod.parameters().unwrap()[1];
+
+    // METHOD: goodness of fit as in Williams et al. Nature Genetics 2016.
+    // R² is calculated on the cumulative counts. The KS distance is the maximum distance between
+    // the observed and the fitted cumulative distribution, both normalized to their maximum.
+    let fitted = observations
+        .iter()
+        .map(|(f, _)| intercept + slope * f)
+        .collect_vec();
+    let mean_count =
+        observations.iter().map(|(_, c)| *c as f64).sum::<f64>() / observations.len() as f64;
+    let ss_res: f64 = observations
+        .iter()
+        .zip(fitted.iter())
+        .map(|((_, c), fit)| (*c as f64 - fit).powi(2))
+        .sum();
+    let ss_tot: f64 = observations
+        .iter()
+        .map(|(_, c)| (*c as f64 - mean_count).powi(2))
+        .sum();
+    let r_squared = if ss_tot > 0.0 {
+        1.0 - ss_res / ss_tot
+    } else {
+        1.0
+    };
+    let max_observed = observations.last().map_or(0, |(_, c)| *c) as f64;
+    let max_fitted = fitted.last().cloned().unwrap_or(0.0);
+    let ks_distance = observations
+        .iter()
+        .zip(fitted.iter())
+        .map(|((_, c), fit)| (*c as f64 / max_observed - fit / max_fitted).abs())
+        .fold(0.0, f64::max);
+
     Ok(Estimate {
         observations,
-        intercept: lin_mod.parameters().unwrap()[0],
-        slope: lin_mod.parameters().unwrap()[1],
+        intercept,
+        slope,
+        r_squared,
+        ks_distance,
     })
 }
 
+/// Estimate the somatic effective mutation rate from Varlociraptor calls (read from STDIN),
+/// by fitting the neutral evolution model of Williams et al. Nature Genetics 2016 to the
+/// allele frequencies of the given sample. The estimate is printed to STDOUT in YAML format,
+/// such that it can be used as `somatic-effective-mutation-rate` in a scenario.
+///
+/// # Arguments
+/// * `events` - events to consider as somatic mutations (e.g. SOMATIC_TUMOR)
+/// * `sample_name` - sample to take allele frequencies from
+/// * `min_prob` - minimum posterior probability for the given events
+/// * `min_vaf`, `max_vaf` - allele frequency range to fit the model in
+/// * `coverage_factor` - ratio of genome size and size of the covered region (e.g. for exomes)
+/// * `plot` - optional path to write a diagnostic Vega-Lite plot to
+pub(crate) fn collect_estimate<P: AsRef<Path>>(
+    events: &[String],
+    sample_name: &str,
+    min_prob: Prob,
+    min_vaf: f64,
+    max_vaf: f64,
+    coverage_factor: f64,
+    plot: Option<P>,
+) -> Result<()> {
+    let mut bcf = bcf::Reader::from_stdin()?;
+    let header = bcf.header().to_owned();
+    let sample_id = header.sample_id(sample_name.as_bytes()).ok_or_else(|| {
+        errors::Error::InvalidBCFSampleName {
+            name: sample_name.to_owned(),
+        }
+    })?;
+    let min_prob = LogProb::from(min_prob);
+    let tags = events
+        .iter()
+        .map(|e| SimpleEvent { name: e.to_owned() }.tag_name("PROB"))
+        .collect_vec();
+
+    let mut allele_frequencies = Vec::new();
+    loop {
+        let mut rec = bcf.empty_record();
+        match bcf.read(&mut rec) {
+            None => break,
+            Some(res) => res?,
+        }
+
+        let contig = str::from_utf8(header.rid2name(rec.rid().unwrap()).unwrap())?;
+        let vcfpos = rec.pos() + 1;
+        // obtain VAF estimates (do it here already to work around a segfault in htslib)
+        let vafs = rec.format(b"AF").float()?[sample_id].to_owned();
+
+        // collect allele probabilities for given events
+        let allele_probs = utils::tags_prob_sum(&mut rec, &tags, None)?;
+        if allele_probs.iter().all(|prob| prob.is_none()) {
+            info!(
+                "Skipping variant {}:{} because it does not contain any of the required INFO tags.",
+                contig, vcfpos
+            );
+            continue;
+        }
+
+        for (vaf, prob) in vafs.iter().zip(allele_probs.iter()) {
+            let vaf = *vaf as f64;
+            if vaf.is_nan() || prob.unwrap_or_else(LogProb::ln_zero) < min_prob {
+                continue;
+            }
+            if vaf >= min_vaf && vaf <= max_vaf {
+                allele_frequencies.push(AlleleFreq(vaf));
+            }
+        }
+    }
+
+    if allele_frequencies.len() < 2 {
+        return Err(errors::Error::NotEnoughSomaticVariants.into());
+    }
+
+    let estimate = estimate(allele_frequencies)?;
+    info!(
+        "Fitted neutral evolution model: R²={}, KS distance={}.",
+        estimate.r_squared(),
+        estimate.ks_distance()
+    );
+
+    if let Some(plot) = plot {
+        let plot_data = estimate
+            .observations
+            .iter()
+            .map(|(f, c)| FitPoint {
+                reciprocal_vaf: *f,
+                observed: *c,
+                fitted: estimate.fitted(*f),
+            })
+            .collect_vec();
+        let mut blueprint: Value = serde_json::from_str(include_str!(
+            "../../templates/plots/effective_mutation_rate.json"
+        ))?;
+        if let Value::Object(ref mut blueprint) = blueprint {
+            blueprint["data"]["values"] = json!(plot_data);
+            blueprint["title"] = json!(format!(
+                "effective mutation rate: {:.2}, R²: {:.3}, KS distance: {:.3}",
+                estimate.effective_mutation_rate(),
+                estimate.r_squared(),
+                estimate.ks_distance()
+            ));
+        }
+        serde_json::to_writer_pretty(File::create(plot)?, &blueprint)?;
+    }
+
+    // METHOD: the fit is performed on the covered region only. Since the prior relates the rate
+    // to the entire genome, we scale it accordingly.
+    println!(
+        "{}",
+        serde_yaml::to_string(&ScenarioEntry {
+            somatic_effective_mutation_rate: estimate.effective_mutation_rate() * coverage_factor,
+        })?
+    );
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,4 +272,23 @@ mod tests {
         let estimate = estimate(freqs).unwrap();
         assert_relative_eq!(estimate.effective_mutation_rate(), 596.16, epsilon = 0.01);
     }
+
+    #[test]
+    fn test_fit_quality() {
+        // neutral evolution: the reciprocal allele frequencies are uniformly distributed,
+        // such that the cumulative counts are exactly linear in 1/f
+        let freqs = linspace(4.0, 8.0, 1000).map(|reciprocal: f64| AlleleFreq(1.0 / reciprocal));
+        let good = estimate(freqs).unwrap();
+        assert_relative_eq!(good.effective_mutation_rate(), 249.75, epsilon = 1e-3);
+        assert_relative_eq!(good.r_squared(), 1.0, epsilon = 1e-6);
+        assert!(good.ks_distance() < 1e-6);
+
+        // two clusters of allele frequencies (e.g. subclones) do not follow the model
+        let freqs = linspace(4.0, 5.0, 1000)
+            .chain(linspace(7.5, 8.0, 1000))
+            .map(|reciprocal: f64| AlleleFreq(1.0 / reciprocal));
+        let poor = estimate(freqs).unwrap();
+        assert!(poor.r_squared() < 0.9);
+        assert!(poor.ks_distance() > 0.3);
+    }
 }
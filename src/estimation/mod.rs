@@ -4,6 +4,11 @@
 // except according to those terms.
 
 pub mod alignment_properties;
+pub mod callable_regions;
+pub mod clones;
 pub mod effective_mutation_rate;
 pub mod mutational_burden;
+pub mod mutational_signatures;
+pub mod power;
+pub mod purity;
 pub mod sample_variants;
@@ -1,13 +1,17 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
 use std::str;
 use std::str::FromStr;
 
 use anyhow::Result;
+use bio::io::bed;
 use bio::stats::{LogProb, PHREDProb};
 use itertools::Itertools;
 use itertools_num::linspace;
 use rust_htslib::bcf::{self, Read};
 use serde_json::{json, Value};
+use statrs::distribution::{InverseCDF, Normal};
 
 use crate::errors;
 use crate::variants::model::AlleleFreq;
@@ -38,6 +42,116 @@ fn is_valid_variant(rec: &mut bcf::Record) -> Result<bool> {
     Ok(false)
 }
 
+/// Target regions (e.g. of a sequencing panel), defining both the considered sites and the
+/// size of the callable genome.
+#[derive(Debug, Default)]
+pub(crate) struct Targets {
+    /// Sorted, non-overlapping intervals per contig.
+    intervals: HashMap<String, Vec<(u64, u64)>>,
+}
+
+impl Targets {
+    pub(crate) fn from_bed<P: AsRef<Path> + std::fmt::Debug>(path: P) -> Result<Self> {
+        let mut reader = bed::Reader::from_file(path)?;
+        let mut intervals: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for record in reader.records() {
+            let record = record?;
+            intervals
+                .entry(record.chrom().to_owned())
+                .or_default()
+                .push((record.start(), record.end()));
+        }
+        // merge overlapping intervals, such that they are not counted twice in the size
+        for contig_intervals in intervals.values_mut() {
+            contig_intervals.sort_unstable();
+            let mut merged: Vec<(u64, u64)> = Vec::with_capacity(contig_intervals.len());
+            for (start, end) in contig_intervals.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                    _ => merged.push((start, end)),
+                }
+            }
+            *contig_intervals = merged;
+        }
+        Ok(Targets { intervals })
+    }
+
+    /// Total number of bases covered by the targets.
+    pub(crate) fn size(&self) -> u64 {
+        self.intervals
+            .values()
+            .flatten()
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    /// Whether the given (0-based) position is contained in the targets.
+    pub(crate) fn contains(&self, contig: &str, pos: u64) -> bool {
+        if let Some(intervals) = self.intervals.get(contig) {
+            let i = intervals.partition_point(|(start, _)| *start <= pos);
+            i > 0 && pos < intervals[i - 1].1
+        } else {
+            false
+        }
+    }
+}
+
+/// Maximum number of variants for which the Poisson-binomial distribution is calculated exactly.
+/// Beyond, a normal approximation is used.
+const MAX_EXACT_POISSON_BINOMIAL: usize = 10000;
+
+/// Credible interval of the number of true variants, given the posterior probabilities of
+/// the individual variants.
+fn poisson_binomial_interval(probs: &[f64], width: f64) -> (f64, f64) {
+    let lower_quantile = (1.0 - width) / 2.0;
+    let upper_quantile = 1.0 - lower_quantile;
+    if probs.len() > MAX_EXACT_POISSON_BINOMIAL {
+        // METHOD: for many variants, the Poisson-binomial distribution is well approximated
+        // by a normal distribution with the same mean and variance.
+        let mean: f64 = probs.iter().sum();
+        let sd = probs.iter().map(|p| p * (1.0 - p)).sum::<f64>().sqrt();
+        let normal = Normal::new(mean, sd.max(f64::EPSILON)).unwrap();
+        return (
+            normal.inverse_cdf(lower_quantile).max(0.0),
+            normal.inverse_cdf(upper_quantile).min(probs.len() as f64),
+        );
+    }
+
+    // METHOD: the number of true variants follows a Poisson-binomial distribution, which we
+    // calculate exactly via dynamic programming over the variants.
+    let mut pmf = vec![1.0];
+    for p in probs {
+        let mut next = vec![0.0; pmf.len() + 1];
+        for (k, q) in pmf.iter().enumerate() {
+            next[k] += q * (1.0 - p);
+            next[k + 1] += q * p;
+        }
+        pmf = next;
+    }
+    let quantile = |q: f64| {
+        let mut cdf = 0.0;
+        for (k, p) in pmf.iter().enumerate() {
+            cdf += p;
+            if cdf >= q {
+                return k as f64;
+            }
+        }
+        (pmf.len() - 1) as f64
+    };
+    (quantile(lower_quantile), quantile(upper_quantile))
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MBTableRecord {
+    sample: String,
+    vartype: String,
+    min_vaf: f64,
+    expected_count: f64,
+    mb: f64,
+    mb_lower: f64,
+    mb_upper: f64,
+}
+
 #[derive(Debug, Clone, Serialize)]
 struct MB {
     min_vaf: f64,
@@ -91,13 +205,55 @@ pub enum PlotMode {
     Multibar,
 }
 
+#[derive(
+    Display,
+    Debug,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    EnumString,
+    EnumIter,
+    IntoStaticStr,
+    EnumVariantNames,
+    PartialEq,
+)]
+#[strum(serialize_all = "kebab_case")]
+pub enum OutputFormat {
+    Vega,
+    Tsv,
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Vega
+    }
+}
+
+/// Estimate mutational burden from Varlociraptor calls (read from STDIN).
+///
+/// # Arguments
+/// * `mutational_events` - events to consider (e.g. SOMATIC_TUMOR)
+/// * `sample_names` - samples to estimate the mutational burden for
+/// * `coding_genome_size` - size of the coding genome (used if no targets are given)
+/// * `targets` - optional target regions, defining the considered sites and the genome size
+/// * `mode` - plot mode (for Vega output)
+/// * `output_format` - whether to print a Vega-Lite plot or a table
+/// * `cutoff` - minimum VAF (for multibar plot and tabular output)
+/// * `credible_interval` - width of the credible interval of the mutational burden
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn collect_estimates(
     mutational_events: &[String],
     sample_names: &[String],
     coding_genome_size: u64,
+    targets: Option<&Targets>,
     mode: PlotMode,
+    output_format: OutputFormat,
     cutoff: f64,
+    credible_interval: f64,
 ) -> Result<()> {
+    let coding_genome_size = targets.map_or(coding_genome_size, |targets| targets.size());
     let mut bcf = bcf::Reader::from_stdin()?;
     let header = bcf.header().to_owned();
 
@@ -127,7 +283,15 @@ pub(crate) fn collect_estimates(
             let vafs = rec.format(b"AF").float()?[*id].to_owned();
             vafmap.insert(name, vafs);
         }
-        if !is_valid_variant(&mut rec)? {
+        if let Some(targets) = targets {
+            if !targets.contains(contig, rec.pos() as u64) {
+                info!(
+                    "Skipping variant {}:{} because it is not contained in the target regions.",
+                    contig, vcfpos
+                );
+                continue;
+            }
+        } else if !is_valid_variant(&mut rec)? {
             info!(
                 "Skipping variant {}:{} because it is not coding.",
                 contig, vcfpos
@@ -212,6 +376,55 @@ pub(crate) fn collect_estimates(
             }
         };
 
+    if output_format != OutputFormat::Vega {
+        let mut table = Vec::new();
+        for sample in sample_names {
+            let records = mb
+                .range(AlleleFreq(cutoff)..)
+                .flat_map(|(_, records)| records)
+                .filter(|record| record.sample == *sample)
+                .collect_vec();
+            let mut groups = records
+                .iter()
+                .map(|record| (record.vartype.to_string(), record.prob.exp()))
+                .into_group_map()
+                .into_iter()
+                .collect_vec();
+            groups.sort_by(|a, b| a.0.cmp(&b.0));
+            groups.push((
+                "all".to_owned(),
+                records.iter().map(|record| record.prob.exp()).collect_vec(),
+            ));
+            for (vartype, probs) in groups {
+                let (lower, upper) = poisson_binomial_interval(&probs, credible_interval);
+                let per_mb = |count: f64| (count / coding_genome_size as f64) * 1000000.0;
+                let expected_count: f64 = probs.iter().sum();
+                table.push(MBTableRecord {
+                    sample: sample.to_owned(),
+                    vartype,
+                    min_vaf: cutoff,
+                    expected_count,
+                    mb: per_mb(expected_count),
+                    mb_lower: per_mb(lower),
+                    mb_upper: per_mb(upper),
+                });
+            }
+        }
+
+        if output_format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&table)?);
+        } else {
+            let mut writer = csv::WriterBuilder::new()
+                .delimiter(b'\t')
+                .from_writer(io::stdout());
+            for record in table {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+        return Ok(());
+    }
+
     let min_vafs = linspace(0.0, 1.0, 100).map(AlleleFreq);
 
     match mode {
@@ -520,3 +733,32 @@ pub(crate) fn vartypes(record: &bcf::Record) -> Vec<Vartype> {
         })
         .collect_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poisson_binomial_interval() {
+        let (lower, upper) = poisson_binomial_interval(&[1.0; 10], 0.95);
+        assert_eq!(lower, 10.0);
+        assert_eq!(upper, 10.0);
+
+        let (lower, upper) = poisson_binomial_interval(&[0.5; 100], 0.95);
+        assert!(lower < 50.0 && lower > 35.0);
+        assert!(upper > 50.0 && upper < 65.0);
+    }
+
+    #[test]
+    fn test_targets() {
+        let mut intervals = HashMap::new();
+        intervals.insert("chr1".to_owned(), vec![(10, 20), (30, 40)]);
+        let targets = Targets { intervals };
+        assert_eq!(targets.size(), 20);
+        assert!(targets.contains("chr1", 10));
+        assert!(!targets.contains("chr1", 20));
+        assert!(targets.contains("chr1", 39));
+        assert!(!targets.contains("chr1", 5));
+        assert!(!targets.contains("chr2", 15));
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::str;
+
+use anyhow::Result;
+use bio::alphabets::dna;
+use bio::io::fasta;
+use itertools::Itertools;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{rngs::StdRng, SeedableRng};
+use rust_htslib::bcf::{self, Read};
+use rusty_machine::linalg::{BaseMatrix, Matrix, Vector};
+
+use crate::errors;
+use crate::utils;
+use crate::{Event, SimpleEvent};
+
+const SUBSTITUTIONS: [(u8, u8); 6] = [
+    (b'C', b'A'),
+    (b'C', b'G'),
+    (b'C', b'T'),
+    (b'T', b'A'),
+    (b'T', b'C'),
+    (b'T', b'G'),
+];
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+const CHANNELS: usize = 96;
+/// Maximum number of iterations (including inner feasibility steps) of the NNLS active set
+/// algorithm.
+const MAX_NNLS_ITERATIONS: usize = 1000;
+const NNLS_TOLERANCE: f64 = 1e-10;
+
+/// Index of the given trinucleotide context and substitution among the 96 channels, ordered
+/// as in COSMIC (substitution, 5' base, 3' base). The substitution is given relative to the
+/// pyrimidine of the mutated base pair. None if the context contains ambiguous bases.
+fn channel(upstream: u8, refbase: u8, altbase: u8, downstream: u8) -> Option<usize> {
+    let (upstream, refbase, altbase, downstream) = if refbase == b'G' || refbase == b'A' {
+        (
+            dna::complement(downstream),
+            dna::complement(refbase),
+            dna::complement(altbase),
+            dna::complement(upstream),
+        )
+    } else {
+        (upstream, refbase, altbase, downstream)
+    };
+    let substitution = SUBSTITUTIONS
+        .iter()
+        .position(|s| *s == (refbase, altbase))?;
+    let upstream = BASES.iter().position(|b| *b == upstream)?;
+    let downstream = BASES.iter().position(|b| *b == downstream)?;
+    Some(substitution * 16 + upstream * 4 + downstream)
+}
+
+/// Name of the given channel in COSMIC notation (e.g. A[C>T]G).
+fn channel_name(channel: usize) -> String {
+    let (refbase, altbase) = SUBSTITUTIONS[channel / 16];
+    format!(
+        "{}[{}>{}]{}",
+        BASES[(channel % 16) / 4] as char,
+        refbase as char,
+        altbase as char,
+        BASES[channel % 4] as char
+    )
+}
+
+/// 96-channel trinucleotide-context spectrum of single nucleotide variants.
+#[derive(Debug, Clone)]
+pub(crate) struct Spectrum {
+    counts: Vec<f64>,
+}
+
+impl Default for Spectrum {
+    fn default() -> Self {
+        Spectrum {
+            counts: vec![0.0; CHANNELS],
+        }
+    }
+}
+
+impl Spectrum {
+    pub(crate) fn total(&self) -> f64 {
+        self.counts.iter().sum()
+    }
+
+    /// Draw a bootstrap sample of the mutations underlying this spectrum.
+    fn bootstrap(&self, rng: &mut StdRng) -> Self {
+        let mut sample = Spectrum::default();
+        if let Ok(dist) = WeightedIndex::new(&self.counts) {
+            for _ in 0..self.total().round() as usize {
+                sample.counts[dist.sample(rng)] += 1.0;
+            }
+        }
+        sample
+    }
+}
+
+/// Mutational signatures (e.g. COSMIC SBS), given as relative frequencies over the 96 channels.
+#[derive(Debug, Clone)]
+pub(crate) struct Signatures {
+    names: Vec<String>,
+    /// Row-major matrix with one row per channel and one column per signature.
+    matrix: Matrix<f64>,
+}
+
+impl Signatures {
+    /// Read signatures from a tab-separated file in COSMIC format: a header with the
+    /// signature names, and one row per channel, starting with the channel name (e.g. A[C>T]G).
+    pub(crate) fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+        let names = reader
+            .headers()?
+            .iter()
+            .skip(1)
+            .map(str::to_owned)
+            .collect_vec();
+        let channels: HashMap<String, usize> =
+            (0..CHANNELS).map(|i| (channel_name(i), i)).collect();
+
+        let mut rows = vec![None; CHANNELS];
+        for record in reader.records() {
+            let record = record?;
+            let name = record.get(0).unwrap_or_default();
+            let i = *channels
+                .get(name)
+                .ok_or_else(|| errors::Error::InvalidSignatureChannel {
+                    name: name.to_owned(),
+                })?;
+            rows[i] = Some(
+                record
+                    .iter()
+                    .skip(1)
+                    .map(|value| value.parse::<f64>())
+                    .collect::<std::result::Result<Vec<_>, _>>()?,
+            );
+        }
+        let mut data = Vec::with_capacity(CHANNELS * names.len());
+        for (i, row) in rows.into_iter().enumerate() {
+            let row = row.ok_or_else(|| errors::Error::MissingSignatureChannel {
+                name: channel_name(i),
+            })?;
+            data.extend(row);
+        }
+
+        Ok(Signatures {
+            matrix: Matrix::new(CHANNELS, names.len(), data),
+            names,
+        })
+    }
+
+    /// Fit the exposures of the signatures to the given spectrum via non-negative least squares.
+    pub(crate) fn fit(&self, spectrum: &Spectrum) -> Result<Vec<f64>> {
+        nnls(&self.matrix, &spectrum.counts)
+    }
+}
+
+/// Solve min ||Ax - b|| subject to x >= 0, with the active set algorithm of Lawson and Hanson.
+/// Fails if the input contains non-finite values, if a least squares subproblem cannot be
+/// solved, or if the algorithm does not converge within `MAX_NNLS_ITERATIONS`.
+fn nnls(a: &Matrix<f64>, b: &[f64]) -> Result<Vec<f64>> {
+    if !a.iter().chain(b.iter()).all(|v| v.is_finite()) {
+        return Err(errors::Error::NnlsFailed {
+            msg: "non-finite values in signatures or spectrum".to_owned(),
+        }
+        .into());
+    }
+
+    let n = a.cols();
+    let at = a.transpose();
+    let b = Vector::new(b.to_vec());
+    let atb = &at * &b;
+    let ata = &at * a;
+
+    let mut x = vec![0.0; n];
+    let mut passive = vec![false; n];
+
+    // Solve the unconstrained least squares problem on the passive set.
+    let solve_passive = |passive: &[bool]| -> Result<Vec<f64>> {
+        let indices = (0..n).filter(|j| passive[*j]).collect_vec();
+        let mut z = vec![0.0; n];
+        if indices.is_empty() {
+            return Ok(z);
+        }
+        let sub = Matrix::new(
+            indices.len(),
+            indices.len(),
+            indices
+                .iter()
+                .flat_map(|i| indices.iter().map(move |j| (*i, *j)))
+                .map(|(i, j)| ata[[i, j]])
+                .collect_vec(),
+        );
+        let rhs = Vector::new(indices.iter().map(|i| atb[*i]).collect_vec());
+        let solution = sub.solve(rhs).map_err(|e| errors::Error::NnlsFailed {
+            msg: format!("least squares subproblem cannot be solved ({})", e),
+        })?;
+        for (i, value) in indices.iter().zip(solution.iter()) {
+            if !value.is_finite() {
+                return Err(errors::Error::NnlsFailed {
+                    msg: "least squares subproblem yields non-finite solution".to_owned(),
+                }
+                .into());
+            }
+            z[*i] = *value;
+        }
+        Ok(z)
+    };
+
+    let mut iterations = 0;
+    let mut next_iteration = || {
+        iterations += 1;
+        if iterations > MAX_NNLS_ITERATIONS {
+            Err(errors::Error::NnlsFailed {
+                msg: format!("no convergence within {} iterations", MAX_NNLS_ITERATIONS),
+            })
+        } else {
+            Ok(())
+        }
+    };
+
+    loop {
+        next_iteration()?;
+        // gradient of the objective
+        let ax = &ata * &Vector::new(x.clone());
+        let w = (0..n).map(|j| atb[j] - ax[j]).collect_vec();
+        let candidate = (0..n)
+            .filter(|j| !passive[*j] && w[*j] > NNLS_TOLERANCE)
+            .max_by(|i, j| w[*i].partial_cmp(&w[*j]).unwrap());
+        let candidate = if let Some(candidate) = candidate {
+            candidate
+        } else {
+            break;
+        };
+        passive[candidate] = true;
+
+        loop {
+            let z = solve_passive(&passive)?;
+            if (0..n).all(|j| !passive[j] || z[j] > 0.0) {
+                x = z;
+                break;
+            }
+            // METHOD: step towards z as far as possible without leaving the feasible region,
+            // and move variables that become zero back to the active set.
+            next_iteration()?;
+            let alpha = (0..n)
+                .filter(|j| passive[*j] && z[*j] <= 0.0)
+                .map(|j| x[j] / (x[j] - z[j]))
+                .fold(f64::INFINITY, f64::min);
+            for j in 0..n {
+                x[j] += alpha * (z[j] - x[j]);
+                if passive[j] && x[j] <= NNLS_TOLERANCE {
+                    passive[j] = false;
+                    x[j] = 0.0;
+                }
+            }
+        }
+    }
+
+    Ok(x)
+}
+
+#[derive(Debug, Serialize)]
+struct ExposureRecord<'a> {
+    signature: &'a str,
+    exposure: f64,
+    lower: f64,
+    upper: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct SpectrumRecord {
+    channel: String,
+    count: f64,
+}
+
+/// Estimate the 96-channel trinucleotide spectrum of Varlociraptor calls (read from STDIN) and
+/// fit the exposures of the given signatures. Each SNV contributes with the posterior probability
+/// of the given events. Exposures and their bootstrapped confidence intervals are printed to
+/// STDOUT in TSV format.
+///
+/// # Arguments
+/// * `events` - events to consider (e.g. SOMATIC_TUMOR)
+/// * `reference` - reference genome the calls refer to
+/// * `signatures` - signature matrix in COSMIC format
+/// * `bootstrap_samples` - number of bootstrap samples for the confidence intervals
+/// * `confidence` - width of the confidence intervals (e.g. 0.95)
+/// * `spectrum_path` - optional path to write the spectrum to (TSV format)
+pub(crate) fn estimate<P: AsRef<Path> + std::fmt::Debug>(
+    events: &[String],
+    reference: P,
+    signatures: P,
+    bootstrap_samples: usize,
+    confidence: f64,
+    spectrum_path: Option<P>,
+) -> Result<()> {
+    let signatures = Signatures::from_path(signatures)?;
+    let mut reference = fasta::IndexedReader::from_file(&reference)?;
+    let mut bcf = bcf::Reader::from_stdin()?;
+    let header = bcf.header().to_owned();
+
+    let tags = events
+        .iter()
+        .map(|e| SimpleEvent { name: e.to_owned() }.tag_name("PROB"))
+        .collect_vec();
+
+    let mut spectrum = Spectrum::default();
+    let mut context = Vec::new();
+    loop {
+        let mut rec = bcf.empty_record();
+        match bcf.read(&mut rec) {
+            None => break,
+            Some(res) => res?,
+        }
+
+        let alleles = rec
+            .alleles()
+            .into_iter()
+            .map(|allele| allele.to_owned())
+            .collect_vec();
+        if alleles[0].len() != 1 {
+            continue;
+        }
+        let contig = str::from_utf8(header.rid2name(rec.rid().unwrap()).unwrap())?;
+        let pos = rec.pos() as u64;
+        if pos == 0 {
+            continue;
+        }
+
+        let allele_probs = utils::tags_prob_sum(&mut rec, &tags, None)?;
+        if allele_probs.iter().all(|prob| prob.is_none()) {
+            info!(
+                "Skipping variant {}:{} because it does not contain any of the required INFO tags.",
+                contig,
+                pos + 1
+            );
+            continue;
+        }
+
+        reference.fetch(contig, pos - 1, pos + 2)?;
+        reference.read(&mut context)?;
+        if context.len() != 3 {
+            continue;
+        }
+        let trinucleotide = context.to_ascii_uppercase();
+
+        for (alt_allele, prob) in alleles[1..].iter().zip(allele_probs.iter()) {
+            let prob = match prob {
+                Some(prob) if alt_allele.len() == 1 => prob,
+                _ => continue,
+            };
+            if let Some(channel) = channel(
+                trinucleotide[0],
+                alleles[0][0].to_ascii_uppercase(),
+                alt_allele[0].to_ascii_uppercase(),
+                trinucleotide[2],
+            ) {
+                // METHOD: instead of a hard cutoff, each SNV contributes its posterior probability.
+                spectrum.counts[channel] += prob.exp();
+            }
+        }
+    }
+
+    if spectrum.total() == 0.0 {
+        return Err(errors::Error::NoRecordsFound.into());
+    }
+
+    if let Some(spectrum_path) = spectrum_path {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_path(spectrum_path)?;
+        for (i, count) in spectrum.counts.iter().enumerate() {
+            writer.serialize(SpectrumRecord {
+                channel: channel_name(i),
+                count: *count,
+            })?;
+        }
+        writer.flush()?;
+    }
+
+    let exposures = signatures.fit(&spectrum)?;
+
+    // METHOD: bootstrap confidence intervals by resampling mutations from the spectrum
+    // and refitting the exposures.
+    let mut rng = StdRng::seed_from_u64(48074578);
+    let mut bootstrap_exposures = vec![Vec::with_capacity(bootstrap_samples); exposures.len()];
+    for _ in 0..bootstrap_samples {
+        let sample = spectrum.bootstrap(&mut rng);
+        for (i, exposure) in signatures.fit(&sample)?.into_iter().enumerate() {
+            bootstrap_exposures[i].push(exposure);
+        }
+    }
+    let quantile = |values: &mut Vec<f64>, q: f64| {
+        if values.is_empty() {
+            return f64::NAN;
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        values[((values.len() - 1) as f64 * q).round() as usize]
+    };
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(io::stdout());
+    for ((name, exposure), mut samples) in signatures
+        .names
+        .iter()
+        .zip(exposures.iter())
+        .zip(bootstrap_exposures)
+    {
+        writer.serialize(ExposureRecord {
+            signature: name,
+            exposure: *exposure,
+            lower: quantile(&mut samples, (1.0 - confidence) / 2.0),
+            upper: quantile(&mut samples, 1.0 - (1.0 - confidence) / 2.0),
+        })?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel() {
+        assert_eq!(channel(b'A', b'C', b'A', b'A'), Some(0));
+        assert_eq!(channel_name(0), "A[C>A]A");
+        // G>A in context T_C is C>T in context G_A on the reverse strand.
+        let i = channel(b'T', b'G', b'A', b'C').unwrap();
+        assert_eq!(channel_name(i), "G[C>T]A");
+        assert_eq!(channel_name(CHANNELS - 1), "T[T>G]T");
+        assert_eq!(channel(b'N', b'C', b'A', b'A'), None);
+    }
+
+    #[test]
+    fn test_nnls() {
+        let a = Matrix::new(3, 2, vec![1.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        let x = nnls(&a, &[2.0, 3.0, 5.0]).unwrap();
+        assert_relative_eq!(x[0], 2.0, epsilon = 1e-6);
+        assert_relative_eq!(x[1], 3.0, epsilon = 1e-6);
+
+        // unconstrained solution would be negative for the second component
+        let x = nnls(&a, &[2.0, -3.0, -1.0]).unwrap();
+        assert!(x[1] == 0.0);
+        assert!(x[0] > 0.0);
+    }
+
+    #[test]
+    fn test_nnls_degenerate() {
+        // duplicated signature, a signature that is a combination of two others and
+        // an empty signature
+        let a = Matrix::new(
+            4,
+            5,
+            vec![
+                1.0, 1.0, 0.0, 0.5, 0.0, //
+                0.0, 0.0, 1.0, 0.5, 0.0, //
+                1.0, 1.0, 1.0, 1.0, 0.0, //
+                0.0, 0.0, 0.0, 0.0, 0.0,
+            ],
+        );
+        let b = [2.0, 3.0, 5.0, 1.0];
+        let x = nnls(&a, &b).unwrap();
+        assert!(x.iter().all(|v| v.is_finite() && *v >= 0.0));
+        assert_eq!(x[4], 0.0);
+        // the fit reproduces the part of the spectrum that lies in the span of the signatures
+        let fitted = &a * &Vector::new(x);
+        assert_relative_eq!(fitted[0], 2.0, epsilon = 1e-6);
+        assert_relative_eq!(fitted[1], 3.0, epsilon = 1e-6);
+        assert_relative_eq!(fitted[2], 5.0, epsilon = 1e-6);
+    }
+
+    #[test]
+    fn test_nnls_non_finite() {
+        let a = Matrix::new(2, 1, vec![1.0, f64::NAN]);
+        assert!(nnls(&a, &[1.0, 1.0]).is_err());
+        let a = Matrix::new(2, 1, vec![1.0, 1.0]);
+        assert!(nnls(&a, &[1.0, f64::INFINITY]).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use anyhow::Result;
+use bio::stats::{LogProb, PHREDProb};
+use bio_types::sequence::SequenceReadPairOrientation;
+use derive_builder::Builder;
+use itertools::Itertools;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+
+use crate::calling::variants::calling::Model;
+use crate::errors;
+use crate::grammar;
+use crate::variants::evidence::observation::{
+    IndelOperations, ObservationBuilder, ReadPosition, Strand,
+};
+use crate::variants::model::bias::Biases;
+use crate::variants::model::modes::generic::Data;
+use crate::variants::model::prior::Prior;
+use crate::variants::model::{self, Contamination};
+use crate::variants::sample::Pileup;
+
+/// Distribution of mapping qualities of simulated reads.
+#[derive(Debug, Clone)]
+pub(crate) struct MapqProfile {
+    mapqs: Vec<u8>,
+    weights: Vec<f64>,
+}
+
+impl Default for MapqProfile {
+    fn default() -> Self {
+        MapqProfile {
+            mapqs: vec![60],
+            weights: vec![1.0],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MapqProfileEntry {
+    mapq: u8,
+    weight: f64,
+}
+
+impl MapqProfile {
+    /// Read profile from a TSV file with columns mapq and weight.
+    pub(crate) fn from_tsv<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+        let mut mapqs = Vec::new();
+        let mut weights = Vec::new();
+        for entry in reader.deserialize() {
+            let entry: MapqProfileEntry = entry?;
+            mapqs.push(entry.mapq);
+            weights.push(entry.weight);
+        }
+        Self::new(mapqs, weights)
+    }
+
+    /// Create profile from the given MAPQs and their (not necessarily normalized) weights.
+    /// Weights have to be finite and non-negative, and at least one of them has to be positive.
+    pub(crate) fn new(mapqs: Vec<u8>, weights: Vec<f64>) -> Result<Self> {
+        let invalid = |msg: &str| errors::Error::InvalidMapqProfile {
+            msg: msg.to_owned(),
+        };
+        if mapqs.is_empty() {
+            return Err(invalid("no entries given").into());
+        }
+        if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+            return Err(invalid("weights have to be finite and non-negative").into());
+        }
+        if weights.iter().all(|w| *w == 0.0) {
+            return Err(invalid("at least one weight has to be positive").into());
+        }
+        Ok(MapqProfile { mapqs, weights })
+    }
+}
+
+/// Allele frequencies of the samples of the given scenario, given as `samplename=value`.
+/// Samples that are not mentioned get an allele frequency of zero.
+pub(crate) fn parse_background_vafs(
+    scenario: &grammar::Scenario,
+    specs: &[String],
+) -> Result<grammar::SampleInfo<f64>> {
+    let mut vafs = vec![0.0; scenario.samples().len()];
+    for spec in specs {
+        let invalid = || errors::Error::InvalidBackgroundVAF {
+            spec: spec.to_owned(),
+        };
+        let (sample, vaf) = spec.split('=').collect_tuple().ok_or_else(invalid)?;
+        let vaf: f64 = vaf.parse().map_err(|_| invalid())?;
+        if !(0.0..=1.0).contains(&vaf) {
+            return Err(invalid().into());
+        }
+        let idx = scenario
+            .idx(sample)
+            .ok_or_else(|| errors::Error::InvalidScenarioSampleName {
+                name: sample.to_owned(),
+            })?;
+        vafs[idx] = vaf;
+    }
+    Ok(vafs.into())
+}
+
+/// Events of the given scenario on the given contig, without artifact events (simulated reads
+/// do not exhibit any biases).
+pub(crate) fn events(scenario: &grammar::Scenario, contig: &str) -> Result<Vec<model::Event>> {
+    let mut events = vec![model::Event {
+        name: "absent".to_owned(),
+        vafs: grammar::VAFTree::absent(scenario.samples().len()),
+        biases: vec![Biases::none()],
+    }];
+    for (name, vafs) in scenario.vaftrees(contig)? {
+        events.push(model::Event {
+            name,
+            vafs,
+            biases: vec![Biases::none()],
+        });
+    }
+    events.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(events)
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SensitivityPoint {
+    depth: usize,
+    vaf: f64,
+    event: String,
+    sensitivity: f64,
+    mean_prob: f64,
+}
+
+/// Power analysis for a scenario, simulating pileups of a sample at given depths and allele
+/// frequencies.
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+pub(crate) struct PowerAnalysis {
+    model: Model<Prior>,
+    events: Vec<model::Event>,
+    contaminations: grammar::SampleInfo<Option<Contamination>>,
+    /// Index of the sample whose allele frequency is varied.
+    sample: usize,
+    /// Allele frequencies of all other samples.
+    background_vafs: grammar::SampleInfo<f64>,
+    depths: Vec<usize>,
+    vafs: Vec<f64>,
+    #[builder(default)]
+    mapq_profile: MapqProfile,
+    simulations: usize,
+    /// Posterior probability at which an event is considered to be called.
+    min_prob: LogProb,
+    /// Probability of a sequencing error, determining the evidence of a single read.
+    error_rate: f64,
+}
+
+impl PowerAnalysis {
+    fn simulate_pileup(&self, rng: &mut StdRng, depth: usize, vaf: f64) -> Pileup {
+        let mapqs = WeightedIndex::new(&self.mapq_profile.weights)
+            .expect("bug: MAPQ profile weights are validated upon construction");
+        let prob_correct = LogProb((1.0 - self.error_rate).ln());
+        let prob_error = LogProb(self.error_rate.ln());
+        (0..depth)
+            .map(|_| {
+                let mapq = self.mapq_profile.mapqs[mapqs.sample(rng)];
+                let prob_mapping = LogProb::from(PHREDProb(mapq as f64)).ln_one_minus_exp();
+                // METHOD: reads are sampled from the alt allele according to the allele
+                // frequency. Mismapped reads stem from an unknown locus, and carry the alt
+                // allele with probability 0.5 (as assumed by the likelihood model).
+                let is_alt = if rng.gen::<f64>() < prob_mapping.exp() {
+                    rng.gen::<f64>() < vaf
+                } else {
+                    rng.gen::<f64>() < 0.5
+                };
+                let (prob_alt, prob_ref) = if is_alt {
+                    (prob_correct, prob_error)
+                } else {
+                    (prob_error, prob_correct)
+                };
+                ObservationBuilder::default()
+                    .prob_mapping_mismapping(prob_mapping)
+                    .prob_alt(prob_alt)
+                    .prob_ref(prob_ref)
+                    .prob_missed_allele(prob_ref.ln_add_exp(prob_alt) - LogProb(2.0_f64.ln()))
+                    .prob_sample_alt(LogProb::ln_one())
+                    .prob_overlap(LogProb::ln_one())
+                    .read_orientation(SequenceReadPairOrientation::None)
+                    .read_position(ReadPosition::None)
+                    .strand(Strand::Both)
+                    .softclipped(false)
+                    .indel_operations(IndelOperations::None)
+                    .paired(true)
+                    .build()
+                    .unwrap()
+            })
+            .collect_vec()
+    }
+
+    /// Allele frequencies of the reads of each sample, taking contamination into account.
+    fn sample_vafs(&self, vaf: f64) -> Vec<f64> {
+        let vafs = self
+            .background_vafs
+            .iter()
+            .enumerate()
+            .map(|(i, background)| if i == self.sample { vaf } else { *background })
+            .collect_vec();
+        vafs.iter()
+            .zip(self.contaminations.iter())
+            .map(|(vaf, contamination)| match contamination {
+                Some(Contamination { by, fraction }) => {
+                    (1.0 - fraction) * vaf + fraction * vafs[*by]
+                }
+                None => *vaf,
+            })
+            .collect()
+    }
+
+    fn sensitivities(&self) -> Vec<SensitivityPoint> {
+        let mut rng = StdRng::seed_from_u64(48074578);
+        let mut points = Vec::new();
+        for depth in &self.depths {
+            for vaf in &self.vafs {
+                let sample_vafs = self.sample_vafs(*vaf);
+                let mut calls = vec![0; self.events.len()];
+                let mut probs = vec![0.0; self.events.len()];
+                for _ in 0..self.simulations {
+                    let pileups = sample_vafs
+                        .iter()
+                        .map(|vaf| self.simulate_pileup(&mut rng, *depth, *vaf))
+                        .collect_vec();
+                    let instance = self
+                        .model
+                        .compute(self.events.iter().cloned(), &Data::new(pileups, None));
+                    for (i, event) in self.events.iter().enumerate() {
+                        let prob = instance.posterior(event).unwrap();
+                        if prob >= self.min_prob {
+                            calls[i] += 1;
+                        }
+                        probs[i] += prob.exp();
+                    }
+                }
+                for (i, event) in self.events.iter().enumerate() {
+                    points.push(SensitivityPoint {
+                        depth: *depth,
+                        vaf: *vaf,
+                        event: event.name.clone(),
+                        sensitivity: calls[i] as f64 / self.simulations as f64,
+                        mean_prob: probs[i] / self.simulations as f64,
+                    });
+                }
+            }
+        }
+        points
+    }
+
+    /// Perform the power analysis, print sensitivities as TSV to STDOUT and optionally write
+    /// a Vega-Lite plot of the sensitivity curves.
+    ///
+    /// # Arguments
+    /// * `min_sensitivity` - sensitivity for which to report the minimum detectable VAF per event and depth
+    /// * `plot` - optional path for the plot
+    pub(crate) fn run<P: AsRef<Path>>(&self, min_sensitivity: f64, plot: Option<P>) -> Result<()> {
+        let points = self.sensitivities();
+
+        for ((depth, event), group) in &points
+            .iter()
+            .sorted_by_key(|point| (point.depth, point.event.clone()))
+            .group_by(|point| (point.depth, point.event.clone()))
+        {
+            if let Some(point) = group
+                .filter(|point| point.sensitivity >= min_sensitivity)
+                .min_by(|a, b| a.vaf.partial_cmp(&b.vaf).unwrap())
+            {
+                info!(
+                    "Minimum VAF for calling {} with sensitivity {} at depth {}: {}",
+                    event, min_sensitivity, depth, point.vaf
+                );
+            }
+        }
+
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(io::stdout());
+        for point in &points {
+            writer.serialize(point)?;
+        }
+        writer.flush()?;
+
+        if let Some(plot) = plot {
+            let mut blueprint: Value =
+                serde_json::from_str(include_str!("../../templates/plots/power.json"))?;
+            if let Value::Object(ref mut blueprint) = blueprint {
+                blueprint["data"]["values"] = json!(points);
+            }
+            serde_json::to_writer_pretty(File::create(plot)?, &blueprint)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_background_vafs() {
+        let scenario: grammar::Scenario = serde_yaml::from_str(
+            r#"samples:
+  tumor:
+    resolution: 100
+    universe: "[0.0,1.0]"
+  normal:
+    resolution: 100
+    universe: "[0.0,0.5,1.0]"
+events:
+  somatic: "tumor:]0.0,1.0] & normal:0.0""#,
+        )
+        .unwrap();
+        let vafs = parse_background_vafs(&scenario, &["normal=0.5".to_owned()]).unwrap();
+        assert_eq!(vafs[scenario.idx("normal").unwrap()], 0.5);
+        assert_eq!(vafs[scenario.idx("tumor").unwrap()], 0.0);
+        assert!(parse_background_vafs(&scenario, &["normal=1.5".to_owned()]).is_err());
+        assert!(parse_background_vafs(&scenario, &["father=0.5".to_owned()]).is_err());
+    }
+    #[test]
+    fn test_mapq_profile() {
+        assert!(MapqProfile::new(vec![0, 60], vec![0.1, 0.9]).is_ok());
+        assert!(MapqProfile::new(vec![0, 60], vec![0.0, 1.0]).is_ok());
+        assert!(MapqProfile::new(vec![], vec![]).is_err());
+        assert!(MapqProfile::new(vec![0, 60], vec![0.0, 0.0]).is_err());
+        assert!(MapqProfile::new(vec![60], vec![-1.0]).is_err());
+        assert!(MapqProfile::new(vec![60], vec![f64::NAN]).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::Result;
+use bio::stats::{LogProb, PHREDProb, Prob};
+use itertools::Itertools;
+use itertools_num::linspace;
+use rust_htslib::bcf::{self, Read};
+use serde_json::{json, Value};
+
+use crate::errors;
+use crate::{Event, SimpleEvent};
+
+const MIN_PURITY: f64 = 0.05;
+const MAX_PURITY: f64 = 1.0;
+const PURITY_GRID_POINTS: usize = 96;
+const MIN_PLOIDY: f64 = 1.5;
+const MAX_PLOIDY: f64 = 5.0;
+const PLOIDY_GRID_POINTS: usize = 36;
+/// Candidate fractions of clonal somatic variants (the rest being subclonal).
+const CLONAL_FRACTIONS: [f64; 9] = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9];
+/// Number of grid points for integrating over the cancer cell fraction of subclonal variants.
+const SUBCLONAL_GRID_POINTS: usize = 20;
+const MIN_VAF: f64 = 1e-6;
+
+/// Read counts of a variant in the tumor sample.
+#[derive(Debug, Clone)]
+struct Site {
+    depth: f64,
+    alt_depth: f64,
+    /// Posterior probability of the considered event.
+    weight: f64,
+}
+
+impl Site {
+    /// Binomial log-likelihood of the given VAF (without the binomial coefficient, which is
+    /// constant for a site).
+    fn ln_likelihood(&self, vaf: f64) -> LogProb {
+        let vaf = vaf.clamp(MIN_VAF, 1.0 - MIN_VAF);
+        LogProb(self.alt_depth * vaf.ln() + (self.depth - self.alt_depth) * (1.0 - vaf).ln())
+    }
+}
+
+/// Expected VAF of an allele present in `copies` copies in the tumor cells and `normal_copies`
+/// copies in the (diploid) normal cells.
+fn expected_vaf(copies: f64, normal_copies: f64, purity: f64, ploidy: f64) -> f64 {
+    (purity * copies + (1.0 - purity) * normal_copies) / (purity * ploidy + 2.0 * (1.0 - purity))
+}
+
+/// Log-likelihood of a heterozygous germline site.
+fn ln_likelihood_germline(site: &Site, purity: f64, ploidy: f64) -> LogProb {
+    // METHOD: the tumor is assumed to have the given ploidy at the site, with the copies
+    // distributed between the two alleles in any way (including LOH), uniformly a priori.
+    // Balanced copy states yield VAF 0.5 independently of purity, while imbalanced states
+    // shift the VAF depending on the purity.
+    let total = ploidy.round().max(1.0);
+    let states = (0..=total as usize)
+        .map(|copies| site.ln_likelihood(expected_vaf(copies as f64, 1.0, purity, ploidy)))
+        .collect_vec();
+    LogProb::ln_sum_exp(&states) - LogProb((states.len() as f64).ln())
+}
+
+/// Log-likelihood of a somatic site.
+fn ln_likelihood_somatic(site: &Site, purity: f64, ploidy: f64, clonal_fraction: f64) -> LogProb {
+    // METHOD: clonal variants are present in all tumor cells, with a multiplicity between one
+    // and the ploidy (uniformly a priori). Subclonal variants are present on a single copy in a
+    // fraction of tumor cells that is uniformly distributed.
+    let max_multiplicity = ploidy.round().max(1.0) as usize;
+    let clonal = (1..=max_multiplicity)
+        .map(|m| site.ln_likelihood(expected_vaf(m as f64, 0.0, purity, ploidy)))
+        .collect_vec();
+    let subclonal = linspace(0.0, 1.0, SUBCLONAL_GRID_POINTS + 1)
+        .skip(1)
+        .map(|ccf| site.ln_likelihood(expected_vaf(ccf, 0.0, purity, ploidy)))
+        .collect_vec();
+    (LogProb(clonal_fraction.ln()) + LogProb::ln_sum_exp(&clonal)
+        - LogProb((clonal.len() as f64).ln()))
+    .ln_add_exp(
+        LogProb((1.0 - clonal_fraction).ln()) + LogProb::ln_sum_exp(&subclonal)
+            - LogProb((subclonal.len() as f64).ln()),
+    )
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ProfilePoint {
+    purity: f64,
+    ploidy: f64,
+    log_likelihood: f64,
+}
+
+/// Log-likelihood of all sites, maximized over the fraction of clonal somatic variants.
+fn ln_likelihood(germline: &[Site], somatic: &[Site], purity: f64, ploidy: f64) -> f64 {
+    let germline_ll: f64 = germline
+        .iter()
+        .map(|site| site.weight * *ln_likelihood_germline(site, purity, ploidy))
+        .sum();
+    let somatic_ll = CLONAL_FRACTIONS
+        .iter()
+        .map(|clonal_fraction| {
+            somatic
+                .iter()
+                .map(|site| {
+                    site.weight * *ln_likelihood_somatic(site, purity, ploidy, *clonal_fraction)
+                })
+                .sum::<f64>()
+        })
+        .fold(f64::NEG_INFINITY, f64::max);
+    germline_ll + if somatic.is_empty() { 0.0 } else { somatic_ll }
+}
+
+/// Output of the purity estimation, usable for `--purity` and as a sample definition in a
+/// scenario.
+#[derive(Debug, Serialize)]
+struct PurityEstimate {
+    purity: f64,
+    ploidy: f64,
+}
+
+/// Estimate tumor purity (and optionally ploidy) from Varlociraptor calls (read from STDIN).
+/// Calls have to be obtained without purity correction (e.g. `--purity 1.0` in tumor-normal
+/// mode), such that the allele frequencies reflect the observed ones.
+///
+/// # Arguments
+/// * `sample_name` - the tumor sample
+/// * `somatic_events` - events representing somatic variants (e.g. SOMATIC_TUMOR)
+/// * `germline_events` - events representing heterozygous germline variants (e.g. GERMLINE_HET)
+/// * `min_prob` - minimum posterior probability of the events for a variant to be considered
+/// * `ploidy` - tumor ploidy to assume, or None to estimate it jointly with the purity
+/// * `plot` - optional path to write a Vega-Lite plot of the likelihood profile to
+pub(crate) fn estimate<P: AsRef<Path>>(
+    sample_name: &str,
+    somatic_events: &[String],
+    germline_events: &[String],
+    min_prob: Prob,
+    ploidy: Option<f64>,
+    plot: Option<P>,
+) -> Result<()> {
+    let mut bcf = bcf::Reader::from_stdin()?;
+    let header = bcf.header().to_owned();
+    let sample_id = header.sample_id(sample_name.as_bytes()).ok_or_else(|| {
+        errors::Error::InvalidBCFSampleName {
+            name: sample_name.to_owned(),
+        }
+    })?;
+    let min_prob = LogProb::from(min_prob);
+
+    let event_prob = |rec: &bcf::Record, events: &[String]| -> Result<LogProb> {
+        let mut prob = LogProb::ln_zero();
+        for e in events {
+            let tag_name = SimpleEvent { name: e.to_owned() }.tag_name("PROB");
+            if let Some(probs) = rec.info(tag_name.as_bytes()).float()? {
+                prob = prob.ln_add_exp(LogProb::from(PHREDProb(probs[0] as f64)));
+            }
+        }
+        Ok(prob)
+    };
+
+    let mut germline = Vec::new();
+    let mut somatic = Vec::new();
+    for rec in bcf.records() {
+        let rec = rec?;
+        let af = rec.format(b"AF").float()?[sample_id][0] as f64;
+        let depth = rec.format(b"DP").integer()?[sample_id][0];
+        if af.is_nan() || depth <= 0 {
+            continue;
+        }
+        let site = |prob: LogProb| Site {
+            depth: depth as f64,
+            alt_depth: (af * depth as f64).round(),
+            weight: prob.exp(),
+        };
+
+        let prob_somatic = event_prob(&rec, somatic_events)?;
+        let prob_germline = event_prob(&rec, germline_events)?;
+        if prob_somatic >= min_prob {
+            somatic.push(site(prob_somatic));
+        } else if prob_germline >= min_prob {
+            germline.push(site(prob_germline));
+        }
+    }
+
+    if germline.is_empty() && somatic.is_empty() {
+        return Err(errors::Error::NoRecordsFound.into());
+    }
+    info!(
+        "Estimating purity from {} germline and {} somatic variants.",
+        germline.len(),
+        somatic.len()
+    );
+
+    let ploidies = match ploidy {
+        Some(ploidy) => vec![ploidy],
+        None => linspace(MIN_PLOIDY, MAX_PLOIDY, PLOIDY_GRID_POINTS).collect_vec(),
+    };
+
+    // METHOD: grid search over purity and ploidy, recording the profile likelihood of the
+    // purity (i.e., maximized over the ploidy).
+    let profile = linspace(MIN_PURITY, MAX_PURITY, PURITY_GRID_POINTS)
+        .map(|purity| {
+            ploidies
+                .iter()
+                .map(|ploidy| ProfilePoint {
+                    purity,
+                    ploidy: *ploidy,
+                    log_likelihood: ln_likelihood(&germline, &somatic, purity, *ploidy),
+                })
+                .max_by(|a, b| a.log_likelihood.partial_cmp(&b.log_likelihood).unwrap())
+                .unwrap()
+        })
+        .collect_vec();
+    let best = profile
+        .iter()
+        .max_by(|a, b| a.log_likelihood.partial_cmp(&b.log_likelihood).unwrap())
+        .unwrap();
+
+    if let Some(plot) = plot {
+        let mut blueprint: Value =
+            serde_json::from_str(include_str!("../../templates/plots/purity_profile.json"))?;
+        if let Value::Object(ref mut blueprint) = blueprint {
+            blueprint["data"]["values"] = json!(profile);
+            blueprint["title"] = json!(format!(
+                "purity: {:.2}, ploidy: {:.2}",
+                best.purity, best.ploidy
+            ));
+        }
+        serde_json::to_writer_pretty(File::create(plot)?, &blueprint)?;
+    }
+
+    println!(
+        "{}",
+        serde_yaml::to_string(&PurityEstimate {
+            purity: best.purity,
+            ploidy: best.ploidy,
+        })?
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sites(vaf: f64, n: usize) -> Vec<Site> {
+        vec![
+            Site {
+                depth: 100.0,
+                alt_depth: (vaf * 100.0).round(),
+                weight: 1.0,
+            };
+            n
+        ]
+    }
+
+    #[test]
+    fn test_expected_vaf() {
+        // clonal heterozygous somatic variant in a diploid tumor
+        assert_relative_eq!(expected_vaf(1.0, 0.0, 0.6, 2.0), 0.3);
+        // LOH of a germline variant
+        assert_relative_eq!(expected_vaf(2.0, 1.0, 0.6, 2.0), 0.8);
+    }
+
+    #[test]
+    fn test_purity_profile() {
+        // somatic variants at VAF 0.3 are ambiguous between purity 0.6 (single copy) and 0.3
+        // (two copies), germline variants with LOH resolve this.
+        let somatic = sites(0.3, 50);
+        let germline = sites(0.5, 50)
+            .into_iter()
+            .chain(sites(0.8, 50))
+            .collect_vec();
+        let best = linspace(MIN_PURITY, MAX_PURITY, PURITY_GRID_POINTS)
+            .map(|purity| (purity, ln_likelihood(&germline, &somatic, purity, 2.0)))
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .unwrap();
+        assert_relative_eq!(best.0, 0.6, epsilon = 0.02);
+    }
+}
@@ -8,96 +8,435 @@
 //! Johns Hopkin's University, Dept. of Biostatistics Working Papers. Working Paper 115.
 //! Basically, the expected FDR is calculated directly from the posterior error probabilities.
 
+use std::collections::HashMap;
 use std::path::Path;
 
 use anyhow::Result;
-use bio::stats::{bayesian, LogProb};
+use bio::stats::{bayesian, LogProb, PHREDProb};
 use itertools::Itertools;
+use ordered_float::NotNan;
 use rust_htslib::bcf;
+use rust_htslib::bcf::record::Numeric;
 use rust_htslib::bcf::Read;
 
+use crate::errors;
 use crate::utils;
 use crate::utils::is_phred_scaled;
 use crate::variants::model;
 use crate::Event;
 
-/// Print thresholds to control FDR of given calls at multiple levels.
+/// Filter calls by controlling FDR.
 ///
 /// # Arguments
 ///
 /// * `inbcf` - path to BCF with varlociraptor calls (None for stdin)
 /// * `outbcf` - path to BCF with filtered varlociraptor calls (None for stdout)
-/// * `null_calls` - calls under the null model, e.g. obtained by swapping tumor and normal sample
-/// * `writer` - writer for resulting thresholds
 /// * `events` - the set of events to control (sum of the probabilities of the individual events at a site)
 /// * `vartype` - the variant type to consider
 /// * `alpha` - the FDR threshold to control for
+/// * `local` - control local FDR instead of global FDR
+/// * `smart` - only consider alleles whose maximum a posteriori event is among the given events
 pub fn control_fdr<E: Event, R, W>(
-    inbcf: R,
+    inbcf: Option<R>,
     outbcf: Option<W>,
     events: &[E],
     vartype: Option<&model::VariantType>,
     alpha: LogProb,
     local: bool,
+    smart: bool,
 ) -> Result<()>
 where
     R: AsRef<Path>,
     W: AsRef<Path>,
 {
-    // first pass on bcf file
-    let mut inbcf_reader = bcf::Reader::from_path(&inbcf)?;
+    if let Some(inbcf) = inbcf {
+        let threshold = threshold(&[&inbcf], events, vartype, alpha, local, smart)?;
+        return filter(
+            bcf::Reader::from_path(inbcf)?,
+            outbcf,
+            threshold,
+            events,
+            vartype,
+            smart,
+        );
+    }
 
-    if !is_phred_scaled(&inbcf_reader) {
+    let mut inbcf_reader = bcf::Reader::from_stdin()?;
+    check_phred_scaled(&inbcf_reader);
+    if local || alpha == LogProb::ln_one() {
+        // The threshold does not depend on the other calls, hence stream through directly.
+        let threshold = fixed_threshold(alpha, local);
+        return filter(inbcf_reader, outbcf, threshold, events, vartype, smart);
+    }
+
+    // METHOD: stdin cannot be read twice. Hence, records are spooled into a temporary
+    // (compressed) BCF file while collecting the posterior probabilities. Only the
+    // probability distribution is kept in memory, and the spooled records are filtered
+    // in a second pass.
+    let spool = tempfile::Builder::new()
+        .prefix("varlociraptor-control-fdr")
+        .suffix(".bcf")
+        .tempfile()?;
+    let prob_dist = {
+        let header = bcf::Header::from_template(inbcf_reader.header());
+        let mut spool_writer =
+            bcf::Writer::from_path(spool.path(), &header, false, bcf::Format::BCF)?;
+        utils::collect_prob_dist(
+            &mut inbcf_reader,
+            events,
+            vartype,
+            smart,
+            Some(&mut spool_writer),
+        )?
+    };
+    let threshold = threshold_from_prob_dist(prob_dist, alpha);
+    filter(
+        bcf::Reader::from_path(spool.path())?,
+        outbcf,
+        threshold,
+        events,
+        vartype,
+        smart,
+    )
+}
+
+/// Filter the calls of a cohort by controlling FDR jointly, i.e., with a single threshold
+/// such that the union of the filtered calls of all inputs meets the given FDR.
+///
+/// # Arguments
+///
+/// * `inbcfs` - paths to BCFs with varlociraptor calls
+/// * `outbcfs` - paths to BCFs with filtered varlociraptor calls, one per input
+/// * `events` - the set of events to control (sum of the probabilities of the individual events at a site)
+/// * `vartype` - the variant type to consider
+/// * `alpha` - the FDR threshold to control for
+/// * `local` - control local FDR instead of global FDR
+/// * `smart` - only consider alleles whose maximum a posteriori event is among the given events
+pub fn control_fdr_joint<E: Event, R, W>(
+    inbcfs: &[R],
+    outbcfs: &[W],
+    events: &[E],
+    vartype: Option<&model::VariantType>,
+    alpha: LogProb,
+    local: bool,
+    smart: bool,
+) -> Result<()>
+where
+    R: AsRef<Path>,
+    W: AsRef<Path>,
+{
+    if inbcfs.len() != outbcfs.len() {
+        return Err(errors::Error::InvalidFDROutputs {
+            n_inputs: inbcfs.len(),
+            n_outputs: outbcfs.len(),
+        }
+        .into());
+    }
+    let threshold = threshold(inbcfs, events, vartype, alpha, local, smart)?;
+    for (inbcf, outbcf) in inbcfs.iter().zip(outbcfs) {
+        filter(
+            bcf::Reader::from_path(inbcf)?,
+            Some(outbcf),
+            threshold,
+            events,
+            vartype,
+            smart,
+        )?;
+    }
+    Ok(())
+}
+
+fn check_phred_scaled(inbcf_reader: &bcf::Reader) {
+    if !is_phred_scaled(inbcf_reader) {
         panic!("Event probabilities are not PHRED scaled, aborting.")
     }
+}
+
+/// Threshold that does not depend on the distribution of posterior probabilities, i.e., in
+/// case of local FDR control or if alpha is 1.0 (then, we do not filter by FDR at all).
+fn fixed_threshold(alpha: LogProb, local: bool) -> Option<LogProb> {
+    if local {
+        Some(alpha.ln_one_minus_exp())
+    } else {
+        None
+    }
+}
+
+/// Determine the threshold on the posterior probability of the given events that controls
+/// FDR over all given BCF files.
+fn threshold<E: Event, R: AsRef<Path>>(
+    inbcfs: &[R],
+    events: &[E],
+    vartype: Option<&model::VariantType>,
+    alpha: LogProb,
+    local: bool,
+    smart: bool,
+) -> Result<Option<LogProb>> {
+    let mut prob_dist = Vec::new();
+    for inbcf in inbcfs {
+        let mut inbcf_reader = bcf::Reader::from_path(inbcf)?;
+        check_phred_scaled(&inbcf_reader);
+
+        if !local && alpha != LogProb::ln_one() {
+            // METHOD: without smart mode, calls where another event has a higher probability can
+            // end up in the filtered results if there are just enough calls (e.g.
+            // PROB_SOMATIC=8, PROB_ABSENT=2). In smart mode, only alleles whose MAP event is among
+            // the given events are considered.
+            // With multiple inputs, posterior error probabilities are pooled, such that the
+            // threshold controls the FDR of the union of all filtered calls.
+            prob_dist.extend(utils::collect_prob_dist(
+                &mut inbcf_reader,
+                events,
+                vartype,
+                smart,
+                None,
+            )?);
+        }
+    }
+
+    if local || alpha == LogProb::ln_one() {
+        return Ok(fixed_threshold(alpha, local));
+    }
+
+    Ok(threshold_from_prob_dist(prob_dist, alpha))
+}
+
+/// Determine the threshold on the posterior probability that controls FDR for the given
+/// distribution of posterior probabilities.
+fn threshold_from_prob_dist(mut prob_dist: Vec<NotNan<f64>>, alpha: LogProb) -> Option<LogProb> {
+    prob_dist.sort();
+    let prob_dist = prob_dist
+        .into_iter()
+        .rev()
+        .map(|p| LogProb(*p))
+        .collect_vec();
+
+    // estimate FDR
+    let pep_dist = prob_dist.iter().map(|p| p.ln_one_minus_exp()).collect_vec();
+    let fdrs = bayesian::expected_fdr(&pep_dist);
+
+    if fdrs.is_empty() {
+        None
+    } else if fdrs[0] > alpha {
+        Some(LogProb::ln_one())
+    } else {
+        // find the largest pep for which fdr <= alpha
+        // do not let peps with the same value cross the boundary
+        for i in (0..fdrs.len()).rev() {
+            if fdrs[i] <= alpha && (i == 0 || pep_dist[i] != pep_dist[i - 1]) {
+                return Some(prob_dist[i]);
+            }
+        }
+        None
+    }
+}
 
+/// Filter the calls of the given BCF reader by the given threshold.
+fn filter<E: Event, W: AsRef<Path>>(
+    mut inbcf_reader: bcf::Reader,
+    outbcf: Option<W>,
+    threshold: Option<LogProb>,
+    events: &[E],
+    vartype: Option<&model::VariantType>,
+    smart: bool,
+) -> Result<()> {
     // setup output file
-    let header = bcf::Header::from_template(inbcf_reader.header());
+    let mut header = bcf::Header::from_template(inbcf_reader.header());
+    if smart {
+        utils::add_map_event_header_records(&mut header);
+    }
     let mut outbcf = match outbcf {
         Some(p) => bcf::Writer::from_path(p, &header, false, bcf::Format::BCF)?,
         None => bcf::Writer::from_stdout(&header, false, bcf::Format::BCF)?,
     };
 
-    let mut threshold = None;
+    utils::filter_by_threshold(
+        &mut inbcf_reader,
+        threshold,
+        &mut outbcf,
+        events,
+        vartype,
+        smart,
+    )
+}
 
-    if local {
-        threshold = Some(alpha.ln_one_minus_exp());
-    } else if alpha != LogProb::ln_one() {
-        // do not filter by FDR if alpha is 1.0
-        // TODO: remove hits where another event has a higher probability
-        // Otherwise, if there are just enough calls, events like PROB_SOMATIC=8, PROB_ABSENT=2
-        // can end up in the filtered results.
-        let prob_dist = utils::collect_prob_dist(&mut inbcf_reader, events, vartype)?
-            .into_iter()
-            .rev()
-            .map(|p| LogProb(*p))
-            .collect_vec();
+/// Calculate q-values (the minimum expected FDR at which a call would be accepted) for the
+/// given posterior error probabilities, which have to be sorted in ascending order.
+fn qvalues(peps: &[LogProb]) -> Vec<LogProb> {
+    let mut qvalues = bayesian::expected_fdr(peps);
+    // METHOD: the q-value is the minimum expected FDR over all thresholds that would accept
+    // the call.
+    for i in (0..qvalues.len().saturating_sub(1)).rev() {
+        if qvalues[i + 1] < qvalues[i] {
+            qvalues[i] = qvalues[i + 1];
+        }
+    }
+    qvalues
+}
 
-        // estimate FDR
-        let pep_dist = prob_dist.iter().map(|p| p.ln_one_minus_exp()).collect_vec();
-        let fdrs = bayesian::expected_fdr(&pep_dist);
+/// Annotate calls with q-values and posterior error probabilities of the given set of events,
+/// without removing any calls. Annotations are written as INFO tags `Q_<EVENTS>` and
+/// `PEP_<EVENTS>` (PHRED scaled), with the upper case event names joined by underscores.
+///
+/// # Arguments
+///
+/// * `inbcf` - path to BCF with varlociraptor calls
+/// * `outbcf` - path to BCF with annotated varlociraptor calls (None for stdout)
+/// * `events` - the set of events to consider (sum of the probabilities of the individual events at a site)
+/// * `vartype` - the variant type to consider (alleles of other types are not annotated)
+/// * `stratify` - calculate q-values separately for each variant type
+pub fn annotate_fdr<E: Event, R, W>(
+    inbcf: R,
+    outbcf: Option<W>,
+    events: &[E],
+    vartype: Option<&model::VariantType>,
+    stratify: bool,
+) -> Result<()>
+where
+    R: AsRef<Path>,
+    W: AsRef<Path>,
+{
+    let mut inbcf_reader = bcf::Reader::from_path(&inbcf)?;
+    check_phred_scaled(&inbcf_reader);
+
+    let name = events
+        .iter()
+        .map(|e| e.name().to_ascii_uppercase())
+        .join("_");
+    let qvalue_tag = format!("Q_{}", name);
+    let pep_tag = format!("PEP_{}", name);
+
+    let mut header = bcf::Header::from_template(inbcf_reader.header());
+    header.push_record(
+        format!(
+            "##INFO=<ID={},Number=A,Type=Float,Description=\"Q-value (minimum expected FDR \
+             at which the call is accepted) for events {} (PHRED)\">",
+            qvalue_tag,
+            events.iter().map(|e| e.name()).join(",")
+        )
+        .as_bytes(),
+    );
+    header.push_record(
+        format!(
+            "##INFO=<ID={},Number=A,Type=Float,Description=\"Posterior error probability \
+             (local FDR) for events {} (PHRED)\">",
+            pep_tag,
+            events.iter().map(|e| e.name()).join(",")
+        )
+        .as_bytes(),
+    );
+    let mut outbcf = match outbcf {
+        Some(p) => bcf::Writer::from_path(p, &header, false, bcf::Format::BCF)?,
+        None => bcf::Writer::from_stdout(&header, false, bcf::Format::BCF)?,
+    };
 
-        if fdrs.is_empty() {
-            threshold = None;
-        } else if fdrs[0] > alpha {
-            threshold = Some(LogProb::ln_one());
+    let tags = utils::events_to_tags(events);
+    let stratum = |variant: &model::Variant| -> &'static str {
+        if stratify {
+            variant.to_type().into()
         } else {
-            // find the largest pep for which fdr <= alpha
-            // do not let peps with the same value cross the boundary
-            for i in (0..fdrs.len()).rev() {
-                if fdrs[i] <= alpha && (i == 0 || pep_dist[i] != pep_dist[i - 1]) {
-                    let prob = prob_dist[i];
-
-                    threshold = Some(prob);
-                    break;
+            "all"
+        }
+    };
+
+    // first pass: collect posterior error probabilities of all alleles
+    // (stratum and index into the per stratum distribution)
+    let mut allele_peps: Vec<Vec<Option<(&'static str, usize)>>> = Vec::new();
+    let mut pep_dists: HashMap<&'static str, Vec<LogProb>> = HashMap::new();
+    let mut breakend_events = HashMap::new();
+    let mut record = inbcf_reader.empty_record();
+    loop {
+        match inbcf_reader.read(&mut record) {
+            None => break,
+            Some(res) => res?,
+        }
+        let variants = utils::collect_variants(&mut record, false, None)?;
+        let probs = utils::tags_prob_sum(&mut record, &tags, vartype)?;
+        let bnd_event = utils::info_tag_event(&mut record).ok().flatten();
+
+        let mut record_peps = vec![None; record.allele_count() as usize - 1];
+        for ((entry, variant), prob) in record_peps.iter_mut().zip(&variants).zip(probs) {
+            if let Some(prob) = prob {
+                if let Some(entry_idx) = bnd_event
+                    .as_ref()
+                    .and_then(|event| breakend_events.get(event))
+                {
+                    // Do not record the probability of a breakend event twice.
+                    *entry = Some(*entry_idx);
+                    continue;
+                }
+                let stratum = stratum(variant);
+                let dist = pep_dists.entry(stratum).or_default();
+                dist.push(prob.ln_one_minus_exp());
+                *entry = Some((stratum, dist.len() - 1));
+                if let Some(event) = bnd_event.as_ref() {
+                    breakend_events.insert(event.to_owned(), (stratum, dist.len() - 1));
                 }
             }
         }
+        allele_peps.push(record_peps);
     }
 
-    // second pass on bcf file
+    // calculate q-values per stratum
+    let qvalue_dists: HashMap<_, _> = pep_dists
+        .iter()
+        .map(|(stratum, peps)| {
+            let order = (0..peps.len())
+                .sorted_by(|a, b| peps[*a].partial_cmp(&peps[*b]).unwrap())
+                .collect_vec();
+            let sorted_qvalues = qvalues(&order.iter().map(|i| peps[*i]).collect_vec());
+            let mut qvalues = vec![LogProb::ln_zero(); peps.len()];
+            for (i, q) in order.into_iter().zip(sorted_qvalues) {
+                qvalues[i] = q;
+            }
+            (*stratum, qvalues)
+        })
+        .collect();
+
+    // second pass: annotate
     let mut inbcf_reader = bcf::Reader::from_path(&inbcf)?;
-    utils::filter_by_threshold(&mut inbcf_reader, threshold, &mut outbcf, events, vartype)?;
+    let mut record = inbcf_reader.empty_record();
+    for record_peps in allele_peps {
+        match inbcf_reader.read(&mut record) {
+            None => break,
+            Some(res) => res?,
+        }
+        outbcf.translate(&mut record);
+        let phred = |dists: &HashMap<&'static str, Vec<LogProb>>| {
+            record_peps
+                .iter()
+                .map(|entry| match entry {
+                    Some((stratum, i)) => *PHREDProb::from(dists[stratum][*i]) as f32,
+                    None => f32::missing(),
+                })
+                .collect_vec()
+        };
+        if record_peps.iter().any(|entry| entry.is_some()) {
+            record.push_info_float(qvalue_tag.as_bytes(), &phred(&qvalue_dists))?;
+            record.push_info_float(pep_tag.as_bytes(), &phred(&pep_dists))?;
+        }
+        outbcf.write(&record)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qvalues() {
+        let peps = [0.01, 0.02, 0.5, 0.5, 0.9]
+            .iter()
+            .map(|p| LogProb(f64::ln(*p)))
+            .collect_vec();
+        let qvalues = qvalues(&peps);
+        // expected FDR of the first call
+        assert_relative_eq!(qvalues[0].exp(), 0.01, epsilon = 1e-9);
+        assert_relative_eq!(qvalues[1].exp(), 0.015, epsilon = 1e-9);
+        // q-values are monotonically increasing
+        assert!(qvalues.windows(2).all(|w| w[0] <= w[1]));
+        assert_relative_eq!(qvalues[4].exp(), 1.93 / 5.0, epsilon = 1e-9);
+    }
+}
@@ -5,3 +5,4 @@
 
 pub mod fdr;
 pub mod posterior_odds;
+pub(crate) mod sample_filter;
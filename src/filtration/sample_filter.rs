@@ -0,0 +1,461 @@
+//! Filtering of calls by expressions over the sample specific FORMAT values written by
+//! varlociraptor, e.g. `tumor.AF >= 0.05 && normal.DP >= 10`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::str;
+use std::str::FromStr;
+
+use anyhow::Result;
+use itertools::Itertools;
+use pest::iterators::Pair;
+use pest::Parser;
+use rust_htslib::bcf;
+use rust_htslib::bcf::record::Numeric;
+use rust_htslib::bcf::Read;
+
+use crate::errors;
+use crate::utils;
+use crate::variants::model::bias::Biases;
+
+#[derive(Parser)]
+#[grammar = "filtration/sample_filter.pest"]
+struct SampleFilterParser;
+
+/// Sample specific value a filter expression can refer to.
+#[derive(Debug, Clone, PartialEq)]
+enum Field {
+    /// FORMAT tag with a float value per allele (AF, AFLO, AFHI).
+    Float(String),
+    /// Expected sequencing depth (DP).
+    Depth,
+    /// Number of observations with at least barely positive evidence for the alt allele,
+    /// parsed from SOBS (or OBS).
+    AltObs,
+    /// Number of observations with strong or very strong evidence for the alt allele, parsed
+    /// from SOBS (or OBS).
+    StrongAltObs,
+    /// Bias FORMAT tag (e.g. SB, ROB).
+    Bias(String),
+}
+
+impl Field {
+    fn is_numeric(&self) -> bool {
+        !matches!(self, Field::Bias(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    fn compare<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            Operator::Less => lhs < rhs,
+            Operator::LessEqual => lhs <= rhs,
+            Operator::Greater => lhs > rhs,
+            Operator::GreaterEqual => lhs >= rhs,
+            Operator::Equal => lhs == rhs,
+            Operator::NotEqual => lhs != rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Comparison {
+    sample: String,
+    field: Field,
+    operator: Operator,
+    value: Value,
+}
+
+/// Boolean expression over sample specific FORMAT values, as used by
+/// `varlociraptor filter-calls sample-filter`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum SampleFilter {
+    Conjunction { operands: Vec<SampleFilter> },
+    Disjunction { operands: Vec<SampleFilter> },
+    Negation { operand: Box<SampleFilter> },
+    Comparison(Comparison),
+}
+
+impl FromStr for SampleFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let mut pairs = SampleFilterParser::parse(Rule::filter, expression).map_err(|e| {
+            errors::Error::InvalidSampleFilter {
+                msg: format!("\n{}", e),
+            }
+        })?;
+        parse_filter(pairs.next().expect("bug: expecting filter expression"))
+    }
+}
+
+fn parse_filter(pair: Pair<Rule>) -> Result<SampleFilter> {
+    Ok(match pair.as_rule() {
+        Rule::disjunction | Rule::conjunction => {
+            let rule = pair.as_rule();
+            let mut operands: Vec<_> = pair.into_inner().map(parse_filter).try_collect()?;
+            if operands.len() == 1 {
+                operands.pop().unwrap()
+            } else if rule == Rule::disjunction {
+                SampleFilter::Disjunction { operands }
+            } else {
+                SampleFilter::Conjunction { operands }
+            }
+        }
+        Rule::negation => SampleFilter::Negation {
+            operand: Box::new(parse_filter(pair.into_inner().next().unwrap())?),
+        },
+        Rule::comparison => {
+            let mut inner = pair.into_inner();
+            let mut field = inner.next().unwrap().into_inner();
+            let sample = field.next().unwrap().as_str().to_owned();
+            let attribute = field.next().unwrap().as_str();
+            let operator = match inner.next().unwrap().as_str() {
+                "<" => Operator::Less,
+                "<=" => Operator::LessEqual,
+                ">" => Operator::Greater,
+                ">=" => Operator::GreaterEqual,
+                "==" => Operator::Equal,
+                "!=" => Operator::NotEqual,
+                _ => unreachable!(),
+            };
+            let value = inner.next().unwrap();
+            let value = match value.as_rule() {
+                Rule::number => Value::Number(value.as_str().parse()?),
+                Rule::string => Value::String(value.into_inner().as_str().to_owned()),
+                _ => unreachable!(),
+            };
+
+            let field = match attribute {
+                "AF" | "AFLO" | "AFHI" => Field::Float(attribute.to_owned()),
+                "DP" => Field::Depth,
+                "ALT" => Field::AltObs,
+                "STRONG_ALT" => Field::StrongAltObs,
+                tag if Biases::format_tags().contains(&tag) => Field::Bias(tag.to_owned()),
+                _ => {
+                    return Err(errors::Error::InvalidSampleFilter {
+                        msg: format!(
+                        "unknown field {}, must be one of AF, AFLO, AFHI, DP, ALT, STRONG_ALT, {}",
+                        attribute,
+                        Biases::format_tags().join(", ")
+                    ),
+                    }
+                    .into())
+                }
+            };
+            match (&value, field.is_numeric()) {
+                (Value::Number(_), true) => (),
+                (Value::String(_), false)
+                    if operator == Operator::Equal || operator == Operator::NotEqual => {}
+                _ => {
+                    return Err(errors::Error::InvalidSampleFilter {
+                        msg: format!(
+                            "field {} can only be compared {}",
+                            attribute,
+                            if field.is_numeric() {
+                                "with numbers"
+                            } else {
+                                "with quoted strings via == or !="
+                            }
+                        ),
+                    }
+                    .into())
+                }
+            }
+
+            SampleFilter::Comparison(Comparison {
+                sample,
+                field,
+                operator,
+                value,
+            })
+        }
+        _ => unreachable!(),
+    })
+}
+
+/// Count observations in an OBS or SOBS entry whose evidence for the alt allele is among the
+/// given (upper case) Kass Raftery letters. Lower case letters (uncertain mapping) are counted
+/// as well.
+fn count_obs(entry: &[u8], scores: &[u8]) -> u32 {
+    let mut total = 0;
+    let mut count = 0;
+    let mut expect_score = false;
+    for c in entry {
+        if c.is_ascii_digit() {
+            count = count * 10 + (c - b'0') as u32;
+            expect_score = true;
+        } else if expect_score {
+            // the first symbol after the count is the score, the rest is ignored
+            if scores.contains(&c.to_ascii_uppercase()) {
+                total += count;
+            }
+            count = 0;
+            expect_score = false;
+        }
+    }
+    total
+}
+
+impl SampleFilter {
+    fn samples(&self) -> Vec<&str> {
+        match self {
+            SampleFilter::Conjunction { operands } | SampleFilter::Disjunction { operands } => {
+                operands.iter().flat_map(|op| op.samples()).collect()
+            }
+            SampleFilter::Negation { operand } => operand.samples(),
+            SampleFilter::Comparison(comparison) => vec![&comparison.sample],
+        }
+    }
+
+    /// Evaluate the expression for the given alt allele of the given record. Comparisons
+    /// on missing values are false.
+    fn eval(
+        &self,
+        record: &bcf::Record,
+        sample_idx: &HashMap<String, usize>,
+        allele: usize,
+    ) -> Result<bool> {
+        Ok(match self {
+            SampleFilter::Conjunction { operands } => {
+                for op in operands {
+                    if !op.eval(record, sample_idx, allele)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            SampleFilter::Disjunction { operands } => {
+                for op in operands {
+                    if op.eval(record, sample_idx, allele)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            SampleFilter::Negation { operand } => !operand.eval(record, sample_idx, allele)?,
+            SampleFilter::Comparison(comparison) => {
+                let sample = sample_idx[&comparison.sample];
+                let tag = |name: &str| errors::Error::MissingBCFTag {
+                    name: name.to_owned(),
+                };
+                let obs_entry = || -> Result<Option<Vec<u8>>> {
+                    let values = record
+                        .format(b"SOBS")
+                        .string()
+                        .or_else(|_| record.format(b"OBS").string())
+                        .map_err(|_| tag("SOBS"))?;
+                    Ok(allele_entry(values[sample], allele, true))
+                };
+                let numeric = match &comparison.field {
+                    Field::Float(name) => {
+                        let values = record
+                            .format(name.as_bytes())
+                            .float()
+                            .map_err(|_| tag(name))?;
+                        values[sample]
+                            .get(allele)
+                            .filter(|value| !value.is_missing() && !value.is_nan())
+                            .map(|value| *value as f64)
+                    }
+                    Field::Depth => {
+                        let values = record.format(b"DP").integer().map_err(|_| tag("DP"))?;
+                        values[sample]
+                            .first()
+                            .filter(|value| !value.is_missing())
+                            .map(|value| *value as f64)
+                    }
+                    Field::AltObs => obs_entry()?.map(|entry| count_obs(&entry, b"BPSV") as f64),
+                    Field::StrongAltObs => {
+                        obs_entry()?.map(|entry| count_obs(&entry, b"SV") as f64)
+                    }
+                    Field::Bias(name) => {
+                        let values = record
+                            .format(name.as_bytes())
+                            .string()
+                            .map_err(|_| tag(name))?;
+                        return Ok(
+                            match (
+                                allele_entry(values[sample], allele, false),
+                                &comparison.value,
+                            ) {
+                                (Some(entry), Value::String(value)) => comparison
+                                    .operator
+                                    .compare(entry.as_slice(), value.as_bytes()),
+                                _ => false,
+                            },
+                        );
+                    }
+                };
+                match (numeric, &comparison.value) {
+                    (Some(lhs), Value::Number(rhs)) => comparison.operator.compare(lhs, *rhs),
+                    _ => false,
+                }
+            }
+        })
+    }
+}
+
+/// Entry of the given alt allele in a comma separated FORMAT string value, None if missing.
+/// If `dot_is_missing` is false, `.` is treated as a regular value (e.g. no bias).
+fn allele_entry(value: &[u8], allele: usize, dot_is_missing: bool) -> Option<Vec<u8>> {
+    value
+        .split(|c| *c == b',')
+        .nth(allele)
+        .filter(|entry| !entry.is_empty() && (!dot_is_missing || *entry != b"."))
+        .map(|entry| entry.to_owned())
+}
+
+/// Filter calls by the given expression over sample specific FORMAT values. Alleles for which
+/// the expression does not hold are removed.
+///
+/// # Arguments
+///
+/// * `inbcf` - path to BCF with varlociraptor calls (None for stdin)
+/// * `outbcf` - path to BCF with filtered varlociraptor calls (None for stdout)
+/// * `filter` - the filter expression
+pub(crate) fn filter_by_sample_expression<R, W>(
+    inbcf: Option<R>,
+    outbcf: Option<W>,
+    filter: &SampleFilter,
+) -> Result<()>
+where
+    R: AsRef<Path>,
+    W: AsRef<Path>,
+{
+    let mut inbcf_reader = match inbcf {
+        Some(p) => bcf::Reader::from_path(p)?,
+        None => bcf::Reader::from_stdin()?,
+    };
+
+    let sample_idx: HashMap<String, usize> = inbcf_reader
+        .header()
+        .samples()
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| Ok((str::from_utf8(sample)?.to_owned(), i)))
+        .collect::<Result<_>>()?;
+    if let Some(sample) = filter
+        .samples()
+        .into_iter()
+        .find(|sample| !sample_idx.contains_key(*sample))
+    {
+        return Err(errors::Error::InvalidBCFSampleName {
+            name: sample.to_owned(),
+        }
+        .into());
+    }
+
+    // setup output file
+    let header = bcf::Header::from_template(inbcf_reader.header());
+    let mut outbcf = match outbcf {
+        Some(p) => bcf::Writer::from_path(p, &header, false, bcf::Format::BCF)?,
+        None => bcf::Writer::from_stdout(&header, false, bcf::Format::BCF)?,
+    };
+
+    utils::filter_calls(
+        &mut inbcf_reader,
+        &mut outbcf,
+        |record: &mut bcf::Record| {
+            (0..record.allele_count() as usize - 1)
+                .map(|allele| filter.eval(record, &sample_idx, allele))
+                .collect::<Result<Vec<_>>>()
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(writer: &bcf::Writer) -> bcf::Record {
+        let mut record = writer.empty_record();
+        record.set_alleles(&[b"A", b"T"]).unwrap();
+        record.push_format_float(b"AF", &[0.1, 0.0]).unwrap();
+        record
+            .push_format_integer(b"DP", &[30, i32::missing()])
+            .unwrap();
+        record
+            .push_format_string(b"SOBS", &[&b"2V1s10N"[..], b"."])
+            .unwrap();
+        record
+            .push_format_string(b"SB", &[&b"+"[..], b"."])
+            .unwrap();
+        record
+    }
+
+    fn writer() -> (tempfile::NamedTempFile, bcf::Writer) {
+        let mut header = bcf::Header::new();
+        header.push_record(b"##contig=<ID=1,length=1000>");
+        header.push_record(b"##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"\">");
+        header.push_record(b"##FORMAT=<ID=AF,Number=A,Type=Float,Description=\"\">");
+        header.push_record(b"##FORMAT=<ID=SOBS,Number=A,Type=String,Description=\"\">");
+        header.push_record(b"##FORMAT=<ID=SB,Number=A,Type=String,Description=\"\">");
+        header.push_sample(b"tumor");
+        header.push_sample(b"normal");
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        let writer = bcf::Writer::from_path(tmp.path(), &header, true, bcf::Format::BCF).unwrap();
+        (tmp, writer)
+    }
+
+    fn eval(expression: &str) -> bool {
+        let (_tmp, writer) = writer();
+        let record = record(&writer);
+        let sample_idx = vec![("tumor".to_owned(), 0), ("normal".to_owned(), 1)]
+            .into_iter()
+            .collect();
+        expression
+            .parse::<SampleFilter>()
+            .unwrap()
+            .eval(&record, &sample_idx, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(
+            "tumor.AF >= 0.05 && (normal.DP >= 10 || !tumor.SB == \".\")"
+                .parse::<SampleFilter>()
+                .is_ok()
+        );
+        assert!("tumor.FOO >= 0.05".parse::<SampleFilter>().is_err());
+        assert!("tumor.SB >= 1".parse::<SampleFilter>().is_err());
+        assert!("tumor.AF == \"x\"".parse::<SampleFilter>().is_err());
+        assert!("tumor.AF >=".parse::<SampleFilter>().is_err());
+    }
+
+    #[test]
+    fn test_count_obs() {
+        assert_eq!(count_obs(b"2V1s10N", b"SV"), 3);
+        assert_eq!(count_obs(b"12Sp+>*.3Bs-<*.", b"BPSV"), 15);
+        assert_eq!(count_obs(b"12Sp+>*.3Bs-<*.", b"SV"), 12);
+    }
+
+    #[test]
+    fn test_eval() {
+        assert!(eval("tumor.AF >= 0.05 && tumor.DP >= 10"));
+        assert!(!eval("tumor.AF >= 0.05 && normal.DP >= 10"));
+        assert!(eval("tumor.AF > 0.05 || normal.DP >= 10"));
+        assert!(eval("tumor.STRONG_ALT == 3 && tumor.ALT == 3"));
+        assert!(!eval("normal.STRONG_ALT == 0"));
+        assert!(eval("tumor.SB != \".\" && !(tumor.SB == \".\")"));
+        assert!(eval("normal.SB == \".\""));
+        assert!(eval("normal.AF <= 0.0"));
+    }
+}
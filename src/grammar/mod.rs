@@ -3,11 +3,12 @@ use std::convert::TryFrom;
 use std::fs::File;
 use std::io::Read;
 use std::ops::{Deref, DerefMut};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 use std::sync::Mutex;
 
 use anyhow::{Context, Result};
+use bio::alphabets::dna;
 use vec_map::VecMap;
 
 pub(crate) mod formula;
@@ -358,6 +359,149 @@ pub(crate) struct Species {
     #[serde(default)]
     #[serde(rename = "genome-size")]
     genome_size: Option<f64>,
+    /// Indexed VCF/BCF with population allele frequencies (AF INFO field), e.g. from gnomAD.
+    #[serde(default, rename = "population-allele-frequencies")]
+    population_allele_frequencies: Option<PathBuf>,
+    /// Indexed VCF/BCF or BED with weights of recurrent somatic mutation hotspots.
+    #[serde(default, rename = "somatic-hotspots")]
+    somatic_hotspots: Option<SomaticHotspots>,
+    /// Relative SNV mutation rates per trinucleotide context.
+    #[serde(default, rename = "context-mutation-rates")]
+    context_mutation_rates: Option<ContextMutationRates>,
+}
+
+fn default_hotspot_info_field() -> String {
+    "WEIGHT".to_owned()
+}
+
+/// Somatic mutation hotspots, given as an indexed VCF/BCF (weights taken from the given INFO
+/// field) or a tabix indexed BED file (weights taken from the fourth column).
+#[derive(Deserialize, Getters, Clone, Debug)]
+#[get = "pub(crate)"]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SomaticHotspots {
+    path: PathBuf,
+    #[serde(default = "default_hotspot_info_field", rename = "info-field")]
+    info_field: String,
+}
+
+impl SomaticHotspots {
+    /// Whether hotspots are given as BED file (otherwise as VCF/BCF).
+    pub(crate) fn is_bed(&self) -> bool {
+        let path = self.path.to_string_lossy();
+        path.ends_with(".bed") || path.ends_with(".bed.gz")
+    }
+}
+
+/// Relative SNV mutation rates, given per trinucleotide context (e.g. ACG), optionally
+/// restricted to a particular alternative base (e.g. ACG>T). Contexts are strand symmetric,
+/// i.e., ACG>T also applies to CGT>A. Contexts that are not listed have a relative rate of 1.
+/// Upon loading, rates are normalized to a mean of 1 over all 96 SNV channels (see
+/// `ContextMutationRates::snv_channels`), such that they redistribute the somatic mutation
+/// rate over the contexts without changing it on average. This assumes that all trinucleotide
+/// contexts occur equally often in the genome.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "HashMap<String, f64>")]
+pub(crate) struct ContextMutationRates {
+    inner: HashMap<Vec<u8>, f64>,
+    /// Mean of the given rates over all SNV channels.
+    mean: f64,
+}
+
+impl ContextMutationRates {
+    /// Normalize the given context and alternative base to the strand with a pyrimidine
+    /// (C or T) as reference base.
+    fn normalize(context: &[u8], alt_base: Option<u8>) -> (Vec<u8>, Option<u8>) {
+        let context = context.to_ascii_uppercase();
+        if context[1] == b'A' || context[1] == b'G' {
+            (
+                dna::revcomp(&context),
+                alt_base.map(|base| dna::complement(base.to_ascii_uppercase())),
+            )
+        } else {
+            (context, alt_base.map(|base| base.to_ascii_uppercase()))
+        }
+    }
+
+    fn key(context: &[u8], alt_base: Option<u8>) -> Vec<u8> {
+        let (mut key, alt_base) = Self::normalize(context, alt_base);
+        if let Some(alt_base) = alt_base {
+            key.push(b'>');
+            key.push(alt_base);
+        }
+        key
+    }
+
+    /// All 96 SNV channels, i.e., trinucleotide contexts with a pyrimidine at their center,
+    /// combined with each alternative base.
+    pub(crate) fn snv_channels() -> impl Iterator<Item = ([u8; 3], u8)> {
+        b"ACGT".iter().flat_map(|left| {
+            b"CT".iter().flat_map(move |center| {
+                b"ACGT".iter().flat_map(move |right| {
+                    b"ACGT"
+                        .iter()
+                        .filter(move |alt_base| *alt_base != center)
+                        .map(move |alt_base| ([*left, *center, *right], *alt_base))
+                })
+            })
+        })
+    }
+
+    /// Given (unnormalized) rate of the given context and alternative base.
+    fn given_rate(&self, context: &[u8], alt_base: u8) -> f64 {
+        self.inner
+            .get(&Self::key(context, Some(alt_base)))
+            .or_else(|| self.inner.get(&Self::key(context, None)))
+            .cloned()
+            .unwrap_or(1.0)
+    }
+
+    /// Relative mutation rate of the given trinucleotide context with the given alternative
+    /// base at its center. Rates given for the specific alternative base take precedence over
+    /// rates given for the context alone.
+    pub(crate) fn rate(&self, context: &[u8], alt_base: u8) -> f64 {
+        assert_eq!(context.len(), 3, "bug: context must be a trinucleotide");
+        self.given_rate(context, alt_base) / self.mean
+    }
+}
+
+impl TryFrom<HashMap<String, f64>> for ContextMutationRates {
+    type Error = errors::Error;
+
+    fn try_from(rates: HashMap<String, f64>) -> Result<Self, Self::Error> {
+        let is_base = |base: &u8| b"ACGT".contains(&base.to_ascii_uppercase());
+        let mut inner = HashMap::new();
+        for (context, rate) in rates {
+            let spec = context.as_bytes();
+            let alt_base = match spec.len() {
+                3 => None,
+                5 if spec[3] == b'>' && is_base(&spec[4]) => Some(spec[4]),
+                _ => {
+                    return Err(errors::Error::InvalidContextMutationRate {
+                        context,
+                        value: rate,
+                    })
+                }
+            };
+            if !spec[..3].iter().all(is_base)
+                || matches!(alt_base, Some(base) if base.eq_ignore_ascii_case(&spec[1]))
+                || rate <= 0.0
+                || !rate.is_finite()
+            {
+                return Err(errors::Error::InvalidContextMutationRate {
+                    context,
+                    value: rate,
+                });
+            }
+            inner.insert(Self::key(&spec[..3], alt_base), rate);
+        }
+        let mut rates = ContextMutationRates { inner, mean: 1.0 };
+        let (sum, n) = Self::snv_channels().fold((0.0, 0), |(sum, n), (context, alt_base)| {
+            (sum + rates.given_rate(&context, alt_base), n + 1)
+        });
+        rates.mean = sum / n as f64;
+        Ok(rates)
+    }
 }
 
 impl Species {
@@ -387,38 +531,58 @@ fn default_sv_fraction() -> f64 {
 /// * mnvs: 0.001 (see https://www.nature.com/articles/s41467-019-12438-5)
 /// * indels: 0.0125 (see https://gatk.broadinstitute.org/hc/en-us/articles/360036826431-HaplotypeCaller, reduction in heterozygosity)
 /// * svs: 0.001 (predicted several hundred times less frequent that SNVs: https://doi.org/10.1038/s41588-018-0107-y)
-#[derive(Deserialize, Getters, Clone, Debug)]
-#[get = "pub(crate)"]
+///
+/// Fractions that are not given in the scenario are `None`, and the defaults are used instead.
+#[derive(Deserialize, Clone, Debug, Default)]
 #[serde(deny_unknown_fields)]
 pub(crate) struct VariantTypeFraction {
-    #[serde(default = "default_indel_fraction")]
-    indel: f64,
-    #[serde(default = "default_mnv_fraction")]
-    mnv: f64,
-    #[serde(default = "default_sv_fraction")]
-    sv: f64,
+    #[serde(default)]
+    indel: Option<f64>,
+    #[serde(default)]
+    mnv: Option<f64>,
+    #[serde(default)]
+    sv: Option<f64>,
 }
 
 impl VariantTypeFraction {
     pub(crate) fn get(&self, variant_type: &VariantType) -> f64 {
         match variant_type {
             VariantType::Insertion(_) | VariantType::Deletion(_) | VariantType::Replacement => {
-                self.indel
+                self.indel.unwrap_or_else(default_indel_fraction)
+            }
+            VariantType::Mnv => self.mnv.unwrap_or_else(default_mnv_fraction),
+            VariantType::Inversion | VariantType::Breakend | VariantType::Duplication => {
+                self.sv.unwrap_or_else(default_sv_fraction)
             }
-            VariantType::Mnv => self.mnv,
-            VariantType::Inversion | VariantType::Breakend | VariantType::Duplication => self.sv,
             _ => 1.0,
         }
     }
-}
 
-impl Default for VariantTypeFraction {
-    fn default() -> Self {
-        VariantTypeFraction {
-            indel: default_indel_fraction(),
-            mnv: default_mnv_fraction(),
-            sv: default_sv_fraction(),
-        }
+    /// Fraction of indels, if defined in the scenario.
+    pub(crate) fn indel(&self) -> Option<f64> {
+        self.indel
+    }
+
+    /// Fraction of MNVs, if defined in the scenario.
+    pub(crate) fn mnv(&self) -> Option<f64> {
+        self.mnv
+    }
+
+    /// Fraction of SVs, if defined in the scenario.
+    pub(crate) fn sv(&self) -> Option<f64> {
+        self.sv
+    }
+
+    pub(crate) fn set_indel(&mut self, fraction: f64) {
+        self.indel = Some(fraction);
+    }
+
+    pub(crate) fn set_mnv(&mut self, fraction: f64) {
+        self.mnv = Some(fraction);
+    }
+
+    pub(crate) fn set_sv(&mut self, fraction: f64) {
+        self.sv = Some(fraction);
     }
 }
 
@@ -451,6 +615,10 @@ pub(crate) struct Sample {
     inheritance: Option<Inheritance>,
     #[serde(default)]
     sex: Option<Sex>,
+    /// whether the sample is formalin-fixed paraffin-embedded (FFPE), which makes it prone to deamination artifacts
+    #[serde(default)]
+    #[get = "pub(crate)"]
+    ffpe: bool,
 }
 
 impl Sample {
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::fs;
 use std::str;
 use std::sync::Arc;
@@ -13,6 +14,12 @@ pub(crate) struct Buffer {
     sequences: Mutex<LruCache<String, Arc<Vec<u8>>>>,
 }
 
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Buffer").finish()
+    }
+}
+
 impl Buffer {
     pub(crate) fn new(fasta: fasta::IndexedReader<fs::File>, capacity: usize) -> Self {
         Buffer {
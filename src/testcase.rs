@@ -313,7 +313,8 @@ impl Testcase {
         // second pass, write samples
         let mut samples = HashMap::new();
         for (name, path) in &self.bams {
-            let properties = sample::estimate_alignment_properties(path, false, false)?;
+            let properties =
+                sample::estimate_alignment_properties(path, false, false, false, false)?;
             let mut bam_reader = bam::IndexedReader::from_path(path)?;
             let filename = Path::new(name).with_extension("bam");
 
@@ -19,6 +19,7 @@ use half::f16;
 use itertools::join;
 use itertools::Itertools;
 use ordered_float::NotNan;
+use rust_htslib::bcf::record::Numeric;
 use rust_htslib::bcf::Read;
 use rust_htslib::{bam, bam::record::Cigar, bcf};
 
@@ -186,6 +187,66 @@ pub(crate) fn tags_prob_sum(
         .collect_vec())
 }
 
+/// Determine the maximum a posteriori (MAP) event of each alternative allele in the given BCF
+/// record, as index into the given tags, along with its posterior probability.
+/// Alleles without any valid event probability yield None.
+///
+/// # Arguments
+///
+/// * `record` - BCF record
+/// * `event_tags` - tags of all events in the BCF file
+pub(crate) fn map_events(
+    record: &mut bcf::Record,
+    event_tags: &[String],
+) -> Result<Vec<Option<(usize, LogProb)>>> {
+    let mut map_events = vec![None; record.allele_count() as usize - 1];
+    for (tag_idx, tag) in event_tags.iter().enumerate() {
+        if let Some(tag_probs) = record.info(tag.as_bytes()).float()? {
+            for (map_event, tag_prob) in map_events.iter_mut().zip(tag_probs.iter()) {
+                if tag_prob.is_nan() {
+                    continue;
+                }
+                let prob = LogProb::from(PHREDProb(*tag_prob as f64));
+                match map_event {
+                    Some((_, map_prob)) if *map_prob >= prob => (),
+                    _ => *map_event = Some((tag_idx, prob)),
+                }
+            }
+        }
+    }
+    Ok(map_events)
+}
+
+/// Like `tags_prob_sum`, but in smart mode, alleles are only considered if their maximum a
+/// posteriori event is among the given tags.
+///
+/// # Arguments
+///
+/// * `record` - BCF record
+/// * `tags` - tags of the set of events to sum up for a particular site and variant
+/// * `event_tags` - tags of all events in the BCF file, Some to enable smart mode
+/// * `vartype` - the variant type to consider
+fn smart_tags_prob_sum(
+    record: &mut bcf::Record,
+    tags: &[String],
+    event_tags: Option<&[String]>,
+    vartype: Option<&model::VariantType>,
+) -> Result<Vec<Option<LogProb>>> {
+    let probs = tags_prob_sum(record, tags, vartype)?;
+    if let Some(event_tags) = event_tags {
+        Ok(probs
+            .into_iter()
+            .zip(map_events(record, event_tags)?)
+            .map(|(prob, map_event)| match map_event {
+                Some((tag_idx, _)) if tags.contains(&event_tags[tag_idx]) => prob,
+                _ => None,
+            })
+            .collect())
+    } else {
+        Ok(probs)
+    }
+}
+
 pub(crate) fn events_to_tags<E>(events: &[E]) -> Vec<String>
 where
     E: Event,
@@ -201,10 +262,14 @@ where
 /// * `calls` - BCF reader with varlociraptor calls
 /// * `events` - the set of events to sum up for a particular site
 /// * `vartype` - the variant type to consider
+/// * `smart` - only consider alleles whose maximum a posteriori event is among the given events
+/// * `spool` - optional BCF writer that receives a copy of every record read
 pub(crate) fn collect_prob_dist<E>(
     calls: &mut bcf::Reader,
     events: &[E],
     vartype: Option<&model::VariantType>,
+    smart: bool,
+    mut spool: Option<&mut bcf::Writer>,
 ) -> Result<Vec<NotNan<f64>>>
 where
     E: Event,
@@ -214,11 +279,19 @@ where
     let mut record = calls.empty_record();
     let mut prob_dist = Vec::new();
     let tags = events_to_tags(events);
+    let event_tags = if smart {
+        Some(event_tag_ids(calls))
+    } else {
+        None
+    };
     loop {
         match calls.read(&mut record) {
             None => break,
             Some(res) => res?,
         }
+        if let Some(spool) = spool.as_mut() {
+            spool.write(&record)?;
+        }
         if let Ok(Some(event)) = info_tag_event(&mut record) {
             if visited_breakend_events.contains(&event) {
                 // Do not record probability of this event twice.
@@ -228,7 +301,7 @@ where
             }
         }
 
-        for p in (tags_prob_sum(&mut record, &tags, vartype)?)
+        for p in (smart_tags_prob_sum(&mut record, &tags, event_tags.as_deref(), vartype)?)
             .into_iter()
             .flatten()
         {
@@ -251,16 +324,25 @@ where
 /// * `calls` - BCF writer for the filtered varlociraptor calls
 /// * `events` - the set of Events to filter on
 /// * `vartype` - the variant type to consider (if None, use all types)
+/// * `smart` - only keep alleles whose maximum a posteriori event is among the given events,
+///   and annotate them with the MAP event and its posterior error probability (the header of `out`
+///   has to contain the INFO tags MAP_EVENT and MAP_PEP, see `add_map_event_header_records`)
 pub(crate) fn filter_by_threshold<E: Event>(
     calls: &mut bcf::Reader,
     threshold: Option<LogProb>,
     out: &mut bcf::Writer,
     events: &[E],
     vartype: Option<&model::VariantType>,
+    smart: bool,
 ) -> Result<()> {
     let mut breakend_event_decisions = HashMap::new();
 
     let tags = events.iter().map(|e| e.tag_name("PROB")).collect_vec();
+    let event_tags = if smart {
+        Some(event_tag_ids(calls))
+    } else {
+        None
+    };
     let filter = |record: &mut bcf::Record| -> Result<Vec<bool>> {
         let bnd_event = info_tag_event(record).ok().flatten();
         let keep = if let Some(event) = bnd_event.as_ref() {
@@ -269,7 +351,10 @@ pub(crate) fn filter_by_threshold<E: Event>(
             None
         };
 
-        let probs = tags_prob_sum(record, &tags, vartype)?;
+        let probs = smart_tags_prob_sum(record, &tags, event_tags.as_deref(), vartype)?;
+        if let Some(event_tags) = event_tags.as_ref() {
+            annotate_map_events(record, event_tags)?;
+        }
 
         assert!(
             bnd_event.is_none() || probs.len() == 1,
@@ -329,6 +414,8 @@ where
             None => return Ok(()),
             Some(res) => res?,
         }
+        // Translate into the output header, such that the filter may add annotations.
+        out.translate(&mut record);
 
         let mut remove = vec![false]; // don't remove the reference allele
         remove.extend(filter(&mut record)?.into_iter().map(|keep| !keep));
@@ -350,6 +437,50 @@ where
     }
 }
 
+/// Add header records for the maximum a posteriori event annotation (see
+/// `annotate_map_events`).
+pub(crate) fn add_map_event_header_records(header: &mut bcf::Header) {
+    header.push_record(
+        b"##INFO=<ID=MAP_EVENT,Number=A,Type=String,\
+          Description=\"Maximum a posteriori event\">",
+    );
+    header.push_record(
+        b"##INFO=<ID=MAP_PEP,Number=A,Type=Float,\
+          Description=\"Posterior error probability of the maximum a posteriori event (PHRED)\">",
+    );
+}
+
+/// Annotate the maximum a posteriori event of each allele, along with its posterior error
+/// probability, as INFO tags MAP_EVENT and MAP_PEP.
+fn annotate_map_events(record: &mut bcf::Record, event_tags: &[String]) -> Result<()> {
+    let map_events = map_events(record, event_tags)?;
+    let names = map_events
+        .iter()
+        .map(|map_event| match map_event {
+            Some((tag_idx, _)) => event_tags[*tag_idx].trim_start_matches("PROB_").as_bytes(),
+            None => b".",
+        })
+        .collect_vec();
+    let peps = map_events
+        .iter()
+        .map(|map_event| match map_event {
+            Some((_, prob)) => *PHREDProb::from(prob.ln_one_minus_exp()) as f32,
+            None => f32::missing(),
+        })
+        .collect_vec();
+    record.push_info_string(b"MAP_EVENT", &names)?;
+    record.push_info_float(b"MAP_PEP", &peps)?;
+    Ok(())
+}
+
+/// Returns IDs of all PROB_{event} INFO tags.
+pub(crate) fn event_tag_ids(inbcf: &bcf::Reader) -> Vec<String> {
+    get_event_tags(inbcf)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect()
+}
+
 /// Returns true if all PROB_{event}s are PHRED scaled
 pub(crate) fn is_phred_scaled(inbcf: &bcf::Reader) -> bool {
     get_event_tags(inbcf)
@@ -467,13 +598,15 @@ mod tests {
         let del = VariantType::Deletion(None);
 
         let mut del_calls_1 = bcf::Reader::from_path(test_file).unwrap();
-        let prob_del = collect_prob_dist(&mut del_calls_1, &events, Some(&del)).unwrap();
+        let prob_del =
+            collect_prob_dist(&mut del_calls_1, &events, Some(&del), false, None).unwrap();
         println!("prob_del[0]: {:?}", prob_del[0].into_inner());
         assert_eq!(prob_del.len(), 1);
         assert_relative_eq!(prob_del[0].into_inner(), Prob(0.8).ln(), epsilon = 0.000005);
 
         let mut del_calls_2 = bcf::Reader::from_path(test_file).unwrap();
-        let prob_del_abs = collect_prob_dist(&mut del_calls_2, &absent_event, Some(&del)).unwrap();
+        let prob_del_abs =
+            collect_prob_dist(&mut del_calls_2, &absent_event, Some(&del), false, None).unwrap();
         assert_eq!(prob_del_abs.len(), 1);
         assert_relative_eq!(
             prob_del_abs[0].into_inner(),
@@ -485,12 +618,14 @@ mod tests {
         let ins = VariantType::Insertion(None);
 
         let mut ins_calls_1 = bcf::Reader::from_path(test_file).unwrap();
-        let prob_ins = collect_prob_dist(&mut ins_calls_1, &events, Some(&ins)).unwrap();
+        let prob_ins =
+            collect_prob_dist(&mut ins_calls_1, &events, Some(&ins), false, None).unwrap();
         assert_eq!(prob_ins.len(), 1);
         assert_relative_eq!(prob_ins[0].into_inner(), Prob(0.2).ln(), epsilon = 0.000005);
 
         let mut ins_calls_2 = bcf::Reader::from_path(test_file).unwrap();
-        let prob_ins_abs = collect_prob_dist(&mut ins_calls_2, &absent_event, Some(&ins)).unwrap();
+        let prob_ins_abs =
+            collect_prob_dist(&mut ins_calls_2, &absent_event, Some(&ins), false, None).unwrap();
         assert_eq!(prob_ins_abs.len(), 1);
         assert_relative_eq!(
             prob_ins_abs[0].into_inner(),
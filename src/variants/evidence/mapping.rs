@@ -0,0 +1,174 @@
+use std::str;
+
+use bio::stats::{LogProb, PHREDProb};
+use itertools::Itertools;
+use rust_htslib::bam;
+
+use crate::estimation::alignment_properties::AlignmentProperties;
+
+/// Probability of an edit operation (mismatch or gap) between read and reference, used to
+/// compare the likelihoods of alternative hits.
+const PROB_EDIT: f64 = 0.01;
+
+/// Alternative hit of a read, as reported by BWA in the XA tag.
+#[derive(Debug, Clone, PartialEq, Eq, Getters, CopyGetters)]
+pub(crate) struct AlternativeHit {
+    #[getset(get = "pub(crate)")]
+    contig: String,
+    #[getset(get_copy = "pub(crate)")]
+    pos: i64,
+    #[getset(get_copy = "pub(crate)")]
+    reverse: bool,
+    #[getset(get_copy = "pub(crate)")]
+    edit_distance: u32,
+}
+
+/// Alternative hits of the given record (XA tag, formatted as `chr,±pos,CIGAR,NM;`).
+/// None if the mapper did not report any alternative hits.
+///
+/// Supplementary alignments (SA tag) are deliberately not considered here: they place other
+/// parts of a chimeric read (e.g. beyond a breakpoint), hence they are not competing loci for the
+/// aligned part of the read and carry no information about its mismapping.
+pub(crate) fn alternative_hits(record: &bam::Record) -> Option<Vec<AlternativeHit>> {
+    if let Some(bam::record::Aux::String(xa)) = record.aux(b"XA") {
+        let xa = str::from_utf8(xa).ok()?;
+        Some(
+            xa.split(';')
+                .filter(|hit| !hit.is_empty())
+                .filter_map(|hit| {
+                    let (contig, pos, _cigar, edit_distance) = hit.split(',').collect_tuple()?;
+                    let pos: i64 = pos.parse().ok()?;
+                    Some(AlternativeHit {
+                        contig: contig.to_owned(),
+                        pos: pos.abs() - 1,
+                        reverse: pos < 0,
+                        edit_distance: edit_distance.parse().ok()?,
+                    })
+                })
+                .collect(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Edit distance of the given record to the reference (NM tag).
+fn edit_distance(record: &bam::Record) -> Option<u32> {
+    if let Some(bam::record::Aux::Integer(nm)) = record.aux(b"NM") {
+        Some(nm as u32)
+    } else {
+        None
+    }
+}
+
+/// Posterior probability that the record stems from one of the given alternative hits instead
+/// of its reported locus.
+pub(crate) fn prob_mismapping_alt_hits(
+    edit_distance: u32,
+    alternative_hits: &[AlternativeHit],
+) -> LogProb {
+    // METHOD: we assume a uniform prior over all candidate loci. The likelihood of a locus is
+    // given by the probability to observe the read with the edit distance of the alignment at that
+    // locus. Since all hits cover the same read, only the difference in edit distances matters.
+    let ln_edit_ratio = (PROB_EDIT / (1.0 - PROB_EDIT)).ln();
+    let alternatives = alternative_hits
+        .iter()
+        .map(|hit| LogProb((hit.edit_distance as f64 - edit_distance as f64) * ln_edit_ratio))
+        .collect_vec();
+    let prob_alternatives = LogProb::ln_sum_exp(&alternatives);
+    prob_alternatives - LogProb::ln_one().ln_add_exp(prob_alternatives)
+}
+
+/// Posterior probability that the given record is mismapped.
+///
+/// # Arguments
+/// * `record` - the alignment
+/// * `alignment_properties` - alignment properties, optionally containing a MAPQ calibration
+/// * `use_alt_hits` - whether to consider alternative hits reported by the mapper
+pub(crate) fn prob_mismapping(
+    record: &bam::Record,
+    alignment_properties: &AlignmentProperties,
+    use_alt_hits: bool,
+) -> LogProb {
+    if use_alt_hits {
+        if let (Some(hits), Some(edit_distance)) = (alternative_hits(record), edit_distance(record))
+        {
+            // METHOD: if the mapper reports alternative hits, we use an explicit likelihood over
+            // all loci instead of MAPQ. This way, reads in paralogous regions are not discarded
+            // but contribute according to their support for each locus.
+            return prob_mismapping_alt_hits(edit_distance, &hits);
+        }
+    }
+    if let Some(ref calibration) = alignment_properties.mapq_calibration {
+        calibration.prob_mismapping(record.mapq())
+    } else {
+        LogProb::from(PHREDProb(record.mapq() as f64))
+    }
+}
+
+/// Whether the given record reports alternative hits.
+pub(crate) fn has_alternative_hits(record: &bam::Record) -> bool {
+    record.aux(b"XA").is_some()
+}
+
+/// Whether the locus of the given record is ambiguous, i.e., it has MAPQ zero and no known
+/// alternative hits. Such records are not considered by the statistical model.
+pub(crate) fn is_ambiguous(record: &bam::Record, use_alt_hits: bool) -> bool {
+    record.mapq() == 0 && !(use_alt_hits && has_alternative_hits(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bio::stats::Prob;
+
+    fn record(xa: &[u8], nm: i64) -> bam::Record {
+        let mut record = bam::Record::new();
+        record.set(b"read", None, b"ACGT", b"IIII");
+        record.push_aux(b"XA", &bam::record::Aux::String(xa));
+        record.push_aux(b"NM", &bam::record::Aux::Integer(nm));
+        record
+    }
+
+    #[test]
+    fn test_alternative_hits() {
+        let record = record(b"chr1,+1000,100M,1;chr2,-2000,100M,3;", 1);
+        let hits = alternative_hits(&record).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].contig(), "chr1");
+        assert_eq!(hits[0].pos(), 999);
+        assert!(!hits[0].reverse());
+        assert_eq!(hits[0].edit_distance(), 1);
+        assert!(hits[1].reverse());
+        assert_eq!(hits[1].edit_distance(), 3);
+    }
+
+    #[test]
+    fn test_prob_mismapping_equal_hit() {
+        let record = record(b"chr1,+1000,100M,1;", 1);
+        let hits = alternative_hits(&record).unwrap();
+        let p = prob_mismapping_alt_hits(1, &hits);
+        assert_relative_eq!(*Prob::from(p), 0.5, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_supplementary_alignments_ignored() {
+        let mut record = bam::Record::new();
+        record.set(b"read", None, b"ACGT", b"IIII");
+        record.set_mapq(0);
+        record.push_aux(b"SA", &bam::record::Aux::String(b"chr2,2000,+,2S2M,60,0;"));
+        record.push_aux(b"NM", &bam::record::Aux::Integer(0));
+        assert!(alternative_hits(&record).is_none());
+        assert!(!has_alternative_hits(&record));
+        // MAPQ 0 remains ambiguous, since there are no alternative hits to compare with
+        assert!(is_ambiguous(&record, true));
+    }
+
+    #[test]
+    fn test_prob_mismapping_worse_hit() {
+        let record = record(b"chr1,+1000,100M,3;", 1);
+        let hits = alternative_hits(&record).unwrap();
+        let p = prob_mismapping_alt_hits(1, &hits);
+        assert!(*Prob::from(p) < 0.001);
+    }
+}
@@ -5,5 +5,6 @@
 
 pub(crate) mod bases;
 pub(crate) mod insert_size;
+pub(crate) mod mapping;
 pub(crate) mod observation;
 pub(crate) mod realignment;
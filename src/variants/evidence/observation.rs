@@ -4,6 +4,7 @@
 // except according to those terms.
 
 use std::char;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::ops;
 use std::ops::Deref;
@@ -114,15 +115,47 @@ impl ops::BitOrAssign for Strand {
     }
 }
 
+/// Number of bins used for storing the relative read position of an observation.
+const READ_POSITION_BINS: f64 = 256.0;
+
+/// Relative position of the variant in the read, given as the distance to the closest
+/// read end in relation to half of the read length (0.0: at a read end, 1.0: at the center of the read).
+/// For compact storage, the distance is discretized into 256 bins.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum ReadPosition {
-    Major,
-    Some,
+    Some(u8),
+    None,
+}
+
+impl ReadPosition {
+    /// Relative read position of the given position in a read of the given length.
+    pub(crate) fn new(qpos: u32, read_len: usize) -> Self {
+        if read_len == 0 {
+            return ReadPosition::None;
+        }
+        let qpos = qpos as f64;
+        let half_len = read_len as f64 / 2.0;
+        // METHOD: consider the center of the base, such that the first and the last base of a read
+        // obtain the same distance.
+        let end_distance = (qpos + 0.5).min(read_len as f64 - qpos - 0.5) / half_len;
+        let bin = (end_distance * READ_POSITION_BINS)
+            .floor()
+            .clamp(0.0, READ_POSITION_BINS - 1.0);
+        ReadPosition::Some(bin as u8)
+    }
+
+    /// Distance to the closest read end in relation to half of the read length (center of the bin).
+    pub(crate) fn end_distance(&self) -> Option<f64> {
+        match self {
+            ReadPosition::Some(bin) => Some((*bin as f64 + 0.5) / READ_POSITION_BINS),
+            ReadPosition::None => None,
+        }
+    }
 }
 
 impl Default for ReadPosition {
     fn default() -> Self {
-        ReadPosition::Some
+        ReadPosition::None
     }
 }
 
@@ -171,7 +204,7 @@ pub(crate) fn read_orientation(record: &bam::Record) -> Result<SequenceReadPairO
 
 /// An observation for or against a variant.
 #[derive(Clone, Debug, Builder, Default)]
-pub(crate) struct Observation<P = Option<u32>, I = Vec<AlignmentOperation>>
+pub(crate) struct Observation<P = ReadPosition, I = Vec<AlignmentOperation>>
 where
     P: Clone,
 {
@@ -201,7 +234,6 @@ where
     /// Probability to overlap with one strand only (1-prob_double_overlap)
     #[builder(private)]
     pub(crate) prob_single_overlap: LogProb,
-    pub(crate) prob_hit_base: LogProb,
     /// Strand evidence this observation relies on
     pub(crate) strand: Strand,
     /// Read orientation support this observation relies on
@@ -226,10 +258,9 @@ impl<P: Clone, I: Clone> ObservationBuilder<P, I> {
     }
 }
 
-impl Observation<Option<u32>, Vec<AlignmentOperation>> {
+impl Observation<ReadPosition, Vec<AlignmentOperation>> {
     pub(crate) fn process(
         &self,
-        major_read_position: Option<u32>,
         major_indel_operations: Option<&Vec<AlignmentOperation>>,
     ) -> Observation<ReadPosition, IndelOperations> {
         Observation {
@@ -243,22 +274,11 @@ impl Observation<Option<u32>, Vec<AlignmentOperation>> {
             prob_sample_alt: self.prob_sample_alt,
             prob_double_overlap: self.prob_double_overlap,
             prob_single_overlap: self.prob_single_overlap,
-            prob_hit_base: self.prob_hit_base,
             strand: self.strand,
             read_orientation: self.read_orientation,
             softclipped: self.softclipped,
             paired: self.paired,
-            read_position: self.read_position.map_or(ReadPosition::Some, |pos| {
-                if let Some(major_pos) = major_read_position {
-                    if pos == major_pos {
-                        ReadPosition::Major
-                    } else {
-                        ReadPosition::Some
-                    }
-                } else {
-                    ReadPosition::Some
-                }
-            }),
+            read_position: self.read_position,
             indel_operations: if self.indel_operations.is_empty() {
                 IndelOperations::None
             } else if let Some(major_indel_operations) = major_indel_operations {
@@ -311,20 +331,8 @@ impl<P: Clone, I: Clone> Observation<P, I> {
     }
 }
 
-pub(crate) fn major_read_position(
-    pileup: &[Observation<Option<u32>, Vec<AlignmentOperation>>],
-) -> Option<u32> {
-    let counter: Counter<_> = pileup.iter().filter_map(|obs| obs.read_position).collect();
-    let most_common = counter.most_common();
-    if most_common.is_empty() {
-        None
-    } else {
-        Some(most_common[0].0)
-    }
-}
-
 pub(crate) fn major_indel_operations(
-    pileup: &[Observation<Option<u32>, Vec<AlignmentOperation>>],
+    pileup: &[Observation<ReadPosition, Vec<AlignmentOperation>>],
 ) -> Option<Vec<AlignmentOperation>> {
     let counter: Counter<_> = pileup
         .iter()
@@ -344,6 +352,59 @@ pub(crate) fn major_indel_operations(
     }
 }
 
+/// Combine observations stemming from duplicates of the same fragment into a single observation
+/// per fragment. Observations without a duplicate key are kept as they are. The order of
+/// observations is preserved (by first occurrence of each fragment).
+pub(crate) fn merge_duplicates(
+    observations: Vec<(Observation, Option<sample::DuplicateKey>)>,
+) -> Vec<Observation> {
+    let mut groups: Vec<Vec<Observation>> = Vec::new();
+    let mut group_idx: HashMap<sample::DuplicateKey, usize> = HashMap::new();
+    for (obs, key) in observations {
+        if let Some(key) = key {
+            if let Some(&i) = group_idx.get(&key) {
+                groups[i].push(obs);
+                continue;
+            }
+            group_idx.insert(key, groups.len());
+        }
+        groups.push(vec![obs]);
+    }
+
+    groups
+        .into_iter()
+        .map(|mut group| {
+            if group.len() == 1 {
+                return group.pop().unwrap();
+            }
+            // METHOD: Duplicates do not provide independent evidence, because PCR errors are
+            // shared between them. Hence, we do not multiply their likelihoods but average them,
+            // such that the entire group has the weight of a single fragment.
+            let n = LogProb((group.len() as f64).ln());
+            let mean = |f: &dyn Fn(&Observation) -> LogProb| {
+                LogProb::ln_sum_exp(&group.iter().map(f).collect_vec()) - n
+            };
+            let prob_alt = mean(&|obs| obs.prob_alt);
+            let prob_ref = mean(&|obs| obs.prob_ref);
+            let prob_missed_allele = mean(&|obs| obs.prob_missed_allele);
+            let prob_mapping = mean(&|obs| obs.prob_mapping);
+            let mut strand = Strand::None;
+            for obs in &group {
+                strand |= obs.strand;
+            }
+
+            let mut merged = group.swap_remove(0);
+            merged.prob_alt = prob_alt;
+            merged.prob_ref = prob_ref;
+            merged.prob_missed_allele = prob_missed_allele;
+            merged.prob_mapping = prob_mapping;
+            merged.prob_mismapping = prob_mapping.ln_one_minus_exp();
+            merged.strand = strand;
+            merged
+        })
+        .collect()
+}
+
 impl Serialize for Observation {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -372,14 +433,21 @@ where
     ) -> Result<Vec<Observation>>;
 
     /// Convert MAPQ (from read mapper) to LogProb for the event that the read maps
-    /// correctly.
-    fn prob_mapping(&self, evidence: &E) -> LogProb;
+    /// correctly. If `use_alt_hits` is true, alternative hits reported by the mapper are
+    /// considered instead of MAPQ.
+    fn prob_mapping(
+        &self,
+        evidence: &E,
+        alignment_properties: &AlignmentProperties,
+        use_alt_hits: bool,
+    ) -> LogProb;
 
     /// Calculate an observation from the given evidence.
     fn evidence_to_observation(
         &self,
         evidence: &E,
         alignment_properties: &AlignmentProperties,
+        use_alt_hits: bool,
     ) -> Result<Option<Observation>> {
         Ok(match self.allele_support(evidence, alignment_properties)? {
             // METHOD: only consider allele support if it comes either from forward or reverse strand.
@@ -387,7 +455,11 @@ where
             // any information (e.g. no overlap).
             Some(allele_support) if allele_support.strand() != Strand::None => {
                 let obs = ObservationBuilder::default()
-                    .prob_mapping_mismapping(self.prob_mapping(evidence))
+                    .prob_mapping_mismapping(self.prob_mapping(
+                        evidence,
+                        alignment_properties,
+                        use_alt_hits,
+                    ))
                     .prob_alt(allele_support.prob_alt_allele())
                     .prob_ref(allele_support.prob_ref_allele())
                     .prob_sample_alt(self.prob_sample_alt(evidence, alignment_properties))
@@ -408,7 +480,6 @@ where
                     })
                     .read_position(allele_support.read_position())
                     .paired(evidence.is_paired())
-                    .prob_hit_base(LogProb::ln_one() - LogProb((evidence.len() as f64).ln()))
                     .build()
                     .unwrap();
                 Some(obs)
@@ -424,8 +495,6 @@ pub(crate) trait Evidence {
     fn softclipped(&self) -> bool;
 
     fn is_paired(&self) -> bool;
-
-    fn len(&self) -> usize;
 }
 
 #[derive(new, Clone, Eq, Debug)]
@@ -456,10 +525,6 @@ impl Evidence for SingleEndEvidence {
         let cigar = self.cigar_cached().unwrap();
         cigar.leading_softclips() > 0 || cigar.trailing_softclips() > 0
     }
-
-    fn len(&self) -> usize {
-        self.inner.seq_len()
-    }
 }
 
 impl PartialEq for SingleEndEvidence {
@@ -514,13 +579,6 @@ impl Evidence for PairedEndEvidence {
             }
         }
     }
-
-    fn len(&self) -> usize {
-        match self {
-            PairedEndEvidence::SingleEnd(rec) => rec.seq_len(),
-            PairedEndEvidence::PairedEnd { left, right } => left.seq_len() + right.seq_len(),
-        }
-    }
 }
 
 impl PartialEq for PairedEndEvidence {
@@ -546,3 +604,70 @@ impl Hash for PairedEndEvidence {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variants::sample::tests::record;
+    use crate::variants::sample::DuplicateDetection;
+
+    fn observation(prob_alt: f64, strand: Strand) -> Observation {
+        Observation {
+            prob_mapping: LogProb(0.9_f64.ln()),
+            prob_mismapping: LogProb(0.1_f64.ln()),
+            prob_alt: LogProb(prob_alt.ln()),
+            prob_ref: LogProb((1.0 - prob_alt).ln()),
+            prob_missed_allele: LogProb(0.5_f64.ln()),
+            strand,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_position() {
+        assert_eq!(ReadPosition::new(0, 0), ReadPosition::None);
+        // first and last base of a read have the same distance to the read end
+        assert_eq!(ReadPosition::new(0, 100), ReadPosition::new(99, 100));
+        assert_eq!(ReadPosition::new(0, 100), ReadPosition::Some(2));
+        assert_eq!(ReadPosition::new(10, 100), ReadPosition::new(89, 100));
+        // center of the read
+        assert_eq!(ReadPosition::new(49, 100), ReadPosition::new(50, 100));
+        assert_eq!(ReadPosition::new(49, 100), ReadPosition::Some(253));
+        assert_eq!(ReadPosition::new(0, 1), ReadPosition::Some(255));
+        // bin centers approximate the relative distance to the read end
+        assert_relative_eq!(
+            ReadPosition::new(10, 100).end_distance().unwrap(),
+            10.5 / 50.0,
+            epsilon = 1.0 / 256.0
+        );
+        assert_eq!(ReadPosition::None.end_distance(), None);
+    }
+
+    #[test]
+    fn test_merge_duplicates() {
+        let detection = DuplicateDetection::default();
+        let key = |pos, cigar| Some(detection.key(&record(pos, cigar, 0, None)).unwrap());
+        let observations = vec![
+            (observation(0.9, Strand::Forward), key(100, "100M")),
+            (observation(0.8, Strand::Forward), key(200, "100M")),
+            // soft clipped duplicate of the first fragment
+            (observation(0.5, Strand::Reverse), key(105, "5S95M")),
+            // no duplicate key
+            (observation(0.1, Strand::Forward), None),
+            (observation(0.1, Strand::Forward), None),
+        ];
+        let merged = merge_duplicates(observations);
+
+        assert_eq!(merged.len(), 4);
+        // likelihoods of duplicates are averaged
+        assert_relative_eq!(merged[0].prob_alt.exp(), 0.7, epsilon = 1e-9);
+        assert_relative_eq!(merged[0].prob_ref.exp(), 0.3, epsilon = 1e-9);
+        assert_relative_eq!(merged[0].prob_mapping.exp(), 0.9, epsilon = 1e-9);
+        assert_relative_eq!(merged[0].prob_mismapping.exp(), 0.1, epsilon = 1e-9);
+        assert_eq!(merged[0].strand, Strand::Both);
+        // other observations are kept unchanged and in order
+        assert_relative_eq!(merged[1].prob_alt.exp(), 0.8, epsilon = 1e-9);
+        assert_relative_eq!(merged[2].prob_alt.exp(), 0.1, epsilon = 1e-9);
+        assert_relative_eq!(merged[3].prob_alt.exp(), 0.1, epsilon = 1e-9);
+    }
+}
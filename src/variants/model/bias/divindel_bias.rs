@@ -4,7 +4,7 @@ use bio::stats::probs::LogProb;
 use ordered_float::NotNan;
 
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
-use crate::variants::model::bias::Bias;
+use crate::variants::model::bias::{Bias, BiasContext, BiasParams};
 
 #[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, Hash)]
 pub(crate) enum DivIndelBias {
@@ -15,25 +15,52 @@ pub(crate) enum DivIndelBias {
     },
 }
 
-impl DivIndelBias {
-    pub(crate) fn values(min_other_rate: f64) -> Vec<Self> {
+impl Default for DivIndelBias {
+    fn default() -> Self {
+        DivIndelBias::None
+    }
+}
+
+impl Bias for DivIndelBias {
+    fn values(params: &BiasParams) -> Vec<Self> {
         vec![
             DivIndelBias::None,
             DivIndelBias::Some {
                 other_rate: NotNan::new(0.0).unwrap(),
-                min_other_rate: NotNan::new(min_other_rate).unwrap(),
+                min_other_rate: NotNan::new(params.min_divindel_other_rate).unwrap(),
             },
         ]
     }
-}
 
-impl Default for DivIndelBias {
-    fn default() -> Self {
-        DivIndelBias::None
+    fn format_tag() -> &'static str {
+        "DIB"
+    }
+
+    fn format_description() -> &'static str {
+        "Divindel bias estimate: # indicates that ALT allele is associated with \
+         with indel operations of varying length, . indicates that there is no divindel bias. \
+         Divindel bias is indicative of systematic PCR amplification errors, e.g. induced by \
+         homopolymers. Probability for divindel bias is captured by the ARTIFACT \
+         event (PROB_ARTIFACT)."
+    }
+
+    fn format_symbol(&self) -> u8 {
+        match self {
+            DivIndelBias::None => b'.',
+            DivIndelBias::Some { .. } => b'#',
+        }
+    }
+
+    fn observation_symbol(
+        observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> Option<char> {
+        Some(match observation.indel_operations {
+            IndelOperations::Major => '*',
+            IndelOperations::Other => '#',
+            IndelOperations::None => '.',
+        })
     }
-}
 
-impl Bias for DivIndelBias {
     fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
         match self {
             DivIndelBias::None => match observation.indel_operations {
@@ -95,7 +122,11 @@ impl Bias for DivIndelBias {
         }
     }
 
-    fn learn_parameters(&mut self, pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) {
+    fn learn_parameters(
+        &mut self,
+        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+        _context: &BiasContext,
+    ) {
         // METHOD: by default, there is nothing to learn, however, a bias can use this to
         // infer some parameters over which we would otherwise need to integrate (which would hamper
         // performance too much).
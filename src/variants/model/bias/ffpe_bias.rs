@@ -0,0 +1,430 @@
+use bio::stats::probs::LogProb;
+use bio_types::sequence::SequenceReadPairOrientation;
+use itertools::Itertools;
+use ordered_float::NotNan;
+
+use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
+use crate::variants::model::bias::{Bias, BiasContext, BiasParams, ReadOrientationBias};
+
+/// Pseudo counts of a symmetric Beta prior on the per sample artifact orientation rate. They
+/// shrink the rate towards 0.5 (i.e. no enrichment), such that a few reads in artifact
+/// orientation do not suffice to make the artifact hypothesis more likely than the null.
+const RATE_PSEUDO_COUNT: f64 = 5.0;
+/// Maximum artifact orientation rate. Genuine variants can also be observed in artifact
+/// orientation, hence the opposite orientation must never become impossible.
+const MAX_RATE: f64 = 0.99;
+
+/// Bias caused by cytosine deamination in formalin-fixed paraffin-embedded (FFPE) samples.
+#[derive(Clone, PartialOrd, PartialEq, Eq, Debug, Ord, Hash)]
+pub(crate) enum FfpeBias {
+    None,
+    /// Deamination artifact, affecting all FFPE samples.
+    Some {
+        /// Read orientation that artifacts are associated with for the given substitution.
+        orientation: ReadOrientationBias,
+        /// Per sample fraction of ALT supporting observations that show the artifact
+        /// orientation. None for samples that are not FFPE.
+        rates: Vec<Option<NotNan<f64>>>,
+    },
+    /// Deamination artifact, as it affects a single FFPE sample in which the artifact
+    /// orientation is enriched (rate > 0.5).
+    Sample {
+        orientation: ReadOrientationBias,
+        rate: NotNan<f64>,
+    },
+}
+
+impl Default for FfpeBias {
+    fn default() -> Self {
+        FfpeBias::None
+    }
+}
+
+impl FfpeBias {
+    /// Read orientation that deamination artifacts are associated with for the given substitution.
+    /// None if the substitution is not compatible with deamination.
+    fn artifact_orientation(context: &BiasContext) -> Option<ReadOrientationBias> {
+        // METHOD: deamination turns C into U, which is read as T. If the damaged strand is sequenced
+        // by the first read, we observe C>T in F1R2 fragments. On the opposite strand, the same
+        // damage appears as G>A in F2R1 fragments.
+        context.snv.as_ref().and_then(|snv| {
+            match (
+                snv.refbase().to_ascii_uppercase(),
+                snv.altbase().to_ascii_uppercase(),
+            ) {
+                (b'C', b'T') => Some(ReadOrientationBias::F1R2),
+                (b'G', b'A') => Some(ReadOrientationBias::F2R1),
+                _ => None,
+            }
+        })
+    }
+
+    fn is_artifact_orientation(
+        &self,
+        observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> bool {
+        match self {
+            FfpeBias::Some { orientation, .. } | FfpeBias::Sample { orientation, .. } => {
+                match orientation {
+                    ReadOrientationBias::F1R2 => {
+                        observation.read_orientation == SequenceReadPairOrientation::F1R2
+                    }
+                    ReadOrientationBias::F2R1 => {
+                        observation.read_orientation == SequenceReadPairOrientation::F2R1
+                    }
+                    ReadOrientationBias::None => false,
+                }
+            }
+            FfpeBias::None => false,
+        }
+    }
+
+    /// Pileups of FFPE samples in which the artifact orientation is enriched.
+    fn enriched_pileups<'a>(
+        &'a self,
+        pileups: &'a [Vec<Observation<ReadPosition, IndelOperations>>],
+    ) -> impl Iterator<Item = &'a Vec<Observation<ReadPosition, IndelOperations>>> {
+        let rates: &[Option<NotNan<f64>>] = match self {
+            FfpeBias::Some { rates, .. } => rates,
+            _ => &[],
+        };
+        pileups
+            .iter()
+            .zip(rates.iter())
+            .filter(|(_, rate)| matches!(rate, Some(rate) if **rate > 0.5))
+            .map(|(pileup, _)| pileup)
+    }
+}
+
+fn is_standard_orientation(observation: &Observation<ReadPosition, IndelOperations>) -> bool {
+    observation.read_orientation == SequenceReadPairOrientation::F1R2
+        || observation.read_orientation == SequenceReadPairOrientation::F2R1
+}
+
+impl Bias for FfpeBias {
+    fn values(_params: &BiasParams) -> Vec<Self> {
+        vec![
+            FfpeBias::None,
+            FfpeBias::Some {
+                orientation: ReadOrientationBias::F1R2,
+                rates: Vec::new(),
+            },
+        ]
+    }
+
+    fn is_applicable(context: &BiasContext) -> bool {
+        context.ffpe_samples.iter().any(|ffpe| *ffpe)
+            && Self::artifact_orientation(context).is_some()
+    }
+
+    fn format_tag() -> &'static str {
+        "FFB"
+    }
+
+    fn format_description() -> &'static str {
+        "FFPE bias estimate: ~ indicates that the ALT allele is associated with the read orientation \
+         expected for cytosine deamination (C>T in F1R2, G>A in F2R1), . indicates that there is no FFPE bias. \
+         FFPE bias is only considered for samples that are marked as FFPE in the scenario. \
+         Probability for FFPE bias is captured by the ARTIFACT event (PROB_ARTIFACT)."
+    }
+
+    fn format_symbol(&self) -> u8 {
+        match self {
+            FfpeBias::None => b'.',
+            FfpeBias::Some { .. } | FfpeBias::Sample { .. } => b'~',
+        }
+    }
+
+    fn observation_symbol(
+        _observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> Option<char> {
+        // The read orientation is already summarized by the read orientation bias.
+        None
+    }
+
+    fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
+        match self {
+            // METHOD: the artifact only affects observations once it is specialized to an FFPE
+            // sample (see for_sample).
+            FfpeBias::None | FfpeBias::Some { .. } => LogProb::ln_one(),
+            FfpeBias::Sample { rate, .. } => {
+                if !is_standard_orientation(observation) {
+                    LogProb::ln_one()
+                } else {
+                    // METHOD: the read orientation is already modelled by the read orientation
+                    // bias, which is None in this case and assigns 0.5 to both orientations.
+                    // Hence, we provide the ratio to that, such that the product yields the
+                    // learned rate for the artifact orientation. The ratio exceeds one for the
+                    // artifact orientation. This is only valid because artifact combinations
+                    // never combine FFPE bias with a read orientation bias other than None
+                    // (see Biases::all_artifact_combinations).
+                    let prob = if self.is_artifact_orientation(observation) {
+                        **rate
+                    } else {
+                        1.0 - **rate
+                    };
+                    LogProb((prob / 0.5).ln())
+                }
+            }
+        }
+    }
+
+    fn prob_any(&self, _observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
+        LogProb::ln_one()
+    }
+
+    fn is_artifact(&self) -> bool {
+        *self != FfpeBias::None
+    }
+
+    fn for_sample(&self, sample: usize) -> Self {
+        match self {
+            FfpeBias::Some { orientation, rates } => {
+                match rates.get(sample) {
+                    Some(Some(rate)) if **rate > 0.5 => FfpeBias::Sample {
+                        orientation: *orientation,
+                        rate: *rate,
+                    },
+                    // METHOD: samples that are not FFPE are not affected by deamination. FFPE
+                    // samples without enrichment of the artifact orientation show no damage.
+                    // They behave like any other sample under the artifact, instead of
+                    // ruling it out for all samples.
+                    _ => FfpeBias::None,
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    fn is_possible(&self, pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) -> bool {
+        if !self.is_artifact() {
+            return true;
+        }
+        self.enriched_pileups(pileups)
+            .any(|pileup| pileup.iter().any(|obs| self.is_artifact_orientation(obs)))
+    }
+
+    fn is_informative(&self, pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) -> bool {
+        if !self.is_artifact() {
+            return true;
+        }
+        // METHOD: this bias is only relevant if there are observations with known read orientation.
+        self.enriched_pileups(pileups)
+            .any(|pileup| pileup.iter().any(is_standard_orientation))
+    }
+
+    fn is_likely(&self, pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) -> bool {
+        if !self.is_artifact() {
+            return true;
+        }
+        // METHOD: only FFPE samples can provide evidence for the artifact.
+        self.enriched_pileups(pileups)
+            .any(|pileup| self.is_likely_in_pileup(pileup))
+    }
+
+    fn is_bias_evidence(&self, observation: &Observation<ReadPosition, IndelOperations>) -> bool {
+        self.is_artifact_orientation(observation)
+    }
+
+    fn learn_parameters(
+        &mut self,
+        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+        context: &BiasContext,
+    ) {
+        let artifact_orientation = Self::artifact_orientation(context);
+        if let FfpeBias::Some {
+            ref mut orientation,
+            ref mut rates,
+        } = self
+        {
+            if let Some(artifact_orientation) = artifact_orientation {
+                *orientation = artifact_orientation;
+            }
+            let is_artifact_orientation =
+                |obs: &Observation<ReadPosition, IndelOperations>| match orientation {
+                    ReadOrientationBias::F1R2 => {
+                        obs.read_orientation == SequenceReadPairOrientation::F1R2
+                    }
+                    ReadOrientationBias::F2R1 => {
+                        obs.read_orientation == SequenceReadPairOrientation::F2R1
+                    }
+                    ReadOrientationBias::None => false,
+                };
+
+            // METHOD: learn the fraction of strong ALT observations in artifact orientation
+            // separately for each FFPE sample, since the amount of damage differs between
+            // samples. Other samples are not expected to show deamination artifacts.
+            // The rate is the posterior mean under a symmetric Beta prior, capped at MAX_RATE.
+            *rates = pileups
+                .iter()
+                .zip(context.ffpe_samples.iter())
+                .map(|(pileup, ffpe)| {
+                    if !*ffpe || artifact_orientation.is_none() {
+                        return None;
+                    }
+                    let standard = pileup
+                        .iter()
+                        .filter(|obs| Self::is_strong_obs(obs) && is_standard_orientation(obs))
+                        .collect_vec();
+                    let n_artifact = standard
+                        .iter()
+                        .filter(|obs| is_artifact_orientation(obs))
+                        .count();
+                    let rate = (n_artifact as f64 + RATE_PSEUDO_COUNT)
+                        / (standard.len() as f64 + 2.0 * RATE_PSEUDO_COUNT);
+                    Some(NotNan::new(rate.min(MAX_RATE)).unwrap())
+                })
+                .collect();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bio::stats::LogProb;
+
+    use crate::variants::model::bias::{BiasFlags, Biases};
+    use crate::variants::model::modes::generic::Snv;
+    use crate::variants::model::tests::observation;
+
+    fn obs(orientation: SequenceReadPairOrientation) -> Observation<ReadPosition, IndelOperations> {
+        let mut obs = observation(
+            LogProb(0.99_f64.ln()),
+            LogProb(0.99_f64.ln()),
+            LogProb(0.0001_f64.ln()),
+        );
+        obs.read_orientation = orientation;
+        obs
+    }
+
+    fn pileup(n_f1r2: usize, n_f2r1: usize) -> Vec<Observation<ReadPosition, IndelOperations>> {
+        let mut pileup = vec![obs(SequenceReadPairOrientation::F1R2); n_f1r2];
+        pileup.extend(vec![obs(SequenceReadPairOrientation::F2R1); n_f2r1]);
+        pileup
+    }
+
+    fn context(ffpe_samples: Vec<bool>) -> BiasContext {
+        BiasContext::new(true, Some(Snv::new(b'C', b'T')), ffpe_samples)
+    }
+
+    fn learned(
+        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+        ffpe_samples: Vec<bool>,
+    ) -> FfpeBias {
+        let mut bias = FfpeBias::values(&BiasParams::new(0.05))
+            .into_iter()
+            .find(|bias| bias.is_artifact())
+            .unwrap();
+        bias.learn_parameters(pileups, &context(ffpe_samples));
+        bias
+    }
+
+    #[test]
+    fn test_prob() {
+        let bias = FfpeBias::Sample {
+            orientation: ReadOrientationBias::F1R2,
+            rate: NotNan::new(0.8).unwrap(),
+        };
+        let artifact = obs(SequenceReadPairOrientation::F1R2);
+        let other = obs(SequenceReadPairOrientation::F2R1);
+        let unknown = obs(SequenceReadPairOrientation::None);
+        assert_relative_eq!(bias.prob(&artifact).exp(), 1.6);
+        assert_relative_eq!(bias.prob(&other).exp(), 0.4);
+        assert_relative_eq!(bias.prob(&unknown).exp(), 1.0);
+        // together with the 0.5 of the read orientation bias, both orientations sum up to one
+        assert_relative_eq!(
+            0.5 * bias.prob(&artifact).exp() + 0.5 * bias.prob(&other).exp(),
+            1.0
+        );
+        for observation in &[artifact, other, unknown] {
+            assert_relative_eq!(*bias.prob_any(observation), *LogProb::ln_one());
+            assert_relative_eq!(*FfpeBias::None.prob(observation), *LogProb::ln_one());
+        }
+    }
+
+    #[test]
+    fn test_read_orientation_bias_none() {
+        // prob relies on the read orientation bias being None whenever there is an FFPE artifact
+        let considered = BiasFlags {
+            read_orientation_bias: true,
+            ffpe_bias: true,
+            ..Default::default()
+        };
+        let combinations = Biases::all_artifact_combinations(&considered, &BiasParams::new(0.05))
+            .filter(|biases| biases.ffpe_bias().is_artifact())
+            .collect_vec();
+        assert!(!combinations.is_empty());
+        for biases in combinations {
+            assert_eq!(*biases.read_orientation_bias(), ReadOrientationBias::None);
+        }
+    }
+
+    #[test]
+    fn test_learn_per_sample() {
+        // sample 0: FFPE with strong damage, sample 1: fresh frozen with the same pattern,
+        // sample 2: FFPE without damage
+        let pileups = vec![pileup(90, 10), pileup(90, 10), pileup(50, 50)];
+        let bias = learned(&pileups, vec![true, false, true]);
+        if let FfpeBias::Some {
+            orientation,
+            ref rates,
+        } = bias
+        {
+            assert_eq!(orientation, ReadOrientationBias::F1R2);
+            assert_relative_eq!(*rates[0].unwrap(), 95.0 / 110.0);
+            assert!(rates[1].is_none());
+            assert_relative_eq!(*rates[2].unwrap(), 0.5);
+        } else {
+            panic!("bug: expected FFPE artifact");
+        }
+
+        // the artifact only applies to FFPE samples with enriched artifact orientation
+        assert!(bias.for_sample(0).is_artifact());
+        assert_eq!(bias.for_sample(1), FfpeBias::None);
+        assert_eq!(bias.for_sample(2), FfpeBias::None);
+        // the undamaged FFPE sample does not veto the artifact
+        assert_eq!(
+            bias.for_sample(2)
+                .prob(&obs(SequenceReadPairOrientation::F1R2)),
+            LogProb::ln_one()
+        );
+        assert!(bias.is_possible(&pileups));
+        assert!(bias.is_likely(&pileups));
+
+        // without any FFPE sample showing damage, the artifact is not possible
+        let bias = learned(&pileups, vec![false, true, true]);
+        assert!(!bias.is_possible(&pileups));
+    }
+
+    #[test]
+    fn test_learn_regularized() {
+        // METHOD: a handful of reads in artifact orientation is no evidence for damage.
+        let pileups = vec![pileup(2, 0)];
+        let bias = learned(&pileups, vec![true]).for_sample(0);
+        let prob_artifact = pileups[0]
+            .iter()
+            .map(|obs| *bias.prob(obs) + 0.5_f64.ln())
+            .sum::<f64>();
+        let prob_null = 2.0 * 0.5_f64.ln();
+        assert!(prob_artifact - prob_null < 2.0_f64.ln());
+
+        // the rate is capped, such that the other orientation stays possible
+        let pileups = vec![pileup(1000, 0)];
+        let bias = learned(&pileups, vec![true]).for_sample(0);
+        assert_relative_eq!(
+            bias.prob(&obs(SequenceReadPairOrientation::F2R1)).exp(),
+            (1.0 - MAX_RATE) / 0.5,
+            epsilon = 1e-9
+        );
+
+        // substitutions that are not caused by deamination
+        let mut bias = FfpeBias::values(&BiasParams::new(0.05)).pop().unwrap();
+        bias.learn_parameters(
+            &pileups,
+            &BiasContext::new(true, Some(Snv::new(b'A', b'G')), vec![true]),
+        );
+        assert!(!bias.is_possible(&pileups));
+    }
+}
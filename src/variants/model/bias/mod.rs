@@ -2,25 +2,48 @@ use std::cmp;
 
 use bio::stats::bayesian::bayes_factors::{evidence::KassRaftery, BayesFactor};
 use bio::stats::probs::LogProb;
-use itertools::Itertools;
-use strum::IntoEnumIterator;
 
 use crate::utils::PROB_095;
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
+use crate::variants::model::modes::generic::Snv;
 
 pub(crate) mod divindel_bias;
+pub(crate) mod ffpe_bias;
 pub(crate) mod read_orientation_bias;
 pub(crate) mod read_position_bias;
 pub(crate) mod softclip_bias;
 pub(crate) mod strand_bias;
 
 pub(crate) use divindel_bias::DivIndelBias;
+pub(crate) use ffpe_bias::FfpeBias;
 pub(crate) use read_orientation_bias::ReadOrientationBias;
 pub(crate) use read_position_bias::ReadPositionBias;
 pub(crate) use softclip_bias::SoftclipBias;
 pub(crate) use strand_bias::StrandBias;
 
-pub(crate) trait Bias: Default + cmp::PartialEq {
+pub(crate) trait Bias: Default + Clone + cmp::PartialEq {
+    /// Values of this bias to consider when enumerating artifact events.
+    fn values(params: &BiasParams) -> Vec<Self>;
+
+    /// Whether this bias can occur for the given variant.
+    fn is_applicable(_context: &BiasContext) -> bool {
+        true
+    }
+
+    /// Name of the FORMAT tag that reports the estimated bias per sample.
+    fn format_tag() -> &'static str;
+
+    /// Description of the FORMAT tag, as shown in the VCF header.
+    fn format_description() -> &'static str;
+
+    /// Symbol representing this bias value in the FORMAT tag.
+    fn format_symbol(&self) -> u8;
+
+    /// Symbol summarizing the observation field this bias is based on, as used in the
+    /// OBS FORMAT tag. None if the field is already summarized by another bias.
+    fn observation_symbol(observation: &Observation<ReadPosition, IndelOperations>)
+        -> Option<char>;
+
     fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb;
 
     fn prob_any(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb;
@@ -51,26 +74,41 @@ pub(crate) trait Bias: Default + cmp::PartialEq {
         if *self == Self::default() {
             true
         } else {
-            pileups.iter().any(|pileup| {
-                let strong_all = pileup.iter().filter(&Self::is_strong_obs).count();
-                if strong_all >= 10 {
-                    let strong_bias_evidence = pileup
-                        .iter()
-                        .filter(|obs| Self::is_strong_obs(obs) && self.is_bias_evidence(obs))
-                        .count();
-                    // METHOD: there is bias evidence if we have at least two third of the strong observations supporting the bias
-                    let ratio = strong_bias_evidence as f64 / strong_all as f64;
-                    ratio >= self.min_strong_evidence_ratio()
-                } else {
-                    // METHOD: not enough reads, rather consider all biases to be sure
-                    true
-                }
-            })
+            pileups
+                .iter()
+                .any(|pileup| self.is_likely_in_pileup(pileup))
+        }
+    }
+
+    /// Whether the given pileup provides evidence for this bias.
+    fn is_likely_in_pileup(&self, pileup: &[Observation<ReadPosition, IndelOperations>]) -> bool {
+        let strong_all = pileup.iter().filter(&Self::is_strong_obs).count();
+        if strong_all >= 10 {
+            let strong_bias_evidence = pileup
+                .iter()
+                .filter(|obs| Self::is_strong_obs(obs) && self.is_bias_evidence(obs))
+                .count();
+            // METHOD: there is bias evidence if we have at least two third of the strong observations supporting the bias
+            let ratio = strong_bias_evidence as f64 / strong_all as f64;
+            ratio >= self.min_strong_evidence_ratio()
+        } else {
+            // METHOD: not enough reads, rather consider all biases to be sure
+            true
         }
     }
 
+    /// Value of this bias as it applies to the given sample. By default, biases affect all
+    /// samples in the same way.
+    fn for_sample(&self, _sample: usize) -> Self {
+        self.clone()
+    }
+
     /// Learn parameters needed for estimation on current pileup.
-    fn learn_parameters(&mut self, _pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) {
+    fn learn_parameters(
+        &mut self,
+        _pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+        _context: &BiasContext,
+    ) {
         // METHOD: by default, there is nothing to learn, however, a bias can use this to
         // infer some parameters over which we would otherwise need to integrate (which would hamper
         // performance too much).
@@ -83,177 +121,266 @@ pub(crate) trait Bias: Default + cmp::PartialEq {
     }
 }
 
-#[derive(Builder, CopyGetters, Getters, Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub(crate) struct Biases {
-    #[getset(get = "pub(crate)")]
-    strand_bias: StrandBias,
-    #[getset(get = "pub(crate)")]
-    read_orientation_bias: ReadOrientationBias,
-    #[getset(get = "pub(crate)")]
-    read_position_bias: ReadPositionBias,
-    #[getset(get = "pub(crate)")]
-    softclip_bias: SoftclipBias,
-    #[getset(get = "pub(crate)")]
-    divindel_bias: DivIndelBias,
+/// Parameters for the enumeration of bias values.
+#[derive(Debug, Clone, new)]
+pub(crate) struct BiasParams {
+    /// Minimum rate of other indel operations for considering a divindel bias.
+    pub(crate) min_divindel_other_rate: f64,
+}
+
+/// Properties of the variant and samples under consideration, determining which biases
+/// are applicable.
+#[derive(Debug, Clone, new)]
+pub(crate) struct BiasContext {
+    pub(crate) is_snv_or_mnv: bool,
+    /// Reference and alternative base in case of an SNV.
+    pub(crate) snv: Option<Snv>,
+    /// Whether each sample is formalin-fixed paraffin-embedded (FFPE).
+    pub(crate) ffpe_samples: Vec<bool>,
 }
 
-impl Biases {
-    pub(crate) fn all_artifact_combinations(
-        consider_read_orientation_bias: bool,
-        consider_strand_bias: bool,
-        consider_read_position_bias: bool,
-        consider_softclip_bias: bool,
-        consider_divindel_bias: bool,
-        min_divindel_other_rate: f64,
-    ) -> Box<dyn Iterator<Item = Self>> {
-        if !consider_strand_bias
-            && !consider_read_orientation_bias
-            && !consider_read_position_bias
-            && !consider_softclip_bias
-            && !consider_divindel_bias
-        {
-            return Box::new(std::iter::empty());
+/// Register the given biases. This generates the struct `Biases`, which holds a value for
+/// each registered bias, and the struct `BiasFlags`, which holds a boolean per registered bias
+/// (e.g. to denote whether it shall be considered).
+///
+/// The registry is resolved at compile time: `Biases` is part of the event keys of the model
+/// (and hence has to be hashable and ordered), and static dispatch keeps the likelihood
+/// computation free of virtual calls. There is no runtime registration of biases.
+/// In order to model an additional artifact, implement `Bias` for it in a separate module and
+/// add a line to the invocation of this macro below. Artifact events, OBS encoding and FORMAT
+/// tags are derived from the registry. The command line flag for omitting the bias
+/// (`--omit-*` in `cli.rs`) has to be added by hand, and passed on via `BiasFlags`.
+macro_rules! register_biases {
+    ($($field:ident: $bias:ty),+ $(,)?) => {
+        #[derive(Default, Getters, Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+        pub(crate) struct Biases {
+            $(
+                #[getset(get = "pub(crate)")]
+                $field: $bias,
+            )+
         }
 
-        let strand_biases = if consider_strand_bias {
-            StrandBias::iter().collect_vec()
-        } else {
-            vec![StrandBias::None]
-        };
-        let read_position_biases = if consider_read_position_bias {
-            ReadPositionBias::iter().collect_vec()
-        } else {
-            vec![ReadPositionBias::None]
-        };
-        let read_orientation_biases = if consider_read_orientation_bias {
-            ReadOrientationBias::iter().collect_vec()
-        } else {
-            vec![ReadOrientationBias::None]
-        };
-        let softclip_biases = if consider_softclip_bias {
-            SoftclipBias::iter().collect_vec()
-        } else {
-            vec![SoftclipBias::None]
-        };
-        let divindel_biases = if consider_divindel_bias {
-            DivIndelBias::values(min_divindel_other_rate)
-        } else {
-            vec![DivIndelBias::None]
-        };
-
-        Box::new(
-            strand_biases
-                .into_iter()
-                .cartesian_product(read_orientation_biases.into_iter())
-                .cartesian_product(read_position_biases.into_iter())
-                .cartesian_product(softclip_biases.into_iter())
-                .cartesian_product(divindel_biases.into_iter())
-                .filter_map(|((((sb, rob), rpb), scb), dib)| {
-                    if [
-                        sb.is_artifact(),
-                        rob.is_artifact(),
-                        rpb.is_artifact(),
-                        scb.is_artifact(),
-                        dib.is_artifact(),
-                    ]
-                    .iter()
-                    .map(|artifact| if *artifact { 1 } else { 0 })
-                    .sum::<usize>()
-                        == 1
-                    {
-                        Some(
-                            BiasesBuilder::default()
-                                .strand_bias(sb)
-                                .read_orientation_bias(rob)
-                                .read_position_bias(rpb)
-                                .softclip_bias(scb)
-                                .divindel_bias(dib)
-                                .build()
-                                .unwrap(),
-                        )
-                    } else {
-                        None
+        #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub(crate) struct BiasFlags {
+            $(pub(crate) $field: bool,)+
+        }
+
+        impl BiasFlags {
+            /// Flags denoting which biases are applicable to the given variant.
+            pub(crate) fn applicable(context: &BiasContext) -> Self {
+                BiasFlags {
+                    $($field: <$bias>::is_applicable(context),)+
+                }
+            }
+
+            /// Flags denoting which of the biases in self are not set in other.
+            pub(crate) fn without(&self, other: &BiasFlags) -> Self {
+                BiasFlags {
+                    $($field: self.$field && !other.$field,)+
+                }
+            }
+        }
+
+        #[cfg(test)]
+        impl BiasFlags {
+            /// Flags with all registered biases set.
+            fn all() -> Self {
+                BiasFlags {
+                    $($field: true,)+
+                }
+            }
+        }
+
+        #[cfg(test)]
+        impl Biases {
+            /// Number of artifact values of each registered bias.
+            fn artifact_value_counts(params: &BiasParams) -> Vec<usize> {
+                vec![$(
+                    <$bias>::values(params)
+                        .iter()
+                        .filter(|value| value.is_artifact())
+                        .count(),
+                )+]
+            }
+        }
+
+        impl Biases {
+            pub(crate) fn all_artifact_combinations(
+                considered: &BiasFlags,
+                params: &BiasParams,
+            ) -> Box<dyn Iterator<Item = Self>> {
+                // METHOD: each combination contains exactly one artifact, all other biases are
+                // set to their non-artifact value. Hence, the cartesian product of the values of
+                // all considered biases reduces to the union of their artifact values.
+                let mut combinations = Vec::new();
+                $(
+                    if considered.$field {
+                        for value in <$bias>::values(params) {
+                            if value.is_artifact() {
+                                combinations.push(Biases {
+                                    $field: value,
+                                    ..Self::none()
+                                });
+                            }
+                        }
                     }
-                }),
-        )
-    }
+                )+
+                Box::new(combinations.into_iter())
+            }
 
-    pub(crate) fn none() -> Self {
-        BiasesBuilder::default()
-            .strand_bias(StrandBias::None)
-            .read_orientation_bias(ReadOrientationBias::None)
-            .read_position_bias(ReadPositionBias::None)
-            .softclip_bias(SoftclipBias::None)
-            .divindel_bias(DivIndelBias::None)
-            .build()
-            .unwrap()
-    }
+            pub(crate) fn none() -> Self {
+                Self::default()
+            }
 
-    pub(crate) fn is_possible(
-        &self,
-        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
-    ) -> bool {
-        self.strand_bias.is_possible(pileups)
-            && self.read_orientation_bias.is_possible(pileups)
-            && self.read_position_bias.is_possible(pileups)
-            && self.softclip_bias.is_possible(pileups)
-            && self.divindel_bias.is_possible(pileups)
-    }
+            /// Biases as they apply to the given sample.
+            pub(crate) fn for_sample(&self, sample: usize) -> Self {
+                Biases {
+                    $($field: self.$field.for_sample(sample),)+
+                }
+            }
 
-    pub(crate) fn is_informative(
-        &self,
-        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
-    ) -> bool {
-        self.strand_bias.is_informative(pileups)
-            && self.read_orientation_bias.is_informative(pileups)
-            && self.read_position_bias.is_informative(pileups)
-            && self.softclip_bias.is_informative(pileups)
-            && self.divindel_bias.is_informative(pileups)
-    }
+            /// Header entries for the FORMAT tags of all registered biases.
+            pub(crate) fn format_header_entries() -> Vec<String> {
+                vec![$(
+                    format!(
+                        "##FORMAT=<ID={},Number=A,Type=String,Description=\"{}\">",
+                        <$bias>::format_tag(),
+                        <$bias>::format_description(),
+                    ),
+                )+]
+            }
 
-    pub(crate) fn is_likely(
-        &self,
-        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
-    ) -> bool {
-        self.strand_bias.is_likely(pileups)
-            && self.read_orientation_bias.is_likely(pileups)
-            && self.read_position_bias.is_likely(pileups)
-            && self.softclip_bias.is_likely(pileups)
-            && self.divindel_bias.is_likely(pileups)
-    }
+            /// FORMAT tags of all registered biases.
+            pub(crate) fn format_tags() -> Vec<&'static str> {
+                vec![$(<$bias>::format_tag(),)+]
+            }
 
-    pub(crate) fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
-        //dbg!(self.strand_bias.prob(observation), self.read_orientation_bias.prob(observation), self.read_position_bias.prob(observation));
-        self.strand_bias.prob(observation)
-            + self.read_orientation_bias.prob(observation)
-            + self.read_position_bias.prob(observation)
-            + self.softclip_bias.prob(observation)
-            + self.divindel_bias.prob(observation)
-    }
+            /// FORMAT tag values of all registered biases.
+            pub(crate) fn format_symbols(&self) -> Vec<u8> {
+                vec![$(self.$field.format_symbol(),)+]
+            }
+
+            /// Summary of the observation fields of all registered biases, as used in the
+            /// OBS FORMAT tag.
+            pub(crate) fn observation_symbols(
+                observation: &Observation<ReadPosition, IndelOperations>,
+            ) -> String {
+                let mut symbols = String::new();
+                $(
+                    if let Some(symbol) = <$bias>::observation_symbol(observation) {
+                        symbols.push(symbol);
+                    }
+                )+
+                symbols
+            }
+
+            pub(crate) fn is_possible(
+                &self,
+                pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+            ) -> bool {
+                $(self.$field.is_possible(pileups))&&+
+            }
+
+            pub(crate) fn is_informative(
+                &self,
+                pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+            ) -> bool {
+                $(self.$field.is_informative(pileups))&&+
+            }
+
+            pub(crate) fn is_likely(
+                &self,
+                pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+            ) -> bool {
+                $(self.$field.is_likely(pileups))&&+
+            }
+
+            pub(crate) fn prob(
+                &self,
+                observation: &Observation<ReadPosition, IndelOperations>,
+            ) -> LogProb {
+                LogProb::ln_one() $(+ self.$field.prob(observation))+
+            }
 
-    pub(crate) fn prob_any(
-        &self,
-        observation: &Observation<ReadPosition, IndelOperations>,
-    ) -> LogProb {
-        self.strand_bias.prob_any(observation)
-            + self.read_orientation_bias.prob_any(observation)
-            + self.read_position_bias.prob_any(observation)
-            + self.softclip_bias.prob_any(observation)
-            + self.divindel_bias.prob_any(observation)
+            pub(crate) fn prob_any(
+                &self,
+                observation: &Observation<ReadPosition, IndelOperations>,
+            ) -> LogProb {
+                LogProb::ln_one() $(+ self.$field.prob_any(observation))+
+            }
+
+            pub(crate) fn is_artifact(&self) -> bool {
+                $(self.$field.is_artifact())||+
+            }
+
+            pub(crate) fn learn_parameters(
+                &mut self,
+                pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+                context: &BiasContext,
+            ) {
+                $(self.$field.learn_parameters(pileups, context);)+
+            }
+        }
+    };
+}
+
+register_biases! {
+    strand_bias: StrandBias,
+    read_orientation_bias: ReadOrientationBias,
+    ffpe_bias: FfpeBias,
+    read_position_bias: ReadPositionBias,
+    softclip_bias: SoftclipBias,
+    divindel_bias: DivIndelBias,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn params() -> BiasParams {
+        BiasParams::new(0.05)
     }
 
-    pub(crate) fn is_artifact(&self) -> bool {
-        self.strand_bias.is_artifact()
-            || self.read_orientation_bias.is_artifact()
-            || self.read_position_bias.is_artifact()
-            || self.softclip_bias.is_artifact()
-            || self.divindel_bias.is_artifact()
+    #[test]
+    fn test_format_tags_unique() {
+        let tags = Biases::format_tags();
+        assert_eq!(tags.len(), tags.iter().collect::<HashSet<_>>().len());
+        assert_eq!(Biases::format_header_entries().len(), tags.len());
+        assert_eq!(Biases::none().format_symbols().len(), tags.len());
     }
 
-    pub(crate) fn learn_parameters(
-        &mut self,
-        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
-    ) {
-        self.divindel_bias.learn_parameters(pileups)
+    #[test]
+    fn test_artifact_combinations() {
+        let combinations =
+            Biases::all_artifact_combinations(&BiasFlags::all(), &params()).collect::<Vec<_>>();
+        let n_artifact_values: usize = Biases::artifact_value_counts(&params()).iter().sum();
+        assert_eq!(combinations.len(), n_artifact_values);
+        for biases in &combinations {
+            assert!(biases.is_artifact());
+            // exactly one bias deviates from its non-artifact value
+            let n_set = biases
+                .format_symbols()
+                .iter()
+                .zip(Biases::none().format_symbols())
+                .filter(|(symbol, none_symbol)| **symbol != *none_symbol)
+                .count();
+            assert_eq!(n_set, 1);
+        }
+        assert!(!Biases::none().is_artifact());
+
+        // biases that are not considered do not contribute
+        let considered = BiasFlags::all().without(&BiasFlags {
+            strand_bias: true,
+            ..Default::default()
+        });
+        assert!(!considered.strand_bias);
+        assert!(Biases::all_artifact_combinations(&considered, &params())
+            .all(|biases| !biases.strand_bias().is_artifact()));
+        assert_eq!(
+            Biases::all_artifact_combinations(&BiasFlags::default(), &params()).count(),
+            0
+        );
     }
 }
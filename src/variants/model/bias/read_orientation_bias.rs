@@ -1,9 +1,10 @@
 use bio::stats::probs::LogProb;
 use bio_types::sequence::SequenceReadPairOrientation;
+use strum::IntoEnumIterator;
 
 use crate::utils::PROB_05;
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
-use crate::variants::model::bias::Bias;
+use crate::variants::model::bias::{Bias, BiasContext, BiasParams};
 
 #[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, EnumIter, Hash)]
 pub(crate) enum ReadOrientationBias {
@@ -19,6 +20,45 @@ impl Default for ReadOrientationBias {
 }
 
 impl Bias for ReadOrientationBias {
+    fn values(_params: &BiasParams) -> Vec<Self> {
+        ReadOrientationBias::iter().collect()
+    }
+
+    fn is_applicable(context: &BiasContext) -> bool {
+        context.is_snv_or_mnv
+    }
+
+    fn format_tag() -> &'static str {
+        "ROB"
+    }
+
+    fn format_description() -> &'static str {
+        "Read orientation bias estimate: > indicates that ALT allele is associated with \
+         F1R2 orientation, < indicates that ALT allele is associated with F2R1 orientation, \
+         . indicates no read orientation bias. Read orientation bias is indicative of Guanin \
+         oxidation artifacts. Probability for read orientation bias is captured by the ARTIFACT \
+         event (PROB_ARTIFACT)."
+    }
+
+    fn format_symbol(&self) -> u8 {
+        match self {
+            ReadOrientationBias::None => b'.',
+            ReadOrientationBias::F1R2 => b'>',
+            ReadOrientationBias::F2R1 => b'<',
+        }
+    }
+
+    fn observation_symbol(
+        observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> Option<char> {
+        Some(match observation.read_orientation {
+            SequenceReadPairOrientation::F1R2 => '>',
+            SequenceReadPairOrientation::F2R1 => '<',
+            SequenceReadPairOrientation::None => '*',
+            _ => '!',
+        })
+    }
+
     fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
         match (self, observation.read_orientation) {
             (ReadOrientationBias::None, SequenceReadPairOrientation::F1R2) => *PROB_05, // normal
@@ -1,12 +1,28 @@
 use bio::stats::probs::LogProb;
+use itertools::Itertools;
+use ordered_float::NotNan;
 
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
-use crate::variants::model::bias::Bias;
+use crate::variants::model::bias::{Bias, BiasContext, BiasParams};
 
-#[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, EnumIter, Hash)]
+/// Relative distance to the read end below which an observation is summarized as being
+/// at a read end.
+const READ_END_DISTANCE: f64 = 0.1;
+/// Minimum and maximum rate of the exponential decay of artifacts with the distance to the read end.
+const MIN_RATE: f64 = 2.0;
+const MAX_RATE: f64 = 100.0;
+const EM_ITERATIONS: usize = 20;
+
+/// Bias caused by systematic sequencing errors near read ends (e.g. in specific cycles).
+#[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, Hash)]
 pub(crate) enum ReadPositionBias {
     None,
-    Some,
+    Some {
+        /// Rate of the exponential decay of ALT observations with the distance to the read end.
+        rate: NotNan<f64>,
+        /// Fraction of ALT observations that are enriched near read ends.
+        weight: NotNan<f64>,
+    },
 }
 
 impl Default for ReadPositionBias {
@@ -15,20 +31,239 @@ impl Default for ReadPositionBias {
     }
 }
 
+/// Density of an exponential distribution with the given rate, truncated to [0, 1].
+fn end_enrichment_density(rate: f64, end_distance: f64) -> f64 {
+    rate * (-rate * end_distance).exp() / (1.0 - (-rate).exp())
+}
+
+impl ReadPositionBias {
+    /// Density of the given relative read position under this bias, in relation to a
+    /// uniform distribution of read positions.
+    fn density(&self, end_distance: f64) -> f64 {
+        match self {
+            ReadPositionBias::None => 1.0,
+            ReadPositionBias::Some { rate, weight } => {
+                // METHOD: mixture of ALT observations that are enriched near read ends and ALT
+                // observations that are uniformly distributed over the read.
+                **weight * end_enrichment_density(**rate, end_distance) + (1.0 - **weight)
+            }
+        }
+    }
+}
+
 impl Bias for ReadPositionBias {
+    fn values(_params: &BiasParams) -> Vec<Self> {
+        vec![
+            ReadPositionBias::None,
+            ReadPositionBias::Some {
+                rate: NotNan::new(MIN_RATE).unwrap(),
+                weight: NotNan::new(1.0).unwrap(),
+            },
+        ]
+    }
+
+    fn is_applicable(context: &BiasContext) -> bool {
+        context.is_snv_or_mnv
+    }
+
+    fn format_tag() -> &'static str {
+        "RPB"
+    }
+
+    fn format_description() -> &'static str {
+        "Read position bias estimate: ^ indicates that ALT allele is enriched near \
+         read ends, . indicates that there is no read position bias. \
+         Read position bias is indicative of systematic sequencing errors, e.g. in specific cycles. \
+         Probability for read position bias is captured by the ARTIFACT \
+         event (PROB_ARTIFACT)."
+    }
+
+    fn format_symbol(&self) -> u8 {
+        match self {
+            ReadPositionBias::None => b'.',
+            ReadPositionBias::Some { .. } => b'^',
+        }
+    }
+
+    fn observation_symbol(
+        observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> Option<char> {
+        Some(match observation.read_position.end_distance() {
+            Some(end_distance) if end_distance < READ_END_DISTANCE => '^',
+            _ => '*',
+        })
+    }
+
     fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
-        match (self, observation.read_position) {
-            (ReadPositionBias::None, _) => observation.prob_hit_base, // normal
-            (ReadPositionBias::Some, ReadPosition::Major) => LogProb::ln_one(), // bias
-            (ReadPositionBias::Some, ReadPosition::Some) => LogProb::ln_zero(), // no bias
+        match observation.read_position.end_distance() {
+            Some(end_distance) => LogProb(self.density(end_distance).ln()),
+            None => LogProb::ln_one(),
         }
     }
 
-    fn prob_any(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
-        observation.prob_hit_base
+    fn prob_any(&self, _observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
+        LogProb::ln_one()
     }
 
     fn is_artifact(&self) -> bool {
         *self != ReadPositionBias::None
     }
+
+    fn is_informative(&self, _pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) -> bool {
+        match self {
+            ReadPositionBias::None => true,
+            // METHOD: only consider the bias if the majority of ALT observations is
+            // attributed to the enrichment near read ends.
+            ReadPositionBias::Some { weight, .. } => **weight > 0.5,
+        }
+    }
+
+    fn is_bias_evidence(&self, observation: &Observation<ReadPosition, IndelOperations>) -> bool {
+        observation
+            .read_position
+            .end_distance()
+            .map_or(false, |end_distance| self.density(end_distance) > 1.0)
+    }
+
+    fn learn_parameters(
+        &mut self,
+        pileups: &[Vec<Observation<ReadPosition, IndelOperations>>],
+        _context: &BiasContext,
+    ) {
+        if let ReadPositionBias::Some {
+            ref mut rate,
+            ref mut weight,
+        } = self
+        {
+            let end_distances = pileups
+                .iter()
+                .flatten()
+                .filter(Self::is_strong_obs)
+                .filter_map(|obs| obs.read_position.end_distance())
+                .collect_vec();
+            if end_distances.is_empty() {
+                *weight = NotNan::new(0.0).unwrap();
+                return;
+            }
+
+            // METHOD: learn the mixture of enriched and uniformly distributed ALT observations
+            // via expectation maximization. The rate is estimated via the weighted mean distance
+            // to the read end, neglecting the truncation of the exponential distribution.
+            let mut current_rate = MIN_RATE;
+            let mut current_weight = 0.5;
+            for _ in 0..EM_ITERATIONS {
+                let responsibilities = end_distances
+                    .iter()
+                    .map(|end_distance| {
+                        let enriched =
+                            current_weight * end_enrichment_density(current_rate, *end_distance);
+                        enriched / (enriched + (1.0 - current_weight))
+                    })
+                    .collect_vec();
+                let total: f64 = responsibilities.iter().sum();
+                current_weight = total / end_distances.len() as f64;
+                if total > 0.0 {
+                    let mean_distance = responsibilities
+                        .iter()
+                        .zip(end_distances.iter())
+                        .map(|(r, d)| r * d)
+                        .sum::<f64>()
+                        / total;
+                    current_rate = (1.0 / mean_distance).clamp(MIN_RATE, MAX_RATE);
+                }
+            }
+
+            *rate = NotNan::new(current_rate).unwrap();
+            *weight = NotNan::new(current_weight).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::variants::model::tests::observation;
+
+    fn obs(end_distance: f64) -> Observation<ReadPosition, IndelOperations> {
+        let mut obs = observation(
+            LogProb(0.99_f64.ln()),
+            LogProb(0.99_f64.ln()),
+            LogProb(0.0001_f64.ln()),
+        );
+        let bin = (end_distance * 256.0).floor().clamp(0.0, 255.0);
+        obs.read_position = ReadPosition::Some(bin as u8);
+        obs
+    }
+
+    /// Deterministic sample of the given size from the mixture of end enriched and uniformly
+    /// distributed read positions (via equally spaced quantiles).
+    fn pileup(n: usize, rate: f64, weight: f64) -> Vec<Observation<ReadPosition, IndelOperations>> {
+        let n_enriched = (n as f64 * weight) as usize;
+        let n_uniform = n - n_enriched;
+        let enriched = (0..n_enriched).map(|i| {
+            let q = (i as f64 + 0.5) / n_enriched as f64;
+            -(1.0 - q * (1.0 - (-rate).exp())).ln() / rate
+        });
+        let uniform = (0..n_uniform).map(|i| (i as f64 + 0.5) / n_uniform as f64);
+        enriched.chain(uniform).map(obs).collect()
+    }
+
+    fn learned(pileups: &[Vec<Observation<ReadPosition, IndelOperations>>]) -> ReadPositionBias {
+        let mut bias = ReadPositionBias::values(&BiasParams::new(0.05))
+            .into_iter()
+            .find(|bias| bias.is_artifact())
+            .unwrap();
+        bias.learn_parameters(pileups, &BiasContext::new(true, None, vec![false]));
+        bias
+    }
+
+    #[test]
+    fn test_learn_parameters() {
+        for &(true_rate, true_weight) in &[(10.0, 0.7), (20.0, 0.8), (50.0, 0.9)] {
+            let bias = learned(&[pileup(1000, true_rate, true_weight)]);
+            match bias {
+                ReadPositionBias::Some { rate, weight } => {
+                    assert_relative_eq!(*rate, true_rate, epsilon = 1.0);
+                    assert_relative_eq!(*weight, true_weight, epsilon = 0.02);
+                }
+                ReadPositionBias::None => panic!("bug: expecting read position bias"),
+            }
+            assert!(bias.is_informative(&[]));
+        }
+    }
+
+    #[test]
+    fn test_learn_parameters_uniform() {
+        let bias = learned(&[pileup(1000, 20.0, 0.0)]);
+        match bias {
+            ReadPositionBias::Some { weight, .. } => assert!(*weight < 0.2),
+            ReadPositionBias::None => panic!("bug: expecting read position bias"),
+        }
+        assert!(!bias.is_informative(&[]));
+    }
+
+    #[test]
+    fn test_learn_parameters_empty() {
+        let bias = learned(&[vec![]]);
+        assert!(!bias.is_informative(&[]));
+    }
+
+    #[test]
+    fn test_prob() {
+        let bias = ReadPositionBias::Some {
+            rate: NotNan::new(20.0).unwrap(),
+            weight: NotNan::new(0.8).unwrap(),
+        };
+        // ALT observations at read ends are more likely under the bias than at the read center
+        assert!(bias.prob(&obs(0.0)) > LogProb::ln_one());
+        assert!(bias.prob(&obs(0.99)) < LogProb::ln_one());
+        assert!(bias.is_bias_evidence(&obs(0.0)));
+        assert!(!bias.is_bias_evidence(&obs(0.99)));
+        // observations without read position are neutral
+        let mut unknown = obs(0.0);
+        unknown.read_position = ReadPosition::None;
+        assert_eq!(bias.prob(&unknown), LogProb::ln_one());
+        assert_eq!(ReadPositionBias::None.prob(&obs(0.0)), LogProb::ln_one());
+    }
 }
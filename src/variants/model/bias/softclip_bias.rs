@@ -1,7 +1,8 @@
 use bio::stats::probs::LogProb;
+use strum::IntoEnumIterator;
 
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition};
-use crate::variants::model::bias::Bias;
+use crate::variants::model::bias::{Bias, BiasContext, BiasParams};
 
 #[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, EnumIter, Hash)]
 pub(crate) enum SoftclipBias {
@@ -16,6 +17,43 @@ impl Default for SoftclipBias {
 }
 
 impl Bias for SoftclipBias {
+    fn values(_params: &BiasParams) -> Vec<Self> {
+        SoftclipBias::iter().collect()
+    }
+
+    fn is_applicable(context: &BiasContext) -> bool {
+        context.is_snv_or_mnv
+    }
+
+    fn format_tag() -> &'static str {
+        "SCB"
+    }
+
+    fn format_description() -> &'static str {
+        "Softclip bias estimate: $ indicates that ALT allele is associated with \
+         with softclips in the same alignment, . indicates that there is no softclip bias. \
+         Softclip bias is indicative of systematic alignment errors, cause by a part of the read \
+         that does not properly align to the reference (and is thus soft clipped). Note that \
+         softclips can also be caused by structural variants. However, structural variants on the \
+         same haplotype as e.g. an SNV should not cause a softclip bias, because there will usually \
+         still be reads that do not reach the SV, thereby providing evidence against a softclip \
+         bias. Probability for softclip bias is captured by the ARTIFACT \
+         event (PROB_ARTIFACT)."
+    }
+
+    fn format_symbol(&self) -> u8 {
+        match self {
+            SoftclipBias::None => b'.',
+            SoftclipBias::Some => b'$',
+        }
+    }
+
+    fn observation_symbol(
+        observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> Option<char> {
+        Some(if observation.softclipped { '$' } else { '.' })
+    }
+
     fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
         match (self, observation.softclipped) {
             (SoftclipBias::Some, true) => LogProb::ln_one(),
@@ -1,8 +1,9 @@
 use bio::stats::probs::LogProb;
+use strum::IntoEnumIterator;
 
 use crate::utils::PROB_05;
 use crate::variants::evidence::observation::{IndelOperations, Observation, ReadPosition, Strand};
-use crate::variants::model::bias::Bias;
+use crate::variants::model::bias::{Bias, BiasParams};
 
 #[derive(Copy, Clone, PartialOrd, PartialEq, Eq, Debug, Ord, EnumIter, Hash)]
 pub(crate) enum StrandBias {
@@ -18,6 +19,40 @@ impl Default for StrandBias {
 }
 
 impl Bias for StrandBias {
+    fn values(_params: &BiasParams) -> Vec<Self> {
+        StrandBias::iter().collect()
+    }
+
+    fn format_tag() -> &'static str {
+        "SB"
+    }
+
+    fn format_description() -> &'static str {
+        "Strand bias estimate: + indicates that ALT allele is associated with \
+         forward strand, - indicates that ALT allele is associated with reverse strand, \
+         . indicates no strand bias. Strand bias is indicative for systematic sequencing \
+         errors. Probability for strand bias is captured by the ARTIFACT event (PROB_ARTIFACT)."
+    }
+
+    fn format_symbol(&self) -> u8 {
+        match self {
+            StrandBias::None => b'.',
+            StrandBias::Forward => b'+',
+            StrandBias::Reverse => b'-',
+        }
+    }
+
+    fn observation_symbol(
+        observation: &Observation<ReadPosition, IndelOperations>,
+    ) -> Option<char> {
+        Some(match observation.strand {
+            Strand::Both => '*',
+            Strand::Reverse => '-',
+            Strand::Forward => '+',
+            _ => panic!("bug: unknown strandedness"),
+        })
+    }
+
     fn prob(&self, observation: &Observation<ReadPosition, IndelOperations>) -> LogProb {
         match (self, observation.strand) {
             (StrandBias::Forward, Strand::Forward) => LogProb::ln_one(),
@@ -138,6 +138,7 @@ impl Deref for ContinuousAlleleFreqs {
     Display,
     Debug,
     Clone,
+    PartialEq,
     Serialize,
     Deserialize,
     EnumString,
@@ -279,12 +280,11 @@ mod tests {
             .prob_sample_alt(LogProb::ln_one())
             .prob_overlap(LogProb::ln_one())
             .read_orientation(SequenceReadPairOrientation::None)
-            .read_position(ReadPosition::Some)
+            .read_position(ReadPosition::None)
             .strand(Strand::Both)
             .softclipped(false)
             .indel_operations(IndelOperations::None)
             .paired(true)
-            .prob_hit_base(LogProb::from(0.01f64.ln()))
             .build()
             .unwrap()
     }
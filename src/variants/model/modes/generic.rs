@@ -1,9 +1,11 @@
 use std::cmp;
 
+use anyhow::Result;
 use bio::stats::bayesian::model::{Likelihood, Model, Posterior, Prior};
 use bio::stats::LogProb;
 use derive_builder::Builder;
 use itertools::Itertools;
+use itertools_num::linspace;
 use vec_map::VecMap;
 
 use crate::grammar;
@@ -13,7 +15,8 @@ use crate::variants::model::likelihood;
 use crate::variants::model::{bias::Biases, AlleleFreq, Contamination, VariantType};
 use crate::variants::sample::Pileup;
 
-#[derive(new, Clone, Debug)]
+#[derive(new, Clone, Debug, CopyGetters)]
+#[getset(get_copy = "pub(crate)")]
 pub(crate) struct Snv {
     refbase: u8,
     altbase: u8,
@@ -121,18 +124,23 @@ impl GenericPosterior {
             .collect()
     }
 
-    fn density<F: FnMut(&<Self as Posterior>::BaseEvent, &<Self as Posterior>::Data) -> LogProb>(
+    /// Density of the given VAF tree node. Each leaf (i.e. each evaluated allele frequency
+    /// combination) is passed to `leaf` together with its integration weight, which returns
+    /// the weighted contribution of the leaf.
+    #[allow(clippy::too_many_arguments)]
+    fn density<F: FnMut(&<Self as Posterior>::BaseEvent, LogProb) -> LogProb>(
         &self,
         vaf_tree_node: &grammar::vaftree::Node,
         base_events: &mut VecMap<likelihood::Event>,
         sample_grid_points: &[usize],
         data: &<Self as Posterior>::Data,
         biases: &Biases,
-        joint_prob: &mut F,
+        weight: LogProb,
+        leaf: &mut F,
     ) -> LogProb {
-        let mut subdensity = |base_events: &mut VecMap<likelihood::Event>| {
+        let mut subdensity = |base_events: &mut VecMap<likelihood::Event>, weight: LogProb| {
             if vaf_tree_node.is_leaf() {
-                joint_prob(&base_events.values().cloned().collect(), data)
+                leaf(&base_events.values().cloned().collect(), weight)
             } else if vaf_tree_node.is_branching() {
                 LogProb::ln_sum_exp(
                     &vaf_tree_node
@@ -145,7 +153,8 @@ impl GenericPosterior {
                                 sample_grid_points,
                                 data,
                                 biases,
-                                joint_prob,
+                                weight,
+                                leaf,
                             )
                         })
                         .collect_vec(),
@@ -157,7 +166,8 @@ impl GenericPosterior {
                     sample_grid_points,
                     data,
                     biases,
-                    joint_prob,
+                    weight,
+                    leaf,
                 )
             }
         };
@@ -170,7 +180,7 @@ impl GenericPosterior {
                         *sample,
                         likelihood::Event {
                             allele_freq,
-                            biases: biases.clone(),
+                            biases: biases.for_sample(*sample),
                         },
                     );
                 };
@@ -179,7 +189,7 @@ impl GenericPosterior {
                     grammar::VAFSpectrum::Set(vafs) => {
                         if vafs.len() == 1 {
                             push_base_event(*vafs.iter().next().unwrap(), base_events);
-                            subdensity(base_events)
+                            subdensity(base_events, weight)
                         } else {
                             LogProb::ln_sum_exp(
                                 &vafs
@@ -187,7 +197,7 @@ impl GenericPosterior {
                                     .map(|vaf| {
                                         let mut base_events = base_events.clone();
                                         push_base_event(*vaf, &mut base_events);
-                                        subdensity(&mut base_events)
+                                        subdensity(&mut base_events, weight)
                                     })
                                     .collect_vec(),
                             )
@@ -195,15 +205,18 @@ impl GenericPosterior {
                     }
                     grammar::VAFSpectrum::Range(vafs) => {
                         let n_obs = data.pileups[*sample].len();
-                        LogProb::ln_simpsons_integrate_exp(
-                            |_, vaf| {
+                        LogProb::ln_sum_exp(
+                            &simpsons_rule(
+                                *vafs.observable_min(n_obs),
+                                *vafs.observable_max(n_obs),
+                                sample_grid_points[*sample],
+                            )
+                            .map(|(vaf, vaf_weight)| {
                                 let mut base_events = base_events.clone();
                                 push_base_event(AlleleFreq(vaf), &mut base_events);
-                                subdensity(&mut base_events)
-                            },
-                            *vafs.observable_min(n_obs),
-                            *vafs.observable_max(n_obs),
-                            sample_grid_points[*sample],
+                                subdensity(&mut base_events, weight + vaf_weight)
+                            })
+                            .collect_vec(),
                         )
                     }
                 }
@@ -221,32 +234,29 @@ impl GenericPosterior {
                         LogProb::ln_zero()
                     } else {
                         // skip this node
-                        subdensity(base_events)
+                        subdensity(base_events, weight)
                     }
                 } else if *positive {
                     // no SNV but branch requires the defined SNV, hence abort with prob 0
                     LogProb::ln_zero()
                 } else {
                     // skip this node, as we don't have the defined SNV but it is negated
-                    subdensity(base_events)
+                    subdensity(base_events, weight)
                 }
             }
         }
     }
 }
 
-impl Posterior for GenericPosterior {
-    type BaseEvent = Vec<likelihood::Event>;
-    type Event = model::Event;
-    type Data = Data;
-
-    fn compute<F: FnMut(&Self::BaseEvent, &Self::Data) -> LogProb>(
+impl GenericPosterior {
+    /// Density of the given event. See `density` for the meaning of `leaf`.
+    fn event_density<F: FnMut(&<Self as Posterior>::BaseEvent, LogProb) -> LogProb>(
         &self,
-        event: &Self::Event,
-        data: &Self::Data,
-        joint_prob: &mut F,
+        event: &model::Event,
+        data: &Data,
+        grid_points: &[usize],
+        leaf: &mut F,
     ) -> LogProb {
-        let grid_points = self.grid_points(&data.pileups);
         let vaf_tree = &event.vafs;
         let bias_prior = if event.is_artifact() {
             *PROB_05 + LogProb((1.0 / event.biases.len() as f64).ln())
@@ -266,19 +276,83 @@ impl Posterior for GenericPosterior {
                 .cartesian_product(vaf_tree)
                 .map(|(biases, node)| {
                     let mut base_events = VecMap::with_capacity(data.pileups.len());
-                    bias_prior
-                        + self.density(
-                            node,
-                            &mut base_events,
-                            &grid_points,
-                            data,
-                            biases,
-                            joint_prob,
-                        )
+                    self.density(
+                        node,
+                        &mut base_events,
+                        grid_points,
+                        data,
+                        biases,
+                        bias_prior,
+                        leaf,
+                    )
                 })
                 .collect_vec(),
         )
     }
+
+    /// Unnormalized posterior distribution of the allele frequency of each sample, marginalized
+    /// over the given events and the allele frequencies of all other samples.
+    /// Each evaluated allele frequency combination contributes its joint posterior (as given by
+    /// `joint_posterior`), weighted by its integration weight in the respective event.
+    pub(crate) fn allele_freq_marginals<'a, E, F>(
+        &self,
+        events: E,
+        data: &Data,
+        joint_posterior: F,
+    ) -> Vec<Vec<(AlleleFreq, LogProb)>>
+    where
+        E: IntoIterator<Item = &'a model::Event>,
+        F: Fn(&<Self as Posterior>::BaseEvent) -> LogProb,
+    {
+        let grid_points = self.grid_points(&data.pileups);
+        let mut marginals = vec![Vec::new(); data.pileups.len()];
+        for event in events {
+            self.event_density(event, data, &grid_points, &mut |base_event, weight| {
+                let prob = weight + joint_posterior(base_event);
+                for (marginal, sample_event) in marginals.iter_mut().zip(base_event.iter()) {
+                    marginal.push((sample_event.allele_freq, prob));
+                }
+                prob
+            });
+        }
+        marginals
+    }
+}
+
+impl Posterior for GenericPosterior {
+    type BaseEvent = Vec<likelihood::Event>;
+    type Event = model::Event;
+    type Data = Data;
+
+    fn compute<F: FnMut(&Self::BaseEvent, &Self::Data) -> LogProb>(
+        &self,
+        event: &Self::Event,
+        data: &Self::Data,
+        joint_prob: &mut F,
+    ) -> LogProb {
+        let grid_points = self.grid_points(&data.pileups);
+        self.event_density(event, data, &grid_points, &mut |base_event, weight| {
+            weight + joint_prob(base_event, data)
+        })
+    }
+}
+
+/// Grid points and log-scale integration weights of Simpson's rule over [a, b] with n
+/// (odd) points. Summing the weighted densities is equivalent to
+/// `LogProb::ln_simpsons_integrate_exp`.
+fn simpsons_rule(a: f64, b: f64, n: usize) -> impl Iterator<Item = (f64, LogProb)> {
+    assert_eq!(n % 2, 1, "n must be odd");
+    let scale = (b - a).ln() - ((n - 1) as f64).ln() - 3.0_f64.ln();
+    linspace(a, b, n).enumerate().map(move |(i, vaf)| {
+        let factor: f64 = if i == 0 || i == n - 1 {
+            1.0
+        } else if i % 2 == 1 {
+            4.0
+        } else {
+            2.0
+        };
+        (vaf, LogProb(factor.ln() + scale))
+    })
 }
 
 #[derive(Clone, Debug)]
@@ -396,5 +470,112 @@ impl model::prior::UpdatablePrior for FlatPrior {
         self.universe = Some(universe);
     }
 
-    fn set_variant_type(&mut self, _: VariantType) {}
+    fn set_variant_type(&mut self, _: VariantType, _: Option<&model::prior::Site>) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_population_allele_freq(&mut self, _: Option<AlleleFreq>) {}
+
+    fn set_somatic_hotspot_weight(&mut self, _: Option<f64>) {}
+}
+
+impl model::prior::LearnablePrior for FlatPrior {
+    fn parameters(&self) -> Vec<(model::prior::PriorParameter, f64)> {
+        Vec::new()
+    }
+
+    fn set_parameter(&mut self, _: model::prior::PriorParameter, _: f64) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::calling::variants::AlleleFreqPosterior;
+    use crate::variants::model::tests::observation;
+
+    fn events(events: &str) -> Vec<model::Event> {
+        let scenario: grammar::Scenario = serde_yaml::from_str(&format!(
+            r#"samples:
+  tumor:
+    resolution: 100
+    universe: "[0.0,1.0]"
+events:
+{}"#,
+            events
+        ))
+        .unwrap();
+        scenario
+            .vaftrees("all")
+            .unwrap()
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(name, vafs)| model::Event {
+                name,
+                vafs,
+                biases: vec![Biases::none()],
+            })
+            .collect()
+    }
+
+    fn data(n_obs: usize) -> Data {
+        let obs = observation(
+            LogProb(0.99_f64.ln()),
+            LogProb(0.5_f64.ln()),
+            LogProb(0.5_f64.ln()),
+        );
+        Data::new(vec![vec![obs; n_obs]], None)
+    }
+
+    fn posterior() -> GenericPosterior {
+        GenericPosterior::new(grammar::SampleInfo::from(vec![100]))
+    }
+
+    #[test]
+    fn test_allele_freq_marginals_binomial() {
+        // k ALT observations out of n, with a flat prior over [0, 1], yield a Beta(k+1, n-k+1)
+        // posterior (mode k/n = 0.25, 90% equal-tailed interval [0.132, 0.437]).
+        let (k, n) = (5.0, 20);
+        let events = events(r#"  present: "tumor:[0.0,1.0]""#);
+        let marginals = posterior().allele_freq_marginals(&events, &data(n), |allele_freqs| {
+            let f = *allele_freqs[0].allele_freq;
+            LogProb(k * f.ln() + (n as f64 - k) * (1.0 - f).ln())
+        });
+        assert_eq!(marginals.len(), 1);
+
+        let (map, _) = marginals[0]
+            .iter()
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .unwrap();
+        assert_relative_eq!(**map, 0.25, epsilon = 1e-9);
+
+        let posterior = AlleleFreqPosterior::new(marginals[0].clone());
+        let (lower, upper) = posterior.credible_interval(0.9);
+        // tolerance of one grid step
+        assert_relative_eq!(*lower, 0.132, epsilon = 0.05);
+        assert_relative_eq!(*upper, 0.437, epsilon = 0.05);
+    }
+
+    #[test]
+    fn test_allele_freq_marginals_weights() {
+        // A single allele frequency (set) and a range with the same (flat) joint posterior density:
+        // the point mass carries weight 1, whereas the grid points of the range together carry the
+        // width of the range (0.95, since the smallest observable allele frequency is 1/20).
+        let events = events(
+            r#"  none: "tumor:0.0"
+  present: "tumor:]0.0,1.0]""#,
+        );
+        let marginals =
+            posterior().allele_freq_marginals(&events, &data(20), |_| LogProb::ln_one());
+        let total = LogProb::ln_sum_exp(&marginals[0].iter().map(|(_, p)| *p).collect_vec());
+        let prob_absent = LogProb::ln_sum_exp(
+            &marginals[0]
+                .iter()
+                .filter(|(allele_freq, _)| **allele_freq == 0.0)
+                .map(|(_, p)| *p)
+                .collect_vec(),
+        );
+        assert_relative_eq!(total.exp(), 1.95, epsilon = 1e-9);
+        assert_relative_eq!((prob_absent - total).exp(), 1.0 / 1.95, epsilon = 1e-9);
+    }
 }
@@ -2,6 +2,7 @@ use std::cell::RefCell;
 use std::cmp;
 use std::collections::HashSet;
 use std::str;
+use std::sync::Arc;
 
 use anyhow::Result;
 use bio::stats::bayesian;
@@ -17,6 +18,7 @@ use statrs::distribution::{self, Discrete};
 
 use crate::errors;
 use crate::grammar;
+use crate::reference;
 use crate::variants::model::{bias::Biases, likelihood, AlleleFreq, VariantType};
 
 pub(crate) trait UpdatablePrior {
@@ -26,14 +28,147 @@ pub(crate) trait UpdatablePrior {
         ploidies: grammar::SampleInfo<Option<u32>>,
     );
 
-    fn set_variant_type(&mut self, variant_type: VariantType);
+    /// Set the variant type of the current variant. The site of the variant is used to look up
+    /// its sequence context (if context dependent mutation rates are defined).
+    fn set_variant_type(&mut self, variant_type: VariantType, site: Option<&Site>) -> Result<()>;
+
+    /// Set the population allele frequency of the current variant (None if unknown).
+    fn set_population_allele_freq(&mut self, allele_freq: Option<AlleleFreq>);
+
+    /// Set the somatic hotspot weight of the current variant (None if not a hotspot).
+    fn set_somatic_hotspot_weight(&mut self, weight: Option<f64>);
+}
+
+/// Genomic site of a variant.
+#[derive(new, Debug, Clone, Getters)]
+#[get = "pub(crate)"]
+pub(crate) struct Site {
+    contig: String,
+    /// 0-based position.
+    pos: u64,
+    alt_allele: Vec<u8>,
 }
 
 pub(crate) trait CheckablePrior {
     fn check(&self) -> Result<()>;
 }
 
+/// Hyperparameter of the prior that can be learned from the data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PriorParameter {
+    Heterozygosity,
+    GermlineMutationRate(usize),
+    SomaticEffectiveMutationRate(usize),
+    IndelFraction,
+    MnvFraction,
+    SvFraction,
+}
+
+impl PriorParameter {
+    /// Name of the parameter, as used in the BCF header.
+    pub(crate) fn name(&self, samplenames: &grammar::SampleInfo<String>) -> String {
+        match self {
+            PriorParameter::Heterozygosity => "heterozygosity".to_owned(),
+            PriorParameter::GermlineMutationRate(sample) => {
+                format!("germline_mutation_rate_{}", samplenames[*sample])
+            }
+            PriorParameter::SomaticEffectiveMutationRate(sample) => {
+                format!("somatic_effective_mutation_rate_{}", samplenames[*sample])
+            }
+            PriorParameter::IndelFraction => "indel_fraction".to_owned(),
+            PriorParameter::MnvFraction => "mnv_fraction".to_owned(),
+            PriorParameter::SvFraction => "sv_fraction".to_owned(),
+        }
+    }
+
+    /// Whether the parameter is a probability (and hence may not exceed 1).
+    pub(crate) fn is_probability(&self) -> bool {
+        !matches!(
+            self,
+            PriorParameter::GermlineMutationRate(_)
+                | PriorParameter::SomaticEffectiveMutationRate(_)
+        )
+    }
+
+    /// Variant type fraction parameter that affects the given variant type, if any.
+    pub(crate) fn variant_type_fraction(variant_type: &VariantType) -> Option<Self> {
+        match variant_type {
+            VariantType::Insertion(_) | VariantType::Deletion(_) | VariantType::Replacement => {
+                Some(PriorParameter::IndelFraction)
+            }
+            VariantType::Mnv => Some(PriorParameter::MnvFraction),
+            VariantType::Inversion | VariantType::Breakend | VariantType::Duplication => {
+                Some(PriorParameter::SvFraction)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) trait LearnablePrior {
+    /// Current values of all hyperparameters that are defined for this prior.
+    fn parameters(&self) -> Vec<(PriorParameter, f64)>;
+
+    fn set_parameter(&mut self, parameter: PriorParameter, value: f64);
+}
+
+/// Learn the given hyperparameters of the prior (empirical Bayes), by maximizing the given log
+/// marginal likelihood. The current values of the parameters serve as starting points.
+pub(crate) fn maximize_marginal_likelihood<P, F>(
+    mut prior: P,
+    parameters: &[PriorParameter],
+    mut log_marginal_likelihood: F,
+) -> Result<P>
+where
+    P: LearnablePrior + CheckablePrior + Clone,
+    F: FnMut(&P) -> Result<f64>,
+{
+    // METHOD: coordinate ascent on log scale, first with a coarse grid of factors spanning
+    // four orders of magnitude around the current value, then with a fine grid.
+    let mut best = log_marginal_likelihood(&prior)?;
+    for grid in &[
+        linspace(-2.0, 2.0, 9).collect_vec(),
+        linspace(-0.4, 0.4, 9).collect_vec(),
+    ] {
+        for parameter in parameters {
+            let current = prior
+                .parameters()
+                .into_iter()
+                .find(|(p, _)| p == parameter)
+                .expect("bug: parameter not defined in prior")
+                .1;
+            for exponent in grid {
+                let value = current * 10.0_f64.powf(*exponent);
+                if *exponent == 0.0 || value <= 0.0 || (parameter.is_probability() && value >= 1.0)
+                {
+                    continue;
+                }
+                let mut candidate = prior.clone();
+                candidate.set_parameter(*parameter, value);
+                if candidate.check().is_err() {
+                    continue;
+                }
+                let log_marginal = log_marginal_likelihood(&candidate)?;
+                if log_marginal > best {
+                    best = log_marginal;
+                    prior = candidate;
+                }
+            }
+        }
+    }
+    Ok(prior)
+}
+
 const SOMATIC_EPSILON: f64 = 0.0001;
+/// Number of parental cells that found a multi cell subclone. The smaller, the more the
+/// subclonal composition may drift away from the parent.
+const MULTICELL_FOUNDERS: u64 = 100;
+/// Founder configurations below this log probability are ignored.
+const MULTICELL_MIN_LN_PROB: f64 = -30.0;
+/// Minimum distance of population allele frequencies from 0 and 1. Reported frequencies of
+/// exactly 1 (e.g. due to rounding or small reference populations) would otherwise rule out
+/// all genotypes that are not homozygous alt.
+const POPULATION_ALLELE_FREQ_EPSILON: f64 = 0.0001;
 
 #[derive(Debug, Clone)]
 pub(crate) enum Inheritance {
@@ -78,6 +213,16 @@ pub(crate) struct Prior {
     #[builder(default)]
     variant_type: Option<VariantType>,
     #[builder(default)]
+    population_allele_freq: Option<AlleleFreq>,
+    #[builder(default)]
+    somatic_hotspot_weight: Option<f64>,
+    #[builder(default)]
+    context_mutation_rates: Option<grammar::ContextMutationRates>,
+    #[builder(default)]
+    reference_buffer: Option<Arc<reference::Buffer>>,
+    #[builder(default)]
+    context_mutation_rate: Option<f64>,
+    #[builder(default)]
     cache: RefCell<Cache>,
 }
 
@@ -95,6 +240,11 @@ impl Clone for Prior {
             cache: RefCell::default(),
             variant_type_fractions: self.variant_type_fractions.clone(),
             variant_type: self.variant_type.clone(),
+            population_allele_freq: self.population_allele_freq,
+            somatic_hotspot_weight: self.somatic_hotspot_weight,
+            context_mutation_rates: self.context_mutation_rates.clone(),
+            reference_buffer: self.reference_buffer.clone(),
+            context_mutation_rate: self.context_mutation_rate,
         }
     }
 }
@@ -247,17 +397,56 @@ impl Prior {
         )
     }
 
+    /// Factor for mutation rates and heterozygosity of the current variant: the variant type
+    /// fraction, scaled by the relative mutation rate of the sequence context (for SNVs).
+    fn variant_rate_factor(&self) -> f64 {
+        self.variant_type_fraction() * self.context_mutation_rate.unwrap_or(1.0)
+    }
+
+    /// Relative mutation rate of the sequence context of the given SNV, None if unknown.
+    fn lookup_context_mutation_rate(&self, site: &Site) -> Result<Option<f64>> {
+        if let (Some(rates), Some(reference_buffer)) =
+            (&self.context_mutation_rates, &self.reference_buffer)
+        {
+            let pos = *site.pos() as usize;
+            let seq = reference_buffer.seq(site.contig())?;
+            if pos == 0 || pos + 1 >= seq.len() || site.alt_allele().len() != 1 {
+                // no complete trinucleotide context available
+                return Ok(None);
+            }
+            Ok(Some(
+                rates.rate(&seq[pos - 1..pos + 2], site.alt_allele()[0]),
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
     fn vartype_somatic_effective_mutation_rate(&self, sample: usize) -> Option<f64> {
-        self.somatic_effective_mutation_rate[sample].map(|rate| rate * self.variant_type_fraction())
+        self.somatic_effective_mutation_rate[sample].map(|rate| {
+            let rate = rate * self.variant_rate_factor();
+            if let Some(weight) = self.somatic_hotspot_weight {
+                // METHOD: at hotspots, the somatic effective mutation rate is scaled by
+                // 1 + weight (e.g. the number of recurrent observations in COSMIC). The scaled
+                // rate is capped such that the probability for a somatic mutation with
+                // VAF >= SOMATIC_EPSILON (rate * (1 - eps) / (genome_size * eps), see
+                // prob_somatic_mutation) cannot exceed 1.
+                (rate * (1.0 + weight.max(0.0)))
+                    .min(self.genome_size.unwrap() * SOMATIC_EPSILON / (1.0 - SOMATIC_EPSILON))
+                    .max(rate)
+            } else {
+                rate
+            }
+        })
     }
 
     fn vartype_germline_mutation_rate(&self, sample: usize) -> Option<f64> {
-        self.germline_mutation_rate[sample].map(|rate| rate * self.variant_type_fraction())
+        self.germline_mutation_rate[sample].map(|rate| rate * self.variant_rate_factor())
     }
 
     fn vartype_heterozygosity(&self) -> Option<LogProb> {
         self.heterozygosity
-            .map(|het| LogProb((het.exp() * self.variant_type_fraction()).ln()))
+            .map(|het| LogProb((het.exp() * self.variant_rate_factor()).min(1.0).ln()))
     }
 
     fn has_somatic_variation(&self, sample: usize) -> bool {
@@ -308,7 +497,19 @@ impl Prior {
                         }
                     })
                     .collect_vec();
-                self.prob_population_germline(&population_samples, &germline_vafs, heterozygosity)
+                if let Some(population_allele_freq) = self.population_allele_freq {
+                    self.prob_population_hardy_weinberg(
+                        &population_samples,
+                        &germline_vafs,
+                        population_allele_freq,
+                    )
+                } else {
+                    self.prob_population_germline(
+                        &population_samples,
+                        &germline_vafs,
+                        heterozygosity,
+                    )
+                }
             } else {
                 LogProb::ln_one()
             };
@@ -443,11 +644,13 @@ impl Prior {
         };
         // METHOD: we take the absolute of the vaf because it can be negative (indicating a back mutation).
         if somatic_vaf.abs() <= SOMATIC_EPSILON {
-            LogProb::ln_simpsons_integrate_exp(
-                |_, vaf: f64| density(vaf.abs()),
-                SOMATIC_EPSILON,
-                1.0,
-                11,
+            // METHOD: the probability for a somatic mutation with VAF >= SOMATIC_EPSILON is the
+            // integral of the density over [SOMATIC_EPSILON, 1], which is
+            // rate * (1 / eps - 1) / genome_size (capped at 1 against numerical overshoot).
+            LogProb(
+                (somatic_effective_mutation_rate.ln() + (1.0 / SOMATIC_EPSILON - 1.0).ln()
+                    - self.genome_size.unwrap().ln())
+                .min(0.0),
             )
             .ln_one_minus_exp()
         } else {
@@ -500,6 +703,39 @@ impl Prior {
         }
     }
 
+    /// Somatic VAF of the parental cells that carry the somatic variant, and the prevalence
+    /// of such carrier cells in the parent. None if the parent has no somatic variation.
+    fn subclonal_carriers(
+        &self,
+        parent: usize,
+        parent_somatic_vaf: AlleleFreq,
+    ) -> Option<(f64, f64)> {
+        if parent_somatic_vaf.abs() <= SOMATIC_EPSILON {
+            return None;
+        }
+        let ploidy = self.ploidies.as_ref().unwrap()[parent].unwrap() as f64;
+        // METHOD: carrier cells host the minimal number of altered allele copies that is able
+        // to explain the parental somatic VAF. The remaining cells do not carry the variant.
+        // Negative somatic VAFs denote a loss of allele copies (back mutation).
+        let n_copies = (parent_somatic_vaf.abs() * ploidy - SOMATIC_EPSILON)
+            .ceil()
+            .max(1.0);
+        let carrier_vaf = parent_somatic_vaf.signum() * n_copies / ploidy;
+        let prevalence = (*parent_somatic_vaf / carrier_vaf).min(1.0);
+        Some((carrier_vaf, prevalence))
+    }
+
+    /// Probability of the given de novo somatic VAF in the given sample.
+    fn prob_denovo_somatic_vaf(&self, sample: usize, somatic_vaf: f64) -> LogProb {
+        if let Some(somatic_mutation_rate) = self.vartype_somatic_effective_mutation_rate(sample) {
+            self.prob_somatic_mutation(somatic_mutation_rate, AlleleFreq(somatic_vaf))
+        } else if somatic_vaf.abs() <= SOMATIC_EPSILON {
+            LogProb::ln_one()
+        } else {
+            LogProb::ln_zero()
+        }
+    }
+
     fn prob_subclonal_inheritance(
         &self,
         sample: usize,
@@ -508,119 +744,57 @@ impl Prior {
         germline_vafs: &[AlleleFreq],
         origin: grammar::SubcloneOrigin,
     ) -> LogProb {
-        warn!("subclonal inheritance implementation is not yet completed and will likely yield wrong results");
-        let total_vaf = event[sample].allele_freq;
-        let germline_vaf = germline_vafs[sample];
-        if !relative_eq!(*germline_vaf, *germline_vafs[parent]) {
-            LogProb::ln_zero()
-        } else {
-            let parent_somatic_vaf = self.effective_somatic_vaf(parent, event, germline_vafs);
-            let parent_total_vaf = event[parent].allele_freq;
-            match (origin, self.vartype_somatic_effective_mutation_rate(sample)) {
-                (grammar::SubcloneOrigin::SingleCell, None) => {
-                    // METHOD: no de novo somatic mutation. total_vaf must reflect ploidy.
-                    if *parent_total_vaf == 1.0 {
-                        if *total_vaf == 1.0 {
-                            LogProb::ln_one()
-                        } else {
-                            // METHOD: impossible, since all parental allele copies host the variant.
-                            LogProb::ln_zero()
-                        }
-                    } else if self.is_valid_germline_vaf(sample, total_vaf) {
-                        let prob_alt = LogProb::from(Prob::from(*parent_total_vaf));
-                        if *total_vaf > 0.0 {
-                            // METHOD: alt present, hence the cell has to come from the subclone with the alt allele.
-                            prob_alt
-                        } else {
-                            // METHOD: alt not present, hence the cell has to come from the ref subclone.
-                            prob_alt.ln_one_minus_exp()
-                        }
-                    } else {
-                        // METHOD: VAF must reflect ploidy.
-                        LogProb::ln_zero()
-                    }
-                }
-                (grammar::SubcloneOrigin::MultiCell, None) => {
-                    if *parent_somatic_vaf == 1.0 {
-                        if *total_vaf == 1.0 {
-                            // METHOD: The parent has a VAF of 1.0.
-                            // In this case, it must be inherited unmodified.
-                            LogProb::ln_one()
-                        } else {
-                            LogProb::ln_zero()
-                        }
-                    } else {
-                        // METHOD: anything is possible here, since we do not know which cells were inherited.
-                        LogProb::ln_one()
-                    }
-                }
-                (grammar::SubcloneOrigin::MultiCell, Some(somatic_mutation_rate)) => {
-                    // METHOD: number of cells in the parent (this just needs to be high, the particular number is irrelevant)
-                    let n: f64 = 10000.0;
-                    let binom =
-                        distribution::Binomial::new(parent_somatic_vaf.abs(), n as u64).unwrap();
-                    // METHOD: we may inherit any fraction of the parental effective somatic vaf
-                    let density = |_, somatic_vaf: f64| {
-                        let somatic_vaf = AlleleFreq(somatic_vaf);
-                        let inherited_somatic_vaf = if *parent_somatic_vaf >= 0.0 {
-                            total_vaf - germline_vaf - somatic_vaf
-                        } else {
-                            somatic_vaf - total_vaf + germline_vaf
-                        };
-                        let q = LogProb(
-                            binom
-                                .pmf((n * inherited_somatic_vaf.abs()).round() as u64)
-                                .ln(),
-                        );
-                        let p = self.prob_somatic_mutation(somatic_mutation_rate, somatic_vaf);
-                        q + p
-                    };
+        if !relative_eq!(*germline_vafs[sample], *germline_vafs[parent]) {
+            return LogProb::ln_zero();
+        }
+        let somatic_vaf = *self.effective_somatic_vaf(sample, event, germline_vafs);
+        let parent_somatic_vaf = self.effective_somatic_vaf(parent, event, germline_vafs);
 
-                    LogProb::ln_simpsons_integrate_exp(density, 0.0, 1.0, 5)
-                }
-                (grammar::SubcloneOrigin::SingleCell, Some(somatic_mutation_rate)) => {
-                    if *parent_total_vaf == 1.0 {
-                        // de novo backmutation or no mutation
-                        let somatic_vaf = total_vaf - AlleleFreq(1.0);
-                        self.prob_somatic_mutation(somatic_mutation_rate, somatic_vaf)
-                    } else {
-                        let prob_alt = LogProb::from(Prob::from(*parent_total_vaf));
-                        let ploidy = self.ploidies.as_ref().unwrap()[sample].unwrap();
-                        if *total_vaf > 0.0 {
-                            // case 1: cell comes from the alt allele subclone
-                            (prob_alt
-                                + LogProb::ln_sum_exp(
-                                    &(0..=ploidy)
-                                        .into_iter()
-                                        .map(|n_alt| {
-                                            self.prob_somatic_mutation(
-                                                somatic_mutation_rate,
-                                                total_vaf
-                                                    - AlleleFreq(n_alt as f64 / ploidy as f64),
-                                            )
-                                        })
-                                        .collect_vec(),
-                                ))
-                            .ln_add_exp(
-                                // case 2: cell comes from the ref allele subclone
-                                prob_alt.ln_one_minus_exp()
-                                    + self.prob_somatic_mutation(somatic_mutation_rate, total_vaf),
-                            )
-                        } else {
-                            // METHOD: alt not present, hence the cell has to come from the ref subclone or there is a backmutation.
-                            (prob_alt.ln_one_minus_exp()
-                                + self
-                                    .prob_somatic_mutation(somatic_mutation_rate, AlleleFreq(0.0)))
-                            .ln_add_exp(
-                                prob_alt
-                                    + self.prob_somatic_mutation(
-                                        somatic_mutation_rate,
-                                        -parent_total_vaf,
-                                    ),
-                            )
+        let (carrier_vaf, prevalence) =
+            if let Some(carriers) = self.subclonal_carriers(parent, parent_somatic_vaf) {
+                carriers
+            } else {
+                // METHOD: nothing to inherit, all effective somatic vaf must be de novo.
+                return self.prob_denovo_somatic_vaf(sample, somatic_vaf);
+            };
+
+        match origin {
+            grammar::SubcloneOrigin::SingleCell => {
+                // METHOD: the subclone is founded by a single parental cell, which is a carrier
+                // with probability given by the prevalence of carriers in the parent.
+                // On top of the inherited somatic vaf, there can be de novo somatic variation.
+                let prob_carrier = LogProb(prevalence.ln());
+                (prob_carrier + self.prob_denovo_somatic_vaf(sample, somatic_vaf - carrier_vaf))
+                    .ln_add_exp(
+                        prob_carrier.ln_one_minus_exp()
+                            + self.prob_denovo_somatic_vaf(sample, somatic_vaf),
+                    )
+            }
+            grammar::SubcloneOrigin::MultiCell => {
+                // METHOD: the subclone is founded by MULTICELL_FOUNDERS parental cells, the
+                // number of carriers among them is binomially distributed. The inherited
+                // somatic vaf is the carrier fraction among the founders times the
+                // carrier vaf.
+                let founders = distribution::Binomial::new(prevalence, MULTICELL_FOUNDERS).unwrap();
+                // METHOD: sum over the number of founding carriers, skipping those with
+                // negligible probability. As for single cell origins, inherited somatic vafs
+                // are point masses, and deviations from them have to be explained by de novo
+                // somatic variation (which is impossible without a somatic mutation rate).
+                let summands = (0..=MULTICELL_FOUNDERS)
+                    .filter_map(|n_carriers| {
+                        let prob_founders = LogProb(founders.ln_pmf(n_carriers));
+                        if *prob_founders < MULTICELL_MIN_LN_PROB {
+                            return None;
                         }
-                    }
-                }
+                        let inherited_vaf =
+                            carrier_vaf * n_carriers as f64 / MULTICELL_FOUNDERS as f64;
+                        Some(
+                            prob_founders
+                                + self.prob_denovo_somatic_vaf(sample, somatic_vaf - inherited_vaf),
+                        )
+                    })
+                    .collect_vec();
+                LogProb::ln_sum_exp(&summands)
             }
         }
     }
@@ -655,6 +829,31 @@ impl Prior {
         }
     }
 
+    /// Site specific population prior, assuming Hardy-Weinberg equilibrium for the given
+    /// population allele frequency, i.e., the number of alt alleles of each sample is binomially
+    /// distributed.
+    fn prob_population_hardy_weinberg(
+        &self,
+        population_samples: &[usize],
+        germline_vafs: &[AlleleFreq],
+        population_allele_freq: AlleleFreq,
+    ) -> LogProb {
+        let population_allele_freq = (*population_allele_freq).clamp(
+            POPULATION_ALLELE_FREQ_EPSILON,
+            1.0 - POPULATION_ALLELE_FREQ_EPSILON,
+        );
+        population_samples
+            .iter()
+            .map(|sample| {
+                let ploidy = self.ploidies.as_ref().unwrap()[*sample].unwrap();
+                let n_alt = (ploidy as f64 * *germline_vafs[*sample]).round() as u64;
+                let binom =
+                    distribution::Binomial::new(population_allele_freq, ploidy as u64).unwrap();
+                LogProb(binom.ln_pmf(n_alt))
+            })
+            .sum()
+    }
+
     fn prob_select_ref_alt_alleles(
         &self,
         ploidy: u32,
@@ -815,8 +1014,87 @@ impl UpdatablePrior for Prior {
         self.ploidies = Some(ploidies);
     }
 
-    fn set_variant_type(&mut self, variant_type: VariantType) {
-        self.variant_type = Some(variant_type);
+    fn set_variant_type(&mut self, variant_type: VariantType, site: Option<&Site>) -> Result<()> {
+        let context_mutation_rate = match (&variant_type, site) {
+            (VariantType::Snv, Some(site)) => self.lookup_context_mutation_rate(site)?,
+            _ => None,
+        };
+        if self.variant_type.as_ref() != Some(&variant_type)
+            || context_mutation_rate != self.context_mutation_rate
+        {
+            // cached probabilities depend on variant type and sequence context
+            self.cache.borrow_mut().clear();
+            self.variant_type = Some(variant_type);
+            self.context_mutation_rate = context_mutation_rate;
+        }
+        Ok(())
+    }
+
+    fn set_population_allele_freq(&mut self, allele_freq: Option<AlleleFreq>) {
+        if allele_freq != self.population_allele_freq {
+            // cached probabilities depend on the population allele frequency
+            self.cache.borrow_mut().clear();
+            self.population_allele_freq = allele_freq;
+        }
+    }
+
+    fn set_somatic_hotspot_weight(&mut self, weight: Option<f64>) {
+        if weight != self.somatic_hotspot_weight {
+            // cached probabilities depend on the somatic hotspot weight
+            self.cache.borrow_mut().clear();
+            self.somatic_hotspot_weight = weight;
+        }
+    }
+}
+
+impl LearnablePrior for Prior {
+    fn parameters(&self) -> Vec<(PriorParameter, f64)> {
+        let mut parameters = Vec::new();
+        if let Some(heterozygosity) = self.heterozygosity {
+            parameters.push((PriorParameter::Heterozygosity, heterozygosity.exp()));
+        }
+        for sample in 0..self.n_samples() {
+            if let Some(rate) = self.germline_mutation_rate[sample] {
+                parameters.push((PriorParameter::GermlineMutationRate(sample), rate));
+            }
+            if let Some(rate) = self.somatic_effective_mutation_rate[sample] {
+                parameters.push((PriorParameter::SomaticEffectiveMutationRate(sample), rate));
+            }
+        }
+        for (parameter, fraction) in &[
+            (
+                PriorParameter::IndelFraction,
+                self.variant_type_fractions.indel(),
+            ),
+            (
+                PriorParameter::MnvFraction,
+                self.variant_type_fractions.mnv(),
+            ),
+            (PriorParameter::SvFraction, self.variant_type_fractions.sv()),
+        ] {
+            if let Some(fraction) = fraction {
+                parameters.push((*parameter, *fraction));
+            }
+        }
+        parameters
+    }
+
+    fn set_parameter(&mut self, parameter: PriorParameter, value: f64) {
+        self.cache.borrow_mut().clear();
+        match parameter {
+            PriorParameter::Heterozygosity => {
+                self.heterozygosity = Some(LogProb::from(Prob(value)))
+            }
+            PriorParameter::GermlineMutationRate(sample) => {
+                self.germline_mutation_rate[sample] = Some(value)
+            }
+            PriorParameter::SomaticEffectiveMutationRate(sample) => {
+                self.somatic_effective_mutation_rate[sample] = Some(value)
+            }
+            PriorParameter::IndelFraction => self.variant_type_fractions.set_indel(value),
+            PriorParameter::MnvFraction => self.variant_type_fractions.set_mnv(value),
+            PriorParameter::SvFraction => self.variant_type_fractions.set_sv(value),
+        }
     }
 }
 
@@ -861,3 +1139,412 @@ impl CheckablePrior for Prior {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const N_SIMULATIONS: usize = 100000;
+
+    /// Prior for a diploid parent (sample 0) and a subclone (sample 1) derived from it.
+    fn subclone_prior(origin: grammar::SubcloneOrigin, somatic_rate: Option<f64>) -> Prior {
+        Prior::builder()
+            .uniform(vec![false, false].into())
+            .ploidies(Some(vec![Some(2), Some(2)].into()))
+            .universe(None)
+            .germline_mutation_rate(vec![None, None].into())
+            .somatic_effective_mutation_rate(vec![Some(1e-6), somatic_rate].into())
+            .heterozygosity(None)
+            .inheritance(vec![None, Some(Inheritance::Subclonal { from: 0, origin })].into())
+            .genome_size(Some(3.5e9))
+            .variant_type_fractions(grammar::VariantTypeFraction::default())
+            .variant_type(Some(VariantType::Snv))
+            .build()
+    }
+
+    fn prob_subclone(prior: &Prior, parent_vaf: f64, vaf: f64) -> f64 {
+        let event = vec![
+            likelihood::Event {
+                allele_freq: AlleleFreq(parent_vaf),
+                biases: Biases::none(),
+            },
+            likelihood::Event {
+                allele_freq: AlleleFreq(vaf),
+                biases: Biases::none(),
+            },
+        ];
+        let germline_vafs = vec![AlleleFreq(0.0), AlleleFreq(0.0)];
+        let origin = match prior.inheritance[1] {
+            Some(Inheritance::Subclonal { origin, .. }) => origin,
+            _ => unreachable!(),
+        };
+        prior
+            .prob_subclonal_inheritance(1, 0, &event, &germline_vafs, origin)
+            .exp()
+    }
+
+    /// Simulate the cell lineage of a parental tissue of diploid cells, growing from a single
+    /// cell by random cell divisions until it consists of the given number of cells. Once the
+    /// tissue has reached `mutation_at` cells, a heterozygous somatic mutation occurs in a random
+    /// cell and is passed on to all its descendants. Returns whether each cell carries the
+    /// mutation.
+    fn simulate_tissue(n_cells: usize, mutation_at: usize) -> Vec<bool> {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut cells = vec![false];
+        while cells.len() < n_cells {
+            if cells.len() == mutation_at {
+                let cell = rng.gen_range(0, cells.len());
+                cells[cell] = true;
+            }
+            let cell = rng.gen_range(0, cells.len());
+            cells.push(cells[cell]);
+        }
+        cells
+    }
+
+    /// Somatic vaf of the given tissue.
+    fn tissue_vaf(tissue: &[bool]) -> f64 {
+        0.5 * tissue.iter().filter(|carrier| **carrier).count() as f64 / tissue.len() as f64
+    }
+
+    /// Simulate subclones that are founded by the given number of distinct cells of the given
+    /// tissue, and return their somatic vafs (assuming that all founders contribute equally to
+    /// the subclone).
+    fn simulate_subclones(tissue: &[bool], n_founders: usize) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(42);
+        (0..N_SIMULATIONS)
+            .map(|_| {
+                let founders = rand::seq::index::sample(&mut rng, tissue.len(), n_founders)
+                    .iter()
+                    .map(|cell| tissue[cell])
+                    .collect_vec();
+                tissue_vaf(&founders)
+            })
+            .collect()
+    }
+
+    fn simulated_freq(simulated: &[f64], vaf: f64) -> f64 {
+        simulated
+            .iter()
+            .filter(|simulated_vaf| relative_eq!(**simulated_vaf, vaf))
+            .count() as f64
+            / simulated.len() as f64
+    }
+
+    #[test]
+    fn test_subclonal_inheritance_single_cell() {
+        let prior = subclone_prior(grammar::SubcloneOrigin::SingleCell, None);
+        let tissue = simulate_tissue(10000, 3);
+        let simulated = simulate_subclones(&tissue, 1);
+        let parent_vaf = tissue_vaf(&tissue);
+
+        for &vaf in &[0.0, 0.5] {
+            assert_relative_eq!(
+                prob_subclone(&prior, parent_vaf, vaf),
+                simulated_freq(&simulated, vaf),
+                epsilon = 0.01
+            );
+        }
+        // subclones from a single cell can only carry whole allele copies
+        assert_relative_eq!(prob_subclone(&prior, parent_vaf, 0.15), 0.0);
+        assert_relative_eq!(prob_subclone(&prior, parent_vaf, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_subclonal_inheritance_single_cell_clonal_parent() {
+        let prior = subclone_prior(grammar::SubcloneOrigin::SingleCell, None);
+        assert_relative_eq!(prob_subclone(&prior, 0.5, 0.5), 1.0);
+        assert_relative_eq!(prob_subclone(&prior, 0.5, 0.0), 0.0);
+        assert_relative_eq!(prob_subclone(&prior, 0.0, 0.0), 1.0);
+        assert_relative_eq!(prob_subclone(&prior, 0.0, 0.5), 0.0);
+    }
+
+    #[test]
+    fn test_subclonal_inheritance_multi_cell() {
+        let prior = subclone_prior(grammar::SubcloneOrigin::MultiCell, None);
+        let tissue = simulate_tissue(10000, 3);
+        let simulated = simulate_subclones(&tissue, MULTICELL_FOUNDERS as usize);
+        let parent_vaf = tissue_vaf(&tissue);
+
+        let expected_carriers = (2.0 * parent_vaf * MULTICELL_FOUNDERS as f64).round() as u64;
+        for n_carriers in expected_carriers - 10..=expected_carriers + 10 {
+            let vaf = 0.5 * n_carriers as f64 / MULTICELL_FOUNDERS as f64;
+            assert_relative_eq!(
+                prob_subclone(&prior, parent_vaf, vaf),
+                simulated_freq(&simulated, vaf),
+                epsilon = 0.01
+            );
+        }
+        // without de novo somatic variation, only inherited somatic vafs are possible
+        assert_relative_eq!(prob_subclone(&prior, parent_vaf, 0.1925), 0.0);
+        // the parental somatic vaf is the most likely one
+        let parent_vaf = 0.15;
+        let probs = (0..=50)
+            .map(|i| prob_subclone(&prior, parent_vaf, i as f64 / 100.0))
+            .collect_vec();
+        assert_eq!(
+            probs
+                .iter()
+                .position_max_by(|a, b| a.partial_cmp(b).unwrap()),
+            Some(15)
+        );
+    }
+
+    #[test]
+    fn test_subclonal_inheritance_denovo() {
+        // With de novo somatic mutations, the inherited configurations are only disturbed by
+        // a small amount of probability mass.
+        for &origin in &[
+            grammar::SubcloneOrigin::SingleCell,
+            grammar::SubcloneOrigin::MultiCell,
+        ] {
+            let without_denovo = subclone_prior(origin, None);
+            let with_denovo = subclone_prior(origin, Some(1e-6));
+            for &vaf in &[0.0, 0.15, 0.5] {
+                let prob = prob_subclone(&with_denovo, 0.15, vaf);
+                assert!(prob <= prob_subclone(&without_denovo, 0.15, vaf) + 1e-6);
+                assert_relative_eq!(
+                    prob,
+                    prob_subclone(&without_denovo, 0.15, vaf),
+                    epsilon = 0.01
+                );
+            }
+            // a de novo mutation in the subclone is possible, but unlikely
+            let prob_novel = prob_subclone(&with_denovo, 0.0, 0.3);
+            assert!(prob_novel > 0.0 && prob_novel < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_context_mutation_rates() {
+        let rates: grammar::ContextMutationRates =
+            serde_yaml::from_str("ACG: 10.0\nACG>T: 20.0\nTCT>G: 0.5").unwrap();
+        // rates are normalized by their mean over all 96 SNV channels:
+        // ACG>A and ACG>G (10), ACG>T (20), TCT>G (0.5), TCT>A and TCT>T (1), 90 unlisted (1)
+        let mean = (10.0 + 10.0 + 20.0 + 0.5 + 1.0 + 1.0 + 90.0) / 96.0;
+        // specific alternative base takes precedence
+        assert_relative_eq!(rates.rate(b"ACG", b'T'), 20.0 / mean);
+        assert_relative_eq!(rates.rate(b"ACG", b'A'), 10.0 / mean);
+        // reverse strand
+        assert_relative_eq!(rates.rate(b"CGT", b'A'), 20.0 / mean);
+        assert_relative_eq!(rates.rate(b"aga", b'c'), 0.5 / mean);
+        // unlisted contexts
+        assert_relative_eq!(rates.rate(b"TCA", b'T'), 1.0 / mean);
+
+        assert!(serde_yaml::from_str::<grammar::ContextMutationRates>("AC: 1.0").is_err());
+        assert!(serde_yaml::from_str::<grammar::ContextMutationRates>("ACG>C: 1.0").is_err());
+        assert!(serde_yaml::from_str::<grammar::ContextMutationRates>("ACG: 0.0").is_err());
+    }
+
+    #[test]
+    fn test_context_mutation_rates_normalization() {
+        let channels = grammar::ContextMutationRates::snv_channels().collect_vec();
+        assert_eq!(channels.len(), 96);
+        assert_eq!(channels.iter().unique().count(), 96);
+
+        let mean_rate = |rates: &grammar::ContextMutationRates| {
+            channels
+                .iter()
+                .map(|(context, alt_base)| rates.rate(context, *alt_base))
+                .sum::<f64>()
+                / channels.len() as f64
+        };
+        for spec in &[
+            "ACG: 10.0\nACG>T: 20.0\nTCT>G: 0.5",
+            "TCA: 0.1",
+            "ACA>G: 5.0",
+        ] {
+            let rates: grammar::ContextMutationRates = serde_yaml::from_str(spec).unwrap();
+            assert_relative_eq!(mean_rate(&rates), 1.0, epsilon = 1e-9);
+        }
+        // normalization preserves the ratios between rates, but elevated contexts lower the
+        // rate of all other contexts
+        let rates: grammar::ContextMutationRates =
+            serde_yaml::from_str("ACG: 10.0\nTCA: 10.0").unwrap();
+        assert!(rates.rate(b"ACG", b'T') > 1.0);
+        assert!(rates.rate(b"TTT", b'A') < 1.0);
+        assert_relative_eq!(
+            rates.rate(b"ACG", b'T') / rates.rate(b"TTT", b'A'),
+            10.0,
+            epsilon = 1e-9
+        );
+    }
+
+    #[test]
+    fn test_context_mutation_rate_scaling() {
+        let mut prior = subclone_prior(grammar::SubcloneOrigin::SingleCell, None);
+        let base_rate = prior.vartype_somatic_effective_mutation_rate(0).unwrap();
+        prior.context_mutation_rate = Some(10.0);
+        assert_relative_eq!(
+            prior.vartype_somatic_effective_mutation_rate(0).unwrap(),
+            10.0 * base_rate
+        );
+        // without a site, the context is unknown
+        prior.set_variant_type(VariantType::Snv, None).unwrap();
+        assert_relative_eq!(
+            prior.vartype_somatic_effective_mutation_rate(0).unwrap(),
+            base_rate
+        );
+    }
+
+    #[test]
+    fn test_somatic_hotspot_rate_scaling() {
+        let mut prior = subclone_prior(grammar::SubcloneOrigin::SingleCell, None);
+        let base_rate = prior.vartype_somatic_effective_mutation_rate(0).unwrap();
+        prior.set_somatic_hotspot_weight(Some(9.0));
+        assert_relative_eq!(
+            prior.vartype_somatic_effective_mutation_rate(0).unwrap(),
+            10.0 * base_rate
+        );
+        // negative weights do not decrease the rate
+        prior.set_somatic_hotspot_weight(Some(-5.0));
+        assert_relative_eq!(
+            prior.vartype_somatic_effective_mutation_rate(0).unwrap(),
+            base_rate
+        );
+        // huge weights are capped such that the probability of a somatic mutation is at most 1
+        prior.set_somatic_hotspot_weight(Some(1e20));
+        let capped_rate = prior.vartype_somatic_effective_mutation_rate(0).unwrap();
+        assert_relative_eq!(
+            capped_rate,
+            3.5e9 * SOMATIC_EPSILON / (1.0 - SOMATIC_EPSILON),
+            max_relative = 1e-9
+        );
+        let prob_no_mutation = prior.prob_somatic_mutation(capped_rate, AlleleFreq(0.0));
+        assert!(!prob_no_mutation.is_nan());
+        assert_relative_eq!(prob_no_mutation.exp(), 0.0, epsilon = 1e-6);
+        // the probability of a somatic mutation with VAF >= SOMATIC_EPSILON is the integral of
+        // the density
+        let prob_no_mutation = prior.prob_somatic_mutation(base_rate, AlleleFreq(0.0));
+        assert_relative_eq!(
+            prob_no_mutation.ln_one_minus_exp().exp(),
+            base_rate * (1.0 / SOMATIC_EPSILON - 1.0) / 3.5e9,
+            max_relative = 1e-4
+        );
+    }
+
+    /// Prior for a single diploid sample with germline variation, given by the heterozygosity.
+    fn germline_prior(heterozygosity: f64) -> Prior {
+        Prior::builder()
+            .uniform(vec![false].into())
+            .ploidies(Some(vec![Some(2)].into()))
+            .universe(None)
+            .germline_mutation_rate(vec![None].into())
+            .somatic_effective_mutation_rate(vec![None].into())
+            .heterozygosity(Some(LogProb(heterozygosity.ln())))
+            .inheritance(vec![None].into())
+            .genome_size(None)
+            .variant_type_fractions(grammar::VariantTypeFraction::default())
+            .variant_type(Some(VariantType::Snv))
+            .build()
+    }
+
+    fn germline_event(allele_freq: f64) -> Vec<likelihood::Event> {
+        vec![likelihood::Event {
+            allele_freq: AlleleFreq(allele_freq),
+            biases: Biases::none(),
+        }]
+    }
+
+    #[test]
+    fn test_population_hardy_weinberg() {
+        let prob = |prior: &Prior, allele_freq| prior.compute(&germline_event(allele_freq)).exp();
+        let mut prior = germline_prior(0.001);
+        let p = 0.2;
+        prior.set_population_allele_freq(Some(AlleleFreq(p)));
+        assert_relative_eq!(prob(&prior, 1.0), p * p, epsilon = 1e-9);
+        assert_relative_eq!(prob(&prior, 0.5), 2.0 * p * (1.0 - p), epsilon = 1e-9);
+        assert_relative_eq!(prob(&prior, 0.0), (1.0 - p) * (1.0 - p), epsilon = 1e-9);
+
+        // a population allele frequency of 1 does not rule out other genotypes
+        prior.set_population_allele_freq(Some(AlleleFreq(1.0)));
+        let eps = POPULATION_ALLELE_FREQ_EPSILON;
+        assert_relative_eq!(prob(&prior, 1.0), (1.0 - eps).powi(2), epsilon = 1e-9);
+        assert_relative_eq!(prob(&prior, 0.5), 2.0 * eps * (1.0 - eps), epsilon = 1e-9);
+        assert_relative_eq!(prob(&prior, 0.0), eps * eps, epsilon = 1e-12);
+        assert!(prob(&prior, 0.0) > 0.0);
+
+        // without a population allele frequency, the prior falls back to the heterozygosity
+        prior.set_population_allele_freq(None);
+        assert!(prob(&prior, 1.0) < 0.001);
+        assert!(prob(&prior, 0.0) > 0.99);
+    }
+
+    #[test]
+    fn test_parameters() {
+        let mut prior = germline_prior(0.001);
+        // variant type fractions are only learnable if they are defined in the scenario
+        assert_eq!(
+            prior.parameters(),
+            vec![(PriorParameter::Heterozygosity, 0.001)]
+        );
+        prior.set_parameter(PriorParameter::IndelFraction, 0.1);
+        assert_eq!(
+            prior.parameters(),
+            vec![
+                (PriorParameter::Heterozygosity, 0.001),
+                (PriorParameter::IndelFraction, 0.1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_maximize_marginal_likelihood() {
+        // METHOD: simulate germline genotypes and read counts with a known heterozygosity,
+        // and recover it from the marginal likelihood of the read counts, starting from a
+        // heterozygosity that is off by an order of magnitude.
+        let true_heterozygosity = 0.01;
+        let (n_sites, depth, error_rate) = (20000, 20, 0.01);
+        let allele_freqs = [0.0, 0.5, 1.0];
+        let prob_alt_read =
+            |allele_freq: f64| allele_freq * (1.0 - error_rate) + (1.0 - allele_freq) * error_rate;
+
+        let true_prior = germline_prior(true_heterozygosity);
+        let genotype_probs = allele_freqs
+            .iter()
+            .map(|allele_freq| true_prior.compute(&germline_event(*allele_freq)).exp())
+            .collect_vec();
+        let mut rng = StdRng::seed_from_u64(42);
+        let alt_counts = (0..n_sites)
+            .map(|_| {
+                let mut u: f64 = rng.gen();
+                let genotype = genotype_probs
+                    .iter()
+                    .position(|prob| {
+                        u -= prob;
+                        u < 0.0
+                    })
+                    .unwrap_or(allele_freqs.len() - 1);
+                let p = prob_alt_read(allele_freqs[genotype]);
+                (0..depth).filter(|_| rng.gen::<f64>() < p).count()
+            })
+            .collect_vec();
+
+        let log_marginal_likelihood = |prior: &Prior| -> Result<f64> {
+            Ok(alt_counts
+                .iter()
+                .map(|k| {
+                    let probs = allele_freqs
+                        .iter()
+                        .map(|allele_freq| {
+                            let p = prob_alt_read(*allele_freq);
+                            prior.compute(&germline_event(*allele_freq))
+                                + LogProb(*k as f64 * p.ln() + (depth - *k) as f64 * (1.0 - p).ln())
+                        })
+                        .collect_vec();
+                    *LogProb::ln_sum_exp(&probs)
+                })
+                .sum())
+        };
+
+        let learned = maximize_marginal_likelihood(
+            germline_prior(0.001),
+            &[PriorParameter::Heterozygosity],
+            log_marginal_likelihood,
+        )
+        .unwrap();
+        let learned_heterozygosity = learned.heterozygosity.unwrap().exp();
+        assert!((learned_heterozygosity / true_heterozygosity).log10().abs() < 0.2);
+    }
+}
@@ -3,6 +3,8 @@
 // This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp;
+use std::convert::TryFrom;
 use std::f64;
 use std::hash::Hash;
 use std::path::Path;
@@ -19,19 +21,23 @@ use rust_htslib::bam;
 
 use crate::estimation::alignment_properties;
 use crate::variants::evidence::observation::{
-    self, major_indel_operations, major_read_position, IndelOperations, Observable, Observation,
-    ReadPosition,
+    self, major_indel_operations, IndelOperations, Observable, Observation, ReadPosition,
 };
 use crate::variants::model::VariantType;
 use crate::variants::{self, types::Variant};
 
-#[derive(new, Getters, Debug)]
+#[derive(new, Getters, CopyGetters, Debug)]
 pub(crate) struct RecordBuffer {
     inner: bam::RecordBuffer,
     #[getset(get = "pub")]
     single_read_window: u64,
     #[getset(get = "pub")]
     read_pair_window: u64,
+    #[getset(get = "pub")]
+    duplicate_detection: Option<DuplicateDetection>,
+    /// Whether to consider alternative hits reported by the mapper for mapping probabilities.
+    #[getset(get_copy = "pub")]
+    use_alt_hits: bool,
 }
 
 impl RecordBuffer {
@@ -75,6 +81,134 @@ impl RecordBuffer {
             .filter(|record| is_valid_record(record.as_ref()))
             .map(|record| Rc::clone(record))
     }
+
+    /// Key of the fragment the given record stems from, in case on-the-fly duplicate
+    /// detection is enabled. Records of fragments with the same key are considered to be duplicates.
+    pub(crate) fn duplicate_key(&self, record: &bam::Record) -> Result<Option<DuplicateKey>> {
+        self.duplicate_detection
+            .as_ref()
+            .map(|detection| detection.key(record))
+            .transpose()
+    }
+
+    /// Like [`duplicate_key`](Self::duplicate_key), but for evidence that consists of single reads.
+    pub(crate) fn single_end_duplicate_key(
+        &self,
+        record: &bam::Record,
+    ) -> Result<Option<DuplicateKey>> {
+        self.duplicate_detection
+            .as_ref()
+            .map(|detection| detection.single_end_key(record))
+            .transpose()
+    }
+}
+
+/// Settings for detecting duplicate fragments that have not been marked in the BAM file.
+#[derive(new, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DuplicateDetection {
+    /// Auxiliary tag that contains the unique molecular identifier (UMI) of a read, if any.
+    umi_tag: Option<String>,
+}
+
+impl DuplicateDetection {
+    /// Key of a single read. Both reads of a pair yield the same fragment key, yet they are
+    /// independent pieces of single end evidence if they overlap the same locus. Hence, the key
+    /// additionally records which read of the pair the record is.
+    pub(crate) fn single_end_key(&self, record: &bam::Record) -> Result<DuplicateKey> {
+        Ok(DuplicateKey {
+            first_in_template: Some(record.is_first_in_template()),
+            ..self.key(record)?
+        })
+    }
+
+    pub(crate) fn key(&self, record: &bam::Record) -> Result<DuplicateKey> {
+        // METHOD: fragments are duplicates if the unclipped 5' ends of their reads (and the
+        // UMI, if available) coincide. Like with Picard, we use the unclipped ends, because
+        // duplicates can be clipped differently by the mapper. The 5' end of a reverse read is
+        // its rightmost base. For read pairs, the mate's 5' end is obtained from the mate
+        // CIGAR (MC tag) and approximated via the insert size if the tag is not available.
+        let five_prime = (
+            unclipped_five_prime(record.pos(), &record.cigar(), record.is_reverse()),
+            record.is_reverse(),
+        );
+        let mate_five_prime =
+            if record.is_paired() && !record.is_mate_unmapped() && record.tid() == record.mtid() {
+                let pos = if let Some(bam::record::Aux::String(mate_cigar)) = record.aux(b"MC") {
+                    let mate_cigar =
+                        bam::record::CigarString::try_from(str::from_utf8(mate_cigar)?)?;
+                    unclipped_five_prime(record.mpos(), &mate_cigar, record.is_mate_reverse())
+                } else if record.is_mate_reverse() {
+                    cmp::min(record.pos(), record.mpos()) + record.insert_size().abs() - 1
+                } else {
+                    record.mpos()
+                };
+                Some((pos, record.is_mate_reverse()))
+            } else {
+                None
+            };
+        // Both reads of a pair have to yield the same key.
+        let (five_prime, mate_five_prime) = match mate_five_prime {
+            Some(mate_five_prime) if mate_five_prime < five_prime => {
+                (mate_five_prime, Some(five_prime))
+            }
+            _ => (five_prime, mate_five_prime),
+        };
+        let umi = self.umi_tag.as_ref().and_then(|tag| {
+            if let Some(bam::record::Aux::String(umi)) = record.aux(tag.as_bytes()) {
+                Some(umi.to_owned())
+            } else {
+                None
+            }
+        });
+
+        Ok(DuplicateKey {
+            tid: record.tid(),
+            five_prime,
+            mate_five_prime,
+            umi,
+            first_in_template: None,
+        })
+    }
+}
+
+/// Reference position of the 5' end of an alignment with the given start and CIGAR, as if
+/// the read was not clipped.
+fn unclipped_five_prime(pos: i64, cigar: &[bam::record::Cigar], reverse: bool) -> i64 {
+    let clip_len = |op: &bam::record::Cigar| match op {
+        bam::record::Cigar::SoftClip(l) | bam::record::Cigar::HardClip(l) => Some(*l as i64),
+        _ => None,
+    };
+    if reverse {
+        let ref_len: i64 = cigar
+            .iter()
+            .map(|op| match op {
+                bam::record::Cigar::Match(l)
+                | bam::record::Cigar::RefSkip(l)
+                | bam::record::Cigar::Del(l)
+                | bam::record::Cigar::Equal(l)
+                | bam::record::Cigar::Diff(l) => *l as i64,
+                _ => 0,
+            })
+            .sum();
+        let trailing_clips: i64 = cigar.iter().rev().map_while(clip_len).sum();
+        pos + ref_len - 1 + trailing_clips
+    } else {
+        let leading_clips: i64 = cigar.iter().map_while(clip_len).sum();
+        pos - leading_clips
+    }
+}
+
+/// Signature of a fragment, identical for all duplicates of that fragment.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct DuplicateKey {
+    tid: i32,
+    /// Leftmost unclipped 5' end (position and whether it belongs to a reverse read).
+    five_prime: (i64, bool),
+    /// Unclipped 5' end of the other read of the pair, if any.
+    mate_five_prime: Option<(i64, bool)>,
+    umi: Option<Vec<u8>>,
+    /// Whether the record is the first read of its pair, if the key refers to a single read.
+    first_in_template: Option<bool>,
 }
 
 #[derive(Default, Derefable)]
@@ -167,9 +301,17 @@ pub(crate) fn estimate_alignment_properties<P: AsRef<Path>>(
     path: P,
     omit_insert_size: bool,
     allow_hardclips: bool,
+    estimate_mapq_calibration: bool,
+    empirical_insert_size: bool,
 ) -> Result<alignment_properties::AlignmentProperties> {
     let mut bam = bam::Reader::from_path(path)?;
-    alignment_properties::AlignmentProperties::estimate(&mut bam, omit_insert_size, allow_hardclips)
+    alignment_properties::AlignmentProperties::estimate(
+        &mut bam,
+        omit_insert_size,
+        allow_hardclips,
+        estimate_mapq_calibration,
+        empirical_insert_size,
+    )
 }
 
 /// A sequenced sample, e.g., a tumor or a normal sample.
@@ -192,17 +334,20 @@ impl SampleBuilder {
     ///
     /// # Arguments
     /// * `bam` - BAM file with the aligned and deduplicated sequence reads.
+    /// * `duplicate_detection` - Optionally detect unmarked duplicate fragments on the fly.
+    /// * `use_alt_hits` - Consider alternative hits (XA tag) reported by the mapper.
     pub(crate) fn alignments(
         self,
         bam: bam::IndexedReader,
         alignment_properties: alignment_properties::AlignmentProperties,
         min_refetch_distance: u64,
+        duplicate_detection: Option<DuplicateDetection>,
+        use_alt_hits: bool,
     ) -> Self {
         let single_read_window = alignment_properties.max_read_len as u64;
-        let read_pair_window = match alignment_properties.insert_size {
-            Some(isize) => (isize.mean + isize.sd * 6.0) as u64,
-            None => single_read_window,
-        };
+        let read_pair_window = alignment_properties
+            .max_expected_insert_size()
+            .unwrap_or(single_read_window);
         let mut record_buffer = bam::RecordBuffer::new(bam, true);
         record_buffer.set_min_refetch_distance(min_refetch_distance);
         self.alignment_properties(alignment_properties)
@@ -210,11 +355,13 @@ impl SampleBuilder {
                 record_buffer,
                 single_read_window,
                 read_pair_window,
+                duplicate_detection,
+                use_alt_hits,
             ))
     }
 }
 
-fn is_valid_record(record: &bam::Record) -> bool {
+pub(crate) fn is_valid_record(record: &bam::Record) -> bool {
     !(record.is_secondary()
         || record.is_duplicate()
         || record.is_unmapped()
@@ -234,12 +381,170 @@ impl Sample {
             &mut self.alignment_properties,
             self.max_depth,
         )?;
-        // Process for each observation whether it is from the major read position or not.
-        let major_pos = major_read_position(&observations);
+        // Process for each observation whether it has the major indel operations or not.
         let major_indel_ops = major_indel_operations(&observations);
         Ok(observations
             .iter()
-            .map(|obs| obs.process(major_pos, major_indel_ops.as_ref()))
+            .map(|obs| obs.process(major_indel_ops.as_ref()))
             .collect())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use rust_htslib::bam::record::{Aux, Cigar, CigarString};
+
+    /// Mapped test record on tid 0 with the given position, CIGAR and flags. For paired
+    /// records, the mate is placed at the given position, with the given CIGAR (MC tag).
+    pub(crate) fn record(
+        pos: i64,
+        cigar: &str,
+        flags: u16,
+        mate: Option<(i64, Option<&str>)>,
+    ) -> bam::Record {
+        let cigar = CigarString::try_from(cigar).unwrap();
+        let len: u32 = cigar
+            .iter()
+            .map(|op| match op {
+                Cigar::Match(l) | Cigar::Ins(l) | Cigar::SoftClip(l) => *l,
+                Cigar::Equal(l) | Cigar::Diff(l) => *l,
+                _ => 0,
+            })
+            .sum();
+        let mut record = bam::Record::new();
+        record.set(
+            b"read",
+            Some(&cigar),
+            &vec![b'A'; len as usize],
+            &vec![30; len as usize],
+        );
+        record.set_tid(0);
+        record.set_pos(pos);
+        record.set_flags(flags);
+        if let Some((mpos, mate_cigar)) = mate {
+            record.set_mtid(0);
+            record.set_mpos(mpos);
+            if let Some(mate_cigar) = mate_cigar {
+                record.push_aux(b"MC", &Aux::String(mate_cigar.as_bytes()));
+            }
+        } else {
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+        }
+        record
+    }
+
+    const PAIRED: u16 = 0x1;
+    const REVERSE: u16 = 0x10;
+    const MATE_REVERSE: u16 = 0x20;
+    const FIRST_IN_TEMPLATE: u16 = 0x40;
+    const LAST_IN_TEMPLATE: u16 = 0x80;
+
+    #[test]
+    fn test_duplicate_key_softclips() {
+        let detection = DuplicateDetection::default();
+        // forward reads: the unclipped 5' end is the leftmost position
+        let key = detection.key(&record(100, "5S95M", 0, None)).unwrap();
+        assert_eq!(key.five_prime, (95, false));
+        assert_eq!(key, detection.key(&record(98, "3S97M", 0, None)).unwrap());
+        assert_eq!(key, detection.key(&record(95, "100M", 0, None)).unwrap());
+        assert_ne!(key, detection.key(&record(96, "100M", 0, None)).unwrap());
+        // reverse reads: the unclipped 5' end is the rightmost position
+        let key = detection
+            .key(&record(100, "90M10S", REVERSE, None))
+            .unwrap();
+        assert_eq!(key.five_prime, (199, true));
+        assert_eq!(
+            key,
+            detection.key(&record(105, "95M", REVERSE, None)).unwrap()
+        );
+        assert_ne!(
+            key,
+            detection.key(&record(100, "95M", REVERSE, None)).unwrap()
+        );
+        // same position, different strand
+        assert_ne!(
+            detection.key(&record(100, "100M", 0, None)).unwrap(),
+            detection.key(&record(1, "100M", REVERSE, None)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_duplicate_key_pairs() {
+        let detection = DuplicateDetection::default();
+        let left = record(
+            100,
+            "2S98M",
+            PAIRED | MATE_REVERSE,
+            Some((300, Some("90M10S"))),
+        );
+        let right = record(300, "90M10S", PAIRED | REVERSE, Some((100, Some("2S98M"))));
+        let key = detection.key(&left).unwrap();
+        assert_eq!(key.five_prime, (98, false));
+        assert_eq!(key.mate_five_prime, Some((399, true)));
+        // both reads of the pair yield the same key
+        assert_eq!(key, detection.key(&right).unwrap());
+        // duplicate with different clipping of the mate
+        let duplicate = record(98, "100M", PAIRED | MATE_REVERSE, Some((305, Some("95M"))));
+        assert_eq!(key, detection.key(&duplicate).unwrap());
+        // different fragment end
+        let other = record(98, "100M", PAIRED | MATE_REVERSE, Some((305, Some("96M"))));
+        assert_ne!(key, detection.key(&other).unwrap());
+    }
+
+    #[test]
+    fn test_duplicate_key_overlapping_mates() {
+        let detection = DuplicateDetection::default();
+        let left = record(
+            100,
+            "100M",
+            PAIRED | MATE_REVERSE | FIRST_IN_TEMPLATE,
+            Some((150, Some("100M"))),
+        );
+        let right = record(
+            150,
+            "100M",
+            PAIRED | REVERSE | LAST_IN_TEMPLATE,
+            Some((100, Some("100M"))),
+        );
+        // the fragment is the same, but as single reads, the overlapping mates are not duplicates
+        assert_eq!(
+            detection.key(&left).unwrap(),
+            detection.key(&right).unwrap()
+        );
+        assert_ne!(
+            detection.single_end_key(&left).unwrap(),
+            detection.single_end_key(&right).unwrap()
+        );
+        // a duplicate of the first read is still detected
+        let duplicate = record(
+            100,
+            "100M",
+            PAIRED | MATE_REVERSE | FIRST_IN_TEMPLATE,
+            Some((150, Some("100M"))),
+        );
+        assert_eq!(
+            detection.single_end_key(&left).unwrap(),
+            detection.single_end_key(&duplicate).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_duplicate_key_umi() {
+        let detection = DuplicateDetection::new(Some("RX".to_owned()));
+        let mut a = record(100, "100M", 0, None);
+        a.push_aux(b"RX", &Aux::String(b"ACGT"));
+        let mut b = record(100, "100M", 0, None);
+        b.push_aux(b"RX", &Aux::String(b"TTTT"));
+        assert_ne!(detection.key(&a).unwrap(), detection.key(&b).unwrap());
+    }
+
+    #[test]
+    fn test_duplicate_key_invalid_mate_cigar() {
+        let detection = DuplicateDetection::default();
+        let record = record(100, "100M", PAIRED, Some((300, Some("100Q"))));
+        assert!(detection.key(&record).is_err());
+    }
+}
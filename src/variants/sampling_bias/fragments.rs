@@ -9,37 +9,30 @@ use bio::stats::LogProb;
 use itertools::Itertools;
 use rgsl::randist::gaussian::ugaussian_P;
 
-use crate::estimation::alignment_properties::AlignmentProperties;
+use crate::estimation::alignment_properties::{AlignmentProperties, InsertSize};
 use crate::utils::NUMERICAL_EPSILON;
 use crate::variants::sampling_bias::SamplingBias;
 use crate::variants::types::Variant;
 
 pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
     /// Get range of insert sizes with probability above zero.
-    /// We use 6 SDs around the mean.
-    fn isize_pmf_range(&self, alignment_properties: &AlignmentProperties) -> Range<u64> {
-        match alignment_properties.insert_size {
-            Some(isize) => {
-                let m = isize.mean.round() as u64;
-                let s = isize.sd.ceil() as u64 * 6;
+    /// For the normal distribution, we use 6 SDs around the mean.
+    fn isize_pmf_range(&self, insert_size: &InsertSize) -> Range<u64> {
+        match insert_size.density {
+            Some(ref density) => density.support(),
+            None => {
+                let m = insert_size.mean.round() as u64;
+                let s = insert_size.sd.ceil() as u64 * 6;
                 m.saturating_sub(s)..m + s
-            },
-            None => panic!("Bug: Tried to create an isize_pmf_range(), but alignment_properties.insert_size was None.")
+            }
         }
     }
 
     /// Get probability of given insert size from distribution shifted by the given value.
-    fn isize_pmf(
-        &self,
-        insert_size: u64,
-        shift: f64,
-        alignment_properties: &AlignmentProperties,
-    ) -> LogProb {
-        match alignment_properties.insert_size {
-            Some(isize) => isize_pmf(insert_size as f64, isize.mean + shift, isize.sd),
-            None => panic!(
-                "Bug: Tried to get an isize_pmf(), but alignment_properties.insert_size was None."
-            ),
+    fn isize_pmf(&self, value: u64, shift: f64, insert_size: &InsertSize) -> LogProb {
+        match insert_size.density {
+            Some(ref density) => density.prob(value as f64 - shift),
+            None => isize_pmf(value as f64, insert_size.mean + shift, insert_size.sd),
         }
     }
 
@@ -53,7 +46,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         delta_ref: u64,
         delta_alt: u64,
         enclose_only: bool,
-        alignment_properties: &AlignmentProperties,
+        insert_size: &InsertSize,
     ) -> LogProb {
         let mut infeasible_read_pos_left = left_read_len.saturating_sub(left_feasible);
         let mut infeasible_read_pos_right = right_read_len.saturating_sub(right_feasible);
@@ -71,7 +64,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         // e.g., a ref fragment.
         let expected_p_alt = LogProb::ln_sum_exp(
             &self
-                .isize_pmf_range(alignment_properties)
+                .isize_pmf_range(insert_size)
                 .filter_map(|x| {
                     let internal_segment = x
                         .saturating_sub(left_read_len)
@@ -96,7 +89,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
                         None
                     } else {
                         // probability to sample a valid placement
-                        let p = self.isize_pmf(x, 0.0, alignment_properties)
+                        let p = self.isize_pmf(x, 0.0, insert_size)
                             + LogProb((valid_pos_alt as f64).ln() - (valid_pos_ref as f64).ln());
 
                         assert!(
@@ -119,6 +112,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         &self,
         left_read_len: u64,
         right_read_len: u64,
+        insert_size: &InsertSize,
         alignment_properties: &AlignmentProperties,
     ) -> LogProb {
         if let (Some(left_feasible), Some(right_feasible)) = (
@@ -136,7 +130,7 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
                     delta_ref,
                     delta_alt,
                     true,
-                    alignment_properties,
+                    insert_size,
                 );
             }
         }
@@ -144,19 +138,10 @@ pub(crate) trait FragmentSamplingBias: Variant + SamplingBias {
         LogProb::ln_zero()
     }
 
-    fn is_within_sd(
-        &self,
-        insert_size: u64,
-        shift: f64,
-        alignment_properties: &AlignmentProperties,
-    ) -> bool {
-        match alignment_properties.insert_size {
-            Some(isize) => {
-                let m = isize.mean + shift;
-                (insert_size as f64 - m).abs() <= isize.sd
-            },
-            None => panic!("Bug: Tried to check whether is_within_sd(), but alignment_properties.insert_size was None.")
-        }
+    fn is_within_sd(&self, value: u64, shift: f64, insert_size: &InsertSize) -> bool {
+        let (lower, upper) = insert_size.central_interval();
+        let value = value as f64 - shift;
+        value >= lower && value <= upper
     }
 }
 
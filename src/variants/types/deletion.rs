@@ -67,14 +67,17 @@ impl<R: Realigner> Deletion<R> {
         alignment_properties: &AlignmentProperties,
     ) -> Result<AlleleSupport> {
         let insert_size = estimate_insert_size(left_record, right_record)?;
+        // METHOD: fragments are scored against the insert size distribution of their read group.
+        let isize_dist = alignment_properties
+            .record_insert_size(left_record)
+            .expect("bug: allele_support_isize() called without insert size distribution");
 
-        let p_ref = self.isize_pmf(insert_size, 0.0, alignment_properties);
-        let p_alt = self.isize_pmf(insert_size, self.len() as f64, alignment_properties);
+        let p_ref = self.isize_pmf(insert_size, 0.0, isize_dist);
+        let p_alt = self.isize_pmf(insert_size, self.len() as f64, isize_dist);
 
         if (p_ref == LogProb::ln_zero()
-            && !self.is_within_sd(insert_size, self.len() as f64, alignment_properties))
-            || (p_alt == LogProb::ln_zero()
-                && !self.is_within_sd(insert_size, 0.0, alignment_properties))
+            && !self.is_within_sd(insert_size, self.len() as f64, isize_dist))
+            || (p_alt == LogProb::ln_zero() && !self.is_within_sd(insert_size, 0.0, isize_dist))
         {
             // METHOD: We cannot consider insert size as a reliable estimate here, because it is
             // outside of the numerical resolution for one of the alleles, and not within a
@@ -262,10 +265,11 @@ impl<R: Realigner> Variant for Deletion<R> {
     ) -> LogProb {
         match evidence {
             PairedEndEvidence::PairedEnd { left, right } => {
-                if alignment_properties.insert_size.is_some() {
+                if let Some(insert_size) = alignment_properties.record_insert_size(left) {
                     self.prob_sample_alt_fragment(
                         left.seq().len() as u64,
                         right.seq().len() as u64,
+                        insert_size,
                         alignment_properties,
                     )
                 } else {
@@ -18,7 +18,7 @@ use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::reference;
 use crate::utils;
 use crate::variants::evidence::bases::prob_read_base;
-use crate::variants::evidence::observation::Strand;
+use crate::variants::evidence::observation::{ReadPosition, Strand};
 use crate::variants::evidence::realignment::pairhmm::{ReadEmission, RefBaseEmission};
 use crate::variants::evidence::realignment::{Realignable, Realigner};
 use crate::variants::types::{
@@ -118,7 +118,7 @@ impl<R: Realigner> Variant for Mnv<R> {
             let mut prob_alt = LogProb::ln_one();
             let aux_strand_info = utils::aux_tag_strand_info(read);
             let mut strand = Strand::None;
-            let mut read_position = None;
+            let mut read_position = ReadPosition::None;
 
             for ((alt_base, ref_base), pos) in self
                 .alt_bases
@@ -132,9 +132,9 @@ impl<R: Realigner> Variant for Mnv<R> {
                     .unwrap()
                     .read_pos(pos as u32, false, false)?
                 {
-                    if read_position.is_none() {
+                    if read_position == ReadPosition::None {
                         // set first MNV position as read position
-                        read_position = Some(qpos);
+                        read_position = ReadPosition::new(qpos, read.seq_len());
                     }
                     let read_base = unsafe { read.seq().decoded_base_unchecked(qpos as usize) };
                     let base_qual = unsafe { *read.qual().get_unchecked(qpos as usize) };
@@ -8,14 +8,16 @@ use std::rc::Rc;
 
 use anyhow::Result;
 use bio::alignment::AlignmentOperation;
-use bio::stats::{LogProb, PHREDProb};
+use bio::stats::LogProb;
 use bio_types::genome::{self, AbstractInterval};
 use rust_htslib::bam;
 use vec_map::VecMap;
 
 use crate::estimation::alignment_properties::AlignmentProperties;
+use crate::variants::evidence::mapping;
 use crate::variants::evidence::observation::{
-    Evidence, Observable, Observation, PairedEndEvidence, SingleEndEvidence, Strand,
+    merge_duplicates, Evidence, Observable, Observation, PairedEndEvidence, ReadPosition,
+    SingleEndEvidence, Strand,
 };
 use crate::variants::sample;
 
@@ -48,7 +50,7 @@ pub(crate) struct AlleleSupport {
     strand: Strand,
     #[builder(default)]
     #[getset(get_copy = "pub")]
-    read_position: Option<u32>,
+    read_position: ReadPosition,
     #[builder(default)]
     #[getset(get = "pub")]
     indel_operations: Vec<AlignmentOperation>,
@@ -126,9 +128,13 @@ impl<V> Observable<SingleEndEvidence> for V
 where
     V: Variant<Evidence = SingleEndEvidence, Loci = SingleLocus>,
 {
-    fn prob_mapping(&self, evidence: &SingleEndEvidence) -> LogProb {
-        let prob_mismapping = LogProb::from(PHREDProb(evidence.mapq() as f64));
-        prob_mismapping.ln_one_minus_exp()
+    fn prob_mapping(
+        &self,
+        evidence: &SingleEndEvidence,
+        alignment_properties: &AlignmentProperties,
+        use_alt_hits: bool,
+    ) -> LogProb {
+        mapping::prob_mismapping(evidence, alignment_properties, use_alt_hits).ln_one_minus_exp()
     }
 
     fn extract_observations(
@@ -148,29 +154,36 @@ where
                 // it is larger in this region.
                 alignment_properties.update_max_cigar_ops_len(record.as_ref(), false);
 
-                let evidence = SingleEndEvidence::new(record);
+                let evidence = SingleEndEvidence::new(Rc::clone(&record));
                 if self
                     .is_valid_evidence(&evidence, alignment_properties)
                     .is_some()
                 {
-                    Some(evidence)
+                    Some(
+                        buffer
+                            .single_end_duplicate_key(record.as_ref())
+                            .map(|duplicate_key| (evidence, duplicate_key)),
+                    )
                 } else {
                     None
                 }
             })
-            .collect();
+            .collect::<Result<_>>()?;
 
         let mut subsampler = sample::SubsampleCandidates::new(max_depth, candidates.len());
 
+        let use_alt_hits = buffer.use_alt_hits();
         let mut observations = Vec::new();
-        for evidence in candidates {
+        for (evidence, duplicate_key) in candidates {
             if subsampler.keep() {
-                if let Some(obs) = self.evidence_to_observation(&evidence, alignment_properties)? {
-                    observations.push(obs);
+                if let Some(obs) =
+                    self.evidence_to_observation(&evidence, alignment_properties, use_alt_hits)?
+                {
+                    observations.push((obs, duplicate_key));
                 }
             }
         }
-        Ok(observations)
+        Ok(merge_duplicates(observations))
     }
 }
 
@@ -178,8 +191,15 @@ impl<V> Observable<PairedEndEvidence> for V
 where
     V: Variant<Evidence = PairedEndEvidence, Loci = MultiLocus>,
 {
-    fn prob_mapping(&self, evidence: &PairedEndEvidence) -> LogProb {
-        let prob = |record: &bam::Record| LogProb::from(PHREDProb(record.mapq() as f64));
+    fn prob_mapping(
+        &self,
+        evidence: &PairedEndEvidence,
+        alignment_properties: &AlignmentProperties,
+        use_alt_hits: bool,
+    ) -> LogProb {
+        let prob = |record: &bam::Record| {
+            mapping::prob_mismapping(record, alignment_properties, use_alt_hits)
+        };
         match evidence {
             PairedEndEvidence::SingleEnd(record) => prob(record).ln_one_minus_exp(),
             PairedEndEvidence::PairedEnd { left, right } => {
@@ -254,8 +274,8 @@ where
 
         let mut candidates = Vec::new();
         let mut locus_depth = VecMap::new();
-        let mut push_evidence = |evidence: PairedEndEvidence, idx| {
-            candidates.push(evidence);
+        let mut push_evidence = |evidence: PairedEndEvidence, idx, duplicate_key| {
+            candidates.push((evidence, duplicate_key));
             for i in idx {
                 let count = locus_depth.entry(i).or_insert(0);
                 *count += 1;
@@ -264,9 +284,11 @@ where
 
         for candidate in candidate_records.values() {
             if let Some(ref right) = candidate.right {
-                if candidate.left.mapq() == 0 || right.mapq() == 0 {
-                    // Ignore pairs with ambiguous alignments.
-                    // The statistical model does not consider them anyway.
+                if mapping::is_ambiguous(&candidate.left, buffer.use_alt_hits())
+                    || mapping::is_ambiguous(right, buffer.use_alt_hits())
+                {
+                    // Ignore pairs with ambiguous alignments, unless their alternative hits
+                    // are known. Otherwise, the statistical model does not consider them anyway.
                     continue;
                 }
                 let evidence = PairedEndEvidence::PairedEnd {
@@ -274,14 +296,14 @@ where
                     right: Rc::clone(right),
                 };
                 if let Some(idx) = self.is_valid_evidence(&evidence, alignment_properties) {
-                    push_evidence(evidence, idx);
+                    push_evidence(evidence, idx, buffer.duplicate_key(&candidate.left)?);
                 }
             } else {
                 // this is a single alignment with unmapped mate or mate outside of the
                 // region of interest
                 let evidence = PairedEndEvidence::SingleEnd(Rc::clone(&candidate.left));
                 if let Some(idx) = self.is_valid_evidence(&evidence, alignment_properties) {
-                    push_evidence(evidence, idx);
+                    push_evidence(evidence, idx, buffer.duplicate_key(&candidate.left)?);
                 }
             }
         }
@@ -291,16 +313,19 @@ where
         let subsample = locus_depth.values().all(|depth| *depth > max_depth);
         let mut subsampler = sample::SubsampleCandidates::new(max_depth, candidates.len());
 
+        let use_alt_hits = buffer.use_alt_hits();
         let mut observations = Vec::new();
-        for evidence in &candidates {
+        for (evidence, duplicate_key) in candidates {
             if !subsample || subsampler.keep() {
-                if let Some(obs) = self.evidence_to_observation(evidence, alignment_properties)? {
-                    observations.push(obs);
+                if let Some(obs) =
+                    self.evidence_to_observation(&evidence, alignment_properties, use_alt_hits)?
+                {
+                    observations.push((obs, duplicate_key));
                 }
             }
         }
 
-        Ok(observations)
+        Ok(merge_duplicates(observations))
     }
 }
 
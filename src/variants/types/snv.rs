@@ -17,7 +17,7 @@ use crate::estimation::alignment_properties::AlignmentProperties;
 use crate::reference;
 use crate::utils;
 use crate::variants::evidence::bases::prob_read_base;
-use crate::variants::evidence::observation::Strand;
+use crate::variants::evidence::observation::{ReadPosition, Strand};
 use crate::variants::evidence::realignment::pairhmm::{ReadEmission, RefBaseEmission};
 use crate::variants::evidence::realignment::{Realignable, Realigner};
 use crate::variants::types::{
@@ -145,7 +145,7 @@ impl<R: Realigner> Variant for Snv<R> {
                     .prob_ref_allele(prob_ref)
                     .prob_alt_allele(prob_alt)
                     .strand(strand)
-                    .read_position(Some(qpos))
+                    .read_position(ReadPosition::new(qpos, read.seq_len()))
                     .build()
                     .unwrap(),
             ))
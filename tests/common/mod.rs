@@ -120,6 +120,14 @@ pub(crate) trait Testcase {
         }
     }
 
+    fn omit_ffpe_bias(&self) -> bool {
+        if self.yaml()["omit_ffpe_bias"].is_badvalue() {
+            false
+        } else {
+            self.yaml()["omit_ffpe_bias"].as_bool().unwrap()
+        }
+    }
+
     fn yaml(&self) -> &Yaml {
         &self.inner()[0]
     }
@@ -234,7 +242,12 @@ pub(crate) trait Testcase {
                         omit_read_position_bias: self.omit_read_position_bias(),
                         omit_softclip_bias: self.omit_softclip_bias(),
                         omit_divindel_bias: self.omit_divindel_bias(),
+                        omit_ffpe_bias: self.omit_ffpe_bias(),
                         min_divindel_other_rate: 0.25,
+                        af_credible_interval: None,
+                        af_posterior_density: false,
+                        learn_prior: None,
+                        reference: None,
                         output: Some(self.output()),
                         mode: VariantCallMode::Generic {
                             scenario: self.scenario().unwrap(),
@@ -271,7 +284,12 @@ pub(crate) trait Testcase {
                         omit_read_position_bias: self.omit_read_position_bias(),
                         omit_softclip_bias: self.omit_softclip_bias(),
                         omit_divindel_bias: self.omit_divindel_bias(),
+                        omit_ffpe_bias: self.omit_ffpe_bias(),
                         min_divindel_other_rate: 0.25,
+                        af_credible_interval: None,
+                        af_posterior_density: false,
+                        learn_prior: None,
+                        reference: None,
                         output: Some(self.output()),
                         mode: VariantCallMode::TumorNormal {
                             tumor_observations: self
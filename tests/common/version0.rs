@@ -103,6 +103,11 @@ impl Testcase for TestcaseVersion0 {
                         reference_buffer_size: 10,
                         min_bam_refetch_distance: 1,
                         pairhmm_mode: "exact".to_owned(),
+                        detect_duplicates: false,
+                        umi_tag: None,
+                        use_alt_hits: false,
+                        estimate_mapq_calibration: false,
+                        empirical_insert_size: false,
                     },
                 };
 
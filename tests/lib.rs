@@ -1,5 +1,6 @@
 use std::fs;
 use std::path::Path;
+use std::process::Command;
 use std::str;
 use std::sync::Mutex;
 
@@ -193,12 +194,12 @@ fn cleanup_file(f: &str) {
     }
 }
 
-fn control_fdr(test: &str, event_str: &str, alpha: f64, local: bool) {
+fn control_fdr(test: &str, event_str: &str, alpha: f64, local: bool, smart: bool) {
     let basedir = basedir(test);
     let output = format!("{}/calls.filtered.bcf", basedir);
     cleanup_file(&output);
     varlociraptor::filtration::fdr::control_fdr(
-        &format!("{}/calls.matched.bcf", basedir),
+        Some(&format!("{}/calls.matched.bcf", basedir)),
         Some(&output),
         &[varlociraptor::SimpleEvent {
             name: event_str.to_owned(),
@@ -208,6 +209,7 @@ fn control_fdr(test: &str, event_str: &str, alpha: f64, local: bool) {
         )),
         LogProb::from(Prob(alpha)),
         local,
+        smart,
     )
     .unwrap();
 }
@@ -229,38 +231,193 @@ fn assert_call_number(test: &str, expected_calls: usize) {
 
 #[test]
 fn test_fdr_control1() {
-    control_fdr("test_fdr_ev_1", "SOMATIC", 0.05, false);
+    control_fdr("test_fdr_ev_1", "SOMATIC", 0.05, false, false);
     //assert_call_number("test_fdr_ev_1", 974);
 }
 
 #[test]
 fn test_fdr_control2() {
-    control_fdr("test_fdr_ev_2", "SOMATIC", 0.05, false);
+    control_fdr("test_fdr_ev_2", "SOMATIC", 0.05, false, false);
     assert_call_number("test_fdr_ev_2", 985);
 }
 
 /// same test, but low alpha
 #[test]
 fn test_fdr_control3() {
-    control_fdr("test_fdr_ev_3", "ABSENT", 0.001, false);
+    control_fdr("test_fdr_ev_3", "ABSENT", 0.001, false, false);
     assert_call_number("test_fdr_ev_3", 0);
 }
 
 #[test]
 fn test_fdr_control4() {
-    control_fdr("test_fdr_ev_4", "SOMATIC_TUMOR", 0.05, false);
+    control_fdr("test_fdr_ev_4", "SOMATIC_TUMOR", 0.05, false, false);
     assert_call_number("test_fdr_ev_4", 0);
 }
 
+#[test]
+fn test_fdr_control_smart() {
+    let basedir = basedir("test_fdr_ev_2");
+    let output = format!("{}/calls.smart.filtered.bcf", basedir);
+    cleanup_file(&output);
+    varlociraptor::filtration::fdr::control_fdr(
+        Some(&format!("{}/calls.matched.bcf", basedir)),
+        Some(&output),
+        &[varlociraptor::SimpleEvent {
+            name: "SOMATIC".to_owned(),
+        }],
+        Some(&varlociraptor::variants::model::VariantType::Deletion(
+            Some(1..30),
+        )),
+        LogProb::from(Prob(0.05)),
+        false,
+        true,
+    )
+    .unwrap();
+
+    // all remaining calls must have SOMATIC as their most likely event
+    let mut reader = bcf::Reader::from_path(&output).unwrap();
+    let records = reader.records().map(|r| r.unwrap()).collect_vec();
+    assert!(!records.is_empty(), "expecting at least one call");
+    for record in &records {
+        let map_events = record.info(b"MAP_EVENT").string().unwrap().unwrap();
+        assert!(map_events.iter().all(|event| *event == b"SOMATIC"));
+    }
+}
+
+#[test]
+fn test_fdr_control_stdin() {
+    // calls from STDIN are spooled to disk before being filtered
+    let basedir = basedir("test_fdr_ev_2");
+    let output = format!("{}/calls.stdin.filtered.bcf", basedir);
+    cleanup_file(&output);
+    let status = Command::new(env!("CARGO_BIN_EXE_varlociraptor"))
+        .args(&[
+            "filter-calls",
+            "control-fdr",
+            "--events",
+            "SOMATIC",
+            "--fdr",
+            "0.05",
+            "--var",
+            "DEL",
+            "--minlen",
+            "1",
+            "--maxlen",
+            "30",
+        ])
+        .stdin(fs::File::open(format!("{}/calls.matched.bcf", basedir)).unwrap())
+        .stdout(fs::File::create(&output).unwrap())
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    // same result as when reading from a file
+    let n_calls = bcf::Reader::from_path(&output).unwrap().records().count();
+    assert!((n_calls as i32 - 985).abs() <= 1);
+}
+
+#[test]
+fn test_fdr_annotate() {
+    let basedir = basedir("test_fdr_ev_1");
+    let input = format!("{}/calls.matched.bcf", basedir);
+    let output = format!("{}/calls.annotated.bcf", basedir);
+    cleanup_file(&output);
+    varlociraptor::filtration::fdr::annotate_fdr(
+        &input,
+        Some(&output),
+        &[varlociraptor::SimpleEvent {
+            name: "SOMATIC".to_owned(),
+        }],
+        None,
+        true,
+    )
+    .unwrap();
+
+    // no call is removed, and q-values are at most as large as the posterior error probabilities
+    let n_input = bcf::Reader::from_path(&input).unwrap().records().count();
+    let mut reader = bcf::Reader::from_path(&output).unwrap();
+    let records = reader.records().map(|r| r.unwrap()).collect_vec();
+    assert_eq!(records.len(), n_input);
+    for record in &records {
+        if let Some(qvalues) = record.info(b"Q_SOMATIC").float().unwrap() {
+            let peps = record.info(b"PEP_SOMATIC").float().unwrap().unwrap();
+            for (q, pep) in qvalues.iter().zip(peps.iter()) {
+                // PHRED scaled, hence smaller q-values have larger values
+                assert!(*q >= *pep - 1e-3);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fdr_control_joint() {
+    let control_fdr_joint = |tests: &[&str], outputs: &[String]| {
+        varlociraptor::filtration::fdr::control_fdr_joint(
+            &tests
+                .iter()
+                .map(|test| format!("{}/calls.matched.bcf", basedir(test)))
+                .collect_vec(),
+            outputs,
+            &[varlociraptor::SimpleEvent {
+                name: "SOMATIC".to_owned(),
+            }],
+            Some(&varlociraptor::variants::model::VariantType::Deletion(
+                Some(1..30),
+            )),
+            LogProb::from(Prob(0.05)),
+            false,
+            false,
+        )
+    };
+
+    // a cohort of a single call file yields the same result as regular FDR control
+    let output = format!("{}/calls.joint.filtered.bcf", basedir("test_fdr_ev_2"));
+    cleanup_file(&output);
+    control_fdr_joint(&["test_fdr_ev_2"], &[output.clone()]).unwrap();
+    let n_calls = bcf::Reader::from_path(&output).unwrap().records().count();
+    assert!((n_calls as i32 - 985).abs() <= 1);
+
+    // each call file needs an output
+    assert!(control_fdr_joint(&["test_fdr_ev_1", "test_fdr_ev_2"], &[output]).is_err());
+
+    // pooling two call files yields a joint threshold that differs from the per-file ones
+    let tests = ["test_fdr_ev_1", "test_fdr_ev_2"];
+    let n_calls_single = tests
+        .iter()
+        .map(|test| {
+            let output = format!("{}/calls.single.filtered.bcf", basedir(test));
+            cleanup_file(&output);
+            control_fdr_joint(&[test], &[output.clone()]).unwrap();
+            bcf::Reader::from_path(&output).unwrap().records().count()
+        })
+        .collect_vec();
+    let outputs = tests
+        .iter()
+        .map(|test| format!("{}/calls.joint.filtered.bcf", basedir(test)))
+        .collect_vec();
+    for output in &outputs {
+        cleanup_file(output);
+    }
+    control_fdr_joint(&tests, &outputs).unwrap();
+    let n_calls_joint = outputs
+        .iter()
+        .map(|output| bcf::Reader::from_path(output).unwrap().records().count())
+        .collect_vec();
+    // the first file has more confident calls than the second, hence the joint threshold
+    // is stricter for the first and more permissive for the second file
+    assert!(n_calls_joint[0] + 10 < n_calls_single[0]);
+    assert!(n_calls_joint[1] > n_calls_single[1] + 10);
+}
+
 #[test]
 fn test_fdr_control_local1() {
-    control_fdr("test_fdr_local1", "SOMATIC", 0.05, true);
+    control_fdr("test_fdr_local1", "SOMATIC", 0.05, true, false);
     assert_call_number("test_fdr_local1", 0);
 }
 
 #[test]
 fn test_fdr_control_local2() {
-    control_fdr("test_fdr_local2", "SOMATIC", 0.25, true);
+    control_fdr("test_fdr_local2", "SOMATIC", 0.25, true, false);
     assert_call_number("test_fdr_local2", 1);
 }
 
@@ -268,5 +425,11 @@ fn test_fdr_control_local2() {
 // Then, also encode SVLEN as negative again for deletions.
 //#[test]
 fn test_fdr_control5() {
-    control_fdr("test_fdr_control_out_of_bounds", "PRESENT", 0.05, false);
+    control_fdr(
+        "test_fdr_control_out_of_bounds",
+        "PRESENT",
+        0.05,
+        false,
+        false,
+    );
 }